@@ -1,4 +1,5 @@
 use std::process::{Command, ExitStatus, Output};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[cfg(not(target_os = "windows"))]
 use std::os::unix::process::ExitStatusExt;
@@ -20,6 +21,14 @@ fn main() {
 			.stdout,
 	)
 	.unwrap_or_default();
-	let git_hash = if output == String::default() { "dev".into() } else { output };
+	let git_hash = if output.trim().is_empty() { "dev" } else { output.trim() };
 	println!("cargo:rustc-env=GIT_HASH={git_hash}");
+
+	// Honour SOURCE_DATE_EPOCH so that reproducible builds stay reproducible
+	println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+	let build_timestamp = std::env::var("SOURCE_DATE_EPOCH")
+		.ok()
+		.and_then(|epoch| epoch.parse::<u64>().ok())
+		.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default());
+	println!("cargo:rustc-env=BUILD_TIMESTAMP={build_timestamp}");
 }
@@ -1,10 +1,9 @@
-use arc_swap::ArcSwap;
 use cached::proc_macro::cached;
+use cached::{Cached, CanExpire, ExpiringValueCache};
 use futures_lite::future::block_on;
 use futures_lite::{future::Boxed, FutureExt};
-use hyper::client::HttpConnector;
 use hyper::header::HeaderValue;
-use hyper::{body, body::Buf, client, header, Body, Client, Method, Request, Response, Uri};
+use hyper::{body, body::Buf, client, header, Body, Client, Method, Request, Response, StatusCode, Uri};
 use hyper_rustls::HttpsConnector;
 use libflate::gzip;
 use log::{error, trace, warn};
@@ -12,14 +11,26 @@ use once_cell::sync::Lazy;
 use percent_encoding::{percent_encode, CONTROLS};
 use serde_json::Value;
 
-use std::sync::atomic::Ordering;
-use std::sync::atomic::{AtomicBool, AtomicU16};
-use std::{io, result::Result};
-
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{atomic::Ordering, Arc};
+use std::{
+	io,
+	result::Result,
+	time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+use tokio::task::JoinHandle;
+
+use crate::config::get_setting;
+use crate::connector::{Proxy, ProxyConnector};
 use crate::dbg_msg;
-use crate::oauth::{force_refresh_token, token_daemon, Oauth};
+use crate::image_metadata::strip_metadata;
+use crate::metrics::UPSTREAM_METRICS;
+use crate::oauth::{force_refresh_token, pool_size, token_daemon, OauthPool, UpstreamRateLimit};
 use crate::server::RequestExt;
-use crate::utils::format_url;
+use crate::utils::{format_url, strip_image_metadata};
 
 const REDDIT_URL_BASE: &str = "https://oauth.reddit.com";
 const REDDIT_URL_BASE_HOST: &str = "oauth.reddit.com";
@@ -30,20 +41,53 @@ const REDDIT_SHORT_URL_BASE_HOST: &str = "redd.it";
 const ALTERNATIVE_REDDIT_URL_BASE: &str = "https://www.reddit.com";
 const ALTERNATIVE_REDDIT_URL_BASE_HOST: &str = "www.reddit.com";
 
-pub static CLIENT: Lazy<Client<HttpsConnector<HttpConnector>>> = Lazy::new(|| {
-	let https = hyper_rustls::HttpsConnectorBuilder::new().with_native_roots().https_only().enable_http1().build();
+pub static CLIENT: Lazy<Client<HttpsConnector<ProxyConnector>>> = Lazy::new(|| {
+	// Refuse to start rather than silently bypass a misconfigured proxy
+	let proxy = get_setting("REDLIB_PROXY").map(|url| Proxy::parse(&url).unwrap_or_else(|e| panic!("{e}")));
+	let https = hyper_rustls::HttpsConnectorBuilder::new()
+		.with_native_roots()
+		.https_only()
+		.enable_http1()
+		.wrap_connector(ProxyConnector::new(proxy));
 	client::Client::builder().build(https)
 });
 
-pub static OAUTH_CLIENT: Lazy<ArcSwap<Oauth>> = Lazy::new(|| {
-	let client = block_on(Oauth::new());
-	tokio::spawn(token_daemon());
-	ArcSwap::new(client.into())
+pub static OAUTH_CLIENT: Lazy<OauthPool> = Lazy::new(|| {
+	let pool = block_on(OauthPool::new(pool_size()));
+	let mut daemons = TOKEN_DAEMONS.lock().unwrap();
+	for index in 0..pool.len() {
+		daemons.push(tokio::spawn(token_daemon(index)));
+	}
+	drop(daemons);
+	pool
 });
 
-pub static OAUTH_RATELIMIT_REMAINING: AtomicU16 = AtomicU16::new(99);
+/// Whether the OAuth clients have been created. They are created in the
+/// background at startup, so that the server can answer health checks while
+/// logging in takes a while.
+pub fn oauth_ready() -> bool {
+	Lazy::get(&OAUTH_CLIENT).is_some()
+}
 
-pub static OAUTH_IS_ROLLING_OVER: AtomicBool = AtomicBool::new(false);
+// Tasks refreshing the tokens of OAUTH_CLIENT, stopped on shutdown
+static TOKEN_DAEMONS: std::sync::Mutex<Vec<JoinHandle<()>>> = std::sync::Mutex::new(Vec::new());
+
+/// Stop the token daemons and save the current tokens, if the OAuth clients
+/// were created.
+pub async fn stop_token_daemons() {
+	let Some(pool) = Lazy::get(&OAUTH_CLIENT) else {
+		return;
+	};
+	let daemons = std::mem::take(&mut *TOKEN_DAEMONS.lock().unwrap());
+	for daemon in &daemons {
+		daemon.abort();
+	}
+	for daemon in daemons {
+		// Aborted tasks end with a cancellation error
+		let _ = daemon.await;
+	}
+	pool.persist();
+}
 
 static URL_PAIRS: [(&str, &str); 2] = [
 	(ALTERNATIVE_REDDIT_URL_BASE, ALTERNATIVE_REDDIT_URL_BASE_HOST),
@@ -92,28 +136,19 @@ pub async fn canonical_path(path: String, tries: i8) -> Result<Option<String>, S
 		// If Reddit responds with a 2xx, then the path is already canonical.
 		200..=299 => Ok(Some(path)),
 
-		// If Reddit responds with a 301, then the path is redirected.
-		301 => match res.headers().get(header::LOCATION) {
+		// If Reddit responds with a permanent or temporary redirect, then the
+		// path is redirected.
+		301 | 302 | 307 | 308 => match res.headers().get(header::LOCATION) {
 			Some(val) => {
 				let Ok(original) = val.to_str() else {
 					return Err("Unable to decode Location header.".to_string());
 				};
 
-				// We need to strip the .json suffix from the original path.
-				// In addition, we want to remove share parameters.
-				// Cut it off here instead of letting it propagate all the way
-				// to main.rs
-				let stripped_uri = original.strip_suffix(".json").unwrap_or(original).split('?').next().unwrap_or_default();
-
-				// The reason why we now have to format_url, is because the new OAuth
-				// endpoints seem to return full paths, instead of relative paths.
-				// So we need to strip the .json suffix from the original path, and
-				// also remove all Reddit domain parts with format_url.
-				// Otherwise, it will literally redirect to Reddit.com.
-				let uri = format_url(stripped_uri);
-
-				// Decrement tries and try again
-				canonical_path(uri, tries - 1).await
+				match redirect_path(&path, original) {
+					// Decrement tries and try again
+					Some(uri) => canonical_path(uri, tries - 1).await,
+					None => Ok(None),
+				}
 			}
 			None => Ok(None),
 		},
@@ -137,6 +172,26 @@ pub async fn canonical_path(path: String, tries: i8) -> Result<Option<String>, S
 	}
 }
 
+/// The path on this instance that a redirect from `path` to `location`
+/// leads to, or `None` if it leads off Reddit or back to `path`.
+fn redirect_path(path: &str, location: &str) -> Option<String> {
+	// We need to strip the .json suffix from the original path. In addition,
+	// we want to remove share parameters. Cut it off here instead of letting
+	// it propagate all the way to main.rs
+	let stripped_uri = location.split('?').next().unwrap_or_default();
+	let stripped_uri = stripped_uri.strip_suffix(".json").unwrap_or(stripped_uri);
+
+	// The reason why we now have to format_url, is because the new OAuth
+	// endpoints seem to return full paths, instead of relative paths. So we
+	// also remove all Reddit domain parts with format_url. Otherwise, it will
+	// literally redirect to Reddit.com.
+	let uri = format_url(stripped_uri);
+
+	// Anything but a local path would send the user off the instance, and a
+	// redirect to the same path would loop
+	(uri.starts_with('/') && !uri.starts_with("//") && uri != path).then_some(uri)
+}
+
 pub async fn proxy(req: Request<Body>, format: &str) -> Result<Response<Body>, String> {
 	let mut url = format!("{format}?{}", req.uri().query().unwrap_or_default());
 
@@ -149,57 +204,226 @@ pub async fn proxy(req: Request<Body>, format: &str) -> Result<Response<Body>, S
 	stream(&url, &req).await
 }
 
+// Names Reddit has given the audio tracks of videos over time, newest first
+const AUDIO_TRACKS: [&str; 4] = ["DASH_AUDIO_128.mp4", "DASH_AUDIO_64.mp4", "DASH_audio.mp4", "audio"];
+
+/// Proxy the audio track of the Reddit video `id`, trying each known track
+/// name in turn. Videos without audio get a 404.
+pub async fn proxy_audio(req: Request<Body>) -> Result<Response<Body>, String> {
+	let id = req.param("id").unwrap_or_default();
+	for track in AUDIO_TRACKS {
+		let res = stream(&format!("https://v.redd.it/{id}/{track}"), &req).await?;
+		if res.status().is_success() || res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+			return Ok(res);
+		}
+	}
+	Response::builder().status(404).body(Body::empty()).map_err(|e| e.to_string())
+}
+
+/// Kinds of valid `Range` header
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+	/// A single byte range, possibly open-ended or a suffix
+	Single,
+	/// Several byte ranges
+	Multiple,
+}
+
+/// Check the value of a `Range` header, returning `None` if it is malformed.
+fn parse_range(value: &str) -> Option<RangeRequest> {
+	let ranges = value.trim().strip_prefix("bytes=")?;
+	let mut count = 0;
+	for range in ranges.split(',') {
+		let (start, end) = range.trim().split_once('-')?;
+		let start = if start.is_empty() { None } else { Some(start.parse::<u64>().ok()?) };
+		let end = if end.is_empty() { None } else { Some(end.parse::<u64>().ok()?) };
+		match (start, end) {
+			(None, None) => return None,
+			(Some(start), Some(end)) if start > end => return None,
+			_ => count += 1,
+		}
+	}
+	Some(if count > 1 { RangeRequest::Multiple } else { RangeRequest::Single })
+}
+
 async fn stream(url: &str, req: &Request<Body>) -> Result<Response<Body>, String> {
+	// Build the hyper client from the HTTPS connector.
+	let client: Client<_, Body> = CLIENT.clone();
+
+	stream_with(url, req, |request| client.request(request)).await
+}
+
+// Proxy `url` for `req`, sending the upstream requests with `send`.
+async fn stream_with<F, Fut, E>(url: &str, req: &Request<Body>, send: F) -> Result<Response<Body>, String>
+where
+	F: Fn(Request<Body>) -> Fut,
+	Fut: Future<Output = Result<Response<Body>, E>>,
+	E: std::fmt::Display,
+{
 	// First parameter is target URL (mandatory).
 	let parsed_uri = url.parse::<Uri>().map_err(|_| "Couldn't parse URL".to_string())?;
 
-	// Build the hyper client from the HTTPS connector.
-	let client: Client<_, Body> = CLIENT.clone();
+	let res = with_upstream_timeout(send(stream_request(&parsed_uri, req, true)?)).await.map(strip_response_headers)?;
+
+	if !strip_image_metadata() {
+		return Ok(res);
+	}
 
-	let mut builder = Request::get(parsed_uri);
+	// Part of an image can't be stripped, and might be the part holding its
+	// metadata, so fetch the whole image instead. The spec allows ignoring a
+	// range like this.
+	let res = if res.status() == StatusCode::PARTIAL_CONTENT && is_image(&res) {
+		with_upstream_timeout(send(stream_request(&parsed_uri, req, false)?)).await.map(strip_response_headers)?
+	} else {
+		res
+	};
+
+	if res.status() == StatusCode::OK {
+		strip_response_metadata(res).await
+	} else {
+		Ok(res)
+	}
+}
+
+// Build the upstream request for a proxied `req`, forwarding its byte range
+// only when `range` is set.
+fn stream_request(uri: &Uri, req: &Request<Body>, range: bool) -> Result<Request<Body>, String> {
+	let mut builder = Request::get(uri.clone());
 
 	// Copy useful headers from original request
-	for &key in &["Range", "If-Modified-Since", "Cache-Control"] {
+	for &key in &["If-Modified-Since", "Cache-Control"] {
 		if let Some(value) = req.headers().get(key) {
 			builder = builder.header(key, value);
 		}
 	}
 
-	let stream_request = builder.body(Body::empty()).map_err(|_| "Couldn't build empty body in stream".to_string())?;
-
-	client
-		.request(stream_request)
-		.await
-		.map(|mut res| {
-			let mut rm = |key: &str| res.headers_mut().remove(key);
-
-			rm("access-control-expose-headers");
-			rm("server");
-			rm("vary");
-			rm("etag");
-			rm("x-cdn");
-			rm("x-cdn-client-region");
-			rm("x-cdn-name");
-			rm("x-cdn-server-region");
-			rm("x-reddit-cdn");
-			rm("x-reddit-video-features");
-			rm("Nel");
-			rm("Report-To");
+	// Forward a single byte range so that media players can seek. Multiple
+	// ranges aren't supported, so they're ignored like malformed ones, which
+	// the spec allows and serves the whole file. Ranges Reddit can't satisfy
+	// still get its 416.
+	if let Some(value) = req.headers().get(header::RANGE).filter(|_| range).and_then(|val| val.to_str().ok()) {
+		if parse_range(value) == Some(RangeRequest::Single) {
+			builder = builder.header(header::RANGE, value);
+		}
+	}
 
-			res
-		})
-		.map_err(|e| e.to_string())
+	builder.body(Body::empty()).map_err(|_| "Couldn't build empty body in stream".to_string())
 }
 
-/// Makes a GET request to Reddit at `path`. By default, this will honor HTTP
+// Remove headers of a proxied response that would leak details of Reddit's
+// CDN, and advertise byte ranges.
+fn strip_response_headers(mut res: Response<Body>) -> Response<Body> {
+	let mut rm = |key: &str| res.headers_mut().remove(key);
+
+	rm("access-control-expose-headers");
+	rm("server");
+	rm("vary");
+	rm("etag");
+	rm("x-cdn");
+	rm("x-cdn-client-region");
+	rm("x-cdn-name");
+	rm("x-cdn-server-region");
+	rm("x-reddit-cdn");
+	rm("x-reddit-video-features");
+	rm("Nel");
+	rm("Report-To");
+
+	if res.status().is_success() && !res.headers().contains_key(header::ACCEPT_RANGES) {
+		res.headers_mut().insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+	}
+
+	res
+}
+
+fn is_image(res: &Response<Body>) -> bool {
+	res
+		.headers()
+		.get(header::CONTENT_TYPE)
+		.and_then(|val| val.to_str().ok())
+		.is_some_and(|val| val.starts_with("image/"))
+}
+
+// Strip metadata from an image response. The body must be buffered, since
+// metadata can come after the image data. Other responses are left untouched.
+async fn strip_response_metadata(res: Response<Body>) -> Result<Response<Body>, String> {
+	let content_type = res.headers().get(header::CONTENT_TYPE).and_then(|val| val.to_str().ok()).unwrap_or_default();
+	if !content_type.starts_with("image/") {
+		return Ok(res);
+	}
+	let content_type = content_type.to_string();
+
+	let (mut parts, body) = res.into_parts();
+	let data = body::to_bytes(body).await.map_err(|e| e.to_string())?;
+	let Some(stripped) = strip_metadata(&content_type, &data) else {
+		return Ok(Response::from_parts(parts, Body::from(data)));
+	};
+	parts.headers.insert(header::CONTENT_LENGTH, stripped.len().into());
+	Ok(Response::from_parts(parts, Body::from(stripped)))
+}
+
+/// Makes a GET request to Reddit at `path`, authenticated with the OAuth
+/// client at `client_index` in the pool. By default, this will honor HTTP
 /// 3xx codes Reddit returns and will automatically redirect.
-fn reddit_get(path: String, quarantine: bool) -> Boxed<Result<Response<Body>, String>> {
-	request(&Method::GET, path, true, quarantine, REDDIT_URL_BASE, REDDIT_URL_BASE_HOST)
+/// If `REDLIB_UPSTREAM_RETRIES` is set, requests that fail or get a 5xx
+/// response are retried that many times, with exponential backoff.
+fn reddit_get(path: String, quarantine: bool, client_index: usize) -> Boxed<Result<Response<Body>, String>> {
+	let retries = get_setting("REDLIB_UPSTREAM_RETRIES").and_then(|val| val.parse::<u32>().ok()).unwrap_or(0);
+	async move {
+		let mut attempt = 0;
+		loop {
+			let result = request(&Method::GET, path.clone(), true, quarantine, REDDIT_URL_BASE, REDDIT_URL_BASE_HOST, client_index).await;
+			let transient = match &result {
+				Ok(response) => response.status().is_server_error(),
+				Err(_) => true,
+			};
+			if !transient || attempt >= retries {
+				return result;
+			}
+			attempt += 1;
+			let delay = retry_delay(attempt);
+			warn!("Request to {path} failed, retrying in {delay:?} (retry {attempt}/{retries})");
+			tokio::time::sleep(delay).await;
+		}
+	}
+	.boxed()
+}
+
+// Time to wait for Reddit to respond, unless overridden by REDLIB_UPSTREAM_TIMEOUT
+const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Wait for a response from Reddit, giving up after REDLIB_UPSTREAM_TIMEOUT
+// seconds so that a stalled upstream can't tie up the request forever.
+async fn with_upstream_timeout<E: std::fmt::Display>(request: impl Future<Output = Result<Response<Body>, E>>) -> Result<Response<Body>, String> {
+	let duration = get_setting("REDLIB_UPSTREAM_TIMEOUT")
+		.and_then(|val| val.parse::<u64>().ok())
+		.filter(|&val| val > 0)
+		.map_or(DEFAULT_UPSTREAM_TIMEOUT, Duration::from_secs);
+	let started = Instant::now();
+	let result = match tokio::time::timeout(duration, request).await {
+		Ok(result) => result.map_err(|e| e.to_string()),
+		Err(_) => Err(format!("Timed out after {duration:?} waiting for Reddit")),
+	};
+	let success = result.as_ref().is_ok_and(|res| !res.status().is_server_error());
+	UPSTREAM_METRICS.record(started.elapsed(), success);
+	result
+}
+
+// Delay before the first retry of a failed upstream request
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Delay before the `attempt`th retry: doubled for every retry, plus up to
+/// 50% of random jitter.
+fn retry_delay(attempt: u32) -> Duration {
+	let delay = INITIAL_RETRY_DELAY * 2u32.saturating_pow(attempt.saturating_sub(1));
+	delay + delay.mul_f64(fastrand::f64() / 2.0)
 }
 
 /// Makes a HEAD request to Reddit at `path, using the short URL base. This will not follow redirects.
 fn reddit_short_head(path: String, quarantine: bool, base_path: &'static str, host: &'static str) -> Boxed<Result<Response<Body>, String>> {
-	request(&Method::HEAD, path, false, quarantine, base_path, host)
+	if !oauth_ready() {
+		return async { Err(STARTING_ERROR.to_string()) }.boxed();
+	}
+	request(&Method::HEAD, path, false, quarantine, base_path, host, OAUTH_CLIENT.next_index())
 }
 
 // /// Makes a HEAD request to Reddit at `path`. This will not follow redirects.
@@ -210,8 +434,17 @@ fn reddit_short_head(path: String, quarantine: bool, base_path: &'static str, ho
 
 /// Makes a request to Reddit. If `redirect` is `true`, `request_with_redirect`
 /// will recurse on the URL that Reddit provides in the Location HTTP header
-/// in its response.
-fn request(method: &'static Method, path: String, redirect: bool, quarantine: bool, base_path: &'static str, host: &'static str) -> Boxed<Result<Response<Body>, String>> {
+/// in its response. The request is authenticated with the OAuth client at
+/// `client_index` in the pool.
+fn request(
+	method: &'static Method,
+	path: String,
+	redirect: bool,
+	quarantine: bool,
+	base_path: &'static str,
+	host: &'static str,
+	client_index: usize,
+) -> Boxed<Result<Response<Body>, String>> {
 	// Build Reddit URL from path.
 	let url = format!("{base_path}{path}");
 
@@ -219,7 +452,7 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 	let client: Client<_, Body> = CLIENT.clone();
 
 	let (token, vendor_id, device_id, user_agent, loid) = {
-		let client = OAUTH_CLIENT.load_full();
+		let client = OAUTH_CLIENT.load(client_index);
 		(
 			client.token.clone(),
 			client.headers_map.get("Client-Vendor-Id").cloned().unwrap_or_default(),
@@ -229,8 +462,8 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 		)
 	};
 
-	// Build request to Reddit. When making a GET, request gzip compression.
-	// (Reddit doesn't do brotli yet.)
+	// Build request to Reddit. When making a GET, request gzip or brotli
+	// compression.
 	let builder = Request::builder()
 		.method(method)
 		.uri(&url)
@@ -240,7 +473,7 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 		.header("x-reddit-loid", loid)
 		.header("Host", host)
 		.header("Authorization", &format!("Bearer {token}"))
-		.header("Accept-Encoding", if method == Method::GET { "gzip" } else { "identity" })
+		.header("Accept-Encoding", if method == Method::GET { "gzip, br" } else { "identity" })
 		.header("Accept-Language", "en-US,en;q=0.5")
 		.header("Connection", "keep-alive")
 		.header(
@@ -255,7 +488,7 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 
 	async move {
 		match builder {
-			Ok(req) => match client.request(req).await {
+			Ok(req) => match with_upstream_timeout(client.request(req)).await {
 				Ok(mut response) => {
 					// Reddit may respond with a 3xx. Decide whether or not to
 					// redirect based on caller params.
@@ -295,6 +528,7 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 							quarantine,
 							base_path,
 							host,
+							client_index,
 						)
 						.await;
 					};
@@ -303,40 +537,27 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 						// Content not compressed.
 						None => Ok(response),
 
-						// Content encoded (hopefully with gzip).
+						// Content encoded (hopefully with gzip or brotli).
 						Some(hdr) => {
-							match hdr.to_str() {
+							let encoding = match hdr.to_str() {
 								Ok(val) => match val {
-									"gzip" => {}
+									"gzip" | "br" => val.to_string(),
 									"identity" => return Ok(response),
 									_ => return Err("Reddit response was encoded with an unsupported compressor".to_string()),
 								},
 								Err(_) => return Err("Reddit response was invalid".to_string()),
-							}
+							};
 
-							// We get here if the body is gzip-compressed.
+							// We get here if the body is compressed.
 
 							// The body must be something that implements
 							// std::io::Read, hence the conversion to
 							// bytes::buf::Buf and then transformation into a
 							// Reader.
-							let mut decompressed: Vec<u8>;
-							{
-								let mut aggregated_body = match body::aggregate(response.body_mut()).await {
-									Ok(b) => b.reader(),
-									Err(e) => return Err(e.to_string()),
-								};
-
-								let mut decoder = match gzip::Decoder::new(&mut aggregated_body) {
-									Ok(decoder) => decoder,
-									Err(e) => return Err(e.to_string()),
-								};
-
-								decompressed = Vec::<u8>::new();
-								if let Err(e) = io::copy(&mut decoder, &mut decompressed) {
-									return Err(e.to_string());
-								};
-							}
+							let decompressed = match body::aggregate(response.body_mut()).await {
+								Ok(b) => decompress(&encoding, b.reader())?,
+								Err(e) => return Err(e.to_string()),
+							};
 
 							response.headers_mut().remove(header::CONTENT_ENCODING);
 							response.headers_mut().insert(header::CONTENT_LENGTH, decompressed.len().into());
@@ -358,26 +579,265 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 	.boxed()
 }
 
-// Make a request to a Reddit API and parse the JSON response
-#[cached(size = 100, time = 30, result = true)]
+// Decompress a response body encoded with `encoding` ("gzip" or "br")
+fn decompress(encoding: &str, mut body: impl io::Read) -> Result<Vec<u8>, String> {
+	let mut decompressed = Vec::<u8>::new();
+	let result = match encoding {
+		"gzip" => gzip::Decoder::new(&mut body).and_then(|mut decoder| io::copy(&mut decoder, &mut decompressed)),
+		"br" => io::copy(&mut brotli::Decompressor::new(&mut body, 4096), &mut decompressed),
+		_ => return Err(format!("Unsupported content encoding {encoding}")),
+	};
+	result.map(|_| decompressed).map_err(|e| e.to_string())
+}
+
+// Reasons Reddit gives for refusing access to a subreddit
+pub const RESTRICTIONS: [&str; 4] = ["quarantined", "gated", "private", "banned"];
+
+// Fields of Reddit's error explaining a restriction, when there is one
+const RESTRICTION_REASONS: [&str; 2] = ["quarantine_message", "interstitial_warning_message"];
+
+/// Split the error returned by [`json`] for a restricted subreddit into the
+/// kind of restriction and Reddit's explanation of it, which may be empty.
+pub fn restriction(msg: &str) -> Option<(&str, &str)> {
+	let (kind, reason) = msg.split_once(": ").unwrap_or((msg, ""));
+	RESTRICTIONS.contains(&kind).then_some((kind, reason))
+}
+
+// Error returned while the OAuth clients are still being created
+const STARTING_ERROR: &str = "Redlib is still starting up";
+
+// Errors returned when Reddit can't answer at all, rather than refusing a page
+const OUTAGE_ERROR: &str = "Reddit is having issues, check if there's an outage";
+const RATE_LIMIT_ERROR: &str = "Reddit rate limit exceeded";
+
+/// Ways in which Reddit can be unavailable, as told by an error of [`json`].
+/// They are passing states of Reddit, unlike Redlib's own errors, so users
+/// are asked to retry rather than to report them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailable {
+	/// Reddit is down or failing, possibly for some endpoints only
+	Outage,
+	/// Reddit is limiting the requests of this instance, until `reset`
+	/// seconds from now when it says so
+	RateLimited { reset: Option<u64> },
+	/// This instance hasn't obtained an OAuth token since it started
+	Starting,
+}
+
+impl Unavailable {
+	pub fn of(msg: &str) -> Option<Self> {
+		if msg.starts_with(OUTAGE_ERROR) {
+			Some(Self::Outage)
+		} else if msg.starts_with(STARTING_ERROR) {
+			Some(Self::Starting)
+		} else if let Some(rest) = msg.strip_prefix(RATE_LIMIT_ERROR) {
+			let reset = rest
+				.rsplit_once(": ")
+				.and_then(|(_, reset)| reset.trim().parse::<f64>().ok())
+				.map(|reset| reset.ceil() as u64);
+			Some(Self::RateLimited { reset })
+		} else {
+			None
+		}
+	}
+
+	/// Seconds after which to try again.
+	pub fn retry_after(self) -> u64 {
+		match self {
+			Self::Outage => 60,
+			Self::RateLimited { reset } => reset.unwrap_or(10),
+			Self::Starting => 5,
+		}
+	}
+}
+
+fn rate_limit_error(reset: Option<&str>) -> String {
+	match reset {
+		Some(reset) => format!("{RATE_LIMIT_ERROR}. Try refreshing in a few seconds. Rate limit will reset in: {reset}"),
+		None => RATE_LIMIT_ERROR.to_string(),
+	}
+}
+
+// Error returned when Reddit rejects our OAuth token
+const UNAUTHORIZED_ERROR: &str = "OAuth token has expired. Please refresh the page!";
+
+// Make a request to a Reddit API and parse the JSON response. Responses are
+// cached for a time depending on the kind of resource, keeping at most
+// REDLIB_CACHE_SIZE.
 pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
+	cached_json(path, quarantine).await.map(|cached| cached.value)
+}
+
+#[cached(
+	ty = "ExpiringValueCache<(String, bool), CachedJson>",
+	create = "{ ExpiringValueCache::with_size(json_cache_size()) }",
+	convert = "{ (path.clone(), quarantine) }",
+	result = true
+)]
+async fn cached_json(path: String, quarantine: bool) -> Result<CachedJson, String> {
+	let expires_at = Instant::now() + Duration::from_secs(Resource::of(&path).cache_ttl());
+	// Concurrent requests for the same page share a single upstream fetch
+	let value = single_flight(&JSON_IN_FLIGHT, (path.clone(), quarantine), json_uncached(path, quarantine)).await?;
+	Ok(CachedJson { value, expires_at })
+}
+
+/// Hits and misses of the cache of `json`.
+pub async fn json_cache_stats() -> (u64, u64) {
+	let cache = CACHED_JSON.lock().await;
+	(cache.cache_hits().unwrap_or_default(), cache.cache_misses().unwrap_or_default())
+}
+
+// A response cached by `json`
+#[derive(Clone)]
+struct CachedJson {
+	value: Value,
+	expires_at: Instant,
+}
+
+impl CanExpire for CachedJson {
+	fn is_expired(&self) -> bool {
+		Instant::now() >= self.expires_at
+	}
+}
+
+/// Kinds of resources requested from Reddit, which are cached for different
+/// times as some change faster than others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resource {
+	/// Subreddit and front page listings
+	Listing,
+	/// Posts and their comments
+	Post,
+	/// User profiles and listings
+	User,
+	Search,
+	/// Subreddit metadata, rules and wiki pages
+	About,
+	Other,
+}
+
+impl Resource {
+	/// The kind of resource at the API `path`.
+	fn of(path: &str) -> Self {
+		let path = path.split('?').next().unwrap_or_default();
+		let mut segments = path.split('/').filter(|s| !s.is_empty()).map(|s| s.trim_end_matches(".json"));
+		match (segments.next(), segments.next(), segments.next()) {
+			(Some("user" | "u"), ..) => Self::User,
+			(Some("search" | "subreddits"), ..) => Self::Search,
+			(Some("comments"), ..) => Self::Post,
+			(Some("r"), Some(_), Some("search")) => Self::Search,
+			(Some("r"), Some(_), Some("comments")) => Self::Post,
+			(Some("r"), Some(_), Some("about" | "wiki")) => Self::About,
+			(Some("r"), Some(_), _) | (None | Some("hot" | "new" | "top" | "rising" | "controversial" | "best"), ..) => Self::Listing,
+			_ => Self::Other,
+		}
+	}
+
+	// The setting overriding the cache time of this kind of resource
+	fn setting(self) -> Option<&'static str> {
+		match self {
+			Self::Listing => Some("REDLIB_CACHE_TTL_LISTING"),
+			Self::Post => Some("REDLIB_CACHE_TTL_POST"),
+			Self::User => Some("REDLIB_CACHE_TTL_USER"),
+			Self::Search => Some("REDLIB_CACHE_TTL_SEARCH"),
+			Self::About => Some("REDLIB_CACHE_TTL_ABOUT"),
+			Self::Other => None,
+		}
+	}
+
+	// Seconds to cache this kind of resource for, without any setting
+	fn default_cache_ttl(self) -> u64 {
+		match self {
+			Self::Listing | Self::Other => 30,
+			Self::Search => 60,
+			Self::Post | Self::User => 120,
+			Self::About => 600,
+		}
+	}
+
+	/// Seconds to cache this kind of resource for: its own setting, else
+	/// REDLIB_CACHE_TTL, else its default.
+	fn cache_ttl(self) -> u64 {
+		let parse = |name: &str| get_setting(name).and_then(|val| val.parse::<u64>().ok());
+		self
+			.setting()
+			.and_then(parse)
+			.or_else(|| parse("REDLIB_CACHE_TTL"))
+			.unwrap_or_else(|| self.default_cache_ttl())
+	}
+}
+
+// Like `json`, but always fetched from Reddit. This is for endpoints such as
+// /r/random, which respond differently every time.
+pub async fn json_uncached(path: String, quarantine: bool) -> Result<Value, String> {
+	if !oauth_ready() {
+		return Err(STARTING_ERROR.to_string());
+	}
+	// Pick a client from the pool for this request
+	let client_index = OAUTH_CLIENT.next_index();
+
+	// A token can be revoked before it expires. By the time Reddit rejects it,
+	// the token has already been refreshed, so retry once with the new one.
+	match json_with_client(path.clone(), quarantine, client_index).await {
+		Err(e) if e == UNAUTHORIZED_ERROR => json_with_client(path, quarantine, client_index).await,
+		result => result,
+	}
+}
+
+// Number of JSON responses to cache
+fn json_cache_size() -> usize {
+	get_setting("REDLIB_CACHE_SIZE")
+		.and_then(|val| val.parse::<usize>().ok())
+		.filter(|&val| val > 0)
+		.unwrap_or(100)
+}
+
+// Fetches that are currently in flight, with the requests waiting on them
+type InFlight<K, V> = std::sync::Mutex<HashMap<K, Arc<OnceCell<V>>>>;
+
+// JSON fetches in flight, keyed by path and quarantine opt-in
+static JSON_IN_FLIGHT: Lazy<InFlight<(String, bool), Result<Value, String>>> = Lazy::new(Default::default);
+
+// Run `fetch`, unless a fetch for the same `key` is already in flight, in
+// which case wait for it and return a copy of its result.
+async fn single_flight<K, V>(in_flight: &InFlight<K, V>, key: K, fetch: impl Future<Output = V>) -> V
+where
+	K: Eq + Hash + Clone,
+	V: Clone,
+{
+	let cell = in_flight.lock().unwrap().entry(key.clone()).or_default().clone();
+	let value = cell.get_or_init(|| fetch).await.clone();
+
+	// The first request to finish removes the entry, so that later requests
+	// fetch again. Entries of fetches started since then are left alone.
+	let mut in_flight = in_flight.lock().unwrap();
+	if in_flight.get(&key).is_some_and(|current| Arc::ptr_eq(current, &cell)) {
+		in_flight.remove(&key);
+	}
+	value
+}
+
+// Make a request to a Reddit API with the pooled client at `client_index`
+async fn json_with_client(path: String, quarantine: bool, client_index: usize) -> Result<Value, String> {
 	// Closure to quickly build errors
 	let err = |msg: &str, e: String, path: String| -> Result<Value, String> {
 		// eprintln!("{} - {}: {}", url, msg, e);
 		Err(format!("{msg}: {e} | {path}"))
 	};
 
+	let pooled = OAUTH_CLIENT.get(client_index);
+
 	// First, handle rolling over the OAUTH_CLIENT if need be.
-	let current_rate_limit = OAUTH_RATELIMIT_REMAINING.load(Ordering::SeqCst);
-	let is_rolling_over = OAUTH_IS_ROLLING_OVER.load(Ordering::SeqCst);
+	let current_rate_limit = pooled.ratelimit_remaining.load(Ordering::SeqCst);
+	let is_rolling_over = pooled.is_rolling_over.load(Ordering::SeqCst);
 	if current_rate_limit < 10 && !is_rolling_over {
-		warn!("Rate limit {current_rate_limit} is low. Spawning force_refresh_token()");
-		tokio::spawn(force_refresh_token());
+		warn!("Rate limit {current_rate_limit} is low. Spawning force_refresh_token({client_index})");
+		tokio::spawn(force_refresh_token(client_index));
 	}
-	OAUTH_RATELIMIT_REMAINING.fetch_sub(1, Ordering::SeqCst);
+	pooled.ratelimit_remaining.fetch_sub(1, Ordering::SeqCst);
 
 	// Fetch the url...
-	match reddit_get(path.clone(), quarantine).await {
+	match reddit_get(path.clone(), quarantine, client_index).await {
 		Ok(response) => {
 			let status = response.status();
 
@@ -390,6 +850,14 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 					"Ratelimit remaining: Header says {remaining}, we have {current_rate_limit}. Resets in {reset}. Rollover: {}. Ratelimit used: {used}",
 					if is_rolling_over { "yes" } else { "no" },
 				);
+				if let Some(limit) = UpstreamRateLimit::parse(&remaining, &used, &reset) {
+					pooled.upstream_ratelimit.store(Some(Arc::new(limit)));
+					// Reddit's own count is running out, switch to a fresh token early
+					if limit.remaining < 10 && !is_rolling_over {
+						warn!("Reddit reports {} requests remaining. Spawning force_refresh_token({client_index})", limit.remaining);
+						tokio::spawn(force_refresh_token(client_index));
+					}
+				}
 				Some(reset)
 			} else {
 				None
@@ -402,14 +870,8 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 
 					if !has_remaining {
 						// Rate limited, so spawn a force_refresh_token()
-						tokio::spawn(force_refresh_token());
-						return match reset {
-							Some(val) => Err(format!(
-								"Reddit rate limit exceeded. Try refreshing in a few seconds.\
-								 Rate limit will reset in: {val}"
-							)),
-							None => Err("Reddit rate limit exceeded".to_string()),
-						};
+						tokio::spawn(force_refresh_token(client_index));
+						return Err(rate_limit_error(reset.as_deref()));
 					}
 
 					// Parse the response from Reddit as JSON
@@ -431,25 +893,25 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 								// OAuth token has expired; http status 401
 								if json["message"] == "Unauthorized" {
 									error!("Forcing a token refresh");
-									let () = force_refresh_token().await;
-									return Err("OAuth token has expired. Please refresh the page!".to_string());
+									let () = force_refresh_token(client_index).await;
+									return Err(UNAUTHORIZED_ERROR.to_string());
 								}
 
-								// Handle quarantined
-								if json["reason"] == "quarantined" {
-									return Err("quarantined".into());
-								}
-								// Handle gated
-								if json["reason"] == "gated" {
-									return Err("gated".into());
-								}
-								// Handle private subs
-								if json["reason"] == "private" {
-									return Err("private".into());
+								// Reddit is limiting or failing every request, not just this one.
+								// The path is kept for the logs, as outages can be partial.
+								match json["error"].as_i64() {
+									Some(429) => return Err(rate_limit_error(reset.as_deref())),
+									Some(500..) => return Err(format!("{OUTAGE_ERROR} | {path}")),
+									_ => {}
 								}
-								// Handle banned subs
-								if json["reason"] == "banned" {
-									return Err("banned".into());
+
+								// Handle quarantined, gated, private and banned subs, passing on
+								// Reddit's explanation when it gives one
+								if let Some(kind) = RESTRICTIONS.iter().find(|kind| json["reason"] == **kind) {
+									let reason = RESTRICTION_REASONS
+										.iter()
+										.find_map(|key| json[key].as_str().map(str::trim).filter(|reason| !reason.is_empty()));
+									return Err(reason.map_or_else(|| (*kind).to_string(), |reason| format!("{kind}: {reason}")));
 								}
 
 								Err(format!("Reddit error {} \"{}\": {} | {path}", json["error"], json["reason"], json["message"]))
@@ -459,8 +921,11 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 						}
 						Err(e) => {
 							error!("Got an invalid response from reddit {e}. Status code: {status}");
+							// Such as the HTML pages Reddit shows during maintenance
 							if status.is_server_error() {
-								Err("Reddit is having issues, check if there's an outage".to_string())
+								Err(format!("{OUTAGE_ERROR} | {path}"))
+							} else if status == StatusCode::TOO_MANY_REQUESTS {
+								Err(rate_limit_error(reset.as_deref()))
 							} else {
 								err("Failed to parse page JSON data", e.to_string(), path)
 							}
@@ -474,6 +939,9 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 	}
 }
 
+#[cfg(test)]
+use sealed_test::prelude::*;
+
 #[cfg(test)]
 static POPULAR_URL: &str = "/r/popular/hot.json?&raw_json=1&geo_filter=GLOBAL";
 
@@ -483,6 +951,22 @@ async fn test_localization_popular() {
 	assert_eq!("GLOBAL", val["data"]["geo_filter"].as_str().unwrap());
 }
 
+#[test]
+fn test_redirect_path() {
+	assert_eq!(
+		redirect_path(
+			"/r/rust/s/kPgq8WNHRK",
+			"https://www.reddit.com/r/rust/comments/18t5968/why_use_tuple_struct/kfbqlbc/?share_id=abc&utm_medium=android_app"
+		),
+		Some("/r/rust/comments/18t5968/why_use_tuple_struct/kfbqlbc/".to_string())
+	);
+	assert_eq!(redirect_path("/17krzvz", "/comments/17krzvz.json"), Some("/comments/17krzvz".to_string()));
+	// Loops and redirects off Reddit
+	assert_eq!(redirect_path("/r/rust/s/kPgq8WNHRK", "https://www.reddit.com/r/rust/s/kPgq8WNHRK"), None);
+	assert_eq!(redirect_path("/r/rust/s/kPgq8WNHRK", "https://example.com/phishing"), None);
+	assert_eq!(redirect_path("/r/rust/s/kPgq8WNHRK", "//example.com/phishing"), None);
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn test_obfuscated_share_link() {
 	let share_link = "/r/rust/s/kPgq8WNHRK".into();
@@ -516,5 +1000,171 @@ async fn test_gated_sub() {
 	// quarantine to false to specifically catch when we _don't_ catch it
 	let link = json("/r/drugs/about.json?raw_json=1".into(), false).await;
 	assert!(link.is_err());
-	assert_eq!(link, Err("gated".into()));
+	assert_eq!(restriction(&link.unwrap_err()).map(|(kind, _)| kind), Some("gated"));
+}
+
+#[test]
+fn test_restriction() {
+	assert_eq!(restriction("private"), Some(("private", "")));
+	assert_eq!(restriction("quarantined: Contains graphic content"), Some(("quarantined", "Contains graphic content")));
+	assert_eq!(restriction("banned"), Some(("banned", "")));
+	assert_eq!(restriction("Reddit error 403 \"private\": \"Forbidden\" | /r/x.json"), None);
+	assert_eq!(restriction("Failed to parse page JSON data: expected value"), None);
+}
+
+#[test]
+fn test_unavailable() {
+	assert_eq!(Unavailable::of(&format!("{OUTAGE_ERROR} | /r/rust.json")), Some(Unavailable::Outage));
+	assert_eq!(Unavailable::of(&rate_limit_error(None)), Some(Unavailable::RateLimited { reset: None }));
+	let limited = Unavailable::of(&rate_limit_error(Some("41.5")));
+	assert_eq!(limited, Some(Unavailable::RateLimited { reset: Some(42) }));
+	assert_eq!(limited.map(Unavailable::retry_after), Some(42));
+	assert_eq!(Unavailable::Outage.retry_after(), 60);
+	assert_eq!(Unavailable::of(STARTING_ERROR), Some(Unavailable::Starting));
+	// Errors of a single page
+	assert_eq!(Unavailable::of("Reddit error 404 \"\": \"Not Found\" | /r/rust/wiki/x.json"), None);
+	assert_eq!(Unavailable::of("private"), None);
+}
+
+#[test]
+fn test_retry_delay() {
+	for (attempt, base) in [(1, 250), (2, 500), (3, 1000)] {
+		let delay = retry_delay(attempt);
+		assert!(delay >= Duration::from_millis(base));
+		assert!(delay <= Duration::from_millis(base * 3 / 2));
+	}
+}
+
+#[test]
+fn test_decompress() {
+	use std::io::Write;
+
+	let json = br#"{"kind": "Listing", "data": {"children": []}}"#;
+
+	let mut gzipped = gzip::Encoder::new(Vec::new()).unwrap();
+	gzipped.write_all(json).unwrap();
+	let gzipped = gzipped.finish().into_result().unwrap();
+	assert_eq!(decompress("gzip", gzipped.as_slice()).unwrap(), json);
+
+	let mut brotlied = Vec::new();
+	brotli::BrotliCompress(&mut json.as_slice(), &mut brotlied, &brotli::enc::BrotliEncoderParams::default()).unwrap();
+	assert_eq!(decompress("br", brotlied.as_slice()).unwrap(), json);
+
+	assert!(decompress("gzip", json.as_slice()).is_err());
+	assert!(decompress("zstd", json.as_slice()).is_err());
+}
+
+#[tokio::test]
+async fn test_single_flight() {
+	use std::sync::atomic::AtomicU32;
+
+	let in_flight: InFlight<&str, u32> = Default::default();
+	let fetches = AtomicU32::new(0);
+	let fetch = |value| {
+		let fetches = &fetches;
+		async move {
+			fetches.fetch_add(1, Ordering::SeqCst);
+			tokio::time::sleep(Duration::from_millis(50)).await;
+			value
+		}
+	};
+
+	// Identical concurrent requests share one fetch, others don't
+	let (a, b, c) = tokio::join!(
+		single_flight(&in_flight, "a", fetch(1)),
+		single_flight(&in_flight, "a", fetch(2)),
+		single_flight(&in_flight, "c", fetch(3))
+	);
+	assert_eq!((a, b, c), (1, 1, 3));
+	assert_eq!(fetches.load(Ordering::SeqCst), 2);
+	assert!(in_flight.lock().unwrap().is_empty());
+
+	// Once finished, the next request fetches again
+	assert_eq!(single_flight(&in_flight, "a", fetch(4)).await, 4);
+	assert_eq!(fetches.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn test_parse_range() {
+	assert_eq!(parse_range("bytes=0-1023"), Some(RangeRequest::Single));
+	assert_eq!(parse_range("bytes=1024-"), Some(RangeRequest::Single));
+	assert_eq!(parse_range("bytes=-500"), Some(RangeRequest::Single));
+	assert_eq!(parse_range("bytes=0-99, 200-299"), Some(RangeRequest::Multiple));
+	assert_eq!(parse_range("bytes=-"), None);
+	assert_eq!(parse_range("bytes=100-0"), None);
+	assert_eq!(parse_range("bytes=a-b"), None);
+	assert_eq!(parse_range("items=0-10"), None);
+}
+
+#[test]
+#[sealed_test(env = [("REDLIB_STRIP_IMAGE_METADATA", "on")])]
+fn test_stream_ranged_image() {
+	let exif = [0xFF, 0xE1, 0x00, 0x0C, b'E', b'x', b'i', b'f', 0, 0, b'G', b'P', b'S', b'!'];
+	let scan = [0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9];
+	let image = [&[0xFF, 0xD8][..], &exif, &scan].concat();
+
+	// Serve the image, honouring a range like Reddit's CDN
+	let upstream = |request: Request<Body>| {
+		let image = image.clone();
+		async move {
+			let builder = Response::builder().header(header::CONTENT_TYPE, "image/jpeg");
+			let res = match request.headers().get(header::RANGE) {
+				Some(_) => builder.status(206).body(Body::from(image[..16].to_vec())),
+				None => builder.body(Body::from(image)),
+			};
+			res.map_err(|e| e.to_string())
+		}
+	};
+
+	let req = Request::get("/img/abc.jpg").header(header::RANGE, "bytes=0-15").body(Body::empty()).unwrap();
+	let runtime = tokio::runtime::Runtime::new().unwrap();
+	let res = runtime.block_on(stream_with("https://i.redd.it/abc.jpg", &req, upstream)).unwrap();
+	assert_eq!(res.status(), StatusCode::OK);
+	let body = runtime.block_on(body::to_bytes(res.into_body())).unwrap();
+	assert_eq!(body, [&[0xFF, 0xD8][..], &scan].concat());
+}
+
+#[tokio::test]
+async fn test_upstream_timeout() {
+	let ok = with_upstream_timeout(async { Ok::<_, String>(Response::new(Body::empty())) }).await;
+	assert!(ok.is_ok());
+
+	let err = with_upstream_timeout(async { Err::<Response<Body>, _>("connection reset") }).await;
+	assert_eq!(err.unwrap_err(), "connection reset");
+}
+
+#[test]
+#[sealed_test(env = [("REDLIB_UPSTREAM_TIMEOUT", "1")])]
+fn test_upstream_timeout_elapsed() {
+	let runtime = tokio::runtime::Runtime::new().unwrap();
+	let started = Instant::now();
+	let err = runtime.block_on(with_upstream_timeout(std::future::pending::<Result<Response<Body>, String>>()));
+	assert_eq!(err.unwrap_err(), "Timed out after 1s waiting for Reddit");
+	assert!(started.elapsed() >= Duration::from_secs(1));
+}
+
+#[test]
+fn test_resource_of() {
+	assert_eq!(Resource::of("/hot.json?raw_json=1"), Resource::Listing);
+	assert_eq!(Resource::of("/r/rust/top.json?t=week"), Resource::Listing);
+	assert_eq!(Resource::of("/r/rust+golang/new.json"), Resource::Listing);
+	assert_eq!(Resource::of("/r/rust/comments/abc/title.json?sort=top"), Resource::Post);
+	assert_eq!(Resource::of("/comments/abc.json"), Resource::Post);
+	assert_eq!(Resource::of("/user/spez/about.json"), Resource::User);
+	assert_eq!(Resource::of("/search.json?q=rust"), Resource::Search);
+	assert_eq!(Resource::of("/r/rust/search.json?q=async&restrict_sr=on"), Resource::Search);
+	assert_eq!(Resource::of("/subreddits/search.json?q=rust"), Resource::Search);
+	assert_eq!(Resource::of("/r/rust/about.json"), Resource::About);
+	assert_eq!(Resource::of("/r/rust/about/rules.json"), Resource::About);
+	assert_eq!(Resource::of("/r/rust/wiki/index.json"), Resource::About);
+	assert_eq!(Resource::of("/api/v1/user/spez/trophies.json"), Resource::Other);
+}
+
+#[test]
+#[sealed_test(env = [("REDLIB_CACHE_TTL", "10"), ("REDLIB_CACHE_TTL_ABOUT", "3600")])]
+fn test_resource_cache_ttl() {
+	assert_eq!(Resource::About.cache_ttl(), 3600);
+	// The global setting takes precedence over the defaults
+	assert_eq!(Resource::Post.cache_ttl(), 10);
+	assert_eq!(Resource::Other.cache_ttl(), 10);
 }
@@ -1,9 +1,9 @@
 // Handler for post duplicates.
 
-use crate::client::json;
+use crate::client::{json, restriction};
 use crate::server::RequestExt;
-use crate::subreddit::{can_access_quarantine, quarantine};
-use crate::utils::{error, filter_posts, get_filters, nsfw_landing, parse_post, template, Post, Preferences};
+use crate::subreddit::{can_access_quarantine, restricted};
+use crate::utils::{error, filter_posts, get_filters, nsfw_landing, parse_post, template, unavailable_subreddit, Post, Preferences, SUBREDDIT_POLICY};
 
 use askama::Template;
 use hyper::{Body, Request, Response};
@@ -66,6 +66,9 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 		// Process response JSON.
 		Ok(response) => {
 			let post = parse_post(&response[0]["data"]["children"][0]).await;
+			if !SUBREDDIT_POLICY.allows(&post.community) {
+				return error(req, &unavailable_subreddit(&post.community)).await;
+			}
 
 			let req_url = req.uri().to_string();
 			// Return landing page if this post if this Reddit deems this post
@@ -210,9 +213,9 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 
 		// Process error.
 		Err(msg) => {
-			if msg == "quarantined" || msg == "gated" {
+			if restriction(&msg).is_some() {
 				let sub = req.param("sub").unwrap_or_default();
-				Ok(quarantine(&req, sub, &msg))
+				Ok(restricted(&req, sub, &msg))
 			} else {
 				error(req, &msg).await
 			}
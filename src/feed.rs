@@ -0,0 +1,450 @@
+// Format-agnostic syndication feeds of posts, serialized as RSS 2.0, Atom 1.0
+// or JSON Feed 1.1.
+
+use hyper::{
+	header::{HeaderValue, ACCEPT, CONTENT_TYPE},
+	Body, Request, Response,
+};
+use serde_json::{json, Value};
+use std::future::Future;
+use time::{
+	format_description::well_known::{Rfc2822, Rfc3339},
+	OffsetDateTime,
+};
+
+use crate::config;
+use crate::utils::{error, get_post_url, rewrite_urls, Post};
+
+/// Most items a feed can ask for with its `limit` parameter, which is the most
+/// Reddit returns at once.
+pub const MAX_FEED_ITEMS: u32 = 100;
+
+/// The query of a feed request to pass on to Reddit, with its `limit`
+/// parameter bounded to [`MAX_FEED_ITEMS`]. Without a limit, Reddit's default
+/// number of items is returned.
+pub fn upstream_query(query: &str) -> String {
+	let mut upstream = url::form_urlencoded::Serializer::new(String::new());
+	for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+		if key != "limit" {
+			upstream.append_pair(&key, &value);
+		} else if let Ok(limit) = value.trim().parse::<u32>() {
+			upstream.append_pair("limit", &limit.clamp(1, MAX_FEED_ITEMS).to_string());
+		}
+	}
+	upstream.finish()
+}
+
+/// Serve a feed built by `build`, in the format requested by the feed's
+/// `extension` (see [`FeedFormat::negotiate`]), if feeds are enabled on this
+/// instance.
+pub async fn serve<F, Fut>(req: Request<Body>, extension: FeedFormat, build: F) -> Result<Response<Body>, String>
+where
+	F: FnOnce(Request<Body>, FeedFormat) -> Fut,
+	Fut: Future<Output = Result<Response<Body>, String>>,
+{
+	if config::get_setting("REDLIB_ENABLE_RSS").is_none() {
+		return Ok(error(req, "RSS is disabled on this instance.").await.unwrap_or_default());
+	}
+	let format = FeedFormat::negotiate(&req, extension);
+	build(req, format).await
+}
+
+/// Syndication format of a feed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeedFormat {
+	Rss,
+	Atom,
+	Json,
+}
+
+impl FeedFormat {
+	/// The format requested by the feed's extension, unless an RSS feed was
+	/// requested by a client that prefers Atom in its `Accept` header.
+	pub fn negotiate(req: &Request<Body>, extension: Self) -> Self {
+		let accept = req.headers().get(ACCEPT).and_then(|value| value.to_str().ok()).unwrap_or_default();
+		if extension == Self::Rss && accept.contains("application/atom+xml") && !accept.contains("application/rss+xml") {
+			Self::Atom
+		} else {
+			extension
+		}
+	}
+
+	fn content_type(self) -> &'static str {
+		match self {
+			Self::Rss => "application/rss+xml",
+			Self::Atom => "application/atom+xml",
+			Self::Json => "application/feed+json",
+		}
+	}
+}
+
+/// A feed of posts, e.g. of a subreddit or user.
+pub struct Feed {
+	pub title: String,
+	pub description: String,
+	/// Path of the page the feed is about, e.g. `/r/rust`
+	pub path: String,
+	/// Path of the feed itself, e.g. `/r/rust.rss`
+	pub self_path: String,
+	pub items: Vec<FeedItem>,
+}
+
+/// An entry of a [`Feed`].
+pub struct FeedItem {
+	/// Permanent link to the post, used as its unique ID
+	pub id: String,
+	pub title: String,
+	/// Where the entry links to, which is the linked page for cross posts
+	pub link: String,
+	pub author: String,
+	/// HTML body of the post
+	pub content: String,
+	/// Optional HTML summary
+	pub summary: Option<String>,
+	pub published: Option<OffsetDateTime>,
+	/// Image or video of the post
+	pub enclosure: Option<Enclosure>,
+}
+
+/// Media attached to a [`FeedItem`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Enclosure {
+	pub url: String,
+	pub mime_type: String,
+	/// Size in bytes, which Reddit doesn't tell us
+	pub length: Option<u64>,
+}
+
+impl Enclosure {
+	/// The media of an image, GIF, video or gallery post.
+	pub fn from_post(post: &Post) -> Option<Self> {
+		let (url, fallback) = match post.post_type.as_str() {
+			"image" => (post.media.url.as_str(), "image/jpeg"),
+			// Reddit serves GIFs as videos
+			"gif" | "video" => (post.media.url.as_str(), "video/mp4"),
+			"gallery" => (post.gallery.first()?.url.as_str(), "image/jpeg"),
+			_ => return None,
+		};
+		if url.is_empty() {
+			return None;
+		}
+		Some(Self {
+			url: if url.starts_with('/') { full_url(url) } else { url.to_string() },
+			mime_type: mime_type(url).unwrap_or(fallback).to_string(),
+			length: None,
+		})
+	}
+}
+
+// MIME type of media, guessed from the extension of its URL
+fn mime_type(url: &str) -> Option<&'static str> {
+	let path = url.split(['?', '#']).next().unwrap_or_default();
+	let extension = path.rsplit_once('.')?.1.to_lowercase();
+	Some(match extension.as_str() {
+		"jpg" | "jpeg" => "image/jpeg",
+		"png" => "image/png",
+		"gif" => "image/gif",
+		"webp" => "image/webp",
+		"mp4" => "video/mp4",
+		"webm" => "video/webm",
+		_ => return None,
+	})
+}
+
+impl FeedItem {
+	pub fn from_post(post: Post) -> Self {
+		Self {
+			id: full_url(&post.permalink),
+			title: post.title.clone(),
+			link: get_post_url(&post),
+			published: OffsetDateTime::from_unix_timestamp(i64::try_from(post.created_ts).unwrap_or_default()).ok(),
+			content: rewrite_urls(&post.body),
+			summary: None,
+			enclosure: Enclosure::from_post(&post),
+			author: post.author.name,
+		}
+	}
+}
+
+impl Feed {
+	/// Serialize the feed in the given format into a response.
+	pub fn response(&self, format: FeedFormat) -> Response<Body> {
+		let body = match format {
+			FeedFormat::Rss => self.to_rss(),
+			FeedFormat::Atom => self.to_atom(),
+			FeedFormat::Json => self.to_json(),
+		};
+		let mut res = Response::new(Body::from(body));
+		res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+		res
+	}
+
+	pub fn to_rss(&self) -> String {
+		use rss::Enclosure as RssEnclosure;
+		use rss::{ChannelBuilder, Guid, Item};
+
+		ChannelBuilder::default()
+			.title(&self.title)
+			.link(full_url(&self.path))
+			.description(&self.description)
+			.items(
+				self
+					.items
+					.iter()
+					.map(|item| Item {
+						title: Some(item.title.clone()),
+						link: Some(item.link.clone()),
+						guid: Some(Guid {
+							value: item.id.clone(),
+							permalink: true,
+						}),
+						pub_date: item.published.and_then(|time| time.format(&Rfc2822).ok()),
+						author: Some(item.author.clone()),
+						content: Some(item.content.clone()),
+						description: item.summary.clone(),
+						// RSS requires a length, and 0 is customary when it isn't known
+						enclosure: item.enclosure.as_ref().map(|enclosure| RssEnclosure {
+							url: enclosure.url.clone(),
+							length: enclosure.length.unwrap_or_default().to_string(),
+							mime_type: enclosure.mime_type.clone(),
+						}),
+						..Default::default()
+					})
+					.collect::<Vec<_>>(),
+			)
+			.build()
+			.to_string()
+	}
+
+	pub fn to_atom(&self) -> String {
+		use atom_syndication::{Content, Entry, Feed, FixedDateTime, Link, Person, Text};
+
+		let updated = |time: Option<OffsetDateTime>| {
+			time
+				.and_then(|time| FixedDateTime::parse_from_rfc3339(&time.format(&Rfc3339).ok()?).ok())
+				.unwrap_or_default()
+		};
+		let link = |href: String, rel: &str| Link {
+			href,
+			rel: rel.to_string(),
+			..Default::default()
+		};
+
+		let entries: Vec<Entry> = self
+			.items
+			.iter()
+			.map(|item| Entry {
+				id: item.id.clone(),
+				title: Text::plain(item.title.clone()),
+				updated: updated(item.published),
+				published: item.published.map(|time| updated(Some(time))),
+				authors: vec![Person {
+					name: item.author.clone(),
+					uri: Some(full_url(&format!("/user/{}", item.author))),
+					..Default::default()
+				}],
+				links: std::iter::once(link(item.link.clone(), "alternate"))
+					.chain(item.enclosure.as_ref().map(|enclosure| Link {
+						mime_type: Some(enclosure.mime_type.clone()),
+						length: enclosure.length.map(|length| length.to_string()),
+						..link(enclosure.url.clone(), "enclosure")
+					}))
+					.collect(),
+				summary: item.summary.clone().map(Text::html),
+				content: Some(Content {
+					value: Some(item.content.clone()),
+					content_type: Some("html".to_string()),
+					..Default::default()
+				}),
+				..Default::default()
+			})
+			.collect();
+
+		Feed {
+			id: full_url(&self.path),
+			title: Text::plain(self.title.clone()),
+			subtitle: Some(Text::plain(self.description.clone())).filter(|subtitle| !subtitle.value.is_empty()),
+			// A feed was last updated with its newest entry
+			updated: entries.iter().map(|entry| entry.updated).max().unwrap_or_default(),
+			links: vec![link(full_url(&self.path), "alternate"), link(full_url(&self.self_path), "self")],
+			entries,
+			..Default::default()
+		}
+		.to_string()
+	}
+
+	pub fn to_json(&self) -> String {
+		let items: Vec<Value> = self
+			.items
+			.iter()
+			.map(|item| {
+				let mut entry = json!({
+					"id": item.id,
+					"url": item.link,
+					"title": item.title,
+					"content_html": item.content,
+					"authors": [{
+						"name": item.author,
+						"url": full_url(&format!("/user/{}", item.author)),
+					}],
+				});
+				if let Some(summary) = &item.summary {
+					entry["summary"] = json!(summary);
+				}
+				if let Some(published) = item.published.and_then(|time| time.format(&Rfc3339).ok()) {
+					entry["date_published"] = json!(published);
+				}
+				// JSON Feed only has a main image, so videos are left out
+				if let Some(enclosure) = item.enclosure.as_ref().filter(|enclosure| enclosure.mime_type.starts_with("image/")) {
+					entry["image"] = json!(enclosure.url);
+				}
+				entry
+			})
+			.collect();
+
+		let mut feed = json!({
+			"version": "https://jsonfeed.org/version/1.1",
+			"title": self.title,
+			"home_page_url": full_url(&self.path),
+			"feed_url": full_url(&self.self_path),
+			"items": items,
+		});
+		if !self.description.is_empty() {
+			feed["description"] = json!(self.description);
+		}
+		feed.to_string()
+	}
+}
+
+// Absolute URL of a path on this instance
+fn full_url(path: &str) -> String {
+	format!("{}{path}", config::get_setting("REDLIB_FULL_URL").unwrap_or_default())
+}
+
+#[cfg(test)]
+use sealed_test::prelude::*;
+
+#[cfg(test)]
+fn test_feed() -> Feed {
+	Feed {
+		title: "rust".to_string(),
+		description: "A place for all things Rust".to_string(),
+		path: "/r/rust".to_string(),
+		self_path: "/r/rust.rss".to_string(),
+		items: vec![FeedItem {
+			id: "/r/rust/comments/abc/hello/".to_string(),
+			title: "Hello".to_string(),
+			link: "/r/rust/comments/abc/hello/".to_string(),
+			author: "ferris".to_string(),
+			content: "<p>Hi</p>".to_string(),
+			summary: Some("<a href='/r/rust/comments/abc/hello/'>Comments</a>".to_string()),
+			published: OffsetDateTime::from_unix_timestamp(1_700_000_000).ok(),
+			enclosure: Some(Enclosure {
+				url: "/img/abc.png".to_string(),
+				mime_type: "image/png".to_string(),
+				length: None,
+			}),
+		}],
+	}
+}
+
+#[test]
+fn test_rss() {
+	let rss = test_feed().to_rss();
+	let channel = rss.parse::<rss::Channel>().unwrap();
+	assert_eq!(channel.title(), "rust");
+	let item = &channel.items()[0];
+	assert_eq!(item.title(), Some("Hello"));
+	assert_eq!(item.author(), Some("ferris"));
+	assert_eq!(item.pub_date(), Some("Tue, 14 Nov 2023 22:13:20 +0000"));
+	assert_eq!(item.guid().map(|guid| guid.value()), Some("/r/rust/comments/abc/hello/"));
+	let enclosure = item.enclosure().unwrap();
+	assert_eq!((enclosure.url(), enclosure.mime_type(), enclosure.length()), ("/img/abc.png", "image/png", "0"));
+}
+
+#[test]
+fn test_atom() {
+	let atom = test_feed().to_atom();
+	let feed = atom.parse::<atom_syndication::Feed>().unwrap();
+	assert_eq!(feed.id(), "/r/rust");
+	assert_eq!(feed.updated().to_rfc3339(), "2023-11-14T22:13:20+00:00");
+	assert!(feed.links().iter().any(|link| link.rel() == "self" && link.href() == "/r/rust.rss"));
+	let entry = &feed.entries()[0];
+	assert_eq!(entry.id(), "/r/rust/comments/abc/hello/");
+	assert_eq!(entry.links()[0].rel(), "alternate");
+	assert_eq!(entry.links()[1].rel(), "enclosure");
+	assert_eq!(entry.links()[1].mime_type(), Some("image/png"));
+	assert_eq!(entry.authors()[0].name(), "ferris");
+}
+
+#[test]
+fn test_json() {
+	let feed: Value = serde_json::from_str(&test_feed().to_json()).unwrap();
+	assert_eq!(feed["version"], "https://jsonfeed.org/version/1.1");
+	assert_eq!(feed["feed_url"], "/r/rust.rss");
+	assert_eq!(feed["description"], "A place for all things Rust");
+	let item = &feed["items"][0];
+	assert_eq!(item["id"], "/r/rust/comments/abc/hello/");
+	assert_eq!(item["url"], "/r/rust/comments/abc/hello/");
+	assert_eq!(item["title"], "Hello");
+	assert_eq!(item["content_html"], "<p>Hi</p>");
+	assert_eq!(item["date_published"], "2023-11-14T22:13:20Z");
+	assert_eq!(item["image"], "/img/abc.png");
+	assert_eq!(item["authors"][0]["name"], "ferris");
+}
+
+#[test]
+fn test_negotiate() {
+	let request = |accept: &str| Request::builder().header(ACCEPT, accept).body(Body::empty()).unwrap();
+	assert_eq!(FeedFormat::negotiate(&request("application/atom+xml"), FeedFormat::Rss), FeedFormat::Atom);
+	assert_eq!(
+		FeedFormat::negotiate(&request("application/rss+xml, application/atom+xml"), FeedFormat::Rss),
+		FeedFormat::Rss
+	);
+	assert_eq!(FeedFormat::negotiate(&request("*/*"), FeedFormat::Rss), FeedFormat::Rss);
+	assert_eq!(FeedFormat::negotiate(&request("application/rss+xml"), FeedFormat::Atom), FeedFormat::Atom);
+}
+
+#[test]
+fn test_empty_feed() {
+	let feed = Feed { items: Vec::new(), ..test_feed() };
+	assert!(feed.to_rss().parse::<rss::Channel>().is_ok_and(|channel| channel.items().is_empty()));
+	assert!(feed.to_atom().parse::<atom_syndication::Feed>().is_ok_and(|feed| feed.entries().is_empty()));
+	assert!(serde_json::from_str::<Value>(&feed.to_json()).is_ok_and(|feed| feed["items"] == json!([])));
+}
+
+#[test]
+fn test_mime_type() {
+	assert_eq!(mime_type("/img/abc.JPG"), Some("image/jpeg"));
+	assert_eq!(mime_type("/preview/pre/abc.png?width=640&s=123"), Some("image/png"));
+	assert_eq!(mime_type("/vid/abc/DASH_720.mp4?source=fallback"), Some("video/mp4"));
+	assert_eq!(mime_type("/vid/abc/DASH_720"), None);
+	assert_eq!(mime_type("https://example.com/"), None);
+}
+
+#[test]
+fn test_upstream_query() {
+	assert_eq!(upstream_query(""), "");
+	assert_eq!(upstream_query("sort=new"), "sort=new");
+	assert_eq!(upstream_query("sort=new&limit=10"), "sort=new&limit=10");
+	assert_eq!(upstream_query("limit=1000&t=week"), "limit=100&t=week");
+	assert_eq!(upstream_query("limit=0"), "limit=1");
+	assert_eq!(upstream_query("limit=lots&q=a+b"), "q=a+b");
+}
+
+#[test]
+#[sealed_test(env = [("REDLIB_ENABLE_RSS", "on")])]
+fn test_serve() {
+	let runtime = tokio::runtime::Runtime::new().unwrap();
+	let build = |_, format: FeedFormat| async move { Ok(Response::new(Body::from(format.content_type()))) };
+	let req = Request::get("/r/rust.rss").header(ACCEPT, "application/atom+xml").body(Body::empty()).unwrap();
+	let res = runtime.block_on(serve(req, FeedFormat::Rss, build)).unwrap();
+	assert_eq!(runtime.block_on(hyper::body::to_bytes(res.into_body())).unwrap(), "application/atom+xml");
+}
+
+#[tokio::test]
+async fn test_serve_disabled() {
+	let build = |_, _| async { panic!("Feed built while feeds are disabled") };
+	let res = serve(Request::get("/r/rust.rss").body(Body::empty()).unwrap(), FeedFormat::Rss, build).await.unwrap();
+	assert_eq!(res.status(), 404);
+}
@@ -0,0 +1,66 @@
+// Health checks for orchestrators and load balancers. /healthz answers as
+// long as the process is up, while /readyz also checks that Reddit can be
+// reached with a valid OAuth token.
+
+use hyper::{Body, Request, Response};
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::client::{oauth_ready, OAUTH_CLIENT};
+use crate::metrics::UPSTREAM_METRICS;
+
+// Seconds a successful request to Reddit vouches for readiness, even if
+// requests failed since
+const UPSTREAM_GRACE_PERIOD: i64 = 300;
+
+/// Handles the liveness probe.
+pub async fn healthz(_req: Request<Body>) -> Result<Response<Body>, String> {
+	respond(Ok(()))
+}
+
+/// Handles the readiness probe.
+pub async fn readyz(_req: Request<Body>) -> Result<Response<Body>, String> {
+	// Not ready while the OAuth clients are still being created at startup
+	let has_token = oauth_ready() && OAUTH_CLIENT.status().iter().any(|status| status.has_token && status.expires_in > 0);
+	let (last_success, last_failure) = UPSTREAM_METRICS.last_outcomes();
+	respond(readiness(has_token, last_success, last_failure, OffsetDateTime::now_utc().unix_timestamp()))
+}
+
+/// Whether the instance is ready, given whether a valid token is held and the
+/// times of the last successful and failed requests to Reddit (0 if there
+/// were none). Instances that haven't made requests yet are ready.
+fn readiness(has_token: bool, last_success: i64, last_failure: i64, now: i64) -> Result<(), &'static str> {
+	if !has_token {
+		Err("No valid OAuth token")
+	} else if last_failure > last_success && now - last_success > UPSTREAM_GRACE_PERIOD {
+		Err("Requests to Reddit are failing")
+	} else {
+		Ok(())
+	}
+}
+
+fn respond(result: Result<(), &str>) -> Result<Response<Body>, String> {
+	let (status, body) = match result {
+		Ok(()) => (200, json!({ "status": "ok" })),
+		Err(reason) => (503, json!({ "status": "unavailable", "reason": reason })),
+	};
+	Response::builder()
+		.status(status)
+		.header("content-type", "application/json")
+		.header("cache-control", "no-store")
+		.body(body.to_string().into())
+		.map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_readiness() {
+	let now = 1_000_000;
+	assert_eq!(readiness(true, 0, 0, now), Ok(()));
+	assert_eq!(readiness(false, now, 0, now), Err("No valid OAuth token"));
+	// A failure shortly after a success is tolerated
+	assert_eq!(readiness(true, now - 10, now - 5, now), Ok(()));
+	assert_eq!(readiness(true, now - 1000, now - 5, now), Err("Requests to Reddit are failing"));
+	assert_eq!(readiness(true, 0, now - 5, now), Err("Requests to Reddit are failing"));
+	// Recovered
+	assert_eq!(readiness(true, now - 1, now - 1000, now), Ok(()));
+}
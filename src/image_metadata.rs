@@ -0,0 +1,187 @@
+// Removal of metadata (EXIF, XMP, IPTC, text comments) from proxied images,
+// enabled by REDLIB_STRIP_IMAGE_METADATA.
+//
+// Only the container is parsed: image data is copied as-is, so stripping is
+// cheap and lossless. Images that can't be parsed are left untouched.
+
+/// Strip metadata from an image with the given content type. Returns `None`
+/// if the format isn't supported or the image is malformed.
+pub fn strip_metadata(content_type: &str, data: &[u8]) -> Option<Vec<u8>> {
+	match content_type.split(';').next().unwrap_or_default().trim() {
+		"image/jpeg" | "image/jpg" => strip_jpeg(data),
+		"image/png" => strip_png(data),
+		"image/webp" => strip_webp(data),
+		_ => None,
+	}
+}
+
+// JPEG segments carrying metadata: APP1 (EXIF and XMP), APP13 (IPTC) and comments
+const JPEG_METADATA_MARKERS: [u8; 3] = [0xE1, 0xED, 0xFE];
+
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+	if !data.starts_with(&[0xFF, 0xD8]) {
+		return None;
+	}
+	let mut out = Vec::with_capacity(data.len());
+	out.extend_from_slice(&data[..2]);
+	let mut i = 2;
+	loop {
+		if data.get(i) != Some(&0xFF) {
+			return None;
+		}
+		let marker = *data.get(i + 1)?;
+		match marker {
+			// Fill byte
+			0xFF => i += 1,
+			// End of image, or start of scan: the rest is image data
+			0xD9 | 0xDA => {
+				out.extend_from_slice(&data[i..]);
+				return Some(out);
+			}
+			// Markers without a length
+			0x01 | 0xD0..=0xD7 => {
+				out.extend_from_slice(&data[i..i + 2]);
+				i += 2;
+			}
+			_ => {
+				let length = usize::from(u16::from_be_bytes([*data.get(i + 2)?, *data.get(i + 3)?]));
+				let end = i + 2 + length;
+				if length < 2 || end > data.len() {
+					return None;
+				}
+				if !JPEG_METADATA_MARKERS.contains(&marker) {
+					out.extend_from_slice(&data[i..end]);
+				}
+				i = end;
+			}
+		}
+	}
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// PNG chunks carrying metadata
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+	if !data.starts_with(&PNG_SIGNATURE) {
+		return None;
+	}
+	let mut out = Vec::with_capacity(data.len());
+	out.extend_from_slice(&PNG_SIGNATURE);
+	let mut i = PNG_SIGNATURE.len();
+	while i < data.len() {
+		let length = u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?) as usize;
+		let kind = data.get(i + 4..i + 8)?;
+		// Length, type, data and CRC
+		let end = i.checked_add(length)?.checked_add(12)?;
+		if end > data.len() {
+			return None;
+		}
+		if !PNG_METADATA_CHUNKS.iter().any(|chunk| chunk.as_slice() == kind) {
+			out.extend_from_slice(&data[i..end]);
+		}
+		i = end;
+		if kind == b"IEND" {
+			break;
+		}
+	}
+	Some(out)
+}
+
+// Flags of the VP8X chunk announcing EXIF and XMP chunks
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+	if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+		return None;
+	}
+	let mut out = Vec::with_capacity(data.len());
+	out.extend_from_slice(&data[..12]);
+	let mut i = 12;
+	while i < data.len() {
+		let kind = data.get(i..i + 4)?;
+		let length = u32::from_le_bytes(data.get(i + 4..i + 8)?.try_into().ok()?) as usize;
+		// Chunks are padded to an even length
+		let end = i.checked_add(8)?.checked_add(length + (length & 1))?.min(data.len());
+		if i + 8 + length > data.len() {
+			return None;
+		}
+		match kind {
+			b"EXIF" | b"XMP " => {}
+			b"VP8X" => {
+				let start = out.len();
+				out.extend_from_slice(&data[i..end]);
+				if let Some(flags) = out.get_mut(start + 8) {
+					*flags &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+				}
+			}
+			_ => out.extend_from_slice(&data[i..end]),
+		}
+		i = end;
+	}
+	// The RIFF size covers everything after the size field itself
+	let riff_size = u32::try_from(out.len() - 8).ok()?;
+	out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+	Some(out)
+}
+
+#[test]
+fn test_strip_jpeg() {
+	let app0 = [0xFF, 0xE0, 0x00, 0x04, b'J', b'F'];
+	let exif = [0xFF, 0xE1, 0x00, 0x06, b'E', b'x', b'i', b'f'];
+	let comment = [0xFF, 0xFE, 0x00, 0x03, b'!'];
+	let scan = [0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0x00, 0xFF, 0xD9];
+	let image = [&[0xFF, 0xD8][..], &app0, &exif, &comment, &scan].concat();
+
+	let stripped = strip_metadata("image/jpeg", &image).unwrap();
+	assert_eq!(stripped, [&[0xFF, 0xD8][..], &app0, &scan].concat());
+
+	assert_eq!(strip_metadata("image/jpeg", b"not a jpeg"), None);
+	assert_eq!(strip_metadata("image/jpeg", &[0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x50]), None);
+}
+
+#[test]
+fn test_strip_png() {
+	let chunk = |kind: &[u8; 4], data: &[u8]| [&(data.len() as u32).to_be_bytes()[..], kind, data, &[0; 4]].concat();
+	let ihdr = chunk(b"IHDR", &[0; 13]);
+	let text = chunk(b"tEXt", b"Author\0Someone");
+	let exif = chunk(b"eXIf", b"MM\0*");
+	let idat = chunk(b"IDAT", &[1, 2, 3]);
+	let iend = chunk(b"IEND", &[]);
+	let image = [&PNG_SIGNATURE[..], &ihdr, &text, &exif, &idat, &iend].concat();
+
+	let stripped = strip_metadata("image/png", &image).unwrap();
+	assert_eq!(stripped, [&PNG_SIGNATURE[..], &ihdr, &idat, &iend].concat());
+
+	assert_eq!(strip_metadata("image/png", &image[..image.len() - 2]), None);
+}
+
+#[test]
+fn test_strip_webp() {
+	let chunk = |kind: &[u8; 4], data: &[u8]| {
+		let mut chunk = [&kind[..], &(data.len() as u32).to_le_bytes(), data].concat();
+		if data.len() % 2 == 1 {
+			chunk.push(0);
+		}
+		chunk
+	};
+	let riff = |chunks: &[u8]| [&b"RIFF"[..], &(chunks.len() as u32 + 4).to_le_bytes(), b"WEBP", chunks].concat();
+	let vp8x = |flags: u8| chunk(b"VP8X", &[flags, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+	let vp8 = chunk(b"VP8 ", &[1, 2, 3]);
+	let exif = chunk(b"EXIF", b"MM\0*");
+	let xmp = chunk(b"XMP ", b"<x/>");
+
+	let image = riff(&[vp8x(0x0C | 0x10), vp8.clone(), exif, xmp].concat());
+	let stripped = strip_metadata("image/webp", &image).unwrap();
+	assert_eq!(stripped, riff(&[vp8x(0x10), vp8].concat()));
+
+	assert_eq!(strip_metadata("image/webp", b"RIFF\0\0\0\0WAVE"), None);
+}
+
+#[test]
+fn test_strip_unsupported() {
+	assert_eq!(strip_metadata("image/gif", b"GIF89a"), None);
+	assert!(strip_metadata("image/png; charset=binary", &PNG_SIGNATURE).is_some());
+}
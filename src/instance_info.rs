@@ -1,5 +1,9 @@
 use crate::{
+	client::{oauth_ready, OAUTH_CLIENT},
 	config::{Config, CONFIG},
+	connector::Proxy,
+	metrics::{OauthMetricsSnapshot, OAUTH_METRICS},
+	oauth::OauthStatus,
 	server::RequestExt,
 	utils::{ErrorTemplate, Preferences},
 };
@@ -15,6 +19,30 @@ use time::OffsetDateTime;
 // retrieved from the info endpoint.
 pub static INSTANCE_INFO: Lazy<InstanceInfo> = Lazy::new(InstanceInfo::new);
 
+/// Handles the version endpoint, reporting which build is running.
+pub async fn version(_req: Request<Body>) -> Result<Response<Body>, String> {
+	let body = serde_json::json!({
+		"version": env!("CARGO_PKG_VERSION"),
+		"commit": env!("GIT_HASH"),
+		"build_date": build_date(),
+	});
+	Response::builder()
+		.status(200)
+		.header("content-type", "application/json")
+		.body(body.to_string().into())
+		.map_err(|e| e.to_string())
+}
+
+/// The time the running binary was built at, set by the build script.
+pub fn build_date() -> String {
+	env!("BUILD_TIMESTAMP")
+		.parse()
+		.ok()
+		.and_then(|ts| OffsetDateTime::from_unix_timestamp(ts).ok())
+		.map(|date| date.to_string())
+		.unwrap_or_default()
+}
+
 /// Handles instance info endpoint
 pub async fn instance_info(req: Request<Body>) -> Result<Response<Body>, String> {
 	// This will retrieve the extension given, or create a new string - which will
@@ -40,7 +68,7 @@ pub async fn instance_info(req: Request<Body>) -> Result<Response<Body>, String>
 }
 
 fn info_json() -> Result<Response<Body>, Error> {
-	if let Ok(body) = serde_json::to_string(&*INSTANCE_INFO) {
+	if let Ok(body) = serde_json::to_string(&InstanceInfo::current()) {
 		Response::builder().status(200).header("content-type", "application/json").body(body.into())
 	} else {
 		Response::builder()
@@ -51,7 +79,7 @@ fn info_json() -> Result<Response<Body>, Error> {
 }
 
 fn info_yaml() -> Result<Response<Body>, Error> {
-	if let Ok(body) = serde_yaml::to_string(&*INSTANCE_INFO) {
+	if let Ok(body) = serde_yaml::to_string(&InstanceInfo::current()) {
 		// We can use `application/yaml` as media type, though there is no guarantee
 		// that browsers will honor it. But we'll do it anyway. See:
 		// https://github.com/ietf-wg-httpapi/mediatypes/blob/main/draft-ietf-httpapi-yaml-mediatypes.md#media-type-applicationyaml-application-yaml
@@ -68,12 +96,12 @@ fn info_txt() -> Result<Response<Body>, Error> {
 	Response::builder()
 		.status(200)
 		.header("content-type", "text/plain")
-		.body(Body::from(INSTANCE_INFO.to_string(&StringType::Raw)))
+		.body(Body::from(InstanceInfo::current().to_string(&StringType::Raw)))
 }
 fn info_html(req: &Request<Body>) -> Result<Response<Body>, Error> {
 	let message = MessageTemplate {
 		title: String::from("Instance information"),
-		body: INSTANCE_INFO.to_string(&StringType::Html),
+		body: InstanceInfo::current().to_string(&StringType::Html),
 		prefs: Preferences::new(req),
 		url: req.uri().to_string(),
 	}
@@ -81,15 +109,21 @@ fn info_html(req: &Request<Body>) -> Result<Response<Body>, Error> {
 	.unwrap();
 	Response::builder().status(200).header("content-type", "text/html; charset=utf8").body(Body::from(message))
 }
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct InstanceInfo {
 	package_name: String,
 	crate_version: String,
 	git_commit: String,
+	#[serde(default)]
+	build_date: String,
 	deploy_date: String,
 	compile_mode: String,
 	deploy_unix_ts: i64,
 	config: Config,
+	#[serde(default)]
+	oauth: Vec<OauthStatus>,
+	#[serde(default)]
+	oauth_metrics: OauthMetricsSnapshot,
 }
 
 impl InstanceInfo {
@@ -98,15 +132,55 @@ impl InstanceInfo {
 			package_name: env!("CARGO_PKG_NAME").to_string(),
 			crate_version: env!("CARGO_PKG_VERSION").to_string(),
 			git_commit: env!("GIT_HASH").to_string(),
+			build_date: build_date(),
 			deploy_date: OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc()).to_string(),
 			#[cfg(debug_assertions)]
 			compile_mode: "Debug".into(),
 			#[cfg(not(debug_assertions))]
 			compile_mode: "Release".into(),
 			deploy_unix_ts: OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc()).unix_timestamp(),
-			config: CONFIG.clone(),
+			config: Config {
+				// The proxy's credentials must not be published
+				proxy: CONFIG.proxy.as_deref().map(Proxy::redact),
+				..CONFIG.clone()
+			},
+			oauth: Vec::new(),
+			oauth_metrics: OauthMetricsSnapshot::default(),
+		}
+	}
+	/// The static instance info, along with the current OAuth token status
+	fn current() -> Self {
+		Self {
+			oauth: if oauth_ready() { OAUTH_CLIENT.status() } else { Vec::new() },
+			oauth_metrics: OAUTH_METRICS.snapshot(),
+			..INSTANCE_INFO.clone()
 		}
 	}
+	/// One-line summary of each OAuth token, for the HTML and raw views
+	fn oauth_summary(&self) -> Vec<(String, String)> {
+		self
+			.oauth
+			.iter()
+			.enumerate()
+			.map(|(index, status)| {
+				let summary = if status.has_token {
+					let upstream = status.upstream_ratelimit.map_or_else(String::new, |limit| {
+						format!(" (Reddit reports {} remaining and {} used, resetting in {}s)", limit.remaining, limit.used, limit.reset)
+					});
+					format!(
+						"{}, expires in {}s, last refreshed at {}, {} requests remaining{upstream}",
+						status.device,
+						status.expires_in,
+						status.last_refresh.unwrap_or_default(),
+						status.ratelimit_remaining
+					)
+				} else {
+					format!("{}, no token, {} failed attempts", status.device, status.failure_count)
+				};
+				(format!("Token #{index}"), summary)
+			})
+			.collect()
+	}
 	fn to_table(&self) -> String {
 		let mut container = Container::default();
 		let convert = |o: &Option<String>| -> String { o.clone().unwrap_or_else(|| "<span class=\"unset\"><i>Unset</i></span>".to_owned()) };
@@ -121,6 +195,7 @@ impl InstanceInfo {
 				["Package name", &self.package_name],
 				["Crate version", &self.crate_version],
 				["Git commit", &self.git_commit],
+				["Build date", &self.build_date],
 				["Deploy date", &self.deploy_date],
 				["Deploy timestamp", &self.deploy_unix_ts.to_string()],
 				["Compile mode", &self.compile_mode],
@@ -128,11 +203,54 @@ impl InstanceInfo {
 				["Pushshift frontend", &convert(&self.config.pushshift)],
 				["RSS enabled", &convert(&self.config.enable_rss)],
 				["Full URL", &convert(&self.config.full_url)],
+				["OAuth max attempts", &convert(&self.config.oauth_max_attempts)],
+				["OAuth pool size", &convert(&self.config.oauth_pool_size)],
+				["OAuth device", &convert(&self.config.oauth_device)],
+				["OAuth login timeout", &convert(&self.config.oauth_login_timeout)],
+				["OAuth cache path", &convert(&self.config.oauth_cache_path)],
+				["OAuth auth endpoint", &convert(&self.config.oauth_auth_endpoint)],
+				["OAuth Android client ID", &convert(&self.config.oauth_android_client_id)],
+				["OAuth iOS client ID", &convert(&self.config.oauth_ios_client_id)],
+				["OAuth app versions file", &convert(&self.config.oauth_app_versions_file)],
+				["Proxy", &convert(&self.config.proxy)],
+				["OAuth rotate device every", &convert(&self.config.oauth_rotate_device_every)],
+				["OAuth scopes", &convert(&self.config.oauth_scopes)],
+				["Upstream retries", &convert(&self.config.upstream_retries)],
+				["Cache TTL", &convert(&self.config.cache_ttl)],
+				["Cache size", &convert(&self.config.cache_size)],
+				["Strip image metadata", &convert(&self.config.strip_image_metadata)],
+				["Upstream timeout", &convert(&self.config.upstream_timeout)],
+				["Metrics enabled", &convert(&self.config.enable_metrics)],
+				["Log format", &convert(&self.config.log_format)],
+				["Allowed subreddits", &convert(&self.config.allowed_subreddits)],
+				["Blocked subreddits", &convert(&self.config.blocked_subreddits)],
+				["Cache TTL (listing)", &convert(&self.config.cache_ttl_listing)],
+				["Cache TTL (post)", &convert(&self.config.cache_ttl_post)],
+				["Cache TTL (user)", &convert(&self.config.cache_ttl_user)],
+				["Cache TTL (search)", &convert(&self.config.cache_ttl_search)],
+				["Cache TTL (about)", &convert(&self.config.cache_ttl_about)],
+				["Sitemap enabled", &convert(&self.config.enable_sitemap)],
+				["JSON API", &convert(&self.config.enable_api)],
+				["Canonical URLs", &convert(&self.config.canonical_url)],
+				["Disable PWA", &convert(&self.config.disable_pwa)],
+				["Themes directory", &convert(&self.config.themes_dir)],
 				//TODO: fallback to crate::config::DEFAULT_PUSHSHIFT_FRONTEND
 			])
 			.with_header_row(["Settings"]),
 		);
 		container.add_raw("<br />");
+		container.add_table(Table::from(self.oauth_summary().into_iter().map(|(name, summary)| [name, summary])).with_header_row(["OAuth tokens"]));
+		container.add_raw("<br />");
+		container.add_table(
+			Table::from([
+				["Successful logins", &self.oauth_metrics.login_successes.to_string()],
+				["Failed logins", &self.oauth_metrics.login_failures.to_string()],
+				["Successful refreshes", &self.oauth_metrics.refresh_successes.to_string()],
+				["Failed refreshes", &self.oauth_metrics.refresh_failures.to_string()],
+			])
+			.with_header_row(["OAuth metrics"]),
+		);
+		container.add_raw("<br />");
 		container.add_table(
 			Table::from([
 				["Hide awards", &convert(&self.config.default_hide_awards)],
@@ -150,6 +268,15 @@ impl InstanceInfo {
 				["Hide HLS notification", &convert(&self.config.default_hide_hls_notification)],
 				["Subscriptions", &convert(&self.config.default_subscriptions)],
 				["Filters", &convert(&self.config.default_filters)],
+				["Collapse bots", &convert(&self.config.default_collapse_bots)],
+				["Bot users", &convert(&self.config.default_bot_users)],
+				["Collapse deleted", &convert(&self.config.default_collapse_deleted)],
+				["Infinite scroll", &convert(&self.config.default_infinite_scroll)],
+				["Keyboard shortcuts", &convert(&self.config.default_keyboard_shortcuts)],
+				["Search suggestions", &convert(&self.config.default_search_suggestions)],
+				["Time zone", &convert(&self.config.default_timezone)],
+				["Locale", &convert(&self.config.default_locale)],
+				["Strip tracking", &convert(&self.config.default_strip_tracking)],
 			])
 			.with_header_row(["Default preferences"]),
 		);
@@ -158,10 +285,11 @@ impl InstanceInfo {
 	fn to_string(&self, string_type: &StringType) -> String {
 		match string_type {
 			StringType::Raw => {
-				format!(
+				let mut info = format!(
 					"Package name: {}\n
 				Crate version: {}\n
                 Git commit: {}\n
+                Build date: {}\n
                 Deploy date: {}\n
                 Deploy timestamp: {}\n
                 Compile mode: {}\n
@@ -169,6 +297,37 @@ impl InstanceInfo {
 				Pushshift frontend: {:?}\n
 				RSS enabled: {:?}\n
 				Full URL: {:?}\n
+				OAuth max attempts: {:?}\n
+				OAuth pool size: {:?}\n
+				OAuth device: {:?}\n
+				OAuth login timeout: {:?}\n
+				OAuth cache path: {:?}\n
+				OAuth auth endpoint: {:?}\n
+				OAuth Android client ID: {:?}\n
+				OAuth iOS client ID: {:?}\n
+				OAuth app versions file: {:?}\n
+				Proxy: {:?}\n
+				OAuth rotate device every: {:?}\n
+				OAuth scopes: {:?}\n
+				Upstream retries: {:?}\n
+				Cache TTL: {:?}\n
+				Cache size: {:?}\n
+				Strip image metadata: {:?}\n
+				Upstream timeout: {:?}\n
+				Metrics enabled: {:?}\n
+				Log format: {:?}\n
+				Allowed subreddits: {:?}\n
+				Blocked subreddits: {:?}\n
+				Cache TTL (listing): {:?}\n
+				Cache TTL (post): {:?}\n
+				Cache TTL (user): {:?}\n
+				Cache TTL (search): {:?}\n
+				Cache TTL (about): {:?}\n
+				Sitemap enabled: {:?}\n
+				JSON API: {:?}\n
+				Canonical URLs: {:?}\n
+				Disable PWA: {:?}\n
+				Themes directory: {:?}\n
                 Config:\n
                     Banner: {:?}\n
                     Hide awards: {:?}\n
@@ -185,10 +344,20 @@ impl InstanceInfo {
                     Default use HLS: {:?}\n
                     Default hide HLS notification: {:?}\n
                     Default subscriptions: {:?}\n
-                    Default filters: {:?}\n",
+                    Default filters: {:?}\n
+                    Default collapse bots: {:?}\n
+                    Default bot users: {:?}\n
+                    Default collapse deleted: {:?}\n
+                    Default infinite scroll: {:?}\n
+                    Default keyboard shortcuts: {:?}\n
+                    Default search suggestions: {:?}\n
+                    Default time zone: {:?}\n
+                    Default locale: {:?}\n
+                    Default strip tracking: {:?}\n",
 					self.package_name,
 					self.crate_version,
 					self.git_commit,
+					self.build_date,
 					self.deploy_date,
 					self.deploy_unix_ts,
 					self.compile_mode,
@@ -196,6 +365,37 @@ impl InstanceInfo {
 					self.config.enable_rss,
 					self.config.full_url,
 					self.config.pushshift,
+					self.config.oauth_max_attempts,
+					self.config.oauth_pool_size,
+					self.config.oauth_device,
+					self.config.oauth_login_timeout,
+					self.config.oauth_cache_path,
+					self.config.oauth_auth_endpoint,
+					self.config.oauth_android_client_id,
+					self.config.oauth_ios_client_id,
+					self.config.oauth_app_versions_file,
+					self.config.proxy,
+					self.config.oauth_rotate_device_every,
+					self.config.oauth_scopes,
+					self.config.upstream_retries,
+					self.config.cache_ttl,
+					self.config.cache_size,
+					self.config.strip_image_metadata,
+					self.config.upstream_timeout,
+					self.config.enable_metrics,
+					self.config.log_format,
+					self.config.allowed_subreddits,
+					self.config.blocked_subreddits,
+					self.config.cache_ttl_listing,
+					self.config.cache_ttl_post,
+					self.config.cache_ttl_user,
+					self.config.cache_ttl_search,
+					self.config.cache_ttl_about,
+					self.config.enable_sitemap,
+					self.config.enable_api,
+					self.config.canonical_url,
+					self.config.disable_pwa,
+					self.config.themes_dir,
 					self.config.banner,
 					self.config.default_hide_awards,
 					self.config.default_hide_score,
@@ -212,7 +412,25 @@ impl InstanceInfo {
 					self.config.default_hide_hls_notification,
 					self.config.default_subscriptions,
 					self.config.default_filters,
-				)
+					self.config.default_collapse_bots,
+					self.config.default_bot_users,
+					self.config.default_collapse_deleted,
+					self.config.default_infinite_scroll,
+					self.config.default_keyboard_shortcuts,
+					self.config.default_search_suggestions,
+					self.config.default_timezone,
+					self.config.default_locale,
+					self.config.default_strip_tracking,
+				);
+				info.push_str("OAuth tokens:\n");
+				for (name, summary) in self.oauth_summary() {
+					info.push_str(&format!("    {name}: {summary}\n"));
+				}
+				info.push_str(&format!(
+					"OAuth metrics:\n    Successful logins: {}\n    Failed logins: {}\n    Successful refreshes: {}\n    Failed refreshes: {}\n",
+					self.oauth_metrics.login_successes, self.oauth_metrics.login_failures, self.oauth_metrics.refresh_successes, self.oauth_metrics.refresh_failures
+				));
+				info
 			}
 			StringType::Html => self.to_table(),
 		}
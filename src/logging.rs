@@ -0,0 +1,69 @@
+// Logger setup. Logs are colored text by default, or one JSON object per line
+// if REDLIB_LOG_FORMAT is "json", for ingestion by log aggregators.
+//
+// Both formats log the same messages, so secrets masked at the call sites
+// (see `oauth::redact`) stay masked.
+
+use std::io::Write;
+
+use log::Level;
+use pretty_env_logger::env_logger::Builder;
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::config::get_setting;
+
+/// Install the logger, configured by `RUST_LOG` like before.
+pub fn init() {
+	if get_setting("REDLIB_LOG_FORMAT").as_deref() == Some("json") {
+		Builder::from_default_env()
+			.format(|buf, record| {
+				let line = json_line(OffsetDateTime::now_utc(), record.level(), record.target(), &record.args().to_string());
+				writeln!(buf, "{line}")
+			})
+			.init();
+	} else {
+		pretty_env_logger::init();
+	}
+}
+
+/// A log line in the JSON format.
+fn json_line(timestamp: OffsetDateTime, level: Level, target: &str, message: &str) -> String {
+	json!({
+		"timestamp": rfc3339(timestamp),
+		"level": level.as_str(),
+		"target": target,
+		"message": message,
+	})
+	.to_string()
+}
+
+/// Format a UTC time as RFC 3339 with millisecond precision.
+fn rfc3339(time: OffsetDateTime) -> String {
+	format!(
+		"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+		time.year(),
+		u8::from(time.month()),
+		time.day(),
+		time.hour(),
+		time.minute(),
+		time.second(),
+		time.millisecond()
+	)
+}
+
+#[test]
+fn test_json_line() {
+	let timestamp = OffsetDateTime::from_unix_timestamp_nanos(1_700_000_000_123_000_000).unwrap();
+	let line = json_line(timestamp, Level::Warn, "redlib::oauth", "Token \"abcd…\" expired");
+	assert_eq!(
+		serde_json::from_str::<serde_json::Value>(&line).unwrap(),
+		json!({
+			"timestamp": "2023-11-14T22:13:20.123Z",
+			"level": "WARN",
+			"target": "redlib::oauth",
+			"message": "Token \"abcd…\" expired",
+		})
+	);
+	assert!(!line.contains('\n'));
+}
@@ -4,14 +4,24 @@
 
 // Reference local files
 mod config;
+mod connector;
 mod duplicates;
+mod feed;
+mod health;
+mod image_metadata;
 mod instance_info;
+mod logging;
+mod metrics;
 mod oauth;
 mod oauth_resources;
+mod oembed;
 mod post;
+mod pwa;
 mod search;
 mod settings;
+mod sitemap;
 mod subreddit;
+mod themes;
 mod user;
 mod utils;
 
@@ -20,10 +30,14 @@ use clap::{Arg, ArgAction, Command};
 
 use futures_lite::FutureExt;
 use hyper::{header::HeaderValue, Body, Request, Response};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
 
 mod client;
-use client::{canonical_path, proxy};
-use log::info;
+use client::{canonical_path, proxy, proxy_audio, stop_token_daemons};
+use feed::FeedFormat;
+use log::{info, warn};
 use once_cell::sync::Lazy;
 use server::RequestExt;
 use utils::{error, redirect, ThemeAssets};
@@ -32,6 +46,32 @@ use crate::client::OAUTH_CLIENT;
 
 mod server;
 
+// How long in-flight requests may take to complete once shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait for SIGINT (CTRL+C) or, on Unix, SIGTERM. Handlers are installed when
+/// the returned future is first polled.
+async fn shutdown_signal() {
+	let ctrl_c = async {
+		tokio::signal::ctrl_c().await.expect("Failed to install CTRL+C signal handler");
+	};
+
+	#[cfg(unix)]
+	let terminate = async {
+		tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+			.expect("Failed to install SIGTERM signal handler")
+			.recv()
+			.await;
+	};
+	#[cfg(not(unix))]
+	let terminate = std::future::pending::<()>();
+
+	tokio::select! {
+		() = ctrl_c => info!("Received SIGINT, shutting down."),
+		() = terminate => info!("Received SIGTERM, shutting down."),
+	}
+}
+
 // Create Services
 
 // Required for the manifest to be valid
@@ -101,6 +141,10 @@ async fn style() -> Result<Response<Body>, String> {
 		let theme = ThemeAssets::get(file.as_ref()).unwrap();
 		res.push_str(std::str::from_utf8(theme.data.as_ref()).unwrap());
 	}
+	for theme in themes::custom_themes() {
+		res.push('\n');
+		res.push_str(&theme.css);
+	}
 	Ok(
 		Response::builder()
 			.status(200)
@@ -117,7 +161,7 @@ async fn main() {
 	_ = dotenvy::dotenv();
 
 	// Initialize logger
-	pretty_env_logger::init();
+	logging::init();
 
 	let matches = Command::new("Redlib")
 		.version(env!("CARGO_PKG_VERSION"))
@@ -175,21 +219,33 @@ async fn main() {
 	// the timestamp so deploy date is accurate - in config case, we need to
 	// evaluate the configuration to avoid paying penalty at first request -
 	// in OAUTH case, we need to retrieve the token to avoid paying penalty
-	// at first request
+	// at first request. Logging in may take a while, so it is done in the
+	// background while the server starts answering, with /readyz failing
+	// until it is done.
 
 	info!("Evaluating config.");
 	Lazy::force(&config::CONFIG);
+	if let Err(errors) = config::CONFIG.validate() {
+		eprintln!("Invalid configuration:");
+		for error in errors {
+			eprintln!("  {error}");
+		}
+		std::process::exit(1);
+	}
 	info!("Evaluating instance info.");
 	Lazy::force(&instance_info::INSTANCE_INFO);
 	info!("Creating OAUTH client.");
-	Lazy::force(&OAUTH_CLIENT);
+	// Blocks until logged in, so it gets a thread of its own
+	tokio::task::spawn_blocking(|| {
+		Lazy::force(&OAUTH_CLIENT);
+	});
 
 	// Define default headers (added to all responses)
 	app.default_headers = headers! {
 		"Referrer-Policy" => "no-referrer",
 		"X-Content-Type-Options" => "nosniff",
 		"X-Frame-Options" => "DENY",
-		"Content-Security-Policy" => "default-src 'none'; font-src 'self'; script-src 'self' blob:; manifest-src 'self'; media-src 'self' data: blob: about:; style-src 'self' 'unsafe-inline'; base-uri 'none'; img-src 'self' data:; form-action 'self'; frame-ancestors 'none'; connect-src 'self'; worker-src blob:;"
+		"Content-Security-Policy" => "default-src 'none'; font-src 'self'; script-src 'self' blob:; manifest-src 'self'; media-src 'self' data: blob: about:; style-src 'self' 'unsafe-inline'; base-uri 'none'; img-src 'self' data:; form-action 'self'; frame-ancestors 'none'; connect-src 'self'; worker-src 'self' blob:;"
 	};
 
 	if let Some(expire_time) = hsts {
@@ -200,9 +256,13 @@ async fn main() {
 
 	// Read static files
 	app.at("/style.css").get(|_| style().boxed());
-	app
-		.at("/manifest.json")
-		.get(|_| resource(include_str!("../static/manifest.json"), "application/json", false).boxed());
+	app.at("/manifest.json").get(|r| pwa::manifest(r).boxed());
+	app.at("/sw.js").get(|r| pwa::service_worker(r).boxed());
+	app.at("/offline.html").get(|r| pwa::offline(r).boxed());
+	app.at("/pwa.js").get(|_| resource(include_str!("../static/pwa.js"), "text/javascript", false).boxed());
+	app.at("/sitemap.xml").get(|r| sitemap::sitemap(r).boxed());
+	app.at("/oembed").get(|r| oembed::oembed(r).boxed());
+	app.at("/opensearch.xml").get(|r| search::opensearch(r).boxed());
 	app.at("/robots.txt").get(|_| {
 		resource(
 			if match config::get_setting("REDLIB_ROBOTS_DISABLE_INDEXING") {
@@ -232,8 +292,27 @@ async fn main() {
 	app
 		.at("/highlighted.js")
 		.get(|_| resource(include_str!("../static/highlighted.js"), "text/javascript", false).boxed());
+	app
+		.at("/readPosts.js")
+		.get(|_| resource(include_str!("../static/readPosts.js"), "text/javascript", false).boxed());
+	app
+		.at("/infiniteScroll.js")
+		.get(|_| resource(include_str!("../static/infiniteScroll.js"), "text/javascript", false).boxed());
+	app
+		.at("/keyboardShortcuts.js")
+		.get(|_| resource(include_str!("../static/keyboardShortcuts.js"), "text/javascript", false).boxed());
+	app
+		.at("/localTime.js")
+		.get(|_| resource(include_str!("../static/localTime.js"), "text/javascript", false).boxed());
+	app
+		.at("/autocomplete.js")
+		.get(|_| resource(include_str!("../static/autocomplete.js"), "text/javascript", false).boxed());
+	app
+		.at("/syncVideoAudio.js")
+		.get(|_| resource(include_str!("../static/syncVideoAudio.js"), "text/javascript", false).boxed());
 
 	// Proxy media through Redlib
+	app.at("/vid/:id/audio").get(|r| proxy_audio(r).boxed());
 	app.at("/vid/:id/:size").get(|r| proxy(r, "https://v.redd.it/{id}/DASH_{size}").boxed());
 	app.at("/hls/:id/*path").get(|r| proxy(r, "https://v.redd.it/{id}/{path}").boxed());
 	app.at("/img/*path").get(|r| proxy(r, "https://i.redd.it/{path}").boxed());
@@ -257,7 +336,9 @@ async fn main() {
 	app.at("/u/:name/comments/:id/:title/:comment_id").get(|r| post::item(r).boxed());
 
 	app.at("/user/[deleted]").get(|req| error(req, "User has deleted their account").boxed());
-	app.at("/user/:name.rss").get(|r| user::rss(r).boxed());
+	app.at("/user/:name.rss").get(|r| feed::serve(r, FeedFormat::Rss, user::feed).boxed());
+	app.at("/user/:name.atom").get(|r| feed::serve(r, FeedFormat::Atom, user::feed).boxed());
+	app.at("/user/:name.json").get(|r| feed::serve(r, FeedFormat::Json, user::feed).boxed());
 	app.at("/user/:name").get(|r| user::profile(r).boxed());
 	app.at("/user/:name/:listing").get(|r| user::profile(r).boxed());
 	app.at("/user/:name/comments/:id").get(|r| post::item(r).boxed());
@@ -266,11 +347,18 @@ async fn main() {
 
 	// Configure settings
 	app.at("/settings").get(|r| settings::get(r).boxed()).post(|r| settings::set(r).boxed());
+	app.at("/settings/themes").get(|r| settings::themes(r).boxed());
 	app.at("/settings/restore").get(|r| settings::restore(r).boxed());
 	app.at("/settings/update").get(|r| settings::update(r).boxed());
+	app.at("/settings/export").get(|r| settings::export(r).boxed());
+	app.at("/settings/import").post(|r| settings::import(r).boxed());
+	app.at("/settings/export_subscriptions").get(|r| settings::export_subscriptions(r).boxed());
+	app.at("/settings/import_subscriptions").post(|r| settings::import_subscriptions(r).boxed());
 
 	// RSS Subscriptions
-	app.at("/r/:sub.rss").get(|r| subreddit::rss(r).boxed());
+	app.at("/r/:sub.rss").get(|r| feed::serve(r, FeedFormat::Rss, subreddit::feed).boxed());
+	app.at("/r/:sub.atom").get(|r| feed::serve(r, FeedFormat::Atom, subreddit::feed).boxed());
+	app.at("/r/:sub.json").get(|r| feed::serve(r, FeedFormat::Json, subreddit::feed).boxed());
 
 	// Subreddit services
 	app
@@ -302,6 +390,9 @@ async fn main() {
 	app.at("/duplicates/:id/:title").get(|r| duplicates::item(r).boxed());
 
 	app.at("/r/:sub/search").get(|r| search::find(r).boxed());
+	app.at("/r/:sub/search.rss").get(|r| feed::serve(r, FeedFormat::Rss, search::feed).boxed());
+	app.at("/r/:sub/search.atom").get(|r| feed::serve(r, FeedFormat::Atom, search::feed).boxed());
+	app.at("/r/:sub/search.json").get(|r| feed::serve(r, FeedFormat::Json, search::feed).boxed());
 
 	app
 		.at("/r/:sub/w")
@@ -314,6 +405,8 @@ async fn main() {
 
 	app.at("/r/:sub/about/sidebar").get(|r| subreddit::sidebar(r).boxed());
 
+	app.at("/r/:sub/random").get(|r| subreddit::random_post(r).boxed());
+
 	app.at("/r/:sub/:sort").get(|r| subreddit::community(r).boxed());
 
 	// Front page
@@ -329,6 +422,10 @@ async fn main() {
 
 	// Search all of Reddit
 	app.at("/search").get(|r| search::find(r).boxed());
+	app.at("/autocomplete").get(|r| search::autocomplete(r).boxed());
+	app.at("/search.rss").get(|r| feed::serve(r, FeedFormat::Rss, search::feed).boxed());
+	app.at("/search.atom").get(|r| feed::serve(r, FeedFormat::Atom, search::feed).boxed());
+	app.at("/search.json").get(|r| feed::serve(r, FeedFormat::Json, search::feed).boxed());
 
 	// Handle about pages
 	app.at("/about").get(|req| error(req, "About pages aren't added yet").boxed());
@@ -336,25 +433,16 @@ async fn main() {
 	// Instance info page
 	app.at("/info").get(|r| instance_info::instance_info(r).boxed());
 	app.at("/info.:extension").get(|r| instance_info::instance_info(r).boxed());
+	app.at("/metrics").get(|r| metrics::metrics(r).boxed());
+	app.at("/version").get(|r| instance_info::version(r).boxed());
+	app.at("/healthz").get(|r| health::healthz(r).boxed());
+	app.at("/readyz").get(|r| health::readyz(r).boxed());
 
 	// Handle obfuscated share links.
 	// Note that this still forces the server to follow the share link to get to the post, so maybe this wants to be updated with a warning before it follow it
-	app.at("/r/:sub/s/:id").get(|req: Request<Body>| {
-		Box::pin(async move {
-			let sub = req.param("sub").unwrap_or_default();
-			match req.param("id").as_deref() {
-				// Share link
-				Some(id) if (8..12).contains(&id.len()) => match canonical_path(format!("/r/{sub}/s/{id}"), 3).await {
-					Ok(Some(path)) => Ok(redirect(&path)),
-					Ok(None) => error(req, "Post ID is invalid. It may point to a post on a community that has been banned.").await,
-					Err(e) => error(req, &e).await,
-				},
-
-				// Error message for unknown pages
-				_ => error(req, "Nothing here").await,
-			}
-		})
-	});
+	app.at("/r/:sub/s/:id").get(|r| post::share_link(r).boxed());
+	app.at("/u/:name/s/:id").get(|r| post::share_link(r).boxed());
+	app.at("/user/:name/s/:id").get(|r| post::share_link(r).boxed());
 
 	app.at("/:id").get(|req: Request<Body>| {
 		Box::pin(async move {
@@ -382,10 +470,30 @@ async fn main() {
 
 	println!("Running Redlib v{} on {listener}!", env!("CARGO_PKG_VERSION"));
 
-	let server = app.listen(&listener);
+	// Notified once a shutdown signal is received
+	let shutting_down = Arc::new(Notify::new());
+	let server = app.listen(&listener, {
+		let shutting_down = shutting_down.clone();
+		async move {
+			shutdown_signal().await;
+			shutting_down.notify_one();
+		}
+	});
 
-	// Run this server for... forever!
-	if let Err(e) = server.await {
-		eprintln!("Server error: {e}");
+	// Run this server until told to stop, giving in-flight requests some time
+	// to complete
+	tokio::select! {
+		result = server => {
+			if let Err(e) = result {
+				eprintln!("Server error: {e}");
+			}
+		}
+		() = async {
+			shutting_down.notified().await;
+			tokio::time::sleep(SHUTDOWN_TIMEOUT).await;
+		} => warn!("Requests still in flight after {SHUTDOWN_TIMEOUT:?}, dropping them."),
 	}
+
+	stop_token_daemons().await;
+	println!("Redlib stopped.");
 }
@@ -0,0 +1,275 @@
+// Counters for things operators may want to monitor. They are shown on the
+// instance info page, and in Prometheus format at /metrics if
+// REDLIB_ENABLE_METRICS is on.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::client::json_cache_stats;
+use crate::config::get_setting;
+
+pub static OAUTH_METRICS: OauthMetrics = OauthMetrics {
+	login_successes: Counter::new(),
+	login_failures: Counter::new(),
+	refresh_successes: Counter::new(),
+	refresh_failures: Counter::new(),
+};
+
+pub static HTTP_METRICS: HttpMetrics = HttpMetrics {
+	requests: Mutex::new(BTreeMap::new()),
+	server_errors: Counter::new(),
+};
+
+pub static UPSTREAM_METRICS: UpstreamMetrics = UpstreamMetrics {
+	errors: Counter::new(),
+	latency: Histogram::new(),
+	last_success: AtomicI64::new(0),
+	last_failure: AtomicI64::new(0),
+};
+
+/// A monotonically increasing counter.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+	pub const fn new() -> Self {
+		Self(AtomicU64::new(0))
+	}
+
+	pub fn inc(&self) {
+		self.0.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn get(&self) -> u64 {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+/// Outcomes of OAuth token requests. A login is a single token request,
+/// while a refresh replaces a pooled token and may take several logins.
+pub struct OauthMetrics {
+	pub login_successes: Counter,
+	pub login_failures: Counter,
+	pub refresh_successes: Counter,
+	pub refresh_failures: Counter,
+}
+
+impl OauthMetrics {
+	pub fn snapshot(&self) -> OauthMetricsSnapshot {
+		OauthMetricsSnapshot {
+			login_successes: self.login_successes.get(),
+			login_failures: self.login_failures.get(),
+			refresh_successes: self.refresh_successes.get(),
+			refresh_failures: self.refresh_failures.get(),
+		}
+	}
+}
+
+/// Requests served by Redlib.
+pub struct HttpMetrics {
+	/// Requests by the route that handled them
+	requests: Mutex<BTreeMap<String, u64>>,
+	/// Responses with a 5xx status
+	pub server_errors: Counter,
+}
+
+impl HttpMetrics {
+	pub fn record_request(&self, route: &str) {
+		let Ok(mut requests) = self.requests.lock() else {
+			return;
+		};
+		match requests.get_mut(route) {
+			Some(count) => *count += 1,
+			None => {
+				requests.insert(route.to_string(), 1);
+			}
+		}
+	}
+}
+
+/// Requests made to Reddit. Their count is that of the latency histogram.
+pub struct UpstreamMetrics {
+	/// Requests that failed or got a 5xx response
+	pub errors: Counter,
+	pub latency: Histogram,
+	// Unix timestamps of the last successful and failed requests
+	last_success: AtomicI64,
+	last_failure: AtomicI64,
+}
+
+impl UpstreamMetrics {
+	/// Record a request that took `duration`, and whether it succeeded.
+	pub fn record(&self, duration: Duration, success: bool) {
+		self.latency.observe(duration);
+		let now = OffsetDateTime::now_utc().unix_timestamp();
+		if success {
+			self.last_success.store(now, Ordering::Relaxed);
+		} else {
+			self.errors.inc();
+			self.last_failure.store(now, Ordering::Relaxed);
+		}
+	}
+
+	/// Unix timestamps of the last successful and failed requests, or 0 if
+	/// there were none.
+	pub fn last_outcomes(&self) -> (i64, i64) {
+		(self.last_success.load(Ordering::Relaxed), self.last_failure.load(Ordering::Relaxed))
+	}
+}
+
+// Upper bounds, in seconds, of the buckets of latency histograms
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 15.0, 30.0];
+
+/// A histogram of durations, with cumulative buckets bounded by [`LATENCY_BUCKETS`].
+pub struct Histogram {
+	buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+	count: AtomicU64,
+	sum_micros: AtomicU64,
+}
+
+impl Histogram {
+	pub const fn new() -> Self {
+		Self {
+			buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+			count: AtomicU64::new(0),
+			sum_micros: AtomicU64::new(0),
+		}
+	}
+
+	pub fn observe(&self, duration: Duration) {
+		let seconds = duration.as_secs_f64();
+		for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+			if seconds <= bound {
+				bucket.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+		self.count.fetch_add(1, Ordering::Relaxed);
+		self.sum_micros.fetch_add(u64::try_from(duration.as_micros()).unwrap_or(u64::MAX), Ordering::Relaxed);
+	}
+
+	fn count(&self) -> u64 {
+		self.count.load(Ordering::Relaxed)
+	}
+
+	// Write the histogram in Prometheus text format
+	fn write(&self, out: &mut String, name: &str) {
+		for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+			let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {}", bucket.load(Ordering::Relaxed));
+		}
+		let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count());
+		let _ = writeln!(out, "{name}_sum {}", self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+		let _ = writeln!(out, "{name}_count {}", self.count());
+	}
+}
+
+// Write the header of a metric in Prometheus text format
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+	let _ = writeln!(out, "# HELP {name} {help}");
+	let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// All metrics in Prometheus text format, given the hits and misses of the
+/// response cache.
+fn render(cache: (u64, u64)) -> String {
+	let mut out = String::new();
+
+	write_header(&mut out, "redlib_http_requests_total", "counter", "Requests handled, by route.");
+	if let Ok(requests) = HTTP_METRICS.requests.lock() {
+		for (route, count) in requests.iter() {
+			let route = route.replace('\\', "\\\\").replace('"', "\\\"");
+			let _ = writeln!(out, "redlib_http_requests_total{{route=\"{route}\"}} {count}");
+		}
+	}
+	write_header(&mut out, "redlib_http_server_errors_total", "counter", "Responses with a 5xx status.");
+	let _ = writeln!(out, "redlib_http_server_errors_total {}", HTTP_METRICS.server_errors.get());
+
+	write_header(&mut out, "redlib_upstream_requests_total", "counter", "Requests made to Reddit.");
+	let _ = writeln!(out, "redlib_upstream_requests_total {}", UPSTREAM_METRICS.latency.count());
+	write_header(&mut out, "redlib_upstream_errors_total", "counter", "Requests to Reddit that failed or got a 5xx response.");
+	let _ = writeln!(out, "redlib_upstream_errors_total {}", UPSTREAM_METRICS.errors.get());
+	write_header(&mut out, "redlib_upstream_request_duration_seconds", "histogram", "Time until Reddit responded.");
+	UPSTREAM_METRICS.latency.write(&mut out, "redlib_upstream_request_duration_seconds");
+
+	let oauth = OAUTH_METRICS.snapshot();
+	write_header(&mut out, "redlib_oauth_logins_total", "counter", "OAuth token requests, by result.");
+	let _ = writeln!(out, "redlib_oauth_logins_total{{result=\"success\"}} {}", oauth.login_successes);
+	let _ = writeln!(out, "redlib_oauth_logins_total{{result=\"failure\"}} {}", oauth.login_failures);
+	write_header(&mut out, "redlib_oauth_refreshes_total", "counter", "Refreshes of pooled OAuth tokens, by result.");
+	let _ = writeln!(out, "redlib_oauth_refreshes_total{{result=\"success\"}} {}", oauth.refresh_successes);
+	let _ = writeln!(out, "redlib_oauth_refreshes_total{{result=\"failure\"}} {}", oauth.refresh_failures);
+
+	write_header(&mut out, "redlib_cache_hits_total", "counter", "Responses from Reddit served from the cache.");
+	let _ = writeln!(out, "redlib_cache_hits_total {}", cache.0);
+	write_header(&mut out, "redlib_cache_misses_total", "counter", "Responses from Reddit not found in the cache.");
+	let _ = writeln!(out, "redlib_cache_misses_total {}", cache.1);
+
+	out
+}
+
+/// Handles the metrics endpoint, which is only served if REDLIB_ENABLE_METRICS
+/// is on so that public instances don't expose their internals.
+pub async fn metrics(_req: Request<Body>) -> Result<Response<Body>, String> {
+	if get_setting("REDLIB_ENABLE_METRICS").as_deref() != Some("on") {
+		return Response::builder().status(404).body(Body::from("Metrics are disabled")).map_err(|e| e.to_string());
+	}
+	Response::builder()
+		.status(200)
+		.header("content-type", "text/plain; version=0.0.4; charset=utf-8")
+		.header("cache-control", "no-store")
+		.body(render(json_cache_stats().await).into())
+		.map_err(|e| e.to_string())
+}
+
+/// The values of [`OauthMetrics`] at a point in time.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OauthMetricsSnapshot {
+	pub login_successes: u64,
+	pub login_failures: u64,
+	pub refresh_successes: u64,
+	pub refresh_failures: u64,
+}
+
+#[test]
+fn test_counter() {
+	let counter = Counter::new();
+	assert_eq!(counter.get(), 0);
+	counter.inc();
+	counter.inc();
+	assert_eq!(counter.get(), 2);
+}
+
+#[test]
+fn test_histogram() {
+	let histogram = Histogram::new();
+	histogram.observe(Duration::from_millis(80));
+	histogram.observe(Duration::from_secs(2));
+	histogram.observe(Duration::from_secs(60));
+
+	let mut out = String::new();
+	histogram.write(&mut out, "latency");
+	assert!(out.contains("latency_bucket{le=\"0.05\"} 0\n"));
+	assert!(out.contains("latency_bucket{le=\"0.1\"} 1\n"));
+	assert!(out.contains("latency_bucket{le=\"2.5\"} 2\n"));
+	assert!(out.contains("latency_bucket{le=\"30\"} 2\n"));
+	assert!(out.contains("latency_bucket{le=\"+Inf\"} 3\n"));
+	assert!(out.contains("latency_sum 62.08\n"));
+	assert!(out.contains("latency_count 3\n"));
+}
+
+#[test]
+fn test_render() {
+	HTTP_METRICS.record_request("/r/:sub");
+	HTTP_METRICS.record_request("/r/:sub");
+	let out = render((3, 1));
+	assert!(out.contains("# TYPE redlib_http_requests_total counter\n"));
+	assert!(out.contains("redlib_http_requests_total{route=\"/r/:sub\"} "));
+	assert!(out.contains("redlib_cache_hits_total 3\n"));
+	assert!(out.contains("redlib_cache_misses_total 1\n"));
+	assert!(out.contains("# TYPE redlib_upstream_request_duration_seconds histogram\n"));
+}
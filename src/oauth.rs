@@ -1,55 +1,224 @@
-use std::{collections::HashMap, sync::atomic::Ordering, time::Duration};
+use std::{
+	collections::HashMap,
+	fs::{read_to_string, remove_file, rename, OpenOptions},
+	future::Future,
+	io::{self, Write},
+	sync::{
+		atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
 
 use crate::{
-	client::{CLIENT, OAUTH_CLIENT, OAUTH_IS_ROLLING_OVER, OAUTH_RATELIMIT_REMAINING},
-	oauth_resources::ANDROID_APP_VERSION_LIST,
+	client::{CLIENT, OAUTH_CLIENT},
+	config::get_setting,
+	metrics::OAUTH_METRICS,
+	oauth_resources::{ANDROID_APP_VERSION_LIST, IOS_APP_VERSION_LIST, IOS_OS_VERSION_LIST},
 };
+use arc_swap::{ArcSwap, ArcSwapOption};
 use base64::{engine::general_purpose, Engine as _};
-use hyper::{client, Body, Method, Request};
-use log::{error, info, trace};
+use hyper::{
+	client::{self, connect::Connect},
+	header, Body, HeaderMap, Method, Request, StatusCode,
+};
+use log::{error, info, trace, warn};
+use once_cell::sync::Lazy;
 
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::time::{error::Elapsed, timeout};
+use time::OffsetDateTime;
+use tokio::{
+	sync::Mutex,
+	time::{error::Elapsed, timeout},
+};
 
 static REDDIT_ANDROID_OAUTH_CLIENT_ID: &str = "ohXpoqrZYub1kg";
+static REDDIT_IOS_OAUTH_CLIENT_ID: &str = "LNDo9k1o8UAEUw";
+
+// iPhone model identifiers sent in the Device-Name header of iOS clients
+static IOS_DEVICES: &[&str; 12] = &[
+	"iPhone11,2",
+	"iPhone11,8",
+	"iPhone12,1",
+	"iPhone12,3",
+	"iPhone13,2",
+	"iPhone13,4",
+	"iPhone14,2",
+	"iPhone14,5",
+	"iPhone14,7",
+	"iPhone15,2",
+	"iPhone15,4",
+	"iPhone16,1",
+];
 
 static AUTH_ENDPOINT: &str = "https://www.reddit.com";
 
+// Versions loaded from REDLIB_OAUTH_APP_VERSIONS_FILE, spoofed alongside the
+// generated lists in oauth_resources.rs
+static EXTRA_APP_VERSIONS: Lazy<AppVersions> = Lazy::new(AppVersions::load);
+
+/// Base URL of the token endpoint, overridable by `REDLIB_OAUTH_AUTH_ENDPOINT`.
+fn auth_endpoint() -> String {
+	get_setting("REDLIB_OAUTH_AUTH_ENDPOINT").unwrap_or_else(|| AUTH_ENDPOINT.to_string())
+}
+
+/// OAuth client ID of the Android app, overridable by `REDLIB_OAUTH_ANDROID_CLIENT_ID`.
+fn android_client_id() -> String {
+	get_setting("REDLIB_OAUTH_ANDROID_CLIENT_ID").unwrap_or_else(|| REDDIT_ANDROID_OAUTH_CLIENT_ID.to_string())
+}
+
+/// OAuth client ID of the iOS app, overridable by `REDLIB_OAUTH_IOS_CLIENT_ID`.
+fn ios_client_id() -> String {
+	get_setting("REDLIB_OAUTH_IOS_CLIENT_ID").unwrap_or_else(|| REDDIT_IOS_OAUTH_CLIENT_ID.to_string())
+}
+
+// Delay before the first login retry. Doubled after each failed attempt,
+// up to MAX_LOGIN_BACKOFF.
+const INITIAL_LOGIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_LOGIN_BACKOFF: Duration = Duration::from_secs(60);
+
+// Logins attempted by a refresh when REDLIB_OAUTH_MAX_ATTEMPTS is unset,
+// before leaving it to the token daemon to retry later.
+const REFRESH_ATTEMPTS: u32 = 3;
+
+// Shortest time the token daemon will wait between refreshes.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+// Range that the token lifetime reported by Reddit is clamped into, so that a
+// bogus value can't stop the daemon from refreshing or make it refresh in a
+// tight loop.
+const MIN_EXPIRES_IN: u64 = 60;
+const MAX_EXPIRES_IN: u64 = 24 * 60 * 60;
+
+// Delay before the token daemon retries a failed refresh. Doubled after each
+// consecutive failure, up to MAX_REFRESH_BACKOFF.
+const INITIAL_REFRESH_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_REFRESH_BACKOFF: Duration = Duration::from_secs(60);
+
+// Largest random offset added to or subtracted from the refresh schedule, so
+// that tokens obtained at the same time don't all refresh at once.
+const MAX_REFRESH_JITTER: Duration = Duration::from_secs(30);
+
+// How long a token request may take before the login is considered failed,
+// unless overridden by REDLIB_OAUTH_LOGIN_TIMEOUT.
+const DEFAULT_LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
 // Spoofed client for Android devices
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Oauth {
 	pub(crate) initial_headers: HashMap<String, String>,
 	pub(crate) headers_map: HashMap<String, String>,
 	pub(crate) token: String,
 	expires_in: u64,
+	// Unix timestamp of when the token was retrieved
+	#[serde(default)]
+	obtained_at: i64,
 	device: Device,
 }
 
 impl Oauth {
-	/// Create a new OAuth client
-	pub(crate) async fn new() -> Self {
-		// Call new_internal until it succeeds
+	/// Create a new OAuth client. Login is retried with exponential backoff
+	/// until it succeeds, or until `REDLIB_OAUTH_MAX_ATTEMPTS` attempts have
+	/// failed. In the latter case, a client without a token is returned so the
+	/// instance can still start; the token is fetched on the next refresh.
+	///
+	/// If `previous` is given, its device identity (headers and loid) is kept
+	/// and only the token is renewed. Otherwise, a fresh device is spoofed.
+	///
+	/// `failures` counts consecutive failed attempts, and is reset once a
+	/// login succeeds.
+	pub(crate) async fn new(previous: Option<&Self>, failures: &AtomicU32) -> Self {
+		match Self::login_with_retries(previous, failures, max_attempts()).await {
+			Some(oauth) => oauth,
+			None => {
+				error!("Giving up on creating OAuth client. Starting without a token until the next refresh succeeds.");
+				Self::unauthenticated(previous)
+			}
+		}
+	}
+
+	/// Log in again to refresh the token of `previous`, or of a fresh device
+	/// if `None`. Unlike [`Oauth::new`], this gives up after
+	/// `REDLIB_OAUTH_MAX_ATTEMPTS` attempts, or `REFRESH_ATTEMPTS` if unset,
+	/// so that the token daemon can back off before trying again.
+	pub(crate) async fn refresh(previous: Option<&Self>, failures: &AtomicU32) -> Option<Self> {
+		Self::login_with_retries(previous, failures, Some(max_attempts().unwrap_or(REFRESH_ATTEMPTS))).await
+	}
+
+	/// Log in with exponential backoff until it succeeds, or until
+	/// `max_attempts` attempts have failed.
+	async fn login_with_retries(previous: Option<&Self>, failures: &AtomicU32, max_attempts: Option<u32>) -> Option<Self> {
+		let mut backoff = INITIAL_LOGIN_BACKOFF;
+		let mut attempt: u32 = 0;
+
+		// Call new_with_timeout until it succeeds
 		loop {
-			let attempt = Self::new_with_timeout().await;
-			match attempt {
-				Ok(Some(oauth)) => {
+			attempt += 1;
+			let mut delay = backoff;
+			let result = Self::new_with_timeout(previous).await;
+			if matches!(result, Ok(Ok(_))) {
+				OAUTH_METRICS.login_successes.inc();
+			} else {
+				OAUTH_METRICS.login_failures.inc();
+			}
+			match result {
+				Ok(Ok(oauth)) => {
 					info!("[✅] Successfully created OAuth client");
-					return oauth;
+					failures.store(0, Ordering::SeqCst);
+					return Some(oauth);
+				}
+				Ok(Err(LoginError::RateLimited(retry_after))) => {
+					error!("Rate limited while creating OAuth client (attempt {attempt}).");
+					// Wait at least as long as Reddit asked us to
+					if let Some(retry_after) = retry_after {
+						delay = delay.max(retry_after);
+					}
 				}
-				Ok(None) => {
-					error!("Failed to create OAuth client. Retrying in 5 seconds...");
-					continue;
+				Ok(Err(LoginError::Failed)) => {
+					error!("Failed to create OAuth client (attempt {attempt}).");
 				}
 				Err(duration) => {
-					error!("Failed to create OAuth client in {duration:?}. Retrying in 5 seconds...");
+					error!("Failed to create OAuth client in {duration:?} (attempt {attempt}).");
 				}
 			}
+
+			failures.fetch_add(1, Ordering::SeqCst);
+
+			if max_attempts.is_some_and(|max| attempt >= max) {
+				error!("Failed to create OAuth client after {attempt} attempts.");
+				return None;
+			}
+
+			info!("Retrying OAuth login in {delay:?}...");
+			tokio::time::sleep(delay).await;
+			backoff = (backoff * 2).min(MAX_LOGIN_BACKOFF);
 		}
 	}
 
-	async fn new_with_timeout() -> Result<Option<Self>, Elapsed> {
-		let mut oauth = Self::default();
-		timeout(Duration::from_secs(5), oauth.login()).await.map(|result| result.map(|_| oauth))
+	/// Log in, giving up after the configured login timeout so that a hung
+	/// connection to the auth endpoint can't block startup or a token refresh
+	/// forever.
+	async fn new_with_timeout(previous: Option<&Self>) -> Result<Result<Self, LoginError>, Elapsed> {
+		let mut oauth = Self::unauthenticated(previous);
+		timeout(login_timeout(), oauth.login()).await.map(|result| result.map(|_| oauth))
+	}
+
+	/// A client without a token, spoofing the same device as `previous` or a
+	/// fresh one.
+	fn unauthenticated(previous: Option<&Self>) -> Self {
+		let Some(previous) = previous else {
+			return Self::default();
+		};
+		let mut headers_map = previous.headers_map.clone();
+		headers_map.remove("Authorization");
+		Self {
+			headers_map,
+			token: String::new(),
+			expires_in: 0,
+			obtained_at: 0,
+			..previous.clone()
+		}
 	}
 
 	pub(crate) fn default() -> Self {
@@ -63,18 +232,32 @@ impl Oauth {
 			initial_headers,
 			token: String::new(),
 			expires_in: 0,
+			obtained_at: 0,
 			device,
 		}
 	}
-	async fn login(&mut self) -> Option<()> {
+	async fn login(&mut self) -> Result<(), LoginError> {
+		let client: client::Client<_, Body> = CLIENT.clone();
+		self.login_with(&client, &auth_endpoint()).await
+	}
+
+	/// Request a token from `endpoint` using `client`.
+	async fn login_with<C>(&mut self, client: &client::Client<C, Body>, endpoint: &str) -> Result<(), LoginError>
+	where
+		C: Connect + Clone + Send + Sync + 'static,
+	{
 		// Construct URL for OAuth token
-		let url = format!("{AUTH_ENDPOINT}/auth/v2/oauth/access-token/loid");
+		let url = format!("{endpoint}/auth/v2/oauth/access-token/loid");
 		let mut builder = Request::builder().method(Method::POST).uri(&url);
 
 		// Add headers from spoofed client
 		for (key, value) in &self.initial_headers {
 			builder = builder.header(key, value);
 		}
+		// Send back the loid from a previous login, like the app does
+		if let Some(loid) = self.headers_map.get("x-reddit-loid") {
+			builder = builder.header("x-reddit-loid", loid);
+		}
 		// Set up HTTP Basic Auth - basically just the const OAuth ID's with no password,
 		// Base64-encoded. https://en.wikipedia.org/wiki/Basic_access_authentication
 		// This could be constant, but I don't think it's worth it. OAuth ID's can change
@@ -84,7 +267,7 @@ impl Oauth {
 
 		// Set JSON body. I couldn't tell you what this means. But that's what the client sends
 		let json = json!({
-				"scopes": ["*","email"]
+				"scopes": scopes()
 		});
 		let body = Body::from(json.to_string());
 
@@ -94,10 +277,26 @@ impl Oauth {
 		trace!("Sending token request...");
 
 		// Send request
-		let client: client::Client<_, Body> = CLIENT.clone();
-		let resp = client.request(request).await.ok()?;
+		let resp = client.request(request).await.map_err(|_| LoginError::Failed)?;
 
 		trace!("Received response with status {} and length {:?}", resp.status(), resp.headers().get("content-length"));
+		trace!("Response headers: {:?}", redact_headers(resp.headers()));
+
+		// Reddit is rate limiting us - back off for as long as it tells us to
+		let status = resp.status();
+		if status == StatusCode::TOO_MANY_REQUESTS {
+			let retry_after = resp.headers().get(header::RETRY_AFTER).and_then(|val| val.to_str().ok()).and_then(parse_retry_after);
+			error!("Token request was rate limited. Retry-After: {retry_after:?}");
+			return Err(LoginError::RateLimited(retry_after));
+		}
+
+		// Any other error status won't carry a token; log a snippet of the body to help diagnose blocks
+		if status.is_client_error() || status.is_server_error() {
+			let body_bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap_or_default();
+			let snippet = String::from_utf8_lossy(&body_bytes).chars().take(200).collect::<String>();
+			error!("Token request failed with status {status}: {snippet}");
+			return Err(LoginError::Failed);
+		}
 
 		// Parse headers - loid header _should_ be saved sent on subsequent token refreshes.
 		// Technically it's not needed, but it's easy for Reddit API to check for this.
@@ -105,69 +304,446 @@ impl Oauth {
 		// Not worried about the privacy implications, since this is randomly changed
 		// and really only as privacy-concerning as the OAuth token itself.
 		if let Some(header) = resp.headers().get("x-reddit-loid") {
-			self.headers_map.insert("x-reddit-loid".to_owned(), header.to_str().ok()?.to_string());
+			self
+				.headers_map
+				.insert("x-reddit-loid".to_owned(), header.to_str().map_err(|_| LoginError::Failed)?.to_string());
 		}
 
 		// Same with x-reddit-session
 		if let Some(header) = resp.headers().get("x-reddit-session") {
-			self.headers_map.insert("x-reddit-session".to_owned(), header.to_str().ok()?.to_string());
+			self
+				.headers_map
+				.insert("x-reddit-session".to_owned(), header.to_str().map_err(|_| LoginError::Failed)?.to_string());
 		}
 
 		trace!("Serializing response...");
 
 		// Serialize response
-		let body_bytes = hyper::body::to_bytes(resp.into_body()).await.ok()?;
-		let json: serde_json::Value = serde_json::from_slice(&body_bytes).ok()?;
+		let body_bytes = hyper::body::to_bytes(resp.into_body()).await.map_err(|_| LoginError::Failed)?;
+		let json: serde_json::Value = serde_json::from_slice(&body_bytes).map_err(|_| LoginError::Failed)?;
 
 		trace!("Accessing relevant fields...");
 
 		// Save token and expiry
-		self.token = json.get("access_token")?.as_str()?.to_string();
-		self.expires_in = json.get("expires_in")?.as_u64()?;
+		self.token = json.get("access_token").and_then(serde_json::Value::as_str).ok_or(LoginError::Failed)?.to_string();
+		self.expires_in = clamp_expires_in(json.get("expires_in").and_then(serde_json::Value::as_u64).ok_or(LoginError::Failed)?);
+		self.obtained_at = OffsetDateTime::now_utc().unix_timestamp();
 		self.headers_map.insert("Authorization".to_owned(), format!("Bearer {}", self.token));
 
-		info!("[✅] Success - Retrieved token \"{}...\", expires in {}", &self.token[..32], self.expires_in);
+		info!("[✅] Success - Retrieved token \"{}\", expires in {}", redact(&self.token), self.expires_in);
+
+		Ok(())
+	}
 
-		Some(())
+	/// Rebase a persisted client onto the current time, so that `expires_in`
+	/// counts from now. Returns `None` if the token is missing or would expire
+	/// before the token daemon gets a chance to refresh it.
+	fn restore(mut self, now: i64) -> Option<Self> {
+		let expires_at = self.obtained_at.saturating_add_unsigned(self.expires_in);
+		let remaining = u64::try_from(expires_at.saturating_sub(now)).ok()?;
+		if self.token.is_empty() || self.obtained_at > now || remaining <= MIN_REFRESH_INTERVAL.as_secs() {
+			return None;
+		}
+		self.expires_in = remaining;
+		self.obtained_at = now;
+		Some(self)
 	}
 }
 
-pub async fn token_daemon() {
+/// Reasons a token request can fail
+#[derive(Debug)]
+enum LoginError {
+	/// Reddit responded with a 429, optionally telling us how long to wait
+	RateLimited(Option<Duration>),
+	/// Any other failure (network error, error status, malformed response)
+	Failed,
+}
+
+/// Clamps a token lifetime into `MIN_EXPIRES_IN..=MAX_EXPIRES_IN`.
+fn clamp_expires_in(expires_in: u64) -> u64 {
+	let clamped = expires_in.clamp(MIN_EXPIRES_IN, MAX_EXPIRES_IN);
+	if clamped != expires_in {
+		warn!("Token response has an unexpected lifetime of {expires_in}s, using {clamped}s instead.");
+	}
+	clamped
+}
+
+// Scopes requested by the official app
+const DEFAULT_SCOPES: [&str; 2] = ["*", "email"];
+
+/// Reads the OAuth scopes to request from the comma-separated
+/// `REDLIB_OAUTH_SCOPES`, falling back to what the official app requests.
+fn scopes() -> Vec<String> {
+	get_setting("REDLIB_OAUTH_SCOPES")
+		.map(|val| parse_scopes(&val))
+		.filter(|scopes| !scopes.is_empty())
+		.unwrap_or_else(|| DEFAULT_SCOPES.map(String::from).to_vec())
+}
+
+fn parse_scopes(value: &str) -> Vec<String> {
+	value.split(',').map(str::trim).filter(|scope| !scope.is_empty()).map(String::from).collect()
+}
+
+/// Reads the configured login timeout, in seconds.
+fn login_timeout() -> Duration {
+	get_setting("REDLIB_OAUTH_LOGIN_TIMEOUT")
+		.and_then(|val| val.parse::<u64>().ok())
+		.filter(|&val| val > 0)
+		.map_or(DEFAULT_LOGIN_TIMEOUT, Duration::from_secs)
+}
+
+/// Parses the value of a `Retry-After` header given in seconds.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+	value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+/// A pool of OAuth clients, each spoofing its own device. Requests are spread
+/// across the pool round-robin so that no single token bears the full rate
+/// limit. The pool size is set by `REDLIB_OAUTH_POOL_SIZE` (default 1).
+pub struct OauthPool {
+	clients: Vec<PooledOauth>,
+	next: AtomicUsize,
+}
+
+/// A single entry of the [`OauthPool`], along with its rate limit state.
+pub struct PooledOauth {
+	client: ArcSwap<Oauth>,
+	pub(crate) ratelimit_remaining: AtomicU16,
+	pub(crate) is_rolling_over: AtomicBool,
+	failures: AtomicU32,
+	// Refreshes since the device identity was last changed
+	refreshes: AtomicU32,
+	// Held while a refresh is in flight, so that concurrent refreshes coalesce
+	refresh_lock: Mutex<()>,
+	// Incremented after every completed refresh
+	generation: AtomicU64,
+	// Rate limit reported by Reddit for the current token
+	pub(crate) upstream_ratelimit: ArcSwapOption<UpstreamRateLimit>,
+}
+
+/// Rate limit reported by Reddit in the `x-ratelimit-*` headers of the latest
+/// response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpstreamRateLimit {
+	/// Requests left in the current period
+	pub remaining: u32,
+	/// Requests made in the current period
+	pub used: u32,
+	/// Seconds until the period resets
+	pub reset: u32,
+}
+
+impl UpstreamRateLimit {
+	/// Parse the values of the `x-ratelimit-remaining`, `x-ratelimit-used` and
+	/// `x-ratelimit-reset` headers. Reddit may send these as decimals.
+	pub(crate) fn parse(remaining: &str, used: &str, reset: &str) -> Option<Self> {
+		let parse = |val: &str| val.trim().parse::<f64>().ok().filter(|val| *val >= 0.0).map(|val| val as u32);
+		Some(Self {
+			remaining: parse(remaining)?,
+			used: parse(used)?,
+			reset: parse(reset)?,
+		})
+	}
+}
+
+impl PooledOauth {
+	/// Run `refresh`, unless another refresh completes while waiting for the
+	/// one in flight. Returns whether `refresh` ran.
+	async fn single_flight(&self, refresh: impl Future<Output = ()>) -> bool {
+		let generation = self.generation.load(Ordering::SeqCst);
+		let _guard = self.refresh_lock.lock().await;
+		if self.generation.load(Ordering::SeqCst) != generation {
+			return false;
+		}
+		self.is_rolling_over.store(true, Ordering::SeqCst);
+		refresh.await;
+		self.generation.fetch_add(1, Ordering::SeqCst);
+		self.is_rolling_over.store(false, Ordering::SeqCst);
+		true
+	}
+}
+
+impl OauthPool {
+	/// Create a pool of `size` OAuth clients. Clients are logged in one after
+	/// the other to avoid a burst of token requests.
+	///
+	/// If `REDLIB_OAUTH_CACHE_PATH` is set, clients whose tokens were saved
+	/// there by a previous run and are still valid are reused instead.
+	pub(crate) async fn new(size: usize) -> Self {
+		let mut persisted = load_persisted().into_iter();
+		let now = OffsetDateTime::now_utc().unix_timestamp();
+		let mut clients = Vec::with_capacity(size);
+		for index in 0..size.max(1) {
+			let failures = AtomicU32::new(0);
+			let oauth = match persisted.next().and_then(|oauth| oauth.restore(now)) {
+				Some(oauth) => {
+					info!("[✅] Restored OAuth token #{index} \"{}\", expires in {}", redact(&oauth.token), oauth.expires_in);
+					oauth
+				}
+				None => Oauth::new(None, &failures).await,
+			};
+			clients.push(PooledOauth {
+				client: ArcSwap::new(oauth.into()),
+				ratelimit_remaining: AtomicU16::new(99),
+				is_rolling_over: AtomicBool::new(false),
+				failures,
+				refreshes: AtomicU32::new(0),
+				refresh_lock: Mutex::new(()),
+				generation: AtomicU64::new(0),
+				upstream_ratelimit: ArcSwapOption::empty(),
+			});
+		}
+		let pool = Self {
+			clients,
+			next: AtomicUsize::new(0),
+		};
+		pool.persist();
+		pool
+	}
+
+	/// Save every client of the pool to `REDLIB_OAUTH_CACHE_PATH`, if set.
+	pub(crate) fn persist(&self) {
+		let Some(path) = get_setting("REDLIB_OAUTH_CACHE_PATH") else {
+			return;
+		};
+		let clients: Vec<Oauth> = self.clients.iter().map(|pooled| Oauth::clone(&pooled.client.load())).collect();
+		let result = serde_json::to_string(&clients)
+			.map_err(|e| e.to_string())
+			.and_then(|json| write_private(&path, &json).map_err(|e| e.to_string()));
+		if let Err(e) = result {
+			warn!("Failed to save OAuth tokens to {path}: {e}");
+		}
+	}
+
+	/// Number of clients in the pool
+	pub(crate) fn len(&self) -> usize {
+		self.clients.len()
+	}
+
+	/// Index of the client that should serve the next request
+	pub(crate) fn next_index(&self) -> usize {
+		self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len()
+	}
+
+	/// Get the pool entry at `index`
+	pub(crate) fn get(&self, index: usize) -> &PooledOauth {
+		&self.clients[index % self.clients.len()]
+	}
+
+	/// Get the current client at `index`
+	pub(crate) fn load(&self, index: usize) -> Arc<Oauth> {
+		self.get(index).client.load_full()
+	}
+
+	/// A read-only snapshot of the health of every client in the pool
+	pub(crate) fn status(&self) -> Vec<OauthStatus> {
+		let now = OffsetDateTime::now_utc().unix_timestamp();
+		self.clients.iter().map(|pooled| OauthStatus::new(pooled, now)).collect()
+	}
+}
+
+/// Health of a single pooled OAuth client, as shown on the instance info page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OauthStatus {
+	/// Which app is being spoofed ("Android" or "iOS")
+	pub device: String,
+	/// Whether the client currently holds a token
+	pub has_token: bool,
+	/// Seconds until the token expires, or 0 if it already has
+	pub expires_in: u64,
+	/// Unix timestamp of the last successful login, if any
+	pub last_refresh: Option<i64>,
+	/// Number of login attempts that have failed since the last success
+	pub failure_count: u32,
+	/// Requests left before the token is rolled over
+	pub ratelimit_remaining: u16,
+	/// Rate limit last reported by Reddit, if any
+	#[serde(default)]
+	pub upstream_ratelimit: Option<UpstreamRateLimit>,
+}
+
+impl OauthStatus {
+	fn new(pooled: &PooledOauth, now: i64) -> Self {
+		let oauth = pooled.client.load();
+		let has_token = !oauth.token.is_empty();
+		let expires_at = oauth.obtained_at.saturating_add_unsigned(oauth.expires_in);
+		Self {
+			device: oauth.device.family().to_string(),
+			has_token,
+			expires_in: if has_token { u64::try_from(expires_at - now).unwrap_or(0) } else { 0 },
+			last_refresh: has_token.then_some(oauth.obtained_at),
+			failure_count: pooled.failures.load(Ordering::SeqCst),
+			ratelimit_remaining: pooled.ratelimit_remaining.load(Ordering::SeqCst),
+			upstream_ratelimit: pooled.upstream_ratelimit.load_full().map(|limit| *limit),
+		}
+	}
+}
+
+/// Load the clients saved to `REDLIB_OAUTH_CACHE_PATH` by a previous run. A
+/// missing or unreadable file yields no clients, which makes the pool fall back
+/// to a fresh login.
+fn load_persisted() -> Vec<Oauth> {
+	let Some(path) = get_setting("REDLIB_OAUTH_CACHE_PATH") else {
+		return Vec::new();
+	};
+	let Ok(contents) = read_to_string(&path) else {
+		return Vec::new();
+	};
+	serde_json::from_str(&contents).unwrap_or_else(|e| {
+		warn!("Ignoring corrupt OAuth token cache at {path}: {e}");
+		Vec::new()
+	})
+}
+
+/// Write `contents` to `path`, readable by the owner only as it holds tokens.
+/// The file is replaced in one go, so that a crash can't leave it truncated.
+fn write_private(path: &str, contents: &str) -> io::Result<()> {
+	// Unique, as tokens of the pool may be saved concurrently
+	let temp = format!("{path}.{:016x}.tmp", fastrand::u64(..));
+	let mut options = OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+	let mut file = options.open(&temp)?;
+	let result = file.write_all(contents.as_bytes()).and_then(|()| file.sync_all()).and_then(|()| rename(&temp, path));
+	if result.is_err() {
+		let _ = remove_file(&temp);
+	}
+	result
+}
+
+/// Reads `REDLIB_OAUTH_MAX_ATTEMPTS`, the number of logins to attempt before
+/// giving up. `None` means retrying forever.
+fn max_attempts() -> Option<u32> {
+	get_setting("REDLIB_OAUTH_MAX_ATTEMPTS").and_then(|val| val.parse::<u32>().ok()).filter(|&val| val > 0)
+}
+
+/// Reads how many refreshes a device identity is kept for. Defaults to 1,
+/// meaning every refresh spoofs a new device; 0 keeps the identity forever.
+fn rotate_device_every() -> u32 {
+	get_setting("REDLIB_OAUTH_ROTATE_DEVICE_EVERY").and_then(|val| val.parse::<u32>().ok()).unwrap_or(1)
+}
+
+/// Whether to spoof a new device on the `refreshes`th refresh since the last
+/// rotation, given the configured `every`.
+fn should_rotate_device(refreshes: u32, every: u32) -> bool {
+	every != 0 && refreshes >= every
+}
+
+/// Reads the configured OAuth pool size, defaulting to a single client.
+pub(crate) fn pool_size() -> usize {
+	get_setting("REDLIB_OAUTH_POOL_SIZE")
+		.and_then(|val| val.parse::<usize>().ok())
+		.filter(|&val| val > 0)
+		.unwrap_or(1)
+}
+
+pub async fn token_daemon(index: usize) {
+	let mut backoff = INITIAL_REFRESH_BACKOFF;
+
 	// Monitor for refreshing token
 	loop {
-		// Get expiry time - be sure to not hold the read lock
-		let expires_in = { OAUTH_CLIENT.load_full().expires_in };
-
-		// sleep for the expiry time minus 2 minutes
-		let duration = Duration::from_secs(expires_in - 120);
+		let duration = {
+			let oauth = OAUTH_CLIENT.load(index);
+			let failed = oauth.token.is_empty() || OAUTH_CLIENT.get(index).failures.load(Ordering::SeqCst) > 0;
+			if failed {
+				warn!("The last refresh of OAuth token #{index} failed.");
+			}
+			refresh_delay(&oauth, failed, &mut backoff)
+		};
 
-		info!("[⏳] Waiting for {duration:?} seconds before refreshing OAuth token...");
+		info!("[⏳] Waiting for {duration:?} seconds before refreshing OAuth token #{index}...");
 
 		tokio::time::sleep(duration).await;
 
-		info!("[⌛] {duration:?} Elapsed! Refreshing OAuth token...");
+		info!("[⌛] {duration:?} Elapsed! Refreshing OAuth token #{index}...");
 
 		// Refresh token - in its own scope
 		{
-			force_refresh_token().await;
+			force_refresh_token(index).await;
 		}
 	}
 }
 
-pub async fn force_refresh_token() {
-	if OAUTH_IS_ROLLING_OVER.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
-		trace!("Skipping refresh token roll over, already in progress");
-		return;
+/// Computes how long the token daemon should wait before the next refresh of
+/// `oauth`. If the last refresh `failed`, the daemon retries after `backoff`,
+/// which is doubled for next time. Once a refresh succeeds, `backoff` is
+/// reset and the normal expiry-based schedule resumes, shifted by a random
+/// jitter that never exceeds the refresh margin.
+fn refresh_delay(oauth: &Oauth, failed: bool, backoff: &mut Duration) -> Duration {
+	if failed {
+		let delay = *backoff;
+		*backoff = (*backoff * 2).min(MAX_REFRESH_BACKOFF);
+		delay
+	} else {
+		*backoff = INITIAL_REFRESH_BACKOFF;
+		jitter(refresh_interval(oauth.expires_in), refresh_margin(oauth.expires_in).min(MAX_REFRESH_JITTER))
+	}
+}
+
+/// Randomly shifts `duration` by up to `max` in either direction, without going
+/// below `MIN_REFRESH_INTERVAL`.
+fn jitter(duration: Duration, max: Duration) -> Duration {
+	let max = max.as_millis() as u64;
+	let offset = Duration::from_millis(fastrand::u64(0..=max * 2));
+	(duration + offset).saturating_sub(Duration::from_millis(max)).max(MIN_REFRESH_INTERVAL)
+}
+
+/// Computes how long to wait before refreshing a token that expires in
+/// `expires_in` seconds. The token is refreshed 2 minutes before expiry, or
+/// at 90% of its lifetime for tokens that live less than 20 minutes. This
+/// never returns less than `MIN_REFRESH_INTERVAL`, so an empty or malformed
+/// token response can't make the daemon spin.
+fn refresh_interval(expires_in: u64) -> Duration {
+	Duration::from_secs(expires_in).saturating_sub(refresh_margin(expires_in)).max(MIN_REFRESH_INTERVAL)
+}
+
+/// How long before expiry a token is refreshed.
+fn refresh_margin(expires_in: u64) -> Duration {
+	Duration::from_secs((expires_in / 10).min(120))
+}
+
+/// Replace the token at `index` with a new one. If a refresh of that token is
+/// already in flight, this waits for it to finish and reuses its result
+/// instead of logging in again.
+pub async fn force_refresh_token(index: usize) {
+	let pooled = OAUTH_CLIENT.get(index);
+	let refreshed = pooled.single_flight(refresh_pooled(index, pooled)).await;
+	if !refreshed {
+		trace!("Token #{index} was refreshed while waiting, reusing it");
 	}
+}
 
-	trace!("Rolling over refresh token. Current rate limit: {}", OAUTH_RATELIMIT_REMAINING.load(Ordering::SeqCst));
-	let new_client = Oauth::new().await;
-	OAUTH_CLIENT.swap(new_client.into());
-	OAUTH_RATELIMIT_REMAINING.store(99, Ordering::SeqCst);
-	OAUTH_IS_ROLLING_OVER.store(false, Ordering::SeqCst);
+async fn refresh_pooled(index: usize, pooled: &PooledOauth) {
+	trace!(
+		"Rolling over refresh token #{index}. Current rate limit: {}",
+		pooled.ratelimit_remaining.load(Ordering::SeqCst)
+	);
+	let rotate = should_rotate_device(pooled.refreshes.fetch_add(1, Ordering::SeqCst) + 1, rotate_device_every());
+	let refreshed = if rotate {
+		Oauth::refresh(None, &pooled.failures).await
+	} else {
+		trace!("Keeping the device identity of token #{index}");
+		Oauth::refresh(Some(&pooled.client.load_full()), &pooled.failures).await
+	};
+	// Keep the current token, which may still be valid, rather than replacing
+	// it and its saved copy with an empty one
+	let Some(new_client) = refreshed else {
+		OAUTH_METRICS.refresh_failures.inc();
+		warn!("Failed to refresh OAuth token #{index}, keeping the current one until the next attempt");
+		return;
+	};
+	OAUTH_METRICS.refresh_successes.inc();
+	if rotate {
+		pooled.refreshes.store(0, Ordering::SeqCst);
+	}
+	pooled.client.swap(new_client.into());
+	pooled.ratelimit_remaining.store(99, Ordering::SeqCst);
+	pooled.upstream_ratelimit.store(None);
+	OAUTH_CLIENT.persist();
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct Device {
 	oauth_id: String,
 	initial_headers: HashMap<String, String>,
@@ -180,7 +756,7 @@ impl Device {
 		let uuid = uuid::Uuid::new_v4().to_string();
 
 		// Generate random user-agent
-		let android_app_version = choose(ANDROID_APP_VERSION_LIST).to_string();
+		let android_app_version = choose_version(ANDROID_APP_VERSION_LIST, &EXTRA_APP_VERSIONS.android).unwrap_or_default();
 		let android_version = fastrand::u8(9..=14);
 
 		let android_user_agent = format!("Reddit/{android_app_version}/Android {android_version}");
@@ -192,44 +768,549 @@ impl Device {
 			("User-Agent".into(), android_user_agent),
 		]);
 
-		info!("[🔄] Spoofing Android client with headers: {headers:?}, uuid: \"{uuid}\", and OAuth ID \"{REDDIT_ANDROID_OAUTH_CLIENT_ID}\"");
+		let oauth_id = android_client_id();
+
+		info!("[🔄] Spoofing Android client with headers: {headers:?}, uuid: \"{uuid}\", and OAuth ID \"{oauth_id}\"");
 
 		Self {
-			oauth_id: REDDIT_ANDROID_OAUTH_CLIENT_ID.to_string(),
+			oauth_id,
 			headers: headers.clone(),
 			initial_headers: headers,
 		}
 	}
+	fn ios() -> Self {
+		// Generate uuid
+		let uuid = uuid::Uuid::new_v4().to_string();
+
+		// Generate random user-agent
+		let ios_app_version = choose_version(IOS_APP_VERSION_LIST, &EXTRA_APP_VERSIONS.ios).unwrap_or_default();
+		let ios_os_version = choose_version(IOS_OS_VERSION_LIST, &EXTRA_APP_VERSIONS.ios_os).unwrap_or_default();
+
+		let ios_user_agent = format!("Reddit/{ios_app_version}/iOS {ios_os_version}");
+
+		// iOS device headers
+		let initial_headers = HashMap::from([
+			("X-Reddit-DPR".into(), "2".into()),
+			("User-Agent".into(), ios_user_agent.clone()),
+			("Client-Vendor-Id".into(), uuid.clone()),
+		]);
+		let mut headers = initial_headers.clone();
+		headers.insert("Device-Name".into(), choose(IOS_DEVICES).to_string());
+
+		let oauth_id = ios_client_id();
+
+		info!("[🔄] Spoofing iOS client with headers: {headers:?}, uuid: \"{uuid}\", and OAuth ID \"{oauth_id}\"");
+
+		Self {
+			oauth_id,
+			initial_headers,
+			headers,
+		}
+	}
+
+	/// Which app this device spoofs
+	fn family(&self) -> &'static str {
+		if self.oauth_id == ios_client_id() {
+			"iOS"
+		} else {
+			"Android"
+		}
+	}
+
+	/// Whether the generated resources contain enough data to spoof an iOS
+	/// client. If scripts/update_oauth_resources.sh could not retrieve iOS
+	/// versions, the lists will only contain empty strings, unless some were
+	/// added through `REDLIB_OAUTH_APP_VERSIONS_FILE`.
+	fn ios_available() -> bool {
+		choose_version(IOS_APP_VERSION_LIST, &EXTRA_APP_VERSIONS.ios).is_some() && choose_version(IOS_OS_VERSION_LIST, &EXTRA_APP_VERSIONS.ios_os).is_some()
+	}
+
+	/// Generate a device to spoof, according to `REDLIB_OAUTH_DEVICE`.
 	fn new() -> Self {
 		// See https://github.com/redlib-org/redlib/issues/8
-		Self::android()
+		let family = get_setting("REDLIB_OAUTH_DEVICE").unwrap_or_default();
+		let use_ios = match family.as_str() {
+			"ios" => true,
+			"random" => fastrand::bool(),
+			_ => false,
+		};
+
+		if use_ios && !Self::ios_available() {
+			warn!("No iOS versions are available to spoof. Falling back to Android.");
+			return Self::android();
+		}
+
+		if use_ios {
+			Self::ios()
+		} else {
+			Self::android()
+		}
 	}
 }
 
+/// Masks a secret (such as a bearer token) for logging, keeping only its
+/// first four characters.
+fn redact(secret: &str) -> String {
+	format!("{}…", secret.chars().take(4).collect::<String>())
+}
+
+// Headers that identify the session and must not be logged verbatim
+const SENSITIVE_HEADERS: [&str; 4] = ["authorization", "set-cookie", "x-reddit-loid", "x-reddit-session"];
+
+/// Renders a header map for logging, with sensitive values masked by `redact`.
+fn redact_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+	headers
+		.iter()
+		.map(|(key, value)| {
+			let value = value.to_str().unwrap_or_default();
+			let value = if SENSITIVE_HEADERS.contains(&key.as_str()) { redact(value) } else { value.to_string() };
+			(key.to_string(), value)
+		})
+		.collect()
+}
+
 fn choose<T: Copy>(list: &[T]) -> T {
 	*fastrand::choose_multiple(list.iter(), 1)[0]
 }
 
+/// Picks a random non-empty version from the generated `list` and the
+/// operator-supplied `extra` versions, if there is one.
+fn choose_version(list: &[&str], extra: &[String]) -> Option<String> {
+	let versions = list.iter().copied().chain(extra.iter().map(String::as_str)).filter(|s| !s.is_empty());
+	fastrand::choose_multiple(versions, 1).first().map(ToString::to_string)
+}
+
+/// Additional versions to spoof, on top of the generated lists.
+#[derive(Debug, Default, PartialEq)]
+struct AppVersions {
+	android: Vec<String>,
+	ios: Vec<String>,
+	ios_os: Vec<String>,
+}
+
+impl AppVersions {
+	/// Read the file at `REDLIB_OAUTH_APP_VERSIONS_FILE`, if set.
+	fn load() -> Self {
+		let Some(path) = get_setting("REDLIB_OAUTH_APP_VERSIONS_FILE") else {
+			return Self::default();
+		};
+		match read_to_string(&path) {
+			Ok(contents) => {
+				let versions = Self::parse(&contents);
+				info!(
+					"Loaded {} Android, {} iOS app and {} iOS versions from {path}",
+					versions.android.len(),
+					versions.ios.len(),
+					versions.ios_os.len()
+				);
+				versions
+			}
+			Err(e) => {
+				warn!("Failed to read app versions from {path}: {e}");
+				Self::default()
+			}
+		}
+	}
+
+	/// Parse one version per line, in the same format as oauth_resources.rs,
+	/// prefixed by `android:`, `ios:` (iOS app) or `ios-os:` (iOS itself).
+	/// Blank lines, comments starting with `#` and unknown prefixes are ignored.
+	fn parse(contents: &str) -> Self {
+		let mut versions = Self::default();
+		for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+			let Some((family, version)) = line.split_once(':') else {
+				continue;
+			};
+			let list = match family.trim() {
+				"android" => &mut versions.android,
+				"ios" => &mut versions.ios,
+				"ios-os" => &mut versions.ios_os,
+				_ => continue,
+			};
+			list.push(version.trim().to_string());
+		}
+		versions
+	}
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn test_oauth_client() {
-	assert!(!OAUTH_CLIENT.load_full().token.is_empty());
+	assert!(!OAUTH_CLIENT.load(0).token.is_empty());
 }
 
 #[tokio::test(flavor = "multi_thread")]
 async fn test_oauth_client_refresh() {
-	force_refresh_token().await;
+	force_refresh_token(0).await;
 }
 #[tokio::test(flavor = "multi_thread")]
 async fn test_oauth_token_exists() {
-	assert!(!OAUTH_CLIENT.load_full().token.is_empty());
+	assert!(!OAUTH_CLIENT.load(0).token.is_empty());
 }
 
 #[tokio::test(flavor = "multi_thread")]
 async fn test_oauth_headers_len() {
-	assert!(OAUTH_CLIENT.load_full().headers_map.len() >= 3);
+	assert!(OAUTH_CLIENT.load(0).headers_map.len() >= 3);
 }
 
 #[test]
 fn test_creating_device() {
 	Device::new();
 }
+
+#[test]
+fn test_creating_ios_device() {
+	let device = Device::ios();
+	assert_eq!(device.oauth_id, REDDIT_IOS_OAUTH_CLIENT_ID);
+	assert!(device.headers.contains_key("Device-Name"));
+}
+
+#[test]
+fn test_choose_version() {
+	assert_eq!(choose_version(&["", ""], &[]), None);
+	assert_eq!(choose_version(&["", "a"], &[]), Some("a".to_string()));
+	assert_eq!(choose_version(&[""], &["b".to_string()]), Some("b".to_string()));
+}
+
+#[test]
+fn test_parse_app_versions() {
+	let versions = AppVersions::parse(
+		"# Extra versions
+android: Version 2024.20.0/Build 1612345
+
+ios:Version 2024.20.0/Build 612345
+ios-os: Version 17.5 (Build 21F79)
+windows: Version 1.0
+no prefix",
+	);
+	assert_eq!(
+		versions,
+		AppVersions {
+			android: vec!["Version 2024.20.0/Build 1612345".to_string()],
+			ios: vec!["Version 2024.20.0/Build 612345".to_string()],
+			ios_os: vec!["Version 17.5 (Build 21F79)".to_string()],
+		}
+	);
+}
+
+#[test]
+fn test_parse_retry_after() {
+	assert_eq!(parse_retry_after("30"), Some(Duration::from_secs(30)));
+	assert_eq!(parse_retry_after(" 5 "), Some(Duration::from_secs(5)));
+	assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+}
+
+#[test]
+fn test_redact() {
+	assert_eq!(redact("eyJhbGciOiJSUzI1NiIsImtpZCI6"), "eyJh…");
+	assert_eq!(redact("ab"), "ab…");
+	assert_eq!(redact(""), "…");
+}
+
+#[test]
+fn test_parse_scopes() {
+	assert_eq!(parse_scopes("*,email"), vec!["*", "email"]);
+	assert_eq!(parse_scopes(" read , identity ,"), vec!["read", "identity"]);
+	assert!(parse_scopes(" , ").is_empty());
+}
+
+#[test]
+fn test_parse_upstream_ratelimit() {
+	assert_eq!(
+		UpstreamRateLimit::parse("595.0", "5", "321"),
+		Some(UpstreamRateLimit {
+			remaining: 595,
+			used: 5,
+			reset: 321
+		})
+	);
+	assert_eq!(UpstreamRateLimit::parse("595.0", "", "321"), None);
+	assert_eq!(UpstreamRateLimit::parse("-1", "5", "321"), None);
+}
+
+#[test]
+fn test_clamp_expires_in() {
+	assert_eq!(clamp_expires_in(86400), 86400);
+	assert_eq!(clamp_expires_in(3600), 3600);
+	assert_eq!(clamp_expires_in(0), MIN_EXPIRES_IN);
+	assert_eq!(clamp_expires_in(u64::MAX), MAX_EXPIRES_IN);
+}
+
+#[test]
+fn test_refresh_interval() {
+	assert_eq!(refresh_interval(86400), Duration::from_secs(86280));
+	assert_eq!(refresh_interval(600), Duration::from_secs(540));
+	assert_eq!(refresh_interval(100), Duration::from_secs(90));
+	assert_eq!(refresh_interval(5), MIN_REFRESH_INTERVAL);
+	assert_eq!(refresh_interval(0), MIN_REFRESH_INTERVAL);
+}
+
+#[test]
+fn test_restore_persisted_oauth() {
+	let mut oauth = Oauth::default();
+	oauth.token = "token".to_string();
+	oauth.expires_in = 86400;
+	oauth.obtained_at = 1_000_000;
+
+	let json = serde_json::to_string(&vec![oauth]).unwrap();
+	let persisted: Vec<Oauth> = serde_json::from_str(&json).unwrap();
+	let oauth = persisted.into_iter().next().unwrap();
+
+	let restored = oauth.clone().restore(1_000_000 + 3600).unwrap();
+	assert_eq!(restored.expires_in, 86400 - 3600);
+	assert_eq!(restored.device.oauth_id, oauth.device.oauth_id);
+
+	// Expired, or about to expire
+	assert!(oauth.clone().restore(1_000_000 + 86400).is_none());
+	assert!(oauth.clone().restore(1_000_000 + 86395).is_none());
+
+	// Never logged in
+	assert!(Oauth::default().restore(1_000_000).is_none());
+}
+
+#[test]
+fn test_refresh_delay_backoff() {
+	let mut backoff = INITIAL_REFRESH_BACKOFF;
+	let mut oauth = Oauth::default();
+
+	// Failed refreshes back off exponentially, up to the cap
+	assert_eq!(refresh_delay(&oauth, true, &mut backoff), Duration::from_secs(5));
+	assert_eq!(refresh_delay(&oauth, true, &mut backoff), Duration::from_secs(10));
+	for _ in 0..10 {
+		refresh_delay(&oauth, true, &mut backoff);
+	}
+	assert_eq!(refresh_delay(&oauth, true, &mut backoff), MAX_REFRESH_BACKOFF);
+
+	// Including when the previous, still valid token was kept
+	oauth.token = "token".to_string();
+	oauth.expires_in = 86400;
+	assert_eq!(refresh_delay(&oauth, true, &mut backoff), MAX_REFRESH_BACKOFF);
+
+	// A successful refresh resets the backoff and returns to the expiry-based schedule
+	let delay = refresh_delay(&oauth, false, &mut backoff);
+	assert!(delay.abs_diff(refresh_interval(86400)) <= MAX_REFRESH_JITTER);
+	assert_eq!(backoff, INITIAL_REFRESH_BACKOFF);
+}
+
+#[test]
+fn test_jitter() {
+	let duration = Duration::from_secs(3600);
+	for _ in 0..100 {
+		assert!(jitter(duration, MAX_REFRESH_JITTER).abs_diff(duration) <= MAX_REFRESH_JITTER);
+		assert!(jitter(MIN_REFRESH_INTERVAL, MAX_REFRESH_JITTER) >= MIN_REFRESH_INTERVAL);
+	}
+	assert_eq!(jitter(duration, Duration::ZERO), duration);
+}
+
+#[test]
+fn test_oauth_status() {
+	let mut oauth = Oauth::default();
+	oauth.device = Device::android();
+	let pooled = PooledOauth {
+		client: ArcSwap::new(oauth.clone().into()),
+		ratelimit_remaining: AtomicU16::new(42),
+		is_rolling_over: AtomicBool::new(false),
+		failures: AtomicU32::new(3),
+		refreshes: AtomicU32::new(0),
+		refresh_lock: Mutex::new(()),
+		generation: AtomicU64::new(0),
+		upstream_ratelimit: ArcSwapOption::empty(),
+	};
+
+	let status = OauthStatus::new(&pooled, 1_000_000);
+	assert_eq!(status.device, "Android");
+	assert!(!status.has_token);
+	assert_eq!(status.expires_in, 0);
+	assert_eq!(status.last_refresh, None);
+	assert_eq!(status.failure_count, 3);
+	assert_eq!(status.ratelimit_remaining, 42);
+
+	oauth.token = "token".to_string();
+	oauth.expires_in = 86400;
+	oauth.obtained_at = 1_000_000;
+	pooled.client.store(oauth.into());
+
+	let status = OauthStatus::new(&pooled, 1_000_000 + 400);
+	assert!(status.has_token);
+	assert_eq!(status.expires_in, 86000);
+	assert_eq!(status.last_refresh, Some(1_000_000));
+}
+
+// Requests received by a mock auth server, as (headers, body) pairs
+#[cfg(test)]
+type ReceivedRequests = Arc<std::sync::Mutex<Vec<(HeaderMap, String)>>>;
+
+/// Start a local HTTP server that answers every request with `status`,
+/// `headers` and `body`, and records what it received. Returns the server's
+/// base URL.
+#[cfg(test)]
+async fn mock_auth_server(status: u16, headers: &[(&'static str, &'static str)], body: &'static str) -> (String, ReceivedRequests) {
+	use hyper::{
+		service::{make_service_fn, service_fn},
+		Response, Server,
+	};
+	use std::convert::Infallible;
+
+	let received = ReceivedRequests::default();
+	let headers = headers.to_vec();
+	let make_svc = {
+		let received = received.clone();
+		make_service_fn(move |_conn| {
+			let received = received.clone();
+			let headers = headers.clone();
+			async move {
+				Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+					let received = received.clone();
+					let headers = headers.clone();
+					async move {
+						let req_headers = req.headers().clone();
+						let req_body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+						received.lock().unwrap().push((req_headers, String::from_utf8_lossy(&req_body).to_string()));
+						let mut resp = Response::builder().status(status);
+						for (key, value) in headers {
+							resp = resp.header(key, value);
+						}
+						Ok::<_, Infallible>(resp.body(Body::from(body)).unwrap())
+					}
+				}))
+			}
+		})
+	};
+	let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+	let url = format!("http://{}", server.local_addr());
+	tokio::spawn(server);
+	(url, received)
+}
+
+#[tokio::test]
+async fn test_login_sends_credentials_and_captures_loid() {
+	let (url, received) = mock_auth_server(
+		200,
+		&[("x-reddit-loid", "loid-123"), ("x-reddit-session", "session-456")],
+		r#"{"access_token": "token-abc", "expires_in": 86400}"#,
+	)
+	.await;
+	let client = client::Client::new();
+	let mut oauth = Oauth::default();
+
+	oauth.login_with(&client, &url).await.unwrap();
+	assert_eq!(oauth.token, "token-abc");
+	assert_eq!(oauth.expires_in, 86400);
+	assert_eq!(oauth.headers_map.get("x-reddit-loid").map(String::as_str), Some("loid-123"));
+	assert_eq!(oauth.headers_map.get("x-reddit-session").map(String::as_str), Some("session-456"));
+	assert_eq!(oauth.headers_map.get("Authorization").map(String::as_str), Some("Bearer token-abc"));
+
+	// A second login sends the captured loid back
+	oauth.login_with(&client, &url).await.unwrap();
+
+	let received = received.lock().unwrap();
+	assert_eq!(received.len(), 2);
+	let (headers, body) = &received[0];
+	let expected_auth = format!("Basic {}", general_purpose::STANDARD.encode(format!("{}:", oauth.device.oauth_id)));
+	assert_eq!(headers.get("authorization").unwrap(), expected_auth.as_str());
+	let body: serde_json::Value = serde_json::from_str(body).unwrap();
+	assert_eq!(body, json!({ "scopes": ["*", "email"] }));
+	assert!(headers.get("x-reddit-loid").is_none());
+	assert_eq!(received[1].0.get("x-reddit-loid").unwrap(), "loid-123");
+}
+
+#[tokio::test]
+async fn test_login_rate_limited() {
+	let (url, _) = mock_auth_server(429, &[("retry-after", "42")], "").await;
+	let mut oauth = Oauth::default();
+	let result = oauth.login_with(&client::Client::new(), &url).await;
+	assert!(matches!(result, Err(LoginError::RateLimited(Some(d))) if d == Duration::from_secs(42)));
+	assert!(oauth.token.is_empty());
+}
+
+#[tokio::test]
+async fn test_login_malformed_response() {
+	for body in ["not json", r#"{"expires_in": 86400}"#, r#"{"access_token": "token-abc"}"#] {
+		let (url, _) = mock_auth_server(200, &[], body).await;
+		let mut oauth = Oauth::default();
+		let result = oauth.login_with(&client::Client::new(), &url).await;
+		assert!(matches!(result, Err(LoginError::Failed)), "{body}");
+	}
+}
+
+#[tokio::test]
+async fn test_login_error_status() {
+	let (url, _) = mock_auth_server(403, &[], "blocked").await;
+	let result = Oauth::default().login_with(&client::Client::new(), &url).await;
+	assert!(matches!(result, Err(LoginError::Failed)));
+}
+
+#[test]
+fn test_should_rotate_device() {
+	// Default: every refresh
+	assert!(should_rotate_device(1, 1));
+	// Every third refresh
+	assert!(!should_rotate_device(1, 3));
+	assert!(!should_rotate_device(2, 3));
+	assert!(should_rotate_device(3, 3));
+	// Never
+	assert!(!should_rotate_device(100, 0));
+}
+
+#[test]
+fn test_unauthenticated_keeps_identity() {
+	let mut previous = Oauth::default();
+	previous.token = "token".to_string();
+	previous.expires_in = 86400;
+	previous.headers_map.insert("x-reddit-loid".to_string(), "loid".to_string());
+	previous.headers_map.insert("Authorization".to_string(), "Bearer token".to_string());
+
+	let oauth = Oauth::unauthenticated(Some(&previous));
+	assert!(oauth.token.is_empty());
+	assert_eq!(oauth.expires_in, 0);
+	assert_eq!(oauth.headers_map.get("x-reddit-loid").map(String::as_str), Some("loid"));
+	assert!(!oauth.headers_map.contains_key("Authorization"));
+	assert_eq!(oauth.device.headers, previous.device.headers);
+}
+
+#[tokio::test]
+async fn test_concurrent_refreshes_coalesce() {
+	let pooled = PooledOauth {
+		client: ArcSwap::new(Oauth::default().into()),
+		ratelimit_remaining: AtomicU16::new(99),
+		is_rolling_over: AtomicBool::new(false),
+		failures: AtomicU32::new(0),
+		refreshes: AtomicU32::new(0),
+		refresh_lock: Mutex::new(()),
+		generation: AtomicU64::new(0),
+		upstream_ratelimit: ArcSwapOption::empty(),
+	};
+	let logins = AtomicU32::new(0);
+	let refresh = || async {
+		logins.fetch_add(1, Ordering::SeqCst);
+		tokio::time::sleep(Duration::from_millis(50)).await;
+	};
+
+	let (first, second) = tokio::join!(pooled.single_flight(refresh()), pooled.single_flight(refresh()));
+	assert!(first);
+	assert!(!second);
+	assert_eq!(logins.load(Ordering::SeqCst), 1);
+	assert!(!pooled.is_rolling_over.load(Ordering::SeqCst));
+
+	// A later refresh runs again
+	assert!(pooled.single_flight(refresh()).await);
+	assert_eq!(logins.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_write_private() {
+	let dir = std::env::temp_dir().join(format!("redlib-oauth-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let path = dir.join("tokens.json").to_string_lossy().into_owned();
+	std::fs::write(&path, "old").unwrap();
+
+	write_private(&path, "[]").unwrap();
+	assert_eq!(read_to_string(&path).unwrap(), "[]");
+	// No temporary file is left behind
+	assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::PermissionsExt;
+		assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+	}
+	std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -2,7 +2,7 @@
 // Rerun scripts/update_oauth_resources.sh to update this file
 // Please do not edit manually
 // Filled in with real app versions
-pub static _IOS_APP_VERSION_LIST: &[&str; 1] = &[""];
+pub static IOS_APP_VERSION_LIST: &[&str; 1] = &[""];
 pub static ANDROID_APP_VERSION_LIST: &[&str; 150] = &[
 	"Version 2023.48.0/Build 1319123",
 	"Version 2023.49.0/Build 1321715",
@@ -155,4 +155,4 @@ pub static ANDROID_APP_VERSION_LIST: &[&str; 150] = &[
 	"Version 2022.8.0/Build 423906",
 	"Version 2022.9.0/Build 426592",
 ];
-pub static _IOS_OS_VERSION_LIST: &[&str; 1] = &[""];
+pub static IOS_OS_VERSION_LIST: &[&str; 1] = &[""];
@@ -0,0 +1,128 @@
+// oEmbed provider (https://oembed.com) for posts, so that blogs and link
+// unfurlers can embed them. The returned snippet only links back to this
+// instance and its media proxy, never to Reddit.
+
+use std::collections::HashMap;
+
+use hyper::{Body, Request, Response};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::client::json;
+use crate::config::get_setting;
+use crate::utils::{base_url, error, escape, parse_post, sfw_only, Post, SUBREDDIT_POLICY};
+
+// Size of the embedded snippet, unless the consumer asks for a smaller one
+const EMBED_WIDTH: u64 = 500;
+const EMBED_HEIGHT: u64 = 150;
+
+pub async fn oembed(req: Request<Body>) -> Result<Response<Body>, String> {
+	let query = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()).collect::<HashMap<_, _>>();
+	// The spec asks for a 501 when a format isn't supported
+	if query.get("format").is_some_and(|format| format != "json") {
+		return Response::builder().status(501).body("Only the json format is supported".into()).map_err(|e| e.to_string());
+	}
+
+	let base_url = base_url(get_setting("REDLIB_FULL_URL"), req.headers());
+	let Some(id) = query.get("url").and_then(|url| post_id(url, &base_url)) else {
+		return error(req, "Not a post on this instance").await;
+	};
+
+	let post = match json(format!("/comments/{id}.json?limit=1&raw_json=1"), false).await {
+		Ok(response) => parse_post(&response[0]["data"]["children"][0]).await,
+		Err(msg) => return error(req, &msg).await,
+	};
+	if post.id.is_empty() || !SUBREDDIT_POLICY.allows(&post.community) || (post.nsfw && sfw_only()) {
+		return error(req, "Not a post on this instance").await;
+	}
+
+	let max = |name: &str, default: u64| query.get(name).and_then(|value| value.parse::<u64>().ok()).map_or(default, |max| max.min(default));
+	let body = oembed_json(&post, &base_url, max("maxwidth", EMBED_WIDTH), max("maxheight", EMBED_HEIGHT));
+	Response::builder()
+		.status(200)
+		.header("content-type", "application/json")
+		.body(body.to_string().into())
+		.map_err(|e| e.to_string())
+}
+
+/// The ID of the post at `url`, which may be relative to the instance at
+/// `base_url` but not point elsewhere. The scheme isn't compared, as it is
+/// often lost behind reverse proxies.
+fn post_id(url: &str, base_url: &str) -> Option<String> {
+	let base = Url::parse(base_url).ok()?;
+	let url = base.join(url).ok()?;
+	if url.host_str() != base.host_str() {
+		return None;
+	}
+	let segments = url.path_segments()?.filter(|segment| !segment.is_empty()).collect::<Vec<_>>();
+	let id = match segments.as_slice() {
+		["comments", id, ..] => id,
+		["r" | "u" | "user", _, "comments", id, ..] => id,
+		_ => return None,
+	};
+	id.chars().all(|c| c.is_ascii_alphanumeric()).then(|| id.to_string())
+}
+
+/// Build the oEmbed response for a post, with URLs made absolute so that
+/// they work from other sites.
+fn oembed_json(post: &Post, base_url: &str, width: u64, height: u64) -> Value {
+	let absolute = |url: &str| if url.starts_with('/') { format!("{base_url}{url}") } else { url.to_string() };
+	let permalink = absolute(&post.permalink);
+	let author_url = format!("{base_url}/u/{}", post.author.name);
+	let thumbnail = absolute(&post.thumbnail.url);
+
+	let mut html = format!("<blockquote class=\"redlib-embed\" style=\"max-width: {width}px\">");
+	if !post.thumbnail.url.is_empty() {
+		html.push_str(&format!(
+			"<a href=\"{}\"><img src=\"{}\" alt=\"\" loading=\"lazy\"></a>",
+			escape(&permalink),
+			escape(&thumbnail)
+		));
+	}
+	html.push_str(&format!(
+		"<p><a href=\"{}\">{}</a></p><p>Posted by <a href=\"{}\">u/{}</a> in <a href=\"{base_url}/r/{}\">r/{}</a></p></blockquote>",
+		escape(&permalink),
+		escape(&post.title),
+		escape(&author_url),
+		escape(&post.author.name),
+		escape(&post.community),
+		escape(&post.community),
+	));
+
+	let mut oembed = json!({
+		"version": "1.0",
+		"type": "rich",
+		"title": post.title,
+		"author_name": format!("u/{}", post.author.name),
+		"author_url": author_url,
+		"provider_name": "Redlib",
+		"provider_url": base_url,
+		"html": html,
+		"width": width,
+		"height": height,
+	});
+	// The thumbnail fields must be given all together or not at all
+	if !post.thumbnail.url.is_empty() {
+		oembed["thumbnail_url"] = json!(thumbnail);
+		oembed["thumbnail_width"] = json!(post.thumbnail.width);
+		oembed["thumbnail_height"] = json!(post.thumbnail.height);
+	}
+	oembed
+}
+
+#[test]
+fn test_post_id() {
+	let base_url = "https://redlib.example.com";
+	assert_eq!(post_id("https://redlib.example.com/r/rust/comments/abc123/a_title/", base_url).as_deref(), Some("abc123"));
+	assert_eq!(post_id("/comments/abc123", base_url).as_deref(), Some("abc123"));
+	assert_eq!(post_id("/user/spez/comments/abc123/", base_url).as_deref(), Some("abc123"));
+	// Not a post
+	assert_eq!(post_id("/r/rust", base_url), None);
+	assert_eq!(post_id("/r/rust/wiki/comments/abc123", base_url), None);
+	assert_eq!(post_id("/comments/abc?123", base_url).as_deref(), Some("abc"));
+	// Not on this instance
+	assert_eq!(post_id("https://www.reddit.com/r/rust/comments/abc123/", base_url), None);
+	assert_eq!(post_id("//evil.example/comments/abc123", base_url), None);
+	assert_eq!(post_id("https://other.example.com/comments/abc123", base_url), None);
+	assert_eq!(post_id("http://redlib.example.com/comments/abc123", base_url).as_deref(), Some("abc123"));
+}
@@ -1,16 +1,18 @@
 // CRATES
-use crate::client::json;
+use crate::client::{canonical_path, json, restriction};
 use crate::config::get_setting;
 use crate::server::RequestExt;
-use crate::subreddit::{can_access_quarantine, quarantine};
+use crate::subreddit::{can_access_quarantine, restricted, rules};
 use crate::utils::{
-	error, format_num, get_filters, nsfw_landing, param, parse_post, rewrite_emotes, setting, template, time, val, Author, Awards, Comment, Flair, FlairPart, Post, Preferences,
+	edited, error, format_score, get_filters, json_response, nsfw_landing, param, parse_post, redirect, rewrite_emotes, setting, stream_template, time, unavailable_subreddit,
+	val, wants_json, with_canonical_link, Author, Awards, Comment, Flair, FlairPart, Post, Preferences, SubredditRule, COMMENT_SORTS, SUBREDDIT_POLICY,
 };
 use hyper::{Body, Request, Response};
 
 use askama::Template;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde_json::{json, Value};
 use std::collections::{HashMap, HashSet};
 
 // STRUCTS
@@ -25,13 +27,53 @@ struct PostTemplate {
 	url: String,
 	url_without_query: String,
 	comment_query: String,
+	/// Link back to all comments when showing comments loaded in place of a
+	/// "load more comments" stub, otherwise empty
+	all_comments_link: String,
+	/// Rules of the subreddit, empty if they aren't shown or couldn't be fetched
+	rules: Vec<SubredditRule>,
 }
 
+// Most comments to load at once in place of a "load more comments" stub, which
+// is the most Reddit's morechildren endpoint accepts
+const MORE_CHILDREN_BATCH: usize = 100;
+
+// Most parent comments Reddit shows above a single comment with `context`
+const MAX_CONTEXT: u32 = 8;
+
+// Deepest nesting of comments loaded in place of a stub. Deeper replies are
+// linked to instead.
+const MAX_MORE_DEPTH: usize = 10;
+
 static COMMENT_SEARCH_CAPTURE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\?q=(.*)&type=comment").unwrap());
 
+/// Resolve a share link such as /r/rust/s/kPgq8WNHRK, which hides the post
+/// it leads to, by following its redirect on Reddit.
+pub async fn share_link(req: Request<Body>) -> Result<Response<Body>, String> {
+	let id = req.param("id").unwrap_or_default();
+	if !(8..12).contains(&id.len()) || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+		return error(req, "Nothing here").await;
+	}
+	let path = req.uri().path().trim_end_matches('/').replacen("/user/", "/u/", 1);
+	match canonical_path(path.clone(), 3).await {
+		// Redirecting to the share link itself would loop
+		Ok(Some(resolved)) if resolved.trim_end_matches('/') != path => Ok(redirect(&resolved)),
+		Ok(_) => {
+			error(
+				req,
+				"Share link is invalid. It may point to a post that was removed or on a community that has been banned.",
+			)
+			.await
+		}
+		Err(e) if e == "Too many requests." => error(req, "Reddit is limiting requests, so the share link couldn't be resolved. Try again in a few seconds.").await,
+		Err(e) => error(req, &e).await,
+	}
+}
+
 pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
-	// Build Reddit API path
-	let mut path: String = format!("{}.json?{}&raw_json=1", req.uri().path(), req.uri().query().unwrap_or_default());
+	// Build Reddit API path, leaving out the stub to load more comments of
+	let query = more_comments_query(req.uri().query().unwrap_or_default(), None);
+	let mut path: String = format!("{}.json?{query}&raw_json=1", req.uri().path());
 	let sub = req.param("sub").unwrap_or_default();
 	let quarantined = can_access_quarantine(&req, &sub);
 	let url = req.uri().to_string();
@@ -41,11 +83,12 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 		// Grab default comment sort method from Cookies
 		let default_sort = setting(&req, "comment_sort");
 
-		// If there's no sort query but there's a default sort, set sort to default_sort
-		if default_sort.is_empty() {
+		// If there's no sort query but there's a default sort, set sort to default_sort.
+		// Values that aren't a plain word are ignored rather than spliced into the query.
+		if default_sort.is_empty() || !default_sort.chars().all(|c| c.is_ascii_alphanumeric()) {
 			String::new()
 		} else {
-			path = format!("{}.json?{}&sort={}&raw_json=1", req.uri().path(), req.uri().query().unwrap_or_default(), default_sort);
+			path = format!("{}.json?{query}&sort={}&raw_json=1", req.uri().path(), default_sort);
 			default_sort
 		}
 	});
@@ -54,15 +97,36 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 	#[cfg(debug_assertions)]
 	req.param("id").unwrap_or_default();
 
-	let single_thread = req.param("comment_id").is_some();
 	let highlighted_comment = &req.param("comment_id").unwrap_or_default();
 
+	// The stub to load more comments of, given by the full name of its parent
+	let form = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()).collect::<HashMap<_, _>>();
+	let more = form.get("more").map(|parent| parent.to_string());
+	let offset = form.get("offset").and_then(|offset| offset.parse::<usize>().ok()).unwrap_or_default();
+	let single_thread = req.param("comment_id").is_some() && more.is_none();
+
+	// The subreddit's rules are shown with the post, unless the sidebar is
+	// hidden. They don't depend on the post, so they are fetched alongside it,
+	// and are left out rather than failing the page if they can't be.
+	let show_rules = !sub.is_empty() && more.is_none() && !wants_json(&req) && setting(&req, "hide_sidebar_and_summary") != "on" && SUBREDDIT_POLICY.allows(&sub);
+	let fetch_rules = async {
+		if show_rules {
+			rules(&sub, quarantined).await
+		} else {
+			Vec::new()
+		}
+	};
+
 	// Send a request to the url, receive JSON in response
-	match json(path, quarantined).await {
+	let (response, rules) = tokio::join!(json(path, quarantined), fetch_rules);
+	match response {
 		// Otherwise, grab the JSON output from the request
 		Ok(response) => {
 			// Parse the JSON into Post and Comment structs
 			let post = parse_post(&response[0]["data"]["children"][0]).await;
+			if !SUBREDDIT_POLICY.allows(&post.community) {
+				return error(req, &unavailable_subreddit(&post.community)).await;
+			}
 
 			let req_url = req.uri().to_string();
 			// Return landing page if this post if this Reddit deems this post
@@ -81,28 +145,57 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 			let form = url::form_urlencoded::parse(query_string.as_bytes()).collect::<HashMap<_, _>>();
 			let query = form.get("q").unwrap().clone().to_string();
 
-			let comments = match query.as_str() {
-				"" => parse_comments(&response[1], &post.permalink, &post.author.name, highlighted_comment, &get_filters(&req), &req),
+			let mut comments = match (query.as_str(), &more) {
+				("", Some(parent)) => {
+					let post_id = req.param("id").unwrap_or_default();
+					let listing = match load_more_comments(&response[1], &post_id, parent, offset, &sort, quarantined).await {
+						Ok(listing) => listing,
+						Err(msg) => return error(req, &msg).await,
+					};
+					parse_comments(&listing, &post.permalink, &post.author.name, highlighted_comment, &get_filters(&req), 0, &req)
+				}
+				("", None) => parse_comments(&response[1], &post.permalink, &post.author.name, highlighted_comment, &get_filters(&req), 0, &req),
 				_ => query_comments(&response[1], &post.permalink, &post.author.name, highlighted_comment, &get_filters(&req), &query, &req),
 			};
+			if query.is_empty() && setting(&req, "collapse_deleted") == "on" {
+				collapse_deleted_branches(&mut comments);
+			}
+			let all_comments_link = if more.is_some() {
+				format!("{}?{}", req.uri().path(), more_comments_query(req.uri().query().unwrap_or_default(), None))
+			} else {
+				String::new()
+			};
+
+			if wants_json(&req) {
+				return Ok(json_response(&json!({ "post": post, "comments": comments })));
+			}
+
+			// Comment threads are pages of their own, while other versions of a
+			// post page share the post's permalink
+			let canonical = if single_thread { req.uri().path().to_string() } else { post.permalink.clone() };
 
 			// Use the Post and Comment structs to generate a website to show users
-			Ok(template(&PostTemplate {
-				comments,
-				post,
-				url_without_query: url.clone().trim_end_matches(&format!("?q={query}&type=comment")).to_string(),
-				sort,
-				prefs: Preferences::new(&req),
-				single_thread,
-				url: req_url,
-				comment_query: query,
-			}))
+			Ok(with_canonical_link(
+				stream_template(PostTemplate {
+					comments,
+					post,
+					url_without_query: url.clone().trim_end_matches(&format!("?q={query}&type=comment")).to_string(),
+					sort,
+					prefs: Preferences::new(&req),
+					single_thread,
+					url: req_url,
+					comment_query: query,
+					all_comments_link,
+					rules,
+				}),
+				&canonical,
+			))
 		}
 		// If the Reddit API returns an error, exit and send error page to user
 		Err(msg) => {
-			if msg == "quarantined" || msg == "gated" {
+			if restriction(&msg).is_some() {
 				let sub = req.param("sub").unwrap_or_default();
-				Ok(quarantine(&req, sub, &msg))
+				Ok(restricted(&req, sub, &msg))
 			} else {
 				error(req, &msg).await
 			}
@@ -112,7 +205,111 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 
 // COMMENTS
 
-fn parse_comments(json: &serde_json::Value, post_link: &str, post_author: &str, highlighted_comment: &str, filters: &HashSet<String>, req: &Request<Body>) -> Vec<Comment> {
+// Query of a post page, replacing the stub to load more comments of (given by
+// the full name of its parent) and how many of its comments were already
+// loaded, if any. The number of parent comments to show with `context` is
+// bounded to what Reddit supports.
+fn more_comments_query(query: &str, more: Option<(&str, usize)>) -> String {
+	let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+	for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+		if key == "context" {
+			if let Ok(context) = value.trim().parse::<u32>() {
+				serializer.append_pair("context", &context.min(MAX_CONTEXT).to_string());
+			}
+		} else if key != "more" && key != "offset" {
+			serializer.append_pair(&key, &value);
+		}
+	}
+	if let Some((parent, offset)) = more {
+		serializer.append_pair("more", parent);
+		if offset > 0 {
+			serializer.append_pair("offset", &offset.to_string());
+		}
+	}
+	serializer.finish()
+}
+
+// Find the "load more comments" stub among the replies to `parent`
+fn find_more_stub<'a>(listing: &'a Value, parent: &str) -> Option<&'a Value> {
+	listing["data"]["children"].as_array()?.iter().find_map(|child| match child["kind"].as_str() {
+		Some("more") if child["data"]["parent_id"] == parent => Some(child),
+		Some("t1") if child["data"]["replies"].is_object() => find_more_stub(&child["data"]["replies"], parent),
+		_ => None,
+	})
+}
+
+// Fetch a batch of the comments of the stub among the replies to `parent`,
+// nested into a listing like those of post pages. If the stub has comments
+// left, a stub for those ends the listing.
+async fn load_more_comments(listing: &Value, post_id: &str, parent: &str, offset: usize, sort: &str, quarantined: bool) -> Result<Value, String> {
+	let children: Vec<&str> = find_more_stub(listing, parent)
+		.and_then(|stub| stub["data"]["children"].as_array())
+		.map(|children| children.iter().filter_map(Value::as_str).collect())
+		.unwrap_or_default();
+	let batch = children.iter().skip(offset).take(MORE_CHILDREN_BATCH).copied().collect::<Vec<_>>();
+	if batch.is_empty() {
+		return Err("These comments are no longer available. Try reloading the post.".to_string());
+	}
+
+	let response = json(more_children_path(post_id, &batch, sort), quarantined).await?;
+	let things = response["json"]["data"]["things"].as_array().map_or(&[][..], Vec::as_slice);
+
+	let mut comments = nest_comments(things, parent, 0);
+	let loaded = offset + batch.len();
+	if loaded < children.len() {
+		comments.push(json!({
+			"kind": "more",
+			"data": { "parent_id": parent, "count": children.len() - loaded, "children": children[loaded..], "offset": loaded },
+		}));
+	}
+	Ok(json!({ "data": { "children": comments } }))
+}
+
+// Path of the morechildren endpoint for the comments `batch` of a post
+fn more_children_path(post_id: &str, batch: &[&str], sort: &str) -> String {
+	// The sort comes decoded from the query, so anything but a known sort
+	// could add parameters to the request
+	let sort = if COMMENT_SORTS.contains(&sort) { format!("&sort={sort}") } else { String::new() };
+	format!("/api/morechildren.json?api_type=json&link_id=t3_{post_id}&children={}{sort}&raw_json=1", batch.join(","))
+}
+
+// Nest the flat list of comments returned by the morechildren endpoint under
+// `parent`. Below MAX_MORE_DEPTH, replies are replaced by a stub linking to
+// their thread.
+fn nest_comments(things: &[Value], parent: &str, depth: usize) -> Vec<Value> {
+	things
+		.iter()
+		.filter(|thing| thing["data"]["parent_id"] == parent)
+		.map(|thing| {
+			let mut thing = thing.clone();
+			let name = thing["data"]["name"].as_str().unwrap_or_default().to_string();
+			if thing["kind"] != "t1" || name.is_empty() {
+				return thing;
+			}
+			let replies = if depth + 1 < MAX_MORE_DEPTH {
+				nest_comments(things, &name, depth + 1)
+			} else if things.iter().any(|reply| reply["data"]["parent_id"] == name.as_str()) {
+				vec![json!({ "kind": "more", "data": { "parent_id": name, "count": 0, "children": [] } })]
+			} else {
+				Vec::new()
+			};
+			if !replies.is_empty() {
+				thing["data"]["replies"] = json!({ "data": { "children": replies } });
+			}
+			thing
+		})
+		.collect()
+}
+
+fn parse_comments(
+	json: &serde_json::Value,
+	post_link: &str,
+	post_author: &str,
+	highlighted_comment: &str,
+	filters: &HashSet<String>,
+	depth: usize,
+	req: &Request<Body>,
+) -> Vec<Comment> {
 	// Parse the comment JSON into a Vector of Comments
 	let comments = json["data"]["children"].as_array().map_or(Vec::new(), std::borrow::ToOwned::to_owned);
 
@@ -122,11 +319,11 @@ fn parse_comments(json: &serde_json::Value, post_link: &str, post_author: &str,
 		.map(|comment| {
 			let data = &comment["data"];
 			let replies: Vec<Comment> = if data["replies"].is_object() {
-				parse_comments(&data["replies"], post_link, post_author, highlighted_comment, filters, req)
+				parse_comments(&data["replies"], post_link, post_author, highlighted_comment, filters, depth + 1, req)
 			} else {
 				Vec::new()
 			};
-			build_comment(&comment, data, replies, post_link, post_author, highlighted_comment, filters, req)
+			build_comment(&comment, data, replies, post_link, post_author, highlighted_comment, filters, depth, req)
 		})
 		.collect()
 }
@@ -151,7 +348,7 @@ fn query_comments(
 			results.append(&mut query_comments(&data["replies"], post_link, post_author, highlighted_comment, filters, query, req));
 		}
 
-		let c = build_comment(&comment, data, Vec::new(), post_link, post_author, highlighted_comment, filters, req);
+		let c = build_comment(&comment, data, Vec::new(), post_link, post_author, highlighted_comment, filters, 0, req);
 		if c.body.to_lowercase().contains(&query.to_lowercase()) {
 			results.push(c);
 		}
@@ -168,11 +365,14 @@ fn build_comment(
 	post_author: &str,
 	highlighted_comment: &str,
 	filters: &HashSet<String>,
+	depth: usize,
 	req: &Request<Body>,
 ) -> Comment {
 	let id = val(comment, "id");
 
-	let body = if (val(comment, "author") == "[deleted]" && val(comment, "body") == "[removed]") || val(comment, "body") == "[ Removed by Reddit ]" {
+	let removed = (val(comment, "author") == "[deleted]" && val(comment, "body") == "[removed]") || val(comment, "body") == "[ Removed by Reddit ]";
+	let deleted = removed || (val(comment, "author") == "[deleted]" && val(comment, "body") == "[deleted]");
+	let body = if removed {
 		format!(
 			"<div class=\"md\"><p>[removed] — <a href=\"https://{}{post_link}{id}\">view removed comment</a></p></div>",
 			get_setting("REDLIB_PUSHSHIFT_FRONTEND").unwrap_or_else(|| String::from(crate::config::DEFAULT_PUSHSHIFT_FRONTEND)),
@@ -185,7 +385,7 @@ fn build_comment(
 	let unix_time = data["created_utc"].as_f64().unwrap_or_default();
 	let (rel_time, created) = time(unix_time);
 
-	let edited = data["edited"].as_f64().map_or((String::new(), String::new()), time);
+	let edited = edited(&data["edited"]);
 
 	let score = data["score"].as_i64().unwrap_or(0);
 
@@ -203,6 +403,17 @@ fn build_comment(
 
 	let highlighted = id == highlighted_comment;
 
+	// Stubs of top-level comments and of replies to the highlighted comment are
+	// loaded in place. Others link to the thread of their parent.
+	let has_children = data["children"].as_array().is_some_and(|children| !children.is_empty());
+	let more_link = if kind == "more" && has_children && (parent_info[0] == "t3" || parent_info.get(1) == Some(&highlighted_comment)) {
+		let offset = data["offset"].as_u64().unwrap_or_default() as usize;
+		let query = more_comments_query(req.uri().query().unwrap_or_default(), Some((&parent_kind_and_id, offset)));
+		format!("{}?{query}", req.uri().path())
+	} else {
+		String::new()
+	};
+
 	let author = Author {
 		name: val(comment, "author"),
 		flair: Flair {
@@ -218,6 +429,7 @@ fn build_comment(
 		distinguished: val(comment, "distinguished"),
 	};
 	let is_filtered = filters.contains(&["u_", author.name.as_str()].concat());
+	let is_op = is_submitter(data, &author.name, post_author);
 
 	// Many subreddits have a default comment posted about the sub's rules etc.
 	// Many Redlib users do not wish to see this kind of comment by default.
@@ -225,7 +437,11 @@ fn build_comment(
 	// collapse stickied moderator comments.
 	let is_moderator_comment = data["distinguished"].as_str().unwrap_or_default() == "moderator";
 	let is_stickied = data["stickied"].as_bool().unwrap_or_default();
-	let collapsed = (is_moderator_comment && is_stickied) || is_filtered;
+	// Replies nested deeper than the user's preference start out collapsed too,
+	// except in single threads where that could hide the highlighted comment.
+	let collapsed_by_depth = highlighted_comment.is_empty() && collapse_at_depth(depth, &setting(req, "collapse_comments"));
+	let collapsed_as_bot = !highlighted && setting(req, "collapse_bots") == "on" && is_bot(&author.name, &setting(req, "bot_users"));
+	let collapsed = (is_moderator_comment && is_stickied) || is_filtered || collapsed_by_depth || collapsed_as_bot;
 
 	Comment {
 		id,
@@ -236,13 +452,10 @@ fn build_comment(
 		post_author: post_author.to_string(),
 		body,
 		author,
-		score: if data["score_hidden"].as_bool().unwrap_or_default() {
-			("\u{2022}".to_string(), "Hidden".to_string())
-		} else {
-			format_num(score)
-		},
+		score: format_score(score, data["score_hidden"].as_bool().unwrap_or_default()),
 		rel_time,
 		created,
+		created_ts: unix_time.round() as u64,
 		edited,
 		replies,
 		highlighted,
@@ -250,6 +463,165 @@ fn build_comment(
 		collapsed,
 		is_filtered,
 		more_count,
+		more_link,
+		is_op,
+		deleted,
 		prefs: Preferences::new(req),
 	}
 }
+
+// Collapse deleted or removed comments whose replies are all deleted or
+// removed too, so that moderated threads don't take up space. Comments with
+// surviving replies stay expanded. Returns whether all of `comments` were such
+// dead branches.
+fn collapse_deleted_branches(comments: &mut [Comment]) -> bool {
+	let mut all_dead = true;
+	for comment in comments {
+		let replies_dead = collapse_deleted_branches(&mut comment.replies);
+		// Unloaded replies may be worth reading
+		let dead = comment.kind == "t1" && comment.deleted && replies_dead;
+		if dead && !comment.highlighted {
+			comment.collapsed = true;
+		}
+		all_dead &= dead;
+	}
+	all_dead
+}
+
+// Whether a comment was written by the author of the post. Reddit says so
+// itself, and names are only compared for comments where it doesn't. Authors
+// of deleted posts and comments are unknown.
+fn is_submitter(data: &Value, author: &str, post_author: &str) -> bool {
+	if author == "[deleted]" || post_author == "[deleted]" {
+		return false;
+	}
+	data["is_submitter"].as_bool().unwrap_or_else(|| author == post_author)
+}
+
+// Whether comments of a user are collapsed as those of a bot: AutoModerator's,
+// and those of the users in the `+`-separated bot_users preference
+fn is_bot(author: &str, bot_users: &str) -> bool {
+	author.eq_ignore_ascii_case("AutoModerator") || bot_users.split('+').any(|bot| !bot.is_empty() && bot.eq_ignore_ascii_case(author))
+}
+
+// Whether a comment at the given depth (0 for top-level comments) is collapsed
+// according to the collapse_comments preference, which is the depth from which
+// comments are collapsed or "off".
+fn collapse_at_depth(depth: usize, preference: &str) -> bool {
+	preference.parse::<usize>().is_ok_and(|from| from > 0 && depth >= from)
+}
+
+#[test]
+fn test_collapse_at_depth() {
+	assert!(!collapse_at_depth(0, "1"));
+	assert!(collapse_at_depth(1, "1"));
+	assert!(collapse_at_depth(3, "2"));
+	assert!(!collapse_at_depth(1, "2"));
+	assert!(!collapse_at_depth(5, "off"));
+	assert!(!collapse_at_depth(5, ""));
+	assert!(!collapse_at_depth(5, "0"));
+}
+
+#[test]
+fn test_is_submitter() {
+	assert!(is_submitter(&json!({ "is_submitter": true }), "ferris", "ferris"));
+	assert!(!is_submitter(&json!({ "is_submitter": false }), "ferris", "ferris"));
+	assert!(is_submitter(&json!({}), "ferris", "ferris"));
+	assert!(!is_submitter(&json!({}), "ferris", "corro"));
+	assert!(!is_submitter(&json!({ "is_submitter": true }), "[deleted]", "[deleted]"));
+}
+
+#[test]
+fn test_is_bot() {
+	assert!(is_bot("AutoModerator", ""));
+	assert!(is_bot("automoderator", ""));
+	assert!(is_bot("RemindMeBot", "remindmebot+sneakpeekbot"));
+	assert!(!is_bot("ferris", "remindmebot+sneakpeekbot"));
+	assert!(!is_bot("", "a++b"));
+}
+
+#[test]
+fn test_more_comments_query() {
+	assert_eq!(more_comments_query("sort=new&more=t3_abc&offset=100", None), "sort=new");
+	assert_eq!(more_comments_query("sort=new", Some(("t3_abc", 0))), "sort=new&more=t3_abc");
+	assert_eq!(more_comments_query("more=t3_abc&offset=100", Some(("t3_abc", 200))), "more=t3_abc&offset=200");
+	assert_eq!(more_comments_query("context=9999&sort=top", None), "context=8&sort=top");
+	assert_eq!(more_comments_query("context=3", None), "context=3");
+	assert_eq!(more_comments_query("context=all", None), "");
+}
+
+#[test]
+fn test_find_more_stub() {
+	let listing = json!({ "data": { "children": [
+		{ "kind": "t1", "data": { "name": "t1_a", "parent_id": "t3_post", "replies": { "data": { "children": [
+			{ "kind": "more", "data": { "parent_id": "t1_a", "children": ["b", "c"] } }
+		] } } } },
+		{ "kind": "more", "data": { "parent_id": "t3_post", "children": ["d"] } }
+	] } });
+	assert_eq!(find_more_stub(&listing, "t1_a").unwrap()["data"]["children"], json!(["b", "c"]));
+	assert_eq!(find_more_stub(&listing, "t3_post").unwrap()["data"]["children"], json!(["d"]));
+	assert!(find_more_stub(&listing, "t1_d").is_none());
+}
+
+#[test]
+fn test_more_children_path() {
+	assert_eq!(
+		more_children_path("abc", &["c1", "c2"], "new"),
+		"/api/morechildren.json?api_type=json&link_id=t3_abc&children=c1,c2&sort=new&raw_json=1"
+	);
+	assert_eq!(
+		more_children_path("abc", &["c1"], ""),
+		"/api/morechildren.json?api_type=json&link_id=t3_abc&children=c1&raw_json=1"
+	);
+	assert!(!more_children_path("abc", &["c1"], "new&children=x").contains("children=x"));
+}
+
+#[test]
+fn test_nest_comments() {
+	let comment = |name: &str, parent: &str| json!({ "kind": "t1", "data": { "name": name, "parent_id": parent, "replies": "" } });
+	let things = [comment("t1_a", "t3_post"), comment("t1_b", "t1_a"), comment("t1_c", "t3_post")];
+	let nested = nest_comments(&things, "t3_post", 0);
+	assert_eq!(nested.len(), 2);
+	assert_eq!(nested[0]["data"]["replies"]["data"]["children"][0]["data"]["name"], "t1_b");
+	assert_eq!(nested[1]["data"]["replies"], "");
+
+	// A chain deeper than MAX_MORE_DEPTH ends in a stub linking to its thread
+	let chain = (0..=MAX_MORE_DEPTH)
+		.map(|i| comment(&format!("t1_{i}"), &if i == 0 { "t3_post".to_string() } else { format!("t1_{}", i - 1) }))
+		.collect::<Vec<_>>();
+	let mut level = &nest_comments(&chain, "t3_post", 0)[0];
+	for _ in 1..MAX_MORE_DEPTH {
+		level = &level["data"]["replies"]["data"]["children"][0];
+	}
+	let stub = &level["data"]["replies"]["data"]["children"][0];
+	assert_eq!(stub["kind"], "more");
+	assert_eq!(stub["data"]["parent_id"], format!("t1_{}", MAX_MORE_DEPTH - 1));
+}
+
+#[test]
+fn test_collapse_deleted_branches() {
+	let comment = |kind: &str, deleted: bool, replies: Vec<Comment>| Comment {
+		kind: kind.to_string(),
+		deleted,
+		replies,
+		more_count: 3,
+		..Comment::default()
+	};
+
+	// A removed comment with deleted replies
+	let mut comments = vec![comment("t1", true, vec![comment("t1", true, Vec::new())])];
+	assert!(collapse_deleted_branches(&mut comments));
+	assert!(comments[0].collapsed);
+	assert_eq!(comments[0].reply_count(), 1);
+
+	// Surviving and unloaded replies keep the parent expanded
+	let mut comments = vec![
+		comment("t1", true, vec![comment("t1", false, Vec::new()), comment("t1", true, Vec::new())]),
+		comment("t1", true, vec![comment("more", false, Vec::new())]),
+	];
+	assert!(!collapse_deleted_branches(&mut comments));
+	assert!(!comments[0].collapsed);
+	assert!(comments[0].replies[1].collapsed);
+	assert!(!comments[1].collapsed);
+	assert_eq!(comments[1].reply_count(), 3);
+}
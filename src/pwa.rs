@@ -0,0 +1,97 @@
+// Progressive web app support: the web app manifest, and a service worker
+// caching the app shell for faster loads and an offline page. Both can be
+// turned off with REDLIB_DISABLE_PWA.
+
+use hyper::{Body, Request, Response};
+use serde_json::json;
+use url::Url;
+
+use crate::config::get_setting;
+use crate::themes::style_version;
+
+/// Whether the manifest and service worker are served.
+pub fn enabled() -> bool {
+	get_setting("REDLIB_DISABLE_PWA").as_deref() != Some("on")
+}
+
+/// The path the instance is served under, taken from REDLIB_FULL_URL, without
+/// a trailing slash. Empty if it is served at the root.
+pub fn base_path() -> String {
+	get_setting("REDLIB_FULL_URL")
+		.and_then(|url| Url::parse(&url).ok())
+		.map(|url| url.path().trim_end_matches('/').to_string())
+		.unwrap_or_default()
+}
+
+pub async fn manifest(_req: Request<Body>) -> Result<Response<Body>, String> {
+	if !enabled() {
+		return Response::builder().status(404).body(Body::empty()).map_err(|e| e.to_string());
+	}
+	Response::builder()
+		.status(200)
+		.header("content-type", "application/manifest+json")
+		.body(manifest_json(&base_path()).into())
+		.map_err(|e| e.to_string())
+}
+
+fn manifest_json(base_path: &str) -> String {
+	let manifest = json!({
+		"name": "Redlib",
+		"short_name": "Redlib",
+		"display": "standalone",
+		"background_color": "#1f1f1f",
+		"description": "An alternative private front-end to Reddit",
+		"theme_color": "#1f1f1f",
+		"start_url": format!("{base_path}/"),
+		"scope": format!("{base_path}/"),
+		"icons": [
+			{ "src": format!("{base_path}/logo.png"), "sizes": "512x512", "type": "image/png" },
+			{ "src": format!("{base_path}/apple-touch-icon.png"), "sizes": "180x180", "type": "image/png" },
+			{ "src": format!("{base_path}/favicon.ico"), "sizes": "32x32" },
+		],
+	});
+	serde_json::to_string_pretty(&manifest).unwrap_or_default()
+}
+
+// Browsers that installed the service worker keep running it until it is
+// replaced, so a disabled one unregisters itself
+const UNREGISTER_WORKER: &str = "self.addEventListener(\"install\", function () { self.skipWaiting(); });\n\
+	self.addEventListener(\"activate\", function () { self.registration.unregister(); });\n";
+
+pub async fn service_worker(_req: Request<Body>) -> Result<Response<Body>, String> {
+	let body = if enabled() {
+		include_str!("../static/sw.js").replace("__VERSION__", &style_version())
+	} else {
+		UNREGISTER_WORKER.to_string()
+	};
+	Response::builder()
+		.status(200)
+		.header("content-type", "text/javascript")
+		// Browsers check for updates of the worker against this
+		.header("cache-control", "no-cache")
+		.body(body.into())
+		.map_err(|e| e.to_string())
+}
+
+pub async fn offline(_req: Request<Body>) -> Result<Response<Body>, String> {
+	let body = include_str!("../static/offline.html")
+		.replace("__VERSION__", &style_version())
+		.replace("__BASE__", &base_path());
+	Response::builder()
+		.status(200)
+		.header("content-type", "text/html")
+		.body(body.into())
+		.map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_manifest_json() {
+	let manifest: serde_json::Value = serde_json::from_str(&manifest_json("")).unwrap();
+	assert_eq!(manifest["start_url"], "/");
+	assert_eq!(manifest["icons"][0]["src"], "/logo.png");
+
+	let manifest: serde_json::Value = serde_json::from_str(&manifest_json("/redlib")).unwrap();
+	assert_eq!(manifest["start_url"], "/redlib/");
+	assert_eq!(manifest["scope"], "/redlib/");
+	assert_eq!(manifest["icons"][2]["src"], "/redlib/favicon.ico");
+}
@@ -1,14 +1,20 @@
 // CRATES
-use crate::utils::{self, catch_random, error, filter_posts, format_num, format_url, get_filters, param, redirect, setting, template, val, Post, Preferences};
+use crate::utils::{
+	self, base_url, catch_random, error, filter_keywords, filter_posts, format_num, format_url, get_filters, listing_json, listing_query, param, redirect, setting, template,
+	unavailable_subreddit, val, wants_json, Blocklist, Post, Preferences, SubredditPolicy, SUBREDDIT_POLICY,
+};
 use crate::{
-	client::json,
-	subreddit::{can_access_quarantine, quarantine},
+	client::{json, restriction},
+	config,
+	feed::{upstream_query, Feed, FeedFormat, FeedItem},
+	subreddit::{can_access_quarantine, restricted},
 	RequestExt,
 };
 use askama::Template;
 use hyper::{Body, Request, Response};
 use once_cell::sync::Lazy;
 use regex::Regex;
+use time::{Date, Month, OffsetDateTime};
 
 // STRUCTS
 struct SearchParams {
@@ -19,6 +25,9 @@ struct SearchParams {
 	after: String,
 	restrict_sr: String,
 	typed: String,
+	/// Dates given to restrict results to, as YYYY-MM-DD
+	from: String,
+	to: String,
 }
 
 // STRUCTS
@@ -44,14 +53,87 @@ struct SearchTemplate {
 	/// Whether all fetched posts are filtered (to differentiate between no posts fetched in the first place,
 	/// and all fetched posts being filtered).
 	all_posts_filtered: bool,
+	/// Number of fetched posts hidden by the user's keyword filters and block lists
+	num_posts_filtered: u64,
 	/// Whether all posts were hidden because they are NSFW (and user has disabled show NSFW)
 	all_posts_hidden_nsfw: bool,
 	no_posts: bool,
+	/// Number of fetched posts hidden for being outside the date range
+	num_posts_out_of_range: usize,
+}
+
+impl SearchTemplate {
+	/// Link to the same search across all of Reddit, for results restricted to a subreddit
+	fn search_all_url(&self) -> String {
+		search_all_url(&self.params.q.replace("&quot;", "\""), &self.params.sort, &self.params.t)
+	}
 }
 
 // Regex matched against search queries to determine if they are reddit urls.
 static REDDIT_URL_MATCH: Lazy<Regex> = Lazy::new(|| Regex::new(r"^https?://([^\./]+\.)*reddit.com/").unwrap());
 
+// Build the URL of a search across all of Reddit, keeping the sort and timeframe
+fn search_all_url(query: &str, sort: &str, timeframe: &str) -> String {
+	let mut params = url::form_urlencoded::Serializer::new(String::new());
+	params.append_pair("q", query);
+	if !sort.is_empty() {
+		params.append_pair("sort", sort);
+	}
+	if !timeframe.is_empty() {
+		params.append_pair("t", timeframe);
+	}
+	format!("/search?{}", params.finish())
+}
+
+// Reddit's search timeframes and how many seconds back they reach
+const TIMEFRAMES: [(i64, &str); 5] = [(3_600, "hour"), (86_400, "day"), (604_800, "week"), (2_678_400, "month"), (31_622_400, "year")];
+
+/// A range of creation times, as UNIX timestamps. The start is inclusive and
+/// the end exclusive.
+#[derive(Debug, PartialEq, Eq, Default)]
+struct DateRange {
+	start: Option<i64>,
+	end: Option<i64>,
+}
+
+impl DateRange {
+	/// Parse the range between the `from` and `to` dates, which both count as
+	/// whole days in UTC. Either can be left empty.
+	fn parse(from: &str, to: &str) -> Result<Self, String> {
+		let start = parse_date(from)?.map(|date| date.midnight().assume_utc().unix_timestamp());
+		let end = parse_date(to)?.map(|date| date.next_day().unwrap_or(date).midnight().assume_utc().unix_timestamp());
+		if let (Some(start), Some(end)) = (start, end) {
+			if start >= end {
+				return Err("The start date of the search must be before its end date".to_string());
+			}
+		}
+		Ok(Self { start, end })
+	}
+
+	fn contains(&self, timestamp: i64) -> bool {
+		self.start.is_none_or(|start| timestamp >= start) && self.end.is_none_or(|end| timestamp < end)
+	}
+
+	/// The smallest of Reddit's timeframes that covers the start of the range
+	fn timeframe(&self, now: i64) -> Option<&'static str> {
+		let age = now - self.start?;
+		Some(TIMEFRAMES.iter().find(|(seconds, _)| age <= *seconds).map_or("all", |(_, timeframe)| timeframe))
+	}
+}
+
+// Parse a YYYY-MM-DD date, as sent by date inputs
+fn parse_date(date: &str) -> Result<Option<Date>, String> {
+	if date.is_empty() {
+		return Ok(None);
+	}
+	let invalid = || format!("Invalid date \"{date}\", expected YYYY-MM-DD");
+	let mut parts = date.splitn(3, '-').map(|part| part.parse::<i32>().map_err(|_| invalid()));
+	let (year, month, day) = (parts.next().ok_or_else(invalid)??, parts.next().ok_or_else(invalid)??, parts.next().ok_or_else(invalid)??);
+	let month = u8::try_from(month).ok().and_then(|month| Month::try_from(month).ok()).ok_or_else(invalid)?;
+	let day = u8::try_from(day).map_err(|_| invalid())?;
+	Date::from_calendar_date(year, month, day).map(Some).map_err(|_| invalid())
+}
+
 // SERVICES
 pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 	// This ensures that during a search, no NSFW posts are fetched at all
@@ -61,7 +143,21 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 		""
 	};
 	let uri_path = req.uri().path().replace("+", "%2B");
-	let path = format!("{}.json?{}{}&raw_json=1", uri_path, req.uri().query().unwrap_or_default(), nsfw_results);
+	let mut path = format!("{}.json?{}{}&raw_json=1", uri_path, listing_query(&req), nsfw_results,);
+
+	// Reddit search can't be restricted to dates, so results are filtered here.
+	// Searching within the smallest timeframe covering them leaves fewer to drop.
+	let from = param(&path, "from").unwrap_or_default();
+	let to = param(&path, "to").unwrap_or_default();
+	let date_range = match DateRange::parse(&from, &to) {
+		Ok(date_range) => date_range,
+		Err(msg) => return error(req, &msg).await,
+	};
+	if param(&path, "t").filter(|t| !t.is_empty()).is_none() {
+		if let Some(timeframe) = date_range.timeframe(OffsetDateTime::now_utc().unix_timestamp()) {
+			path.push_str(&format!("&t={timeframe}"));
+		}
+	}
 	let mut query = param(&path, "q").unwrap_or_default();
 	query = REDDIT_URL_MATCH.replace(&query, "").to_string();
 
@@ -80,8 +176,13 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 	let sub = req.param("sub").unwrap_or_default();
 	let quarantined = can_access_quarantine(&req, &sub);
 	// Handle random subreddits
-	if let Ok(random) = catch_random(&sub, "/find").await {
-		return Ok(random);
+	match catch_random(&req, &sub, "/find").await {
+		Ok(Some(random)) => return Ok(random),
+		Ok(None) => {}
+		Err(msg) => return error(req, &msg).await,
+	}
+	if !sub.is_empty() && !SUBREDDIT_POLICY.allows(&sub) {
+		return error(req, &unavailable_subreddit(&sub)).await;
 	}
 
 	let typed = param(&path, "type").unwrap_or_default();
@@ -92,7 +193,7 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 	// If search is not restricted to this subreddit, show other subreddits in search results
 	let subreddits = if param(&path, "restrict_sr").is_none() {
 		let mut subreddits = search_subreddits(&query, &typed).await;
-		subreddits.retain(|s| !filters.contains(s.name.as_str()));
+		subreddits.retain(|s| !filters.contains(s.name.as_str()) && SUBREDDIT_POLICY.allows(&s.name));
 		subreddits
 	} else {
 		Vec::new()
@@ -102,6 +203,9 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 
 	// If all requested subs are filtered, we don't need to fetch posts.
 	if sub.split('+').all(|s| filters.contains(s)) {
+		if wants_json(&req) {
+			return Ok(listing_json(&[], ""));
+		}
 		Ok(template(&SearchTemplate {
 			posts: Vec::new(),
 			subreddits,
@@ -114,20 +218,34 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 				after: String::new(),
 				restrict_sr: param(&path, "restrict_sr").unwrap_or_default(),
 				typed,
+				from,
+				to,
 			},
 			prefs: Preferences::new(&req),
 			url,
 			is_filtered: true,
 			all_posts_filtered: false,
+			num_posts_filtered: 0,
 			all_posts_hidden_nsfw: false,
 			no_posts: false,
+			num_posts_out_of_range: 0,
 		}))
 	} else {
 		match Post::fetch(&path, quarantined).await {
 			Ok((mut posts, after)) => {
+				let fetched = posts.len();
+				posts.retain(|post| date_range.contains(post.created_ts as i64));
+				let num_posts_out_of_range = fetched - posts.len();
+				// Subreddits are only blocked in results that aren't restricted to one
+				let aggregate = sub.is_empty() || sub.contains('+') || param(&path, "restrict_sr").is_none();
+				let num_posts_filtered = SUBREDDIT_POLICY.apply(&mut posts) + filter_keywords(&req, &mut posts) + Blocklist::from_request(&req).apply(&mut posts, aggregate);
 				let (_, all_posts_filtered) = filter_posts(&mut posts, &filters);
+				let all_posts_filtered = all_posts_filtered || (num_posts_filtered > 0 && posts.is_empty());
 				let no_posts = posts.is_empty();
 				let all_posts_hidden_nsfw = !no_posts && (posts.iter().all(|p| p.flags.nsfw) && setting(&req, "show_nsfw") != "on");
+				if wants_json(&req) {
+					return Ok(listing_json(&posts, &after));
+				}
 				Ok(template(&SearchTemplate {
 					posts,
 					subreddits,
@@ -140,19 +258,23 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 						after,
 						restrict_sr: param(&path, "restrict_sr").unwrap_or_default(),
 						typed,
+						from,
+						to,
 					},
 					prefs: Preferences::new(&req),
 					url,
 					is_filtered: false,
 					all_posts_filtered,
+					num_posts_filtered,
 					all_posts_hidden_nsfw,
 					no_posts,
+					num_posts_out_of_range,
 				}))
 			}
 			Err(msg) => {
-				if msg == "quarantined" || msg == "gated" {
+				if restriction(&msg).is_some() {
 					let sub = req.param("sub").unwrap_or_default();
-					Ok(quarantine(&req, sub, &msg))
+					Ok(restricted(&req, sub, &msg))
 				} else {
 					error(req, &msg).await
 				}
@@ -161,6 +283,132 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 	}
 }
 
+/// Build the feed served by [`crate::feed::serve`].
+pub async fn feed(req: Request<Body>, format: FeedFormat) -> Result<Response<Body>, String> {
+	let query_string = upstream_query(req.uri().query().unwrap_or_default());
+	let query = param(&format!("?{query_string}"), "q").unwrap_or_default();
+	if query.trim().is_empty() {
+		return error(req, "A search query is required, e.g. /search.rss?q=rust").await;
+	}
+	let sub = req.param("sub").unwrap_or_default();
+	if !sub.is_empty() && !SUBREDDIT_POLICY.allows(&sub) {
+		return error(req, &unavailable_subreddit(&sub)).await;
+	}
+
+	// Search the listing the feed was requested for, e.g. /r/rust/search.rss
+	let uri_path = req
+		.uri()
+		.path()
+		.trim_end_matches(".rss")
+		.trim_end_matches(".atom")
+		.trim_end_matches(".json")
+		.replace('+', "%2B");
+	let nsfw_results = if setting(&req, "show_nsfw") == "on" && !utils::sfw_only() {
+		"&include_over_18=on"
+	} else {
+		""
+	};
+	let path = format!("{uri_path}.json?{query_string}{nsfw_results}&raw_json=1");
+
+	let mut posts = match Post::fetch(&path, false).await {
+		Ok((posts, _)) => posts,
+		Err(msg) => return error(req, &msg).await,
+	};
+	if utils::sfw_only() {
+		posts.retain(|post| !post.flags.nsfw);
+	}
+	SUBREDDIT_POLICY.apply(&mut posts);
+
+	let sub = Some(sub).filter(|sub| !sub.is_empty() && param(&path, "restrict_sr").is_some());
+	let (title, description) = match &sub {
+		Some(sub) => (format!("\"{query}\" in r/{sub}"), format!("Posts in r/{sub} matching \"{query}\"")),
+		None => (format!("\"{query}\""), format!("Posts matching \"{query}\"")),
+	};
+	let feed = Feed {
+		title: format!("Search results for {title}"),
+		description,
+		path: format!("{uri_path}?{query_string}"),
+		self_path: format!("{}?{query_string}", req.uri().path()),
+		items: posts.into_iter().map(FeedItem::from_post).collect(),
+	};
+
+	Ok(feed.response(format))
+}
+
+/// Describes the search of this instance, so that browsers can offer it as a
+/// search engine.
+#[derive(Template)]
+#[template(path = "opensearch.xml")]
+struct OpenSearchTemplate {
+	base_url: String,
+}
+
+pub async fn opensearch(req: Request<Body>) -> Result<Response<Body>, String> {
+	let body = OpenSearchTemplate {
+		base_url: base_url(config::get_setting("REDLIB_FULL_URL"), req.headers()),
+	}
+	.render()
+	.map_err(|e| e.to_string())?;
+	Response::builder()
+		.status(200)
+		.header("content-type", "application/opensearchdescription+xml")
+		.body(body.into())
+		.map_err(|e| e.to_string())
+}
+
+// Most subreddits suggested while typing a search
+const MAX_SUGGESTIONS: usize = 10;
+
+/// Suggest the names of subreddits starting with the query, as a JSON array.
+/// NSFW subreddits are only suggested to users who view NSFW content, and
+/// filtered ones or those this instance doesn't allow never are. Users have to
+/// turn suggestions on, since what they type is then sent to Reddit.
+pub async fn autocomplete(req: Request<Body>) -> Result<Response<Body>, String> {
+	if setting(&req, "search_suggestions") != "on" {
+		return error(req, "Search suggestions are turned off in your settings.").await;
+	}
+	let query = req.uri().query().and_then(|query| param(&format!("/?{query}"), "q")).unwrap_or_default();
+	let query = query.trim().trim_start_matches("r/");
+
+	let names = if query.is_empty() {
+		Vec::new()
+	} else {
+		let nsfw = setting(&req, "show_nsfw") == "on" && !utils::sfw_only();
+		let path = format!(
+			"/api/search_reddit_names.json?{}",
+			url::form_urlencoded::Serializer::new(String::new())
+				.append_pair("query", query)
+				.append_pair("include_over_18", if nsfw { "true" } else { "false" })
+				.append_pair("exact", "false")
+				.finish()
+		);
+		let filters = get_filters(&req);
+		json(path, false).await.map(|res| parse_suggestions(&res, &filters, &SUBREDDIT_POLICY)).unwrap_or_default()
+	};
+
+	Response::builder()
+		.status(200)
+		.header("content-type", "application/json")
+		.header("cache-control", "private, max-age=300")
+		.body(serde_json::to_string(&names).unwrap_or_default().into())
+		.map_err(|e| e.to_string())
+}
+
+fn parse_suggestions(res: &serde_json::Value, filters: &std::collections::HashSet<String>, policy: &SubredditPolicy) -> Vec<String> {
+	res["names"]
+		.as_array()
+		.map(|names| {
+			names
+				.iter()
+				.filter_map(serde_json::Value::as_str)
+				.filter(|name| !filters.contains(*name) && policy.allows(name))
+				.take(MAX_SUGGESTIONS)
+				.map(String::from)
+				.collect()
+		})
+		.unwrap_or_default()
+}
+
 async fn search_subreddits(q: &str, typed: &str) -> Vec<Subreddit> {
 	let limit = if typed == "sr_user" { "50" } else { "3" };
 	let subreddit_search_path = format!("/subreddits/search.json?q={}&limit={limit}", q.replace(' ', "+"));
@@ -186,3 +434,76 @@ async fn search_subreddits(q: &str, typed: &str) -> Vec<Subreddit> {
 		})
 		.collect::<Vec<Subreddit>>()
 }
+
+#[test]
+fn test_search_all_url() {
+	assert_eq!(search_all_url("rust & \"go\"", "top", "week"), "/search?q=rust+%26+%22go%22&sort=top&t=week");
+	assert_eq!(search_all_url("rust", "", ""), "/search?q=rust");
+}
+
+#[test]
+fn test_date_range() {
+	let range = DateRange::parse("2020-09-13", "2020-09-13").unwrap();
+	assert_eq!(range.start, Some(1_599_955_200));
+	assert_eq!(range.end, Some(1_599_955_200 + 86_400));
+	// Both days are included in full
+	assert!(range.contains(1_599_955_200));
+	assert!(range.contains(1_600_041_599));
+	assert!(!range.contains(1_600_041_600));
+	assert!(!range.contains(1_599_955_199));
+
+	assert_eq!(DateRange::parse("", ""), Ok(DateRange::default()));
+	assert!(DateRange::parse("", "2020-09-13").unwrap().contains(0));
+	assert!(DateRange::parse("2020-09-14", "2020-09-13").is_err());
+	assert!(DateRange::parse("2020-13-01", "").is_err());
+	assert!(DateRange::parse("2020-02-30", "").is_err());
+	assert!(DateRange::parse("yesterday", "").is_err());
+}
+
+#[test]
+fn test_date_range_timeframe() {
+	let range = DateRange::parse("2020-09-13", "").unwrap();
+	let start = 1_599_955_200;
+	assert_eq!(range.timeframe(start + 100), Some("hour"));
+	assert_eq!(range.timeframe(start + 3 * 86_400), Some("week"));
+	assert_eq!(range.timeframe(start + 200 * 86_400), Some("year"));
+	assert_eq!(range.timeframe(start + 400 * 86_400), Some("all"));
+	assert_eq!(DateRange::default().timeframe(start), None);
+}
+
+#[test]
+fn test_parse_suggestions() {
+	let res = serde_json::json!({"names": ["rust", "rust_gamedev", "rustjerk", 42]});
+	let filters = std::collections::HashSet::from(["rustjerk".to_string()]);
+	let policy = SubredditPolicy::default();
+	assert_eq!(parse_suggestions(&res, &filters, &policy), ["rust", "rust_gamedev"]);
+	assert_eq!(parse_suggestions(&res, &filters, &SubredditPolicy::new(None, Some("rust_gamedev"))), ["rust"]);
+	assert_eq!(parse_suggestions(&res, &filters, &SubredditPolicy::new(Some("rust+rustjerk"), None)), ["rust"]);
+
+	let many = serde_json::json!({"names": (0..20).map(|i| format!("sub{i}")).collect::<Vec<_>>()});
+	assert_eq!(parse_suggestions(&many, &filters, &policy).len(), MAX_SUGGESTIONS);
+	assert!(parse_suggestions(&serde_json::Value::Null, &filters, &policy).is_empty());
+}
+
+#[test]
+fn test_opensearch() {
+	let xml = OpenSearchTemplate {
+		base_url: "https://example.com/redlib".to_string(),
+	}
+	.render()
+	.unwrap();
+	assert!(xml.contains(r#"template="https://example.com/redlib/search?q={searchTerms}""#));
+	assert!(xml.contains("<Image width=\"32\" height=\"32\" type=\"image/x-icon\">https://example.com/redlib/favicon.ico</Image>"));
+}
+
+#[tokio::test]
+async fn test_autocomplete_opt_in() {
+	// Nothing typed is sent to Reddit unless suggestions are turned on
+	let req = Request::get("/autocomplete?q=rust").body(Body::empty()).unwrap();
+	assert_eq!(autocomplete(req).await.unwrap().status(), 404);
+
+	let req = Request::get("/autocomplete?q=").header("cookie", "search_suggestions=on").body(Body::empty()).unwrap();
+	let res = autocomplete(req).await.unwrap();
+	assert_eq!(res.status(), 200);
+	assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "[]");
+}
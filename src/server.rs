@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use brotli::enc::{BrotliCompress, BrotliEncoderParams};
+use brotli::CompressorWriter;
 use cached::proc_macro::cached;
 use cookie::Cookie;
 use core::f64;
@@ -18,7 +19,7 @@ use route_recognizer::{Params, Router};
 use std::{
 	cmp::Ordering,
 	fmt::Display,
-	io,
+	io::{self, Write},
 	pin::Pin,
 	result::Result,
 	str::{from_utf8, Split},
@@ -27,6 +28,7 @@ use std::{
 use time::Duration;
 
 use crate::dbg_msg;
+use crate::metrics::HTTP_METRICS;
 
 type BoxResponse = Pin<Box<dyn Future<Output = Result<Response<Body>, String>> + Send>>;
 
@@ -78,14 +80,17 @@ impl Display for CompressionType {
 	}
 }
 
+// A route's handler, with the path it was added at
+type Handler = (String, fn(Request<Body>) -> BoxResponse);
+
 pub struct Route<'a> {
-	router: &'a mut Router<fn(Request<Body>) -> BoxResponse>,
+	router: &'a mut Router<Handler>,
 	path: String,
 }
 
 pub struct Server {
 	pub default_headers: HeaderMap,
-	router: Router<fn(Request<Body>) -> BoxResponse>,
+	router: Router<Handler>,
 }
 
 #[macro_export]
@@ -180,7 +185,7 @@ impl ResponseExt for Response<Body> {
 
 impl Route<'_> {
 	fn method(&mut self, method: &Method, dest: fn(Request<Body>) -> BoxResponse) -> &mut Self {
-		self.router.add(&format!("/{}{}", method.as_str(), self.path), dest);
+		self.router.add(&format!("/{}{}", method.as_str(), self.path), (self.path.clone(), dest));
 		self
 	}
 
@@ -210,7 +215,9 @@ impl Server {
 		}
 	}
 
-	pub fn listen(self, addr: &str) -> Boxed<Result<(), hyper::Error>> {
+	/// Serve requests on `addr` until `shutdown` completes, then stop accepting
+	/// connections and wait for the in-flight requests to be answered.
+	pub fn listen(self, addr: &str, shutdown: impl Future<Output = ()> + Send + 'static) -> Boxed<Result<(), hyper::Error>> {
 		let make_svc = make_service_fn(move |_conn| {
 			// For correct borrowing, these values need to be borrowed
 			let router = self.router.clone();
@@ -241,16 +248,24 @@ impl Server {
 							parammed.set_params(found.params().clone());
 
 							// Run the route's function
-							let func = (found.handler().to_owned().to_owned())(parammed);
+							let (route, handler) = found.handler();
+							HTTP_METRICS.record_request(route);
+							let func = handler(parammed);
 							async move {
 								match func.await {
 									Ok(mut res) => {
+										if res.status().is_server_error() {
+											HTTP_METRICS.server_errors.inc();
+										}
 										res.headers_mut().extend(def_headers);
 										let _ = compress_response(&req_headers, &mut res).await;
 
 										Ok(res)
 									}
-									Err(msg) => new_boilerplate(def_headers, req_headers, 500, Body::from(msg)).await,
+									Err(msg) => {
+										HTTP_METRICS.server_errors.inc();
+										new_boilerplate(def_headers, req_headers, 500, Body::from(msg)).await
+									}
 								}
 							}
 							.boxed()
@@ -265,11 +280,8 @@ impl Server {
 		// Build SocketAddr from provided address
 		let address = &addr.parse().unwrap_or_else(|_| panic!("Cannot parse {addr} as address (example format: 0.0.0.0:8080)"));
 
-		// Bind server to address specified above. Gracefully shut down if CTRL+C is pressed
-		let server = HyperServer::bind(address).serve(make_svc).with_graceful_shutdown(async {
-			// Wait for the CTRL+C signal
-			tokio::signal::ctrl_c().await.expect("Failed to install CTRL+C signal handler");
-		});
+		// Bind server to address specified above
+		let server = HyperServer::bind(address).serve(make_svc).with_graceful_shutdown(shutdown);
 
 		server.boxed()
 	}
@@ -463,6 +475,9 @@ fn determine_compressor(accept_encoding: String) -> Option<CompressionType> {
 /// 3. the Media type in the Content-Type response header is text with any
 ///    subtype (e.g. text/plain) or application/json.
 ///
+/// Bodies that are streamed, such as pages that are sent as they're rendered,
+/// are compressed as they go rather than buffered.
+///
 /// `compress_response` returns Ok on successful compression, or if not all three
 /// conditions above are met. It returns Err if there was a problem decoding
 /// any header in either `req_headers` or res, but res will remain intact.
@@ -494,8 +509,9 @@ async fn compress_response(req_headers: &HeaderMap<header::HeaderValue>, res: &m
 
 	// Don't bother if the size of the size of the response body will fit
 	// within an IP frame (less the bytes that make up the TCP/IP and HTTP
-	// headers).
-	if res.body().size_hint().lower() < 1452 {
+	// headers). The size of streamed bodies isn't known in advance.
+	let streamed = res.body().size_hint().exact().is_none();
+	if !streamed && res.body().size_hint().lower() < 1452 {
 		return Ok(());
 	};
 
@@ -522,6 +538,13 @@ async fn compress_response(req_headers: &HeaderMap<header::HeaderValue>, res: &m
 		None => return Ok(()),
 	};
 
+	if streamed {
+		let body = std::mem::take(res.body_mut());
+		*(res.body_mut()) = compress_stream(compressor, body)?;
+		res.headers_mut().insert(header::CONTENT_ENCODING, compressor.to_string().parse().unwrap());
+		return Ok(());
+	}
+
 	// Get the body from the response.
 	let body_bytes: Vec<u8> = match body::to_bytes(res.body_mut()).await {
 		Ok(b) => b.to_vec(),
@@ -547,6 +570,67 @@ async fn compress_response(req_headers: &HeaderMap<header::HeaderValue>, res: &m
 	Ok(())
 }
 
+/// Compresses a streamed body given a [`CompressionType`], flushing the
+/// compressor after every chunk so that clients can decompress each part as
+/// soon as it arrives.
+///
+/// This is a helper function for [`compress_response`] and should not be
+/// called directly.
+fn compress_stream(compressor: CompressionType, body: Body) -> Result<Body, String> {
+	let encoder = match compressor {
+		CompressionType::Gzip => StreamEncoder::Gzip(Box::new(gzip::Encoder::new(Vec::new()).map_err(|e| e.to_string())?)),
+		CompressionType::Brotli => StreamEncoder::Brotli(Box::new(CompressorWriter::with_params(Vec::new(), 4096, &BrotliEncoderParams::default()))),
+		CompressionType::Passthrough => return Err("unsupported compressor".to_string()),
+	};
+
+	// Stop at the first error, which aborts the response
+	let stream = futures_lite::stream::unfold(Some((body, encoder)), |state| async move {
+		let (mut body, mut encoder) = state?;
+		Some(match body.data().await {
+			Some(Ok(chunk)) => match encoder.compress(&chunk) {
+				Ok(compressed) => (Ok(compressed), Some((body, encoder))),
+				Err(e) => (Err(e), None),
+			},
+			Some(Err(e)) => (Err(io::Error::other(e)), None),
+			None => (encoder.finish(), None),
+		})
+	});
+	Ok(Body::wrap_stream(stream))
+}
+
+/// Compressors of a streamed body
+enum StreamEncoder {
+	Gzip(Box<gzip::Encoder<Vec<u8>>>),
+	Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+impl StreamEncoder {
+	/// Compress a chunk of the body, returning the compressed data so far
+	fn compress(&mut self, chunk: &[u8]) -> io::Result<body::Bytes> {
+		let compressed = match self {
+			Self::Gzip(gz) => {
+				gz.write_all(chunk)?;
+				gz.flush()?;
+				gz.as_inner_mut()
+			}
+			Self::Brotli(br) => {
+				br.write_all(chunk)?;
+				br.flush()?;
+				br.get_mut()
+			}
+		};
+		Ok(std::mem::take(compressed).into())
+	}
+
+	/// Finish compressing the body, returning the rest of the compressed data
+	fn finish(self) -> io::Result<body::Bytes> {
+		match self {
+			Self::Gzip(gz) => gz.finish().into_result().map(Into::into),
+			Self::Brotli(br) => Ok(br.into_inner().into()),
+		}
+	}
+}
+
 /// Compresses a `Vec<u8>` given a [`CompressionType`].
 ///
 /// This is a helper function for [`compress_response`] and should not be
@@ -723,7 +807,7 @@ mod tests {
 
 				CompressionType::Brotli => Box::new(BrotliDecompressor::new(body_cursor, expected_lorem_ipsum.len())),
 
-				_ => panic!("no decompressor for {}", expected_encoding.to_string()),
+				_ => panic!("no decompressor for {expected_encoding}"),
 			};
 
 			let mut decompressed = Vec::<u8>::new();
@@ -734,4 +818,37 @@ mod tests {
 			assert!(decompressed.eq(&expected_lorem_ipsum));
 		}
 	}
+
+	#[tokio::test]
+	async fn test_compress_stream() {
+		let page = lipsum(10000);
+		let (first, rest) = page.split_at(page.len() / 2);
+
+		for encoding in ["gzip", "br"] {
+			let mut req_headers = HeaderMap::new();
+			req_headers.insert(header::ACCEPT_ENCODING, header::HeaderValue::from_static(encoding));
+			let (mut sender, body) = Body::channel();
+			let mut res = Response::builder().header(header::CONTENT_TYPE, "text/html").body(body).unwrap();
+			compress_response(&req_headers, &mut res).await.unwrap();
+			assert_eq!(res.headers()[header::CONTENT_ENCODING], encoding);
+
+			// Each chunk is compressed as soon as it's sent
+			sender.send_data(first.to_string().into()).await.unwrap();
+			let mut compressed = res.body_mut().data().await.unwrap().unwrap().to_vec();
+			assert!(!compressed.is_empty());
+			sender.send_data(rest.to_string().into()).await.unwrap();
+			drop(sender);
+			while let Some(chunk) = res.body_mut().data().await {
+				compressed.extend_from_slice(&chunk.unwrap());
+			}
+
+			let mut decoder: Box<dyn io::Read> = match encoding {
+				"gzip" => Box::new(gzip::Decoder::new(io::Cursor::new(compressed)).unwrap()),
+				_ => Box::new(BrotliDecompressor::new(io::Cursor::new(compressed), 4096)),
+			};
+			let mut decompressed = String::new();
+			decoder.read_to_string(&mut decompressed).unwrap();
+			assert_eq!(decompressed, page);
+		}
+	}
 }
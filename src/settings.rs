@@ -1,12 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 // CRATES
-use crate::server::ResponseExt;
-use crate::utils::{redirect, template, Preferences};
+use crate::config::get_setting;
+use crate::server::{RequestExt, ResponseExt};
+use crate::utils::{
+	available_themes, base_url, decode_cookie_value, enable_rss, encode_cookie_value, encode_subreddit_sorts, error, escape, normalize_name_list, parse_subreddit_sorts,
+	redirect, setting, template, Preferences, COMMENT_SORTS, POSTS_PER_PAGE, POST_SORTS,
+};
 use askama::Template;
 use cookie::Cookie;
 use futures_lite::StreamExt;
-use hyper::{Body, Request, Response};
+use hyper::{header, Body, Request, Response};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::{Map, Value};
 use time::{Duration, OffsetDateTime};
 
 // STRUCTS
@@ -15,11 +22,21 @@ use time::{Duration, OffsetDateTime};
 struct SettingsTemplate {
 	prefs: Preferences,
 	url: String,
+	/// How many subreddits were subscribed to and skipped by the last import
+	/// of subscriptions, if the page is shown after one
+	subscriptions_import: Option<(usize, usize)>,
+}
+
+#[derive(Template)]
+#[template(path = "themes.html")]
+struct ThemesTemplate {
+	prefs: Preferences,
+	url: String,
 }
 
 // CONSTANTS
 
-const PREFS: [&str; 17] = [
+const PREFS: [&str; 35] = [
 	"theme",
 	"front_page",
 	"layout",
@@ -37,16 +54,96 @@ const PREFS: [&str; 17] = [
 	"hide_awards",
 	"hide_score",
 	"disable_visit_reddit_confirmation",
+	"keyword_filters",
+	"keyword_filter_body",
+	"show_filtered_count",
+	"blocked_subreddits",
+	"blocked_users",
+	"min_score",
+	"collapse_comments",
+	"collapse_bots",
+	"bot_users",
+	"collapse_deleted",
+	"read_posts",
+	"posts_per_page",
+	"infinite_scroll",
+	"keyboard_shortcuts",
+	"search_suggestions",
+	"timezone",
+	"locale",
+	"strip_tracking",
+];
+
+// Preferences whose values are percent-encoded in their cookie
+const ENCODED_PREFS: [&str; 1] = ["keyword_filters"];
+
+// Preferences holding lists of subreddit or user names
+const NAME_LIST_PREFS: [&str; 3] = ["blocked_subreddits", "blocked_users", "bot_users"];
+
+// Cookies stored outside the settings form, which are also restored and exported
+const LIST_COOKIES: [&str; 3] = ["subscriptions", "filters", "subreddit_sorts"];
+
+// Toggles, which are either "on" or "off"
+const TOGGLE_PREFS: [&str; 20] = [
+	"wide",
+	"blur_spoiler",
+	"show_nsfw",
+	"blur_nsfw",
+	"use_hls",
+	"hide_hls_notification",
+	"autoplay_videos",
+	"hide_sidebar_and_summary",
+	"fixed_navbar",
+	"hide_awards",
+	"hide_score",
+	"disable_visit_reddit_confirmation",
+	"keyword_filter_body",
+	"show_filtered_count",
+	"collapse_bots",
+	"collapse_deleted",
+	"infinite_scroll",
+	"keyboard_shortcuts",
+	"search_suggestions",
+	"strip_tracking",
 ];
 
+// Largest settings file accepted for import
+const MAX_IMPORT_SIZE: usize = 64 * 1024;
+
+// Largest list of subreddits accepted for import, which fits a Reddit export
+// of thousands of subscriptions
+const MAX_SUBSCRIPTIONS_IMPORT_SIZE: usize = 256 * 1024;
+
+// Room left for the multipart headers and boundaries around an uploaded file
+const MULTIPART_OVERHEAD: usize = 4 * 1024;
+
+// Longest subscriptions cookie, as browsers only store cookies up to 4 KiB
+const MAX_SUBSCRIPTIONS_LENGTH: usize = 4000;
+
+// Valid names of subreddits, including user profiles such as u_spez, whose
+// usernames can be up to 20 characters long
+static SUBREDDIT_NAME: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[A-Za-z0-9][A-Za-z0-9_]{1,21}$").unwrap());
+
 // FUNCTIONS
 
 // Retrieve cookies from request "Cookie" header
 pub async fn get(req: Request<Body>) -> Result<Response<Body>, String> {
 	let url = req.uri().to_string();
+	let query = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()).collect::<HashMap<_, _>>();
+	let count = |name: &str| query.get(name).and_then(|value| value.parse::<usize>().ok());
 	Ok(template(&SettingsTemplate {
 		prefs: Preferences::new(&req),
 		url,
+		subscriptions_import: count("imported").map(|imported| (imported, count("skipped").unwrap_or_default())),
+	}))
+}
+
+// Preview every theme side by side, each sample scoped to the theme's class
+pub async fn themes(req: Request<Body>) -> Result<Response<Body>, String> {
+	let url = req.uri().to_string();
+	Ok(template(&ThemesTemplate {
+		prefs: Preferences::new(&req),
+		url,
 	}))
 }
 
@@ -65,13 +162,7 @@ pub async fn set(req: Request<Body>) -> Result<Response<Body>, String> {
 
 	// Aggregate the body...
 	// let whole_body = hyper::body::aggregate(req).await.map_err(|e| e.to_string())?;
-	let body_bytes = body
-		.try_fold(Vec::new(), |mut data, chunk| {
-			data.extend_from_slice(&chunk);
-			Ok(data)
-		})
-		.await
-		.map_err(|e| e.to_string())?;
+	let body_bytes = read_body(&mut body).await?;
 
 	let form = url::form_urlencoded::parse(&body_bytes).collect::<HashMap<_, _>>();
 
@@ -80,7 +171,7 @@ pub async fn set(req: Request<Body>) -> Result<Response<Body>, String> {
 	for &name in &PREFS {
 		match form.get(name) {
 			Some(value) => response.insert_cookie(
-				Cookie::build((name.to_owned(), value.clone()))
+				Cookie::build((name.to_owned(), cookie_value(name, value)))
 					.path("/")
 					.http_only(true)
 					.expires(OffsetDateTime::now_utc() + Duration::weeks(52))
@@ -90,6 +181,25 @@ pub async fn set(req: Request<Body>) -> Result<Response<Body>, String> {
 		};
 	}
 
+	// Per-subreddit default sorts are submitted as one field per subscription,
+	// so subreddits that are no longer subscribed to are dropped here
+	let sorts = form
+		.iter()
+		.filter_map(|(name, value)| Some((name.strip_prefix("subreddit_sort.")?.to_lowercase(), value.to_string())))
+		.filter(|(_, sort)| POST_SORTS.contains(&sort.as_str()))
+		.collect::<BTreeMap<_, _>>();
+	if sorts.is_empty() {
+		response.remove_cookie("subreddit_sorts".to_string());
+	} else {
+		response.insert_cookie(
+			Cookie::build(("subreddit_sorts", encode_subreddit_sorts(&sorts)))
+				.path("/")
+				.http_only(true)
+				.expires(OffsetDateTime::now_utc() + Duration::weeks(52))
+				.into(),
+		);
+	}
+
 	Ok(response)
 }
 
@@ -116,10 +226,10 @@ fn set_cookies_method(req: Request<Body>, remove_cookies: bool) -> Response<Body
 
 	let mut response = redirect(&path);
 
-	for name in [PREFS.to_vec(), vec!["subscriptions", "filters"]].concat() {
+	for name in [PREFS.as_slice(), &LIST_COOKIES].concat() {
 		match form.get(name) {
 			Some(value) => response.insert_cookie(
-				Cookie::build((name.to_owned(), value.clone()))
+				Cookie::build((name.to_owned(), cookie_value(name, value)))
 					.path("/")
 					.http_only(true)
 					.expires(OffsetDateTime::now_utc() + Duration::weeks(52))
@@ -136,6 +246,45 @@ fn set_cookies_method(req: Request<Body>, remove_cookies: bool) -> Response<Body
 	response
 }
 
+// Encode the value of a preference for storing it in a cookie
+fn cookie_value(name: &str, value: &str) -> String {
+	if ENCODED_PREFS.contains(&name) {
+		encode_cookie_value(value)
+	} else if NAME_LIST_PREFS.contains(&name) {
+		normalize_name_list(value)
+	} else {
+		value.to_string()
+	}
+}
+
+// Collect a request body
+async fn read_body(body: &mut Body) -> Result<Vec<u8>, String> {
+	body
+		.try_fold(Vec::new(), |mut data, chunk| {
+			data.extend_from_slice(&chunk);
+			Ok(data)
+		})
+		.await
+		.map_err(|e| e.to_string())
+}
+
+// Collect the body of an upload, giving up as soon as it is known to exceed
+// `limit` bytes so that large uploads aren't held in memory
+async fn read_upload(headers: &header::HeaderMap, body: &mut Body, limit: usize) -> Option<Vec<u8>> {
+	let length = headers.get(header::CONTENT_LENGTH).and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+	if length.is_some_and(|length| length > limit) {
+		return None;
+	}
+	let mut data = Vec::new();
+	while let Some(chunk) = body.next().await {
+		data.extend_from_slice(&chunk.ok()?);
+		if data.len() > limit {
+			return None;
+		}
+	}
+	Some(data)
+}
+
 // Set cookies using response "Set-Cookie" header
 pub async fn restore(req: Request<Body>) -> Result<Response<Body>, String> {
 	Ok(set_cookies_method(req, true))
@@ -144,3 +293,353 @@ pub async fn restore(req: Request<Body>) -> Result<Response<Body>, String> {
 pub async fn update(req: Request<Body>) -> Result<Response<Body>, String> {
 	Ok(set_cookies_method(req, false))
 }
+
+// Download all preferences as a JSON file
+pub async fn export(req: Request<Body>) -> Result<Response<Body>, String> {
+	let prefs = export_prefs(|name| req.cookie(name).map(|cookie| cookie.value().to_string()));
+	let body = serde_json::to_string_pretty(&prefs).map_err(|e| e.to_string())?;
+	Response::builder()
+		.status(200)
+		.header("content-type", "application/json")
+		.header("content-disposition", "attachment; filename=\"redlib-settings.json\"")
+		.body(body.into())
+		.map_err(|e| e.to_string())
+}
+
+// Apply preferences from an uploaded JSON file
+pub async fn import(req: Request<Body>) -> Result<Response<Body>, String> {
+	let content_type = req
+		.headers()
+		.get(header::CONTENT_TYPE)
+		.and_then(|value| value.to_str().ok())
+		.unwrap_or_default()
+		.to_string();
+	let (parts, mut body) = req.into_parts();
+	let body_bytes = read_upload(&parts.headers, &mut body, MAX_IMPORT_SIZE + MULTIPART_OVERHEAD).await.unwrap_or_default();
+
+	let prefs = match multipart_file(&content_type, &body_bytes)
+		.filter(|file| file.len() <= MAX_IMPORT_SIZE)
+		.map(serde_json::from_slice::<Value>)
+	{
+		Some(Ok(Value::Object(prefs))) => prefs,
+		_ => {
+			return error(
+				Request::from_parts(parts, Body::empty()),
+				"Invalid settings file, expected one exported from the settings page",
+			)
+			.await
+		}
+	};
+
+	let mut response = redirect("/settings");
+	// Fields that are missing or invalid fall back to their defaults
+	for name in [PREFS.as_slice(), &LIST_COOKIES].concat() {
+		match prefs.get(name).and_then(|value| import_value(name, value)) {
+			Some(value) => response.insert_cookie(
+				Cookie::build((name.to_owned(), value))
+					.path("/")
+					.http_only(true)
+					.expires(OffsetDateTime::now_utc() + Duration::weeks(52))
+					.into(),
+			),
+			None => response.remove_cookie(name.to_string()),
+		}
+	}
+
+	Ok(response)
+}
+
+// Download the subscriptions as a list with one subreddit per line, or as an
+// OPML outline of their feeds with ?format=opml
+pub async fn export_subscriptions(req: Request<Body>) -> Result<Response<Body>, String> {
+	let subscriptions = Preferences::new(&req).subscriptions;
+	let (body, content_type, extension) = if req.uri().query().is_some_and(|query| query.split('&').any(|pair| pair == "format=opml")) {
+		let base_url = base_url(get_setting("REDLIB_FULL_URL"), req.headers());
+		(subscriptions_opml(&subscriptions, &base_url, enable_rss()), "text/x-opml", "opml")
+	} else {
+		(subscriptions.iter().map(|sub| format!("{sub}\n")).collect(), "text/plain", "txt")
+	};
+	Response::builder()
+		.status(200)
+		.header("content-type", content_type)
+		.header("content-disposition", format!("attachment; filename=\"redlib-subscriptions.{extension}\""))
+		.body(body.into())
+		.map_err(|e| e.to_string())
+}
+
+// An OPML outline of subscriptions, linking to their RSS feeds if enabled
+fn subscriptions_opml(subscriptions: &[String], base_url: &str, rss: bool) -> String {
+	let mut opml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">\n<head><title>Redlib subscriptions</title></head>\n<body>\n");
+	for sub in subscriptions {
+		let (title, path) = match sub.strip_prefix("u_") {
+			Some(user) => (format!("u/{user}"), format!("/user/{user}")),
+			None => (format!("r/{sub}"), format!("/r/{sub}")),
+		};
+		let html_url = escape(&format!("{base_url}{path}"));
+		let title = escape(&title);
+		if rss {
+			let xml_url = escape(&format!("{base_url}{path}.rss"));
+			opml.push_str(&format!(
+				"<outline type=\"rss\" text=\"{title}\" title=\"{title}\" xmlUrl=\"{xml_url}\" htmlUrl=\"{html_url}\"/>\n"
+			));
+		} else {
+			opml.push_str(&format!("<outline type=\"link\" text=\"{title}\" url=\"{html_url}\"/>\n"));
+		}
+	}
+	opml.push_str("</body>\n</opml>\n");
+	opml
+}
+
+// Subscribe to the subreddits listed in an uploaded file, such as the
+// subscribed_subreddits.csv of a Reddit data export
+pub async fn import_subscriptions(req: Request<Body>) -> Result<Response<Body>, String> {
+	let content_type = req
+		.headers()
+		.get(header::CONTENT_TYPE)
+		.and_then(|value| value.to_str().ok())
+		.unwrap_or_default()
+		.to_string();
+	let subscriptions = setting(&req, "subscriptions");
+	let (parts, mut body) = req.into_parts();
+	let body_bytes = read_upload(&parts.headers, &mut body, MAX_SUBSCRIPTIONS_IMPORT_SIZE + MULTIPART_OVERHEAD)
+		.await
+		.unwrap_or_default();
+
+	let names = match multipart_file(&content_type, &body_bytes)
+		.filter(|file| file.len() <= MAX_SUBSCRIPTIONS_IMPORT_SIZE)
+		.and_then(|file| std::str::from_utf8(file).ok())
+	{
+		Some(file) => parse_subreddit_list(file),
+		None => Vec::new(),
+	};
+	if names.is_empty() {
+		return error(
+			Request::from_parts(parts, Body::empty()),
+			"No subreddits found, expected the subscribed_subreddits.csv of a Reddit data export or one subreddit per line",
+		)
+		.await;
+	}
+
+	let (subscriptions, imported, skipped) = merge_subscriptions(&subscriptions, &names);
+	let mut response = redirect(&format!("/settings?imported={imported}&skipped={skipped}"));
+	response.insert_cookie(
+		Cookie::build(("subscriptions", subscriptions))
+			.path("/")
+			.http_only(true)
+			.expires(OffsetDateTime::now_utc() + Duration::weeks(52))
+			.into(),
+	);
+	Ok(response)
+}
+
+// Names listed in a CSV file with a subreddit column, like Reddit's export, or
+// one per line. Invalid names are kept, to be counted as skipped.
+fn parse_subreddit_list(file: &str) -> Vec<String> {
+	file
+		.lines()
+		.filter_map(|line| {
+			let name = line.split(',').next().unwrap_or_default().trim().trim_matches('"').trim();
+			let name = name.trim_start_matches('/').trim_end_matches('/');
+			let name = match ["u/", "user/"].iter().find_map(|prefix| name.strip_prefix(prefix)) {
+				Some(user) => format!("u_{user}"),
+				None => name.strip_prefix("r/").unwrap_or(name).to_string(),
+			};
+			Some(name).filter(|name| !name.is_empty() && name != "subreddit")
+		})
+		.collect()
+}
+
+// Add names to the subscriptions, returning the new cookie value and how many
+// subreddits were added and skipped. Names that are already subscribed to
+// aren't counted, but invalid ones and those that don't fit in the cookie are.
+fn merge_subscriptions(subscriptions: &str, names: &[String]) -> (String, usize, usize) {
+	let mut merged = subscriptions.split('+').filter(|sub| !sub.is_empty()).map(String::from).collect::<Vec<_>>();
+	let mut length = subscriptions.len();
+	let (mut imported, mut skipped) = (0, 0);
+	for name in names {
+		if merged.iter().any(|sub| sub.eq_ignore_ascii_case(name)) {
+			continue;
+		}
+		if !SUBREDDIT_NAME.is_match(name) || length + name.len() + 1 > MAX_SUBSCRIPTIONS_LENGTH {
+			skipped += 1;
+			continue;
+		}
+		length += name.len() + 1;
+		merged.push(name.clone());
+		imported += 1;
+	}
+	(merged.join("+"), imported, skipped)
+}
+
+// Collect the preferences set in cookies, decoding values for readability
+fn export_prefs(cookie: impl Fn(&str) -> Option<String>) -> Map<String, Value> {
+	[PREFS.as_slice(), &LIST_COOKIES]
+		.concat()
+		.into_iter()
+		.filter_map(|name| {
+			let value = cookie(name)?;
+			let value = if ENCODED_PREFS.contains(&name) { decode_cookie_value(&value) } else { value };
+			Some((name.to_string(), Value::String(value)))
+		})
+		.collect()
+}
+
+// Validate an imported preference, returning the value to store in its cookie
+fn import_value(name: &str, value: &Value) -> Option<String> {
+	let value = match value {
+		Value::String(value) => value.clone(),
+		Value::Number(value) => value.to_string(),
+		_ => return None,
+	};
+	let valid = match name {
+		"theme" => available_themes().contains(&value),
+		"front_page" => ["default", "popular", "all"].contains(&value.as_str()),
+		"layout" => ["card", "clean", "compact", "list"].contains(&value.as_str()),
+		"comment_sort" => COMMENT_SORTS.contains(&value.as_str()),
+		"post_sort" => POST_SORTS.contains(&value.as_str()),
+		"min_score" => value.parse::<i64>().is_ok(),
+		"collapse_comments" => value == "off" || value.parse::<usize>().is_ok(),
+		"read_posts" => ["off", "dim", "hide"].contains(&value.as_str()),
+		"posts_per_page" => value.parse::<u32>().is_ok_and(|limit| POSTS_PER_PAGE.contains(&limit)),
+		"timezone" | "locale" => is_time_pref(&value),
+		name if TOGGLE_PREFS.contains(&name) => value == "on" || value == "off",
+		_ => true,
+	};
+	if !valid {
+		return None;
+	}
+	let value = match name {
+		"subscriptions" | "filters" => normalize_name_list(&value),
+		"subreddit_sorts" => encode_subreddit_sorts(&parse_subreddit_sorts(&value)),
+		_ => cookie_value(name, &value),
+	};
+	Some(value).filter(|value| !value.is_empty())
+}
+
+// Whether a timezone (like "Europe/Paris") or locale (like "en-GB") could be
+// valid, leaving the actual check to the browser
+fn is_time_pref(value: &str) -> bool {
+	value.len() <= 64 && value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '_' | '-' | '+'))
+}
+
+// Extract the contents of the first part of a multipart/form-data body
+fn multipart_file<'a>(content_type: &str, body: &'a [u8]) -> Option<&'a [u8]> {
+	let find = |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).position(|window| window == needle);
+	let boundary = content_type.split(';').find_map(|param| param.trim().strip_prefix("boundary="))?.trim_matches('"');
+	let delimiter = format!("--{boundary}");
+	let part = &body[find(body, delimiter.as_bytes())? + delimiter.len()..];
+	let content = &part[find(part, b"\r\n\r\n")? + 4..];
+	Some(&content[..find(content, format!("\r\n{delimiter}").as_bytes())?])
+}
+
+#[test]
+fn test_import_value() {
+	use serde_json::json;
+	assert_eq!(import_value("layout", &json!("compact")), Some("compact".to_string()));
+	assert_eq!(import_value("layout", &json!("list")), Some("list".to_string()));
+	assert_eq!(import_value("layout", &json!("fancy")), None);
+	assert_eq!(import_value("show_nsfw", &json!("on")), Some("on".to_string()));
+	assert_eq!(import_value("show_nsfw", &json!(true)), None);
+	assert_eq!(import_value("min_score", &json!(50)), Some("50".to_string()));
+	assert_eq!(import_value("min_score", &json!("lots")), None);
+	assert_eq!(import_value("subscriptions", &json!("rust+u_spez")), Some("rust+u_spez".to_string()));
+	assert_eq!(import_value("subreddit_sorts", &json!("News:new+pics:bogus")), Some("news:new".to_string()));
+	assert_eq!(import_value("keyword_filters", &json!("a;b")), Some("a%3Bb".to_string()));
+	assert_eq!(import_value("filters", &json!("")), None);
+	assert_eq!(
+		import_value("timezone", &json!("America/Argentina/Buenos_Aires")),
+		Some("America/Argentina/Buenos_Aires".to_string())
+	);
+	assert_eq!(import_value("timezone", &json!("Etc/GMT+5")), Some("Etc/GMT+5".to_string()));
+	assert_eq!(import_value("locale", &json!("de-DE")), Some("de-DE".to_string()));
+	assert_eq!(import_value("locale", &json!("\"><script>")), None);
+}
+
+#[test]
+fn test_export_prefs() {
+	let prefs = export_prefs(|name| match name {
+		"theme" => Some("dark".to_string()),
+		"keyword_filters" => Some(encode_cookie_value("spoiler\n^\\[meta\\]")),
+		_ => None,
+	});
+	assert_eq!(prefs.len(), 2);
+	assert_eq!(prefs["theme"], "dark");
+	assert_eq!(prefs["keyword_filters"], "spoiler\n^\\[meta\\]");
+	// Exported values are imported unchanged
+	assert_eq!(
+		import_value("keyword_filters", &prefs["keyword_filters"]).map(|value| decode_cookie_value(&value)),
+		Some("spoiler\n^\\[meta\\]".to_string())
+	);
+}
+
+#[test]
+fn test_multipart_file() {
+	let body =
+		b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"redlib-settings.json\"\r\nContent-Type: application/json\r\n\r\n{\"theme\": \"dark\"}\r\n--XyZ--\r\n";
+	assert_eq!(multipart_file("multipart/form-data; boundary=XyZ", body), Some(&b"{\"theme\": \"dark\"}"[..]));
+	assert_eq!(multipart_file("multipart/form-data; boundary=\"XyZ\"", body), Some(&b"{\"theme\": \"dark\"}"[..]));
+	assert_eq!(multipart_file("application/json", body), None);
+	assert_eq!(multipart_file("multipart/form-data; boundary=XyZ", b"garbage"), None);
+}
+
+#[tokio::test]
+async fn test_read_upload() {
+	let headers = |length: Option<&str>| {
+		let mut headers = header::HeaderMap::new();
+		if let Some(length) = length {
+			headers.insert(header::CONTENT_LENGTH, length.parse().unwrap());
+		}
+		headers
+	};
+	assert_eq!(read_upload(&headers(None), &mut Body::from("1234"), 4).await, Some(b"1234".to_vec()));
+	assert_eq!(read_upload(&headers(None), &mut Body::from("12345"), 4).await, None);
+	// Refused without reading the body
+	assert_eq!(read_upload(&headers(Some("1000000")), &mut Body::from("1234"), 4).await, None);
+
+	// Streamed bodies are cut off once over the limit
+	let (mut sender, mut body) = Body::channel();
+	tokio::spawn(async move { while sender.send_data(vec![0; 1024].into()).await.is_ok() {} });
+	assert_eq!(read_upload(&headers(None), &mut body, 4096).await, None);
+}
+
+#[test]
+fn test_parse_subreddit_list() {
+	let csv = "subreddit\nrust\n\"linux\"\r\nr/pics, extra\n\n/u/spez/\n";
+	assert_eq!(parse_subreddit_list(csv), ["rust", "linux", "pics", "u_spez"]);
+	assert_eq!(parse_subreddit_list("rust\nnot a subreddit"), ["rust", "not a subreddit"]);
+}
+
+#[test]
+fn test_merge_subscriptions() {
+	let names = ["Rust", "linux", "linux", "not a subreddit", "x"].map(String::from);
+	assert_eq!(merge_subscriptions("rust+pics", &names), ("rust+pics+linux".to_string(), 1, 2));
+	assert_eq!(merge_subscriptions("", &names[..2]), ("Rust+linux".to_string(), 2, 0));
+
+	// Profiles of users with the longest usernames
+	let names = ["u_abcdefghijklmnopqrst", "u_abcdefghijklmnopqrstu"].map(String::from);
+	assert_eq!(merge_subscriptions("", &names), ("u_abcdefghijklmnopqrst".to_string(), 1, 1));
+
+	// Subreddits that don't fit in the cookie are skipped
+	let names = (0..1000).map(|i| format!("subreddit{i}")).collect::<Vec<_>>();
+	let (subscriptions, imported, skipped) = merge_subscriptions("", &names);
+	assert!(subscriptions.len() <= MAX_SUBSCRIPTIONS_LENGTH);
+	assert_eq!(imported + skipped, 1000);
+	assert!(skipped > 0);
+}
+
+#[test]
+fn test_subscriptions_opml() {
+	let subscriptions = ["rust", "u_spez"].map(String::from);
+	let opml = subscriptions_opml(&subscriptions, "https://redlib.example.com", true);
+	assert!(opml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">"));
+	assert!(
+		opml.contains("<outline type=\"rss\" text=\"r/rust\" title=\"r/rust\" xmlUrl=\"https://redlib.example.com/r/rust.rss\" htmlUrl=\"https://redlib.example.com/r/rust\"/>")
+	);
+	assert!(opml.contains("xmlUrl=\"https://redlib.example.com/user/spez.rss\""));
+	assert!(opml.ends_with("</body>\n</opml>\n"));
+
+	// Without RSS feeds, only the pages are linked
+	let opml = subscriptions_opml(&subscriptions[..1], "https://redlib.example.com", false);
+	assert!(opml.contains("<outline type=\"link\" text=\"r/rust\" url=\"https://redlib.example.com/r/rust\"/>"));
+	assert!(!opml.contains("xmlUrl"));
+}
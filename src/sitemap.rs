@@ -0,0 +1,85 @@
+// Sitemap for search engines, enabled by REDLIB_ENABLE_SITEMAP. It lists the
+// instance's default subscriptions and their top posts of the week, leaving
+// out anything NSFW.
+
+use cached::proc_macro::cached;
+use hyper::{Body, Request, Response};
+
+use crate::config::get_setting;
+use crate::utils::{base_url, error, escape, Post, SUBREDDIT_POLICY};
+
+// Bounds on the size of the sitemap
+const MAX_SUBREDDITS: usize = 20;
+const MAX_POSTS: usize = 100;
+
+pub async fn sitemap(req: Request<Body>) -> Result<Response<Body>, String> {
+	if get_setting("REDLIB_ENABLE_SITEMAP").as_deref() != Some("on") || get_setting("REDLIB_ROBOTS_DISABLE_INDEXING").as_deref() == Some("on") {
+		return error(req, "Sitemap is disabled on this instance.").await;
+	}
+	let body = sitemap_xml(base_url(get_setting("REDLIB_FULL_URL"), req.headers())).await?;
+	Response::builder()
+		.status(200)
+		.header("content-type", "application/xml")
+		.header("cache-control", "public, max-age=3600")
+		.body(body.into())
+		.map_err(|e| e.to_string())
+}
+
+// Build the sitemap, which is cached for an hour as it's costly to generate
+// and crawlers don't need it fresher
+#[cached(size = 4, time = 3600, result = true)]
+async fn sitemap_xml(base_url: String) -> Result<String, String> {
+	let subs = sitemap_subreddits(&get_setting("REDLIB_DEFAULT_SUBSCRIPTIONS").unwrap_or_default());
+	let permalinks = if subs.is_empty() {
+		Vec::new()
+	} else {
+		let path = format!("/r/{}/top.json?t=week&limit={MAX_POSTS}&raw_json=1", subs.join("+"));
+		let (mut posts, _) = Post::fetch(&path, false).await?;
+		SUBREDDIT_POLICY.apply(&mut posts);
+		posts.into_iter().filter(|post| !post.nsfw && !post.flags.nsfw).map(|post| post.permalink).collect()
+	};
+	let paths = std::iter::once("/".to_string()).chain(subs.iter().map(|sub| format!("/r/{sub}"))).chain(permalinks);
+	Ok(render(&base_url, paths))
+}
+
+// The subreddits of the `+`-delimited default subscriptions that may be
+// viewed on this instance, leaving out user profiles
+fn sitemap_subreddits(subscriptions: &str) -> Vec<String> {
+	subscriptions
+		.split('+')
+		.map(str::trim)
+		.filter(|sub| !sub.is_empty() && !sub.starts_with("u_") && SUBREDDIT_POLICY.allows(sub))
+		.take(MAX_SUBREDDITS)
+		.map(String::from)
+		.collect()
+}
+
+fn render(base_url: &str, paths: impl Iterator<Item = String>) -> String {
+	let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+	for path in paths {
+		xml.push_str(&format!("<url><loc>{}</loc></url>\n", escape(&format!("{base_url}{path}"))));
+	}
+	xml.push_str("</urlset>\n");
+	xml
+}
+
+#[test]
+fn test_sitemap_subreddits() {
+	assert_eq!(sitemap_subreddits("rust+ u_spez +linux++"), ["rust", "linux"]);
+	assert_eq!(sitemap_subreddits(""), Vec::<String>::new());
+	assert_eq!(sitemap_subreddits(&vec!["sub"; 50].join("+")).len(), MAX_SUBREDDITS);
+}
+
+#[test]
+fn test_render() {
+	let paths = ["/", "/r/rust", "/r/rust/comments/abc/q&a/"].into_iter().map(String::from);
+	assert_eq!(
+		render("https://example.com", paths),
+		"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+		<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n\
+		<url><loc>https://example.com/</loc></url>\n\
+		<url><loc>https://example.com/r/rust</loc></url>\n\
+		<url><loc>https://example.com/r/rust/comments/abc/q&amp;a/</loc></url>\n\
+		</urlset>\n"
+	);
+}
@@ -1,15 +1,24 @@
-use crate::{config, utils};
+use crate::config;
 // CRATES
+use crate::feed::{upstream_query, Feed, FeedFormat, FeedItem};
 use crate::utils::{
-	catch_random, error, filter_posts, format_num, format_url, get_filters, nsfw_landing, param, redirect, rewrite_urls, setting, template, val, Post, Preferences, Subreddit,
+	catch_random, encode_subreddit_sorts, error, filter_keywords, filter_min_score, filter_posts, format_num, format_url, get_filters, get_subreddit_sorts, listing_json,
+	listing_query, min_score, nsfw_landing, param, random_permalink, redirect, rewrite_urls, setting, template, unavailable_subreddit, val, wants_json, with_canonical_link,
+	Blocklist, Post, Preferences, Subreddit, SubredditRule, SUBREDDIT_POLICY,
+};
+use crate::{
+	client::{json, json_uncached, restriction},
+	server::ResponseExt,
+	RequestExt,
 };
-use crate::{client::json, server::ResponseExt, RequestExt};
 use askama::Template;
 use cookie::Cookie;
 use hyper::{Body, Request, Response};
 
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde_json::Value;
+use std::collections::HashSet;
 use time::{Duration, OffsetDateTime};
 
 // STRUCTS
@@ -28,6 +37,8 @@ struct SubredditTemplate {
 	/// Whether all fetched posts are filtered (to differentiate between no posts fetched in the first place,
 	/// and all fetched posts being filtered).
 	all_posts_filtered: bool,
+	/// Number of fetched posts hidden by the user's keyword filters and block lists
+	num_posts_filtered: u64,
 	/// Whether all posts were hidden because they are NSFW (and user has disabled show NSFW)
 	all_posts_hidden_nsfw: bool,
 	no_posts: bool,
@@ -39,6 +50,10 @@ struct WikiTemplate {
 	sub: String,
 	wiki: String,
 	page: String,
+	/// Who last revised the page, if known
+	revision_by: String,
+	/// When the page was last revised, relative and in full
+	revision_time: (String, String),
 	prefs: Preferences,
 	url: String,
 }
@@ -49,12 +64,24 @@ struct WallTemplate {
 	title: String,
 	sub: String,
 	msg: String,
+	can_continue: bool,
 	prefs: Preferences,
 	url: String,
 }
 
 static GEO_FILTER_MATCH: Lazy<Regex> = Lazy::new(|| Regex::new(r"geo_filter=(?<region>\w+)").unwrap());
 
+// Most subreddits a multireddit path can combine, keeping the URL sent to
+// Reddit well within its length limits
+const MAX_MULTIREDDIT_SUBS: usize = 100;
+
+/// The subreddits of a `+`-joined multireddit path, without empty or repeated
+/// (case-insensitively) names.
+fn multireddit_subs(sub: &str) -> Vec<&str> {
+	let mut seen = HashSet::new();
+	sub.split('+').filter(|s| !s.is_empty() && seen.insert(s.to_lowercase())).collect()
+}
+
 // SERVICES
 pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 	// Build Reddit API path
@@ -62,33 +89,60 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 	let query = req.uri().query().unwrap_or_default().to_string();
 	let subscribed = setting(&req, "subscriptions");
 	let front_page = setting(&req, "front_page");
-	let post_sort = req.cookie("post_sort").map_or_else(|| "hot".to_string(), |c| c.value().to_string());
-	let sort = req.param("sort").unwrap_or_else(|| req.param("id").unwrap_or(post_sort));
 
 	let sub_name = req.param("sub").unwrap_or(if front_page == "default" || front_page.is_empty() {
 		if subscribed.is_empty() {
-			"popular".to_string()
+			SUBREDDIT_POLICY.front_page().unwrap_or_else(|| "popular".to_string())
 		} else {
 			subscribed.clone()
 		}
 	} else {
 		front_page.clone()
 	});
+
+	// Without an explicit sort, prefer the subreddit's own default over the global one
+	let post_sort = get_subreddit_sorts(&req)
+		.remove(&sub_name.to_lowercase())
+		.or_else(|| req.cookie("post_sort").map(|c| c.value().to_string()))
+		.unwrap_or_else(|| "hot".to_string());
+	let sort = req.param("sort").unwrap_or_else(|| req.param("id").unwrap_or(post_sort));
 	let quarantined = can_access_quarantine(&req, &sub_name) || root;
 
 	// Handle random subreddits
-	if let Ok(random) = catch_random(&sub_name, "").await {
-		return Ok(random);
+	match catch_random(&req, &sub_name, "").await {
+		Ok(Some(random)) => return Ok(random),
+		Ok(None) => {}
+		Err(msg) => return error(req, &msg).await,
 	}
 
 	if req.param("sub").is_some() && sub_name.starts_with("u_") {
 		return Ok(redirect(&["/user/", &sub_name[2..]].concat()));
 	}
 
+	if !SUBREDDIT_POLICY.allows(&sub_name) {
+		return error(req, &unavailable_subreddit(&sub_name)).await;
+	}
+
+	// Tidy up multireddits like /r/rust++golang+Rust, keeping the sort and query
+	if req.param("sub").is_some() && sub_name.contains('+') {
+		let subs = multireddit_subs(&sub_name);
+		// The subscription feed is exempt, as it isn't typed in by hand
+		if subs.len() > MAX_MULTIREDDIT_SUBS && sub_name != subscribed {
+			return error(req, &format!("Multireddits can combine at most {MAX_MULTIREDDIT_SUBS} subreddits")).await;
+		}
+		let canonical = subs.join("+");
+		let requested = format!("/r/{sub_name}");
+		let path = req.uri().path_and_query().map_or("", |val| val.as_str());
+		if !canonical.is_empty() && canonical != sub_name && path.starts_with(&requested) {
+			return Ok(redirect(&path.replacen(&requested, &format!("/r/{canonical}"), 1)));
+		}
+	}
+
 	// Request subreddit metadata
 	let sub = if !sub_name.contains('+') && sub_name != subscribed && sub_name != "popular" && sub_name != "all" {
-		// Regular subreddit
-		subreddit(&sub_name, quarantined).await.unwrap_or_default()
+		// Regular subreddit, fetching its rules alongside
+		let (sub, rules) = tokio::join!(subreddit(&sub_name, quarantined), rules(&sub_name, quarantined));
+		Subreddit { rules, ..sub.unwrap_or_default() }
 	} else if sub_name == subscribed {
 		// Subscription feed
 		if req.uri().path().starts_with("/r/") {
@@ -120,13 +174,16 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 		params.push_str(&format!("&geo_filter={geo_filter}"));
 	}
 
-	let path = format!("/r/{}/{sort}.json?{}{params}", sub_name.replace('+', "%2B"), req.uri().query().unwrap_or_default());
+	let path = format!("/r/{}/{sort}.json?{}{params}", sub_name.replace('+', "%2B"), listing_query(&req));
 	let url = String::from(req.uri().path_and_query().map_or("", |val| val.as_str()));
 	let redirect_url = url[1..].replace('?', "%3F").replace('&', "%26").replace('+', "%2B");
 	let filters = get_filters(&req);
 
 	// If all requested subs are filtered, we don't need to fetch posts.
 	if sub_name.split('+').all(|s| filters.contains(s)) {
+		if wants_json(&req) {
+			return Ok(listing_json(&[], ""));
+		}
 		Ok(template(&SubredditTemplate {
 			sub,
 			posts: Vec::new(),
@@ -137,54 +194,102 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 			redirect_url,
 			is_filtered: true,
 			all_posts_filtered: false,
+			num_posts_filtered: 0,
 			all_posts_hidden_nsfw: false,
 			no_posts: false,
 		}))
 	} else {
 		match Post::fetch(&path, quarantined).await {
 			Ok((mut posts, after)) => {
+				// Subreddits are only blocked in feeds mixing several of them
+				let aggregate = sub_name.contains('+') || sub_name == "popular" || sub_name == "all" || sub_name == subscribed;
+				let num_posts_filtered = SUBREDDIT_POLICY.apply(&mut posts)
+					+ filter_keywords(&req, &mut posts)
+					+ Blocklist::from_request(&req).apply(&mut posts, aggregate)
+					+ filter_min_score(&mut posts, min_score(&req));
 				let (_, all_posts_filtered) = filter_posts(&mut posts, &filters);
+				let all_posts_filtered = all_posts_filtered || (num_posts_filtered > 0 && posts.is_empty());
 				let no_posts = posts.is_empty();
 				let all_posts_hidden_nsfw = !no_posts && (posts.iter().all(|p| p.flags.nsfw) && setting(&req, "show_nsfw") != "on");
 				if sort == "new" {
-					posts.sort_by(|a, b| b.created_ts.cmp(&a.created_ts));
-					posts.sort_by(|a, b| b.flags.stickied.cmp(&a.flags.stickied));
+					posts.sort_by_key(|p| std::cmp::Reverse(p.created_ts));
+					posts.sort_by_key(|p| std::cmp::Reverse(p.flags.stickied));
+				}
+				if wants_json(&req) {
+					return Ok(listing_json(&posts, &after));
+				}
+				Ok(with_canonical_link(
+					template(&SubredditTemplate {
+						sub,
+						posts,
+						sort: (sort, param(&path, "t").unwrap_or_default()),
+						ends: (param(&path, "after").unwrap_or_default(), after),
+						prefs: Preferences::new(&req),
+						url,
+						redirect_url,
+						is_filtered: false,
+						all_posts_filtered,
+						num_posts_filtered,
+						all_posts_hidden_nsfw,
+						no_posts,
+					}),
+					req.uri().path(),
+				))
+			}
+			Err(msg) => {
+				if restriction(&msg).is_some() {
+					Ok(restricted(&req, sub_name, &msg))
+				} else {
+					error(req, &msg).await
 				}
-				Ok(template(&SubredditTemplate {
-					sub,
-					posts,
-					sort: (sort, param(&path, "t").unwrap_or_default()),
-					ends: (param(&path, "after").unwrap_or_default(), after),
-					prefs: Preferences::new(&req),
-					url,
-					redirect_url,
-					is_filtered: false,
-					all_posts_filtered,
-					all_posts_hidden_nsfw,
-					no_posts,
-				}))
 			}
-			Err(msg) => match msg.as_str() {
-				"quarantined" | "gated" => Ok(quarantine(&req, sub_name, &msg)),
-				"private" => error(req, &format!("r/{sub_name} is a private community")).await,
-				"banned" => error(req, &format!("r/{sub_name} has been banned from Reddit")).await,
-				_ => error(req, &msg).await,
-			},
 		}
 	}
 }
 
-pub fn quarantine(req: &Request<Body>, sub: String, restriction: &str) -> Response<Body> {
+/// Page explaining why a subreddit can't be viewed, from an error of
+/// [`json`] that [`restriction`] recognises. Quarantined and gated
+/// subreddits can still be viewed after opting in.
+pub fn restricted(req: &Request<Body>, sub: String, msg: &str) -> Response<Body> {
+	let (kind, reason) = restriction(msg).unwrap_or((msg, ""));
+	let name = if sub.is_empty() { "This subreddit".to_string() } else { format!("r/{sub}") };
+	// Reddit answers 404 for banned subreddits, as they are gone for good
+	let (status, title, explanation) = match kind {
+		"quarantined" => (
+			403,
+			format!("{name} is quarantined"),
+			"Reddit has quarantined it, as it may contain shocking or highly offensive content.",
+		),
+		"gated" => (
+			403,
+			format!("{name} is gated"),
+			"Reddit asks you to confirm that you want to view it, as it may contain sensitive content.",
+		),
+		"private" => (403, format!("{name} is private"), "Only users approved by its moderators can view it."),
+		_ => (404, format!("{name} has been banned from Reddit"), "It is no longer available."),
+	};
+	let msg = if reason.is_empty() { explanation.to_string() } else { reason.to_string() };
+
+	if wants_json(req) {
+		let body = serde_json::json!({ "error": title, "reason": msg });
+		return Response::builder()
+			.status(status)
+			.header("content-type", "application/json")
+			.body(body.to_string().into())
+			.unwrap_or_default();
+	}
+
 	let wall = WallTemplate {
-		title: format!("r/{sub} is {restriction}"),
-		msg: "Please click the button below to continue to this subreddit.".to_string(),
+		title,
+		msg,
+		can_continue: matches!(kind, "quarantined" | "gated") && !sub.is_empty(),
 		url: req.uri().to_string(),
 		sub,
 		prefs: Preferences::new(req),
 	};
 
 	Response::builder()
-		.status(403)
+		.status(status)
 		.header("content-type", "text/html")
 		.body(wall.render().unwrap_or_default().into())
 		.unwrap_or_default()
@@ -227,6 +332,7 @@ pub async fn subscriptions_filters(req: Request<Body>) -> Result<Response<Body>,
 	let preferences = Preferences::new(&req);
 	let mut sub_list = preferences.subscriptions;
 	let mut filters = preferences.filters;
+	let mut sorts = preferences.subreddit_sorts;
 
 	// Retrieve list of posts for these subreddits to extract display names
 
@@ -267,6 +373,11 @@ pub async fn subscriptions_filters(req: Request<Body>) -> Result<Response<Body>,
 			.unwrap_or(part)
 		};
 
+		// Forget the default sort of subreddits that are no longer subscribed to
+		if action.contains(&"unsubscribe".to_string()) || action.contains(&"filter".to_string()) {
+			sorts.remove(&part.to_lowercase());
+		}
+
 		// Modify sub list based on action
 		if action.contains(&"subscribe".to_string()) && !sub_list.contains(&part.to_owned()) {
 			// Add each sub name to the subscribed list
@@ -302,22 +413,24 @@ pub async fn subscriptions_filters(req: Request<Body>) -> Result<Response<Body>,
 	let mut response = redirect(&path);
 
 	// Delete cookie if empty, else set
-	if sub_list.is_empty() {
-		response.remove_cookie("subscriptions".to_string());
-	} else {
-		response.insert_cookie(
-			Cookie::build(("subscriptions", sub_list.join("+")))
-				.path("/")
-				.http_only(true)
-				.expires(OffsetDateTime::now_utc() + Duration::weeks(52))
-				.into(),
-		);
+	for (name, list) in [("subscriptions", &sub_list), ("filters", &filters)] {
+		let has_default = config::get_setting(&format!("REDLIB_DEFAULT_{}", name.to_uppercase())).is_some();
+		match list_cookie_value(list, has_default) {
+			Some(value) => response.insert_cookie(
+				Cookie::build((name, value))
+					.path("/")
+					.http_only(true)
+					.expires(OffsetDateTime::now_utc() + Duration::weeks(52))
+					.into(),
+			),
+			None => response.remove_cookie(name.to_string()),
+		}
 	}
-	if filters.is_empty() {
-		response.remove_cookie("filters".to_string());
+	if sorts.is_empty() {
+		response.remove_cookie("subreddit_sorts".to_string());
 	} else {
 		response.insert_cookie(
-			Cookie::build(("filters", filters.join("+")))
+			Cookie::build(("subreddit_sorts", encode_subreddit_sorts(&sorts)))
 				.path("/")
 				.http_only(true)
 				.expires(OffsetDateTime::now_utc() + Duration::weeks(52))
@@ -328,29 +441,98 @@ pub async fn subscriptions_filters(req: Request<Body>) -> Result<Response<Body>,
 	Ok(response)
 }
 
+/// The value to store a subscription or filter list as, or `None` to delete
+/// the cookie. An emptied list is kept as an empty cookie if the instance has
+/// a default for it, which would otherwise take its place again.
+fn list_cookie_value(list: &[String], has_default: bool) -> Option<String> {
+	if !list.is_empty() || has_default {
+		Some(list.join("+"))
+	} else {
+		None
+	}
+}
+
 pub async fn wiki(req: Request<Body>) -> Result<Response<Body>, String> {
 	let sub = req.param("sub").unwrap_or_else(|| "reddit.com".to_string());
 	let quarantined = can_access_quarantine(&req, &sub);
 	// Handle random subreddits
-	if let Ok(random) = catch_random(&sub, "/wiki").await {
-		return Ok(random);
+	match catch_random(&req, &sub, "/wiki").await {
+		Ok(Some(random)) => return Ok(random),
+		Ok(None) => {}
+		Err(msg) => return error(req, &msg).await,
+	}
+	if !SUBREDDIT_POLICY.allows(&sub) {
+		return error(req, &unavailable_subreddit(&sub)).await;
 	}
 
-	let page = req.param("page").unwrap_or_else(|| "index".to_string());
+	// Pages can be nested, e.g. /r/rust/wiki/faq/tooling
+	let page = req
+		.param("page")
+		.map(|page| page.trim_matches('/').to_string())
+		.filter(|page| !page.is_empty())
+		.unwrap_or_else(|| "index".to_string());
 	let path: String = format!("/r/{sub}/wiki/{page}.json?raw_json=1");
 	let url = req.uri().to_string();
 
 	match json(path, quarantined).await {
-		Ok(response) => Ok(template(&WikiTemplate {
-			sub,
-			wiki: rewrite_urls(response["data"]["content_html"].as_str().unwrap_or("<h3>Wiki not found</h3>")),
-			page,
-			prefs: Preferences::new(&req),
-			url,
-		})),
+		Ok(response) => {
+			let data = &response["data"];
+			Ok(template(&WikiTemplate {
+				wiki: rewrite_urls(data["content_html"].as_str().unwrap_or("<h3>Wiki not found</h3>")),
+				revision_by: data["revision_by"]["data"]["name"].as_str().unwrap_or_default().to_string(),
+				revision_time: data["revision_date"].as_f64().map(crate::utils::time).unwrap_or_default(),
+				sub,
+				page,
+				prefs: Preferences::new(&req),
+				url,
+			}))
+		}
 		Err(msg) => {
-			if msg == "quarantined" || msg == "gated" {
-				Ok(quarantine(&req, sub, &msg))
+			if restriction(&msg).is_some() {
+				Ok(restricted(&req, sub, &msg))
+			} else {
+				let msg = wiki_error(&msg, &sub, &page);
+				error(req, &msg).await
+			}
+		}
+	}
+}
+
+/// A readable explanation of why a wiki page couldn't be fetched, based on the
+/// reason Reddit gave.
+fn wiki_error(msg: &str, sub: &str, page: &str) -> String {
+	if msg.contains("MAY_NOT_VIEW") {
+		format!("The moderators of r/{sub} only allow approved users to view this wiki page")
+	} else if msg.contains("WIKI_DISABLED") {
+		format!("r/{sub} has disabled its wiki")
+	} else if msg.contains("PAGE_NOT_CREATED") {
+		format!("r/{sub} has no wiki page named \"{page}\"")
+	} else {
+		msg.to_string()
+	}
+}
+
+// Redirect to a random post of a subreddit
+pub async fn random_post(req: Request<Body>) -> Result<Response<Body>, String> {
+	let sub = req.param("sub").unwrap_or_default();
+	match catch_random(&req, &sub, "/random").await {
+		Ok(Some(random)) => return Ok(random),
+		Ok(None) => {}
+		Err(msg) => return error(req, &msg).await,
+	}
+	if !SUBREDDIT_POLICY.allows(&sub) {
+		return error(req, &unavailable_subreddit(&sub)).await;
+	}
+
+	let quarantined = can_access_quarantine(&req, &sub);
+	match json_uncached(format!("/r/{sub}/random.json?raw_json=1"), quarantined).await {
+		Ok(response) => match random_permalink(&response) {
+			Some(permalink) => Ok(redirect(&permalink)),
+			None => error(req, &format!("Couldn't find a random post in r/{sub}")).await,
+		},
+		Err(msg) => {
+			if restriction(&msg).is_some() {
+				Ok(restricted(&req, sub, &msg))
 			} else {
 				error(req, &msg).await
 			}
@@ -363,8 +545,13 @@ pub async fn sidebar(req: Request<Body>) -> Result<Response<Body>, String> {
 	let quarantined = can_access_quarantine(&req, &sub);
 
 	// Handle random subreddits
-	if let Ok(random) = catch_random(&sub, "/about/sidebar").await {
-		return Ok(random);
+	match catch_random(&req, &sub, "/about/sidebar").await {
+		Ok(Some(random)) => return Ok(random),
+		Ok(None) => {}
+		Err(msg) => return error(req, &msg).await,
+	}
+	if !SUBREDDIT_POLICY.allows(&sub) {
+		return error(req, &unavailable_subreddit(&sub)).await;
 	}
 
 	// Build the Reddit JSON API url
@@ -383,12 +570,14 @@ pub async fn sidebar(req: Request<Body>) -> Result<Response<Body>, String> {
 			// ),
 			sub,
 			page: "Sidebar".to_string(),
+			revision_by: String::new(),
+			revision_time: (String::new(), String::new()),
 			prefs: Preferences::new(&req),
 			url,
 		})),
 		Err(msg) => {
-			if msg == "quarantined" || msg == "gated" {
-				Ok(quarantine(&req, sub, &msg))
+			if restriction(&msg).is_some() {
+				Ok(restricted(&req, sub, &msg))
 			} else {
 				error(req, &msg).await
 			}
@@ -457,62 +646,108 @@ async fn subreddit(sub: &str, quarantined: bool) -> Result<Subreddit, String> {
 		active: format_num(active),
 		wiki: res["data"]["wiki_enabled"].as_bool().unwrap_or_default(),
 		nsfw: res["data"]["over18"].as_bool().unwrap_or_default(),
+		// Fetched separately where they are shown
+		rules: Vec::new(),
 	})
 }
 
-pub async fn rss(req: Request<Body>) -> Result<Response<Body>, String> {
-	if config::get_setting("REDLIB_ENABLE_RSS").is_none() {
-		return Ok(error(req, "RSS is disabled on this instance.").await.unwrap_or_default());
-	}
+// Fetch the rules of a subreddit, which are left out if they can't be fetched
+pub async fn rules(sub: &str, quarantined: bool) -> Vec<SubredditRule> {
+	let path: String = format!("/r/{sub}/about/rules.json?raw_json=1");
+	json(path, quarantined).await.map(|res| parse_rules(&res)).unwrap_or_default()
+}
 
-	use hyper::header::CONTENT_TYPE;
-	use rss::{ChannelBuilder, Item};
+fn parse_rules(res: &Value) -> Vec<SubredditRule> {
+	let Some(rules) = res["rules"].as_array() else {
+		return Vec::new();
+	};
+	rules
+		.iter()
+		.map(|rule| SubredditRule {
+			name: rule["short_name"].as_str().unwrap_or_default().to_string(),
+			description: rewrite_urls(rule["description_html"].as_str().unwrap_or_default()),
+		})
+		.filter(|rule| !rule.name.is_empty())
+		.collect()
+}
 
+/// Build the feed served by [`crate::feed::serve`].
+pub async fn feed(req: Request<Body>, format: FeedFormat) -> Result<Response<Body>, String> {
 	// Get subreddit
 	let sub = req.param("sub").unwrap_or_default();
+	if !SUBREDDIT_POLICY.allows(&sub) {
+		return error(req, &unavailable_subreddit(&sub)).await;
+	}
 	let post_sort = req.cookie("post_sort").map_or_else(|| "hot".to_string(), |c| c.value().to_string());
 	let sort = req.param("sort").unwrap_or_else(|| req.param("id").unwrap_or(post_sort));
 
 	// Get path
-	let path = format!("/r/{sub}/{sort}.json?{}", req.uri().query().unwrap_or_default());
+	let path = format!("/r/{sub}/{sort}.json?{}", upstream_query(req.uri().query().unwrap_or_default()));
 
 	// Get subreddit data
 	let subreddit = subreddit(&sub, false).await?;
 
 	// Get posts
-	let (posts, _) = Post::fetch(&path, false).await?;
-
-	// Build the RSS feed
-	let channel = ChannelBuilder::default()
-		.title(&subreddit.title)
-		.description(&subreddit.description)
-		.items(
-			posts
-				.into_iter()
-				.map(|post| Item {
-					title: Some(post.title.to_string()),
-					link: Some(utils::get_post_url(&post)),
-					author: Some(post.author.name),
-					content: Some(rewrite_urls(&post.body)),
-					description: Some(format!(
-						"<a href='{}{}'>Comments</a>",
-						config::get_setting("REDLIB_FULL_URL").unwrap_or_default(),
-						post.permalink
-					)),
-					..Default::default()
-				})
-				.collect::<Vec<_>>(),
-		)
-		.build();
-
-	// Serialize the feed to RSS
-	let body = channel.to_string().into_bytes();
-
-	// Create the HTTP response
-	let mut res = Response::new(Body::from(body));
-	res.headers_mut().insert(CONTENT_TYPE, hyper::header::HeaderValue::from_static("application/rss+xml"));
-
-	Ok(res)
+	let (mut posts, _) = Post::fetch(&path, false).await?;
+	SUBREDDIT_POLICY.apply(&mut posts);
+
+	let feed = Feed {
+		title: subreddit.title,
+		description: subreddit.description,
+		path: format!("/r/{sub}"),
+		self_path: req.uri().path().to_string(),
+		items: posts
+			.into_iter()
+			.map(|post| {
+				let comments = format!("<a href='{}{}'>Comments</a>", config::get_setting("REDLIB_FULL_URL").unwrap_or_default(), post.permalink);
+				FeedItem {
+					summary: Some(comments),
+					..FeedItem::from_post(post)
+				}
+			})
+			.collect(),
+	};
+
+	Ok(feed.response(format))
+}
+
+#[test]
+fn test_parse_rules() {
+	let res = serde_json::json!({
+		"rules": [
+			{ "short_name": "Be civil", "description_html": "<p>See <a href=\"https://www.reddit.com/r/rust/wiki/rules\">the wiki</a></p>" },
+			{ "short_name": "No spam", "description_html": null },
+			{ "description_html": "<p>Unnamed</p>" }
+		]
+	});
+	let rules = parse_rules(&res);
+	assert_eq!(rules.len(), 2);
+	assert_eq!(rules[0].name, "Be civil");
+	assert_eq!(rules[0].description, "<p>See <a href=\"/r/rust/wiki/rules\">the wiki</a></p>");
+	assert_eq!(rules[1].description, "");
+	assert!(parse_rules(&serde_json::json!({})).is_empty());
+}
+
+#[test]
+fn test_wiki_error() {
+	let forbidden = "Reddit error 403 \"\\\"MAY_NOT_VIEW\\\"\": \"Forbidden\" | /r/rust/wiki/mods.json";
+	assert_eq!(
+		wiki_error(forbidden, "rust", "mods"),
+		"The moderators of r/rust only allow approved users to view this wiki page"
+	);
+	assert_eq!(
+		wiki_error("Reddit error 404 \"PAGE_NOT_CREATED\"", "rust", "faq/tooling"),
+		"r/rust has no wiki page named \"faq/tooling\""
+	);
+	assert_eq!(wiki_error("Failed to parse page JSON data", "rust", "index"), "Failed to parse page JSON data");
+}
+
+#[test]
+fn test_multireddit_subs() {
+	assert_eq!(multireddit_subs("rust+golang+zig"), ["rust", "golang", "zig"]);
+	assert_eq!(multireddit_subs("rust++golang+"), ["rust", "golang"]);
+	assert_eq!(multireddit_subs("rust+golang+Rust"), ["rust", "golang"]);
+	assert!(multireddit_subs("+").is_empty());
 }
 
 #[tokio::test(flavor = "multi_thread")]
@@ -528,3 +763,44 @@ async fn test_gated_and_quarantined() {
 	let gated = subreddit("drugs", true).await;
 	assert!(gated.is_ok());
 }
+
+#[test]
+fn test_list_cookie_value() {
+	let subs = vec!["rust".to_string(), "linux".to_string()];
+	assert_eq!(list_cookie_value(&subs, false), Some("rust+linux".to_string()));
+	assert_eq!(list_cookie_value(&subs, true), Some("rust+linux".to_string()));
+	assert_eq!(list_cookie_value(&[], false), None);
+	// Unsubscribing from every default subreddit must not bring them back
+	assert_eq!(list_cookie_value(&[], true), Some(String::new()));
+}
+
+#[tokio::test]
+async fn test_restricted() {
+	async fn page(sub: &str, msg: &str) -> (u16, String) {
+		let req = Request::builder().uri(format!("/r/{sub}")).body(Body::empty()).unwrap();
+		let res = restricted(&req, sub.to_string(), msg);
+		let status = res.status().as_u16();
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		(status, String::from_utf8_lossy(&body).into_owned())
+	}
+
+	let (status, body) = page("rust", "private").await;
+	assert_eq!(status, 403);
+	assert!(body.contains("r/rust is private"));
+	assert!(!body.contains("Continue"));
+
+	let (status, body) = page("aaa", "banned").await;
+	assert_eq!(status, 404);
+	assert!(body.contains("r/aaa has been banned from Reddit"));
+
+	let (status, body) = page("edgy", "quarantined: Contains graphic content").await;
+	assert_eq!(status, 403);
+	assert!(body.contains("r/edgy is quarantined"));
+	assert!(body.contains("Contains graphic content"));
+	assert!(body.contains("action=\"/r/edgy?redir=/r/edgy\""));
+
+	// Nothing to opt in to without a subreddit, e.g. on a post at /comments/
+	let (_, body) = page("", "gated").await;
+	assert!(body.contains("This subreddit is gated"));
+	assert!(!body.contains("Continue"));
+}
@@ -0,0 +1,154 @@
+// Themes added by the operator in REDLIB_THEMES_DIR, offered alongside the
+// built-in ones in static/themes/.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{read_dir, read_to_string};
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+use log::{info, warn};
+use once_cell::sync::Lazy;
+
+use crate::config::get_setting;
+use crate::utils::ThemeAssets;
+
+/// Variables every theme must define, as the stylesheet relies on them.
+pub const THEME_VARIABLES: [&str; 11] = [
+	"--accent",
+	"--green",
+	"--text",
+	"--foreground",
+	"--background",
+	"--outside",
+	"--post",
+	"--panel-border",
+	"--highlighted",
+	"--visited",
+	"--shadow",
+];
+
+static CUSTOM_THEMES: Lazy<Vec<CustomTheme>> = Lazy::new(|| match get_setting("REDLIB_THEMES_DIR") {
+	Some(dir) => load(Path::new(&dir)),
+	None => Vec::new(),
+});
+
+pub struct CustomTheme {
+	pub name: String,
+	pub css: String,
+}
+
+/// Themes read from `REDLIB_THEMES_DIR` at startup, sorted by name.
+pub fn custom_themes() -> &'static [CustomTheme] {
+	&CUSTOM_THEMES
+}
+
+/// Version of style.css, used to bust caches of it. Changes with the custom
+/// themes as well as with Redlib, since the stylesheet includes them.
+pub fn style_version() -> String {
+	static VERSION: Lazy<String> = Lazy::new(|| {
+		if CUSTOM_THEMES.is_empty() {
+			return env!("CARGO_PKG_VERSION").to_string();
+		}
+		let mut hasher = DefaultHasher::new();
+		for theme in CUSTOM_THEMES.iter() {
+			theme.name.hash(&mut hasher);
+			theme.css.hash(&mut hasher);
+		}
+		format!("{}-{:x}", env!("CARGO_PKG_VERSION"), hasher.finish())
+	});
+	VERSION.clone()
+}
+
+/// Read every `<name>.css` file in `dir`, skipping those that aren't usable
+/// themes.
+fn load(dir: &Path) -> Vec<CustomTheme> {
+	let entries = match read_dir(dir) {
+		Ok(entries) => entries,
+		Err(e) => {
+			warn!("Failed to read themes from {}: {e}", dir.display());
+			return Vec::new();
+		}
+	};
+	let mut paths = entries.filter_map(Result::ok).map(|entry| entry.path()).collect::<Vec<_>>();
+	paths.sort();
+
+	let mut themes = Vec::new();
+	for path in paths {
+		let Some(name) = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_suffix(".css")) else {
+			continue;
+		};
+		let css = match read_to_string(&path) {
+			Ok(css) => css,
+			Err(e) => {
+				warn!("Failed to read theme {}: {e}", path.display());
+				continue;
+			}
+		};
+		match check(name, &css) {
+			Ok(()) => themes.push(CustomTheme { name: name.to_string(), css }),
+			Err(reason) => warn!("Skipping theme {}: {reason}", path.display()),
+		}
+	}
+	if !themes.is_empty() {
+		info!("Loaded {} custom themes from {}", themes.len(), dir.display());
+	}
+	themes
+}
+
+/// Check that a custom theme can be offered, as `<name>` class defining
+/// every variable and not replacing a built-in theme.
+fn check(name: &str, css: &str) -> Result<(), String> {
+	// Names end up in class attributes and cookies
+	if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+		return Err("name must only contain letters, digits, - and _".to_string());
+	}
+	if name == "system" || ThemeAssets::iter().any(|file| file.strip_suffix(".css") == Some(name)) {
+		return Err(format!("{name} is a built-in theme"));
+	}
+	if !css.contains(&format!(".{name}")) {
+		return Err(format!("no .{name} selector"));
+	}
+	let missing = THEME_VARIABLES
+		.iter()
+		.filter(|variable| !css.contains(&format!("{variable}:")))
+		.copied()
+		.collect::<Vec<_>>();
+	if !missing.is_empty() {
+		return Err(format!("missing {}", missing.join(", ")));
+	}
+	Ok(())
+}
+
+#[test]
+fn test_check() {
+	let css = |name: &str| {
+		let variables = THEME_VARIABLES.iter().map(|variable| format!("\t{variable}: #000;\n")).collect::<String>();
+		format!(".{name} {{\n{variables}}}\n")
+	};
+	assert_eq!(check("brand", &css("brand")), Ok(()));
+	assert_eq!(check("my-brand_2", &css("my-brand_2")), Ok(()));
+	assert!(check("dark", &css("dark")).unwrap_err().contains("built-in"));
+	assert!(check("system", &css("system")).is_err());
+	assert!(check("bad name", &css("bad name")).unwrap_err().contains("name"));
+	assert!(check("\"><script>", &css("x")).is_err());
+	assert_eq!(check("brand", &css("other")), Err("no .brand selector".to_string()));
+	assert_eq!(
+		check("brand", &css("brand").replace("\t--visited: #000;\n", "").replace("\t--shadow: #000;\n", "")),
+		Err("missing --visited, --shadow".to_string())
+	);
+}
+
+#[test]
+fn test_load() {
+	let dir = std::env::temp_dir().join(format!("redlib-themes-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let variables = THEME_VARIABLES.iter().map(|variable| format!("{variable}: #000;")).collect::<String>();
+	std::fs::write(dir.join("brand.css"), format!(".brand {{ {variables} }}")).unwrap();
+	std::fs::write(dir.join("dark.css"), format!(".dark {{ {variables} }}")).unwrap();
+	std::fs::write(dir.join("incomplete.css"), ".incomplete { --accent: red; }").unwrap();
+	std::fs::write(dir.join("notes.txt"), "Not a theme").unwrap();
+
+	let themes = load(&dir);
+	std::fs::remove_dir_all(&dir).unwrap();
+	assert_eq!(themes.iter().map(|theme| theme.name.as_str()).collect::<Vec<_>>(), ["brand"]);
+}
@@ -1,10 +1,15 @@
 // CRATES
 use crate::client::json;
+use crate::feed::{upstream_query, Feed, FeedFormat, FeedItem};
 use crate::server::RequestExt;
-use crate::utils::{error, filter_posts, format_url, get_filters, nsfw_landing, param, setting, template, Post, Preferences, User};
-use crate::{config, utils};
+use crate::utils;
+use crate::utils::{
+	error, filter_posts, format_url, get_filters, listing_json, listing_query, nsfw_landing, param, setting, template, wants_json, with_canonical_link, Post, Preferences,
+	Trophy, User,
+};
 use askama::Template;
 use hyper::{Body, Request, Response};
+use serde_json::Value;
 use time::{macros::format_description, OffsetDateTime};
 
 // STRUCTS
@@ -30,15 +35,33 @@ struct UserTemplate {
 	no_posts: bool,
 }
 
+impl UserTemplate {
+	/// What to say when the listing is empty
+	fn empty_message(&self) -> String {
+		empty_message(&self.listing, &self.user.name, !self.ends.0.is_empty())
+	}
+}
+
+// CONSTANTS
+
+// Listings of a user's profile, each shown as a tab
+const LISTINGS: [&str; 3] = ["overview", "comments", "submitted"];
+
+// Moderated subreddits listed on a profile before the rest are summarised
+const MAX_MODERATED_SHOWN: usize = 25;
+
 // FUNCTIONS
 pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 	let listing = req.param("listing").unwrap_or_else(|| "overview".to_string());
+	if !LISTINGS.contains(&listing.as_str()) {
+		return error(req, &format!("Unknown profile listing \"{listing}\"")).await;
+	}
 
 	// Build the Reddit JSON API path
 	let path = format!(
 		"/user/{}/{listing}.json?{}&raw_json=1",
 		req.param("name").unwrap_or_else(|| "reddit".to_string()),
-		req.uri().query().unwrap_or_default(),
+		listing_query(&req),
 	);
 	let url = String::from(req.uri().path_and_query().map_or("", |val| val.as_str()));
 	let redirect_url = url[1..].replace('?', "%3F").replace('&', "%26");
@@ -47,8 +70,38 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 	let sort = param(&path, "sort").unwrap_or_default();
 	let username = req.param("name").unwrap_or_default();
 
+	// Trophies and moderated subreddits are only shown in the sidebar of the
+	// first page, so they aren't fetched for other pages or JSON to save
+	// requests to Reddit
+	let first_page = param(&path, "after").is_none_or(|after| after.is_empty()) && param(&path, "before").is_none_or(|before| before.is_empty());
+	let show_sidebar = first_page && !wants_json(&req) && setting(&req, "hide_sidebar_and_summary") != "on";
+	let fetch_trophies = async {
+		if show_sidebar {
+			trophies(&username).await
+		} else {
+			Vec::new()
+		}
+	};
+	let fetch_moderated = async {
+		if show_sidebar {
+			moderated(&username, setting(&req, "show_nsfw") == "on").await
+		} else {
+			(Vec::new(), 0)
+		}
+	};
+
 	// Retrieve info from user about page.
-	let user = user(&username).await.unwrap_or_default();
+	let (user, trophies, moderated) = tokio::join!(user(&username), fetch_trophies, fetch_moderated);
+	// Accounts that are gone get a page saying so. Other errors leave the
+	// details of the user out, since the listing may still load.
+	let user = match user {
+		Ok(user) => user,
+		Err(msg) => match user_error(&msg, &username) {
+			Some(msg) => return error(req, &msg).await,
+			None => User::default(),
+		},
+	};
+	let user = User { trophies, moderated, ..user };
 
 	let req_url = req.uri().to_string();
 	// Return landing page if this post if this Reddit deems this user NSFW,
@@ -60,6 +113,9 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 
 	let filters = get_filters(&req);
 	if filters.contains(&["u_", &username].concat()) {
+		if wants_json(&req) {
+			return Ok(listing_json(&[], ""));
+		}
 		Ok(template(&UserTemplate {
 			user,
 			posts: Vec::new(),
@@ -81,27 +137,65 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 				let (_, all_posts_filtered) = filter_posts(&mut posts, &filters);
 				let no_posts = posts.is_empty();
 				let all_posts_hidden_nsfw = !no_posts && (posts.iter().all(|p| p.flags.nsfw) && setting(&req, "show_nsfw") != "on");
-				Ok(template(&UserTemplate {
-					user,
-					posts,
-					sort: (sort, param(&path, "t").unwrap_or_default()),
-					ends: (param(&path, "after").unwrap_or_default(), after),
-					listing,
-					prefs: Preferences::new(&req),
-					url,
-					redirect_url,
-					is_filtered: false,
-					all_posts_filtered,
-					all_posts_hidden_nsfw,
-					no_posts,
-				}))
+				if wants_json(&req) {
+					return Ok(listing_json(&posts, &after));
+				}
+				Ok(with_canonical_link(
+					template(&UserTemplate {
+						user,
+						posts,
+						sort: (sort, param(&path, "t").unwrap_or_default()),
+						ends: (param(&path, "after").unwrap_or_default(), after),
+						listing,
+						prefs: Preferences::new(&req),
+						url,
+						redirect_url,
+						is_filtered: false,
+						all_posts_filtered,
+						all_posts_hidden_nsfw,
+						no_posts,
+					}),
+					req.uri().path(),
+				))
 			}
 			// If there is an error show error page
-			Err(msg) => error(req, &msg).await,
+			Err(msg) => {
+				let msg = user_error(&msg, &username).unwrap_or(msg);
+				error(req, &msg).await
+			}
 		}
 	}
 }
 
+/// A readable explanation for errors Reddit gives about accounts that are
+/// suspended, private or gone. Reddit answers the same for accounts that never
+/// existed, were deleted or were shadowbanned, so these can't be told apart.
+fn user_error(msg: &str, name: &str) -> Option<String> {
+	if msg == "suspended" {
+		Some(format!("u/{name} has been suspended"))
+	} else if msg.starts_with("Reddit error 404") {
+		Some(format!("u/{name} doesn't exist, or has been deleted or shadowbanned"))
+	} else if msg.starts_with("Reddit error 403") {
+		Some(format!("The profile of u/{name} is private"))
+	} else {
+		None
+	}
+}
+
+// Message for an empty listing, which past the first page means its end was reached
+fn empty_message(listing: &str, name: &str, paginated: bool) -> String {
+	let content = match listing {
+		"comments" => "comments",
+		"submitted" => "posts",
+		_ => "posts or comments",
+	};
+	if paginated {
+		format!("No more {content} from u/{name}.")
+	} else {
+		format!("u/{name} has no {content} yet.")
+	}
+}
+
 // USER
 async fn user(name: &str) -> Result<User, String> {
 	// Build the Reddit JSON API path
@@ -126,58 +220,102 @@ async fn user(name: &str) -> Result<User, String> {
 			banner: about("banner_img"),
 			description: about("public_description"),
 			nsfw: res["data"]["subreddit"]["over_18"].as_bool().unwrap_or_default(),
+			trophies: Vec::new(),
+			moderated: (Vec::new(), 0),
 		}
 	})
 }
 
-pub async fn rss(req: Request<Body>) -> Result<Response<Body>, String> {
-	if config::get_setting("REDLIB_ENABLE_RSS").is_none() {
-		return Ok(error(req, "RSS is disabled on this instance.").await.unwrap_or_default());
-	}
-	use crate::utils::rewrite_urls;
-	use hyper::header::CONTENT_TYPE;
-	use rss::{ChannelBuilder, Item};
+// Fetch the trophies of a user, which are left out if they can't be fetched
+async fn trophies(name: &str) -> Vec<Trophy> {
+	let path: String = format!("/api/v1/user/{name}/trophies.json?raw_json=1");
+	json(path, false).await.map(|res| parse_trophies(&res)).unwrap_or_default()
+}
 
+// Fetch the subreddits a user moderates, which are left out if they can't be fetched
+async fn moderated(name: &str, show_nsfw: bool) -> (Vec<String>, usize) {
+	let path: String = format!("/user/{name}/moderated_subreddits.json?raw_json=1");
+	json(path, false).await.map(|res| parse_moderated(&res, show_nsfw)).unwrap_or_default()
+}
+
+// Only public subreddits are listed, since private and quarantined ones
+// shouldn't be exposed, and NSFW ones only if the user wants to see them.
+// Profiles, which are subreddits too, are left out.
+fn parse_moderated(res: &Value, show_nsfw: bool) -> (Vec<String>, usize) {
+	let Some(subs) = res["data"].as_array() else {
+		return (Vec::new(), 0);
+	};
+	let mut names: Vec<String> = subs
+		.iter()
+		.filter(|sub| matches!(sub["subreddit_type"].as_str(), Some("public" | "restricted" | "archived")))
+		.filter(|sub| !sub["quarantine"].as_bool().unwrap_or_default() && (show_nsfw || !sub["over_18"].as_bool().unwrap_or_default()))
+		.filter_map(|sub| sub["sr"].as_str())
+		.filter(|name| !name.is_empty() && !name.starts_with("u_"))
+		.map(String::from)
+		.collect();
+	let hidden = names.len().saturating_sub(MAX_MODERATED_SHOWN);
+	names.truncate(MAX_MODERATED_SHOWN);
+	(names, hidden)
+}
+
+fn parse_trophies(res: &Value) -> Vec<Trophy> {
+	let Some(trophies) = res["data"]["trophies"].as_array() else {
+		return Vec::new();
+	};
+	trophies
+		.iter()
+		.map(|trophy| {
+			let data = &trophy["data"];
+			let granted = data["granted_at"].as_i64().and_then(|ts| OffsetDateTime::from_unix_timestamp(ts).ok());
+			Trophy {
+				name: data["name"].as_str().unwrap_or_default().to_string(),
+				description: data["description"].as_str().unwrap_or_default().to_string(),
+				icon: format_url(data["icon_70"].as_str().or_else(|| data["icon_40"].as_str()).unwrap_or_default()),
+				granted: granted.map_or(String::new(), |granted| {
+					granted.format(format_description!("[month repr:short] [day] '[year repr:last_two]")).unwrap_or_default()
+				}),
+			}
+		})
+		.filter(|trophy| !trophy.name.is_empty())
+		.collect()
+}
+
+/// Build the feed served by [`crate::feed::serve`].
+pub async fn feed(req: Request<Body>, format: FeedFormat) -> Result<Response<Body>, String> {
 	// Get user
 	let user_str = req.param("name").unwrap_or_default();
 
 	let listing = req.param("listing").unwrap_or_else(|| "overview".to_string());
 
-	// Get path
-	let path = format!("/user/{user_str}/{listing}.json?{}&raw_json=1", req.uri().query().unwrap_or_default(),);
+	// Get path, passing on sort and limit params
+	let path = format!("/user/{user_str}/{listing}.json?{}&raw_json=1", upstream_query(req.uri().query().unwrap_or_default()));
 
 	// Get user
 	let user_obj = user(&user_str).await.unwrap_or_default();
 
-	// Get posts
-	let (posts, _) = Post::fetch(&path, false).await?;
-
-	// Build the RSS feed
-	let channel = ChannelBuilder::default()
-		.title(user_str)
-		.description(user_obj.description)
-		.items(
-			posts
-				.into_iter()
-				.map(|post| Item {
-					title: Some(post.title.to_string()),
-					link: Some(utils::get_post_url(&post)),
-					author: Some(post.author.name),
-					content: Some(rewrite_urls(&post.body)),
-					..Default::default()
-				})
-				.collect::<Vec<_>>(),
-		)
-		.build();
-
-	// Serialize the feed to RSS
-	let body = channel.to_string().into_bytes();
-
-	// Create the HTTP response
-	let mut res = Response::new(Body::from(body));
-	res.headers_mut().insert(CONTENT_TYPE, hyper::header::HeaderValue::from_static("application/rss+xml"));
-
-	Ok(res)
+	// NSFW profiles have no feed on SFW-only instances
+	if user_obj.nsfw && utils::sfw_only() {
+		return error(req, "This user's profile is NSFW, which is not shown on this instance.").await;
+	}
+
+	// Get posts. Suspended and deleted users have no posts to list.
+	let mut posts = match Post::fetch(&path, false).await {
+		Ok((posts, _)) => posts,
+		Err(msg) => return error(req, &format!("Couldn't get the posts of u/{user_str}: {msg}")).await,
+	};
+	if utils::sfw_only() {
+		posts.retain(|post| !post.flags.nsfw);
+	}
+
+	let feed = Feed {
+		title: user_str.clone(),
+		description: user_obj.description,
+		path: format!("/user/{user_str}"),
+		self_path: req.uri().path().to_string(),
+		items: posts.into_iter().map(FeedItem::from_post).collect(),
+	};
+
+	Ok(feed.response(format))
 }
 
 #[tokio::test(flavor = "multi_thread")]
@@ -186,3 +324,68 @@ async fn test_fetching_user() {
 	assert!(user.is_ok());
 	assert!(user.unwrap().karma > 100);
 }
+
+#[test]
+fn test_empty_message() {
+	assert_eq!(empty_message("comments", "spez", false), "u/spez has no comments yet.");
+	assert_eq!(empty_message("submitted", "spez", false), "u/spez has no posts yet.");
+	assert_eq!(empty_message("overview", "spez", true), "No more posts or comments from u/spez.");
+}
+
+#[test]
+fn test_parse_trophies() {
+	let res = serde_json::json!({
+		"kind": "TrophyList",
+		"data": {"trophies": [
+			{"kind": "t6", "data": {"name": "Five-Year Club", "description": null, "icon_70": "https://www.redditstatic.com/awards2/5_year_club-70.png", "granted_at": 1_600_000_000}},
+			{"kind": "t6", "data": {"name": "Verified Email", "description": "Since 2015", "icon_40": "https://www.redditstatic.com/awards2/verified_email-40.png", "granted_at": null}},
+			{"kind": "t6", "data": {}}
+		]}
+	});
+	let trophies = parse_trophies(&res);
+	assert_eq!(trophies.len(), 2);
+	assert_eq!(trophies[0].name, "Five-Year Club");
+	assert_eq!(trophies[0].description, "");
+	assert_eq!(trophies[0].icon, "/static/awards2/5_year_club-70.png");
+	assert_eq!(trophies[0].granted, "Sep 13 '20");
+	assert_eq!(trophies[1].icon, "/static/awards2/verified_email-40.png");
+	assert_eq!(trophies[1].granted, "");
+	assert!(parse_trophies(&Value::Null).is_empty());
+}
+
+#[test]
+fn test_parse_moderated() {
+	let sub = |name: &str, kind: &str, nsfw: bool| serde_json::json!({"sr": name, "subreddit_type": kind, "over_18": nsfw});
+	let res = serde_json::json!({
+		"kind": "ModeratedList",
+		"data": [
+			sub("rust", "public", false),
+			sub("secret", "private", false),
+			sub("u_spez", "user", false),
+			sub("nsfw", "public", true),
+			{"sr": "quarantined", "subreddit_type": "public", "quarantine": true},
+		]
+	});
+	assert_eq!(parse_moderated(&res, false), (vec!["rust".to_string()], 0));
+	assert_eq!(parse_moderated(&res, true), (vec!["rust".to_string(), "nsfw".to_string()], 0));
+
+	let many = serde_json::json!({"data": (0..30).map(|i| sub(&format!("sub{i}"), "public", false)).collect::<Vec<_>>()});
+	let (names, hidden) = parse_moderated(&many, false);
+	assert_eq!(names.len(), MAX_MODERATED_SHOWN);
+	assert_eq!(hidden, 5);
+	assert_eq!(parse_moderated(&Value::Null, false), (Vec::new(), 0));
+}
+
+#[test]
+fn test_user_error() {
+	assert_eq!(user_error("suspended", "spez").as_deref(), Some("u/spez has been suspended"));
+	assert_eq!(
+		user_error("Reddit error 404 \"null\": \"Not Found\" | /user/spez/about.json", "spez").as_deref(),
+		Some("u/spez doesn't exist, or has been deleted or shadowbanned")
+	);
+	assert_eq!(
+		user_error("Reddit error 403 \"null\": \"Forbidden\" | /user/spez/overview.json", "spez").as_deref(),
+		Some("The profile of u/spez is private")
+	);
+	assert_eq!(user_error("Reddit rate limit exceeded", "spez"), None);
+}
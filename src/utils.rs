@@ -3,17 +3,22 @@ use crate::config::{self, get_setting};
 //
 // CRATES
 //
-use crate::{client::json, server::RequestExt};
+use crate::{
+	client::{json, json_uncached, Unavailable},
+	server::RequestExt,
+	themes::custom_themes,
+};
 use askama::Template;
 use cookie::Cookie;
-use hyper::{Body, Request, Response};
-use log::error;
+use hyper::{header::HeaderValue, Body, HeaderMap, Request, Response};
+use log::{error, warn};
 use once_cell::sync::Lazy;
-use regex::Regex;
+use regex::{Regex, RegexSet, RegexSetBuilder};
 use rust_embed::RustEmbed;
+use serde::Serialize;
 use serde_json::Value;
 use serde_json_path::{JsonPath, JsonPathExt};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::str::FromStr;
 use std::string::ToString;
@@ -46,6 +51,7 @@ pub enum ResourceType {
 }
 
 // Post flair with content, background color and foreground color
+#[derive(Default, Serialize)]
 pub struct Flair {
 	pub flair_parts: Vec<FlairPart>,
 	pub text: String,
@@ -54,10 +60,12 @@ pub struct Flair {
 }
 
 // Part of flair, either emoji or text
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct FlairPart {
 	pub flair_part_type: String,
 	pub value: String,
+	// Short code of emojis, such as `:verified:`
+	pub name: String,
 }
 
 impl FlairPart {
@@ -78,6 +86,7 @@ impl FlairPart {
 								"emoji" => format_url(value("u")),
 								_ => String::new(),
 							},
+							name: value("a").to_string(),
 						}
 					})
 					.collect::<Vec<Self>>(),
@@ -88,6 +97,7 @@ impl FlairPart {
 				Some(text) => vec![Self {
 					flair_part_type: "text".to_string(),
 					value: text.to_string(),
+					name: String::new(),
 				}],
 				None => Vec::new(),
 			},
@@ -96,39 +106,52 @@ impl FlairPart {
 	}
 }
 
+#[derive(Default, Serialize)]
 pub struct Author {
 	pub name: String,
 	pub flair: Flair,
 	pub distinguished: String,
 }
 
+#[derive(Serialize)]
 pub struct Poll {
 	pub poll_options: Vec<PollOption>,
 	pub voting_end_timestamp: (String, String),
 	pub total_vote_count: u64,
+	/// Whether voting is still open
+	pub open: bool,
 }
 
 impl Poll {
 	pub fn parse(poll_data: &Value) -> Option<Self> {
 		poll_data.as_object()?;
 
-		let total_vote_count = poll_data["total_vote_count"].as_u64()?;
+		let total_vote_count = poll_data["total_vote_count"].as_u64().unwrap_or_default();
 		// voting_end_timestamp is in the format of milliseconds
-		let voting_end_timestamp = time(poll_data["voting_end_timestamp"].as_f64()? / 1000.0);
+		let voting_end = poll_data["voting_end_timestamp"].as_f64()? / 1000.0;
+		let voting_end_timestamp = time(voting_end);
 		let poll_options = PollOption::parse(&poll_data["options"])?;
 
 		Some(Self {
 			poll_options,
 			voting_end_timestamp,
 			total_vote_count,
+			open: voting_end > OffsetDateTime::now_utc().unix_timestamp() as f64,
 		})
 	}
 
+	/// Whether Reddit hides the votes per option, which it does until a poll
+	/// closes.
+	pub fn results_hidden(&self) -> bool {
+		self.poll_options.iter().all(|o| o.vote_count.is_none())
+	}
+
 	pub fn most_votes(&self) -> u64 {
 		self.poll_options.iter().filter_map(|o| o.vote_count).max().unwrap_or(0)
 	}
 }
 
+#[derive(Serialize)]
 pub struct PollOption {
 	pub id: u64,
 	pub text: String,
@@ -157,14 +180,52 @@ impl PollOption {
 	}
 }
 
+// The original post of a crosspost
+#[derive(Serialize)]
+pub struct Crosspost {
+	pub title: String,
+	pub community: String,
+	pub author: String,
+	pub permalink: String,
+	/// Whether the original post can still be viewed, rather than having been
+	/// deleted, removed or posted to a banned subreddit
+	pub available: bool,
+}
+
+impl Crosspost {
+	pub fn parse(data: &Value) -> Option<Self> {
+		let parent = &data["crosspost_parent_list"][0];
+		if !parent.is_object() {
+			// Reddit leaves out originals it can't show anymore
+			let id = data["crosspost_parent"].as_str()?.strip_prefix("t3_")?;
+			return Some(Self {
+				title: String::new(),
+				community: String::new(),
+				author: String::new(),
+				permalink: format!("/comments/{id}"),
+				available: false,
+			});
+		}
+		let text = |key: &str| parent[key].as_str().unwrap_or_default().to_string();
+		Some(Self {
+			title: text("title"),
+			community: text("subreddit"),
+			author: text("author"),
+			permalink: text("permalink"),
+			available: parent["removed_by_category"].is_null() && text("author") != "[deleted]",
+		})
+	}
+}
+
 // Post flags with nsfw and stickied
+#[derive(Serialize)]
 pub struct Flags {
 	pub spoiler: bool,
 	pub nsfw: bool,
 	pub stickied: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Media {
 	pub url: String,
 	pub alt_url: String,
@@ -172,10 +233,25 @@ pub struct Media {
 	pub height: i64,
 	pub poster: String,
 	pub download_name: String,
+	// Separate audio track of Reddit videos
+	pub audio_url: String,
 }
 
 impl Media {
 	pub async fn parse(data: &Value) -> (String, Self, Vec<GalleryMedia>) {
+		let parsed = Self::parse_data(data);
+		// Crossposts without media of their own show that of the original post
+		let parent = &data["crosspost_parent_list"][0];
+		if parsed.0 == "link" && parent.is_object() {
+			let parent_parsed = Self::parse_data(parent);
+			if parent_parsed.0 != "link" && parent_parsed.0 != "self" {
+				return parent_parsed;
+			}
+		}
+		parsed
+	}
+
+	fn parse_data(data: &Value) -> (String, Self, Vec<GalleryMedia>) {
 		let mut gallery = Vec::new();
 
 		// Define the various known places that Reddit might put video URLs.
@@ -236,6 +312,15 @@ impl Media {
 
 		let source = &data["preview"]["images"][0]["source"];
 
+		let audio_url = if post_type == "video" {
+			[secure_media, crosspost_parent_media, data_preview]
+				.into_iter()
+				.find(|video| video["fallback_url"] == *url_val)
+				.map_or(String::new(), video_audio_url)
+		} else {
+			String::new()
+		};
+
 		let alt_url = alt_url_val.map_or(String::new(), |val| format_url(val.as_str().unwrap_or_default()));
 
 		let download_name = if post_type == "image" || post_type == "gif" || post_type == "video" {
@@ -258,12 +343,26 @@ impl Media {
 				height: source["height"].as_i64().unwrap_or_default(),
 				poster: format_url(source["url"].as_str().unwrap_or_default()),
 				download_name,
+				audio_url,
 			},
 			gallery,
 		)
 	}
 }
 
+static REGEX_VIDEO_ID: Lazy<Regex> = Lazy::new(|| Regex::new(r"^https?://v\.redd\.it/([^/?]+)/").unwrap());
+
+// Link to the audio track of a Reddit video, unless Reddit says it has none
+fn video_audio_url(video: &Value) -> String {
+	if video["has_audio"] == false {
+		return String::new();
+	}
+	REGEX_VIDEO_ID
+		.captures(video["fallback_url"].as_str().unwrap_or_default())
+		.map_or(String::new(), |caps| format!("/vid/{}/audio", &caps[1]))
+}
+
+#[derive(Serialize)]
 pub struct GalleryMedia {
 	pub url: String,
 	pub width: i64,
@@ -278,7 +377,7 @@ impl GalleryMedia {
 			.as_array()
 			.unwrap_or(&Vec::new())
 			.iter()
-			.map(|item| {
+			.filter_map(|item| {
 				// For each image in gallery
 				let media_id = item["media_id"].as_str().unwrap_or_default();
 				let image = &metadata[media_id]["s"];
@@ -290,20 +389,26 @@ impl GalleryMedia {
 					image["u"].as_str().unwrap_or_default()
 				};
 
+				// Skip images that failed to upload or have been removed
+				if url.is_empty() || metadata[media_id]["status"].as_str().is_some_and(|status| status != "valid") {
+					return None;
+				}
+
 				// Construct gallery items
-				Self {
+				Some(Self {
 					url: format_url(url),
 					width: image["x"].as_i64().unwrap_or_default(),
 					height: image["y"].as_i64().unwrap_or_default(),
 					caption: item["caption"].as_str().unwrap_or_default().to_string(),
 					outbound_url: item["outbound_url"].as_str().unwrap_or_default().to_string(),
-				}
+				})
 			})
 			.collect::<Vec<Self>>()
 	}
 }
 
 // Post containing content, metadata and media
+#[derive(Serialize)]
 pub struct Post {
 	pub id: String,
 	pub title: String,
@@ -313,6 +418,7 @@ pub struct Post {
 	pub permalink: String,
 	pub link_title: String,
 	pub poll: Option<Poll>,
+	pub crosspost: Option<Crosspost>,
 	pub score: (String, String),
 	pub upvote_ratio: i64,
 	pub post_type: String,
@@ -324,6 +430,7 @@ pub struct Post {
 	pub rel_time: String,
 	pub created: String,
 	pub created_ts: u64,
+	pub edited: (String, String),
 	pub num_duplicates: u64,
 	pub comments: (String, String),
 	pub gallery: Vec<GalleryMedia>,
@@ -390,11 +497,7 @@ impl Post {
 					},
 					distinguished: val(post, "distinguished"),
 				},
-				score: if data["hide_score"].as_bool().unwrap_or_default() {
-					("\u{2022}".to_string(), "Hidden".to_string())
-				} else {
-					format_num(score)
-				},
+				score: format_score(score, data["hide_score"].as_bool().unwrap_or_default()),
 				upvote_ratio: ratio as i64,
 				post_type,
 				thumbnail: Media {
@@ -404,6 +507,7 @@ impl Post {
 					height: data["thumbnail_height"].as_i64().unwrap_or_default(),
 					poster: String::new(),
 					download_name: String::new(),
+					audio_url: String::new(),
 				},
 				media,
 				domain: val(post, "domain"),
@@ -429,9 +533,11 @@ impl Post {
 				permalink: val(post, "permalink"),
 				link_title: val(post, "link_title"),
 				poll: Poll::parse(&data["poll_data"]),
+				crosspost: Crosspost::parse(data),
 				rel_time,
 				created,
 				created_ts,
+				edited: edited(&data["edited"]),
 				num_duplicates: post["data"]["num_duplicates"].as_u64().unwrap_or(0),
 				comments: format_num(data["num_comments"].as_i64().unwrap_or_default()),
 				gallery,
@@ -445,7 +551,7 @@ impl Post {
 	}
 }
 
-#[derive(Template)]
+#[derive(Template, Default, Serialize)]
 #[template(path = "comment.html")]
 // Comment with content, post, score and data/time that it was posted
 pub struct Comment {
@@ -460,6 +566,7 @@ pub struct Comment {
 	pub score: (String, String),
 	pub rel_time: String,
 	pub created: String,
+	pub created_ts: u64,
 	pub edited: (String, String),
 	pub replies: Vec<Comment>,
 	pub highlighted: bool,
@@ -467,10 +574,28 @@ pub struct Comment {
 	pub collapsed: bool,
 	pub is_filtered: bool,
 	pub more_count: i64,
+	/// Where to load the comments of a "more" stub, if they can be loaded in place
+	pub more_link: String,
+	/// Whether the comment was written by the author of the post
+	pub is_op: bool,
+	/// Whether the comment was deleted or removed
+	pub deleted: bool,
+	#[serde(skip)]
 	pub prefs: Preferences,
 }
 
-#[derive(Default, Clone)]
+impl Comment {
+	/// Number of replies below this comment, including those not loaded
+	pub fn reply_count(&self) -> i64 {
+		self
+			.replies
+			.iter()
+			.map(|reply| if reply.kind == "more" { reply.more_count } else { 1 + reply.reply_count() })
+			.sum()
+	}
+}
+
+#[derive(Default, Clone, Serialize)]
 pub struct Award {
 	pub name: String,
 	pub icon_url: String,
@@ -484,8 +609,12 @@ impl std::fmt::Display for Award {
 	}
 }
 
+#[derive(Default, Serialize)]
 pub struct Awards(pub Vec<Award>);
 
+// Kinds of awards shown on comments before the rest are summarised
+const MAX_SHOWN_AWARDS: usize = 5;
+
 impl std::ops::Deref for Awards {
 	type Target = Vec<Award>;
 
@@ -496,7 +625,7 @@ impl std::ops::Deref for Awards {
 
 impl std::fmt::Display for Awards {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		self.iter().fold(Ok(()), |result, award| result.and_then(|()| writeln!(f, "{award}")))
+		self.iter().try_for_each(|award| writeln!(f, "{award}"))
 	}
 }
 
@@ -505,7 +634,7 @@ impl Awards {
 	pub fn parse(items: &Value) -> Self {
 		let parsed = items.as_array().unwrap_or(&Vec::new()).iter().fold(Vec::new(), |mut awards, item| {
 			let name = item["name"].as_str().unwrap_or_default().to_string();
-			let icon_url = format_url(item["resized_icons"][0]["url"].as_str().unwrap_or_default());
+			let icon_url = format_url(item["resized_icons"][0]["url"].as_str().or_else(|| item["icon_url"].as_str()).unwrap_or_default());
 			let description = item["description"].as_str().unwrap_or_default().to_string();
 			let count: i64 = i64::from_str(&item["count"].to_string()).unwrap_or(1);
 
@@ -521,6 +650,16 @@ impl Awards {
 
 		Self(parsed)
 	}
+
+	/// The awards shown in full on comments
+	pub fn shown(&self) -> &[Award] {
+		&self[..self.len().min(MAX_SHOWN_AWARDS)]
+	}
+
+	/// How many more awards were given than those shown
+	pub fn hidden_count(&self) -> i64 {
+		self.iter().skip(MAX_SHOWN_AWARDS).map(|award| award.count).sum()
+	}
 }
 
 #[derive(Template)]
@@ -531,6 +670,18 @@ pub struct ErrorTemplate {
 	pub url: String,
 }
 
+/// Page shown when Reddit can't answer, as opposed to an error of Redlib or
+/// of the requested page.
+#[derive(Template)]
+#[template(path = "unavailable.html")]
+pub struct UnavailableTemplate {
+	pub rate_limited: bool,
+	pub starting: bool,
+	pub retry_after: u64,
+	pub prefs: Preferences,
+	pub url: String,
+}
+
 /// Template for NSFW landing page. The landing page is displayed when a page's
 /// content is wholly NSFW, but a user has not enabled the option to view NSFW
 /// posts.
@@ -563,6 +714,17 @@ pub struct User {
 	pub banner: String,
 	pub description: String,
 	pub nsfw: bool,
+	pub trophies: Vec<Trophy>,
+	/// Public subreddits the user moderates, and how many more weren't listed
+	pub moderated: (Vec<String>, usize),
+}
+
+// A trophy earned by a user, with the date it was granted if known
+pub struct Trophy {
+	pub name: String,
+	pub description: String,
+	pub icon: String,
+	pub granted: String,
 }
 
 #[derive(Default)]
@@ -578,6 +740,13 @@ pub struct Subreddit {
 	pub active: (String, String),
 	pub wiki: bool,
 	pub nsfw: bool,
+	pub rules: Vec<SubredditRule>,
+}
+
+// A rule of a subreddit, with its description as HTML
+pub struct SubredditRule {
+	pub name: String,
+	pub description: String,
 }
 
 // Parser for query params, used in sorting (eg. /r/rust/?sort=hot)
@@ -610,6 +779,25 @@ pub struct Preferences {
 	pub post_sort: String,
 	pub subscriptions: Vec<String>,
 	pub filters: Vec<String>,
+	pub subreddit_sorts: BTreeMap<String, String>,
+	pub keyword_filters: String,
+	pub keyword_filter_body: String,
+	pub show_filtered_count: String,
+	pub blocked_subreddits: Vec<String>,
+	pub blocked_users: Vec<String>,
+	pub min_score: String,
+	pub collapse_comments: String,
+	pub collapse_bots: String,
+	pub bot_users: Vec<String>,
+	pub collapse_deleted: String,
+	pub read_posts: String,
+	pub posts_per_page: String,
+	pub infinite_scroll: String,
+	pub keyboard_shortcuts: String,
+	pub search_suggestions: String,
+	pub timezone: String,
+	pub locale: String,
+	pub strip_tracking: String,
 	pub hide_awards: String,
 	pub hide_score: String,
 }
@@ -622,15 +810,8 @@ pub struct ThemeAssets;
 impl Preferences {
 	// Build preferences from cookies
 	pub fn new(req: &Request<Body>) -> Self {
-		// Read available theme names from embedded css files.
-		// Always make the default "system" theme available.
-		let mut themes = vec!["system".to_string()];
-		for file in ThemeAssets::iter() {
-			let chunks: Vec<&str> = file.as_ref().split(".css").collect();
-			themes.push(chunks[0].to_owned());
-		}
 		Self {
-			available_themes: themes,
+			available_themes: available_themes(),
 			theme: setting(req, "theme"),
 			front_page: setting(req, "front_page"),
 			layout: setting(req, "layout"),
@@ -648,10 +829,84 @@ impl Preferences {
 			post_sort: setting(req, "post_sort"),
 			subscriptions: setting(req, "subscriptions").split('+').map(String::from).filter(|s| !s.is_empty()).collect(),
 			filters: setting(req, "filters").split('+').map(String::from).filter(|s| !s.is_empty()).collect(),
+			subreddit_sorts: get_subreddit_sorts(req),
+			keyword_filters: decode_cookie_value(&setting(req, "keyword_filters")),
+			keyword_filter_body: setting(req, "keyword_filter_body"),
+			show_filtered_count: setting(req, "show_filtered_count"),
+			blocked_subreddits: setting(req, "blocked_subreddits").split('+').map(String::from).filter(|s| !s.is_empty()).collect(),
+			min_score: setting(req, "min_score"),
+			collapse_comments: setting(req, "collapse_comments"),
+			collapse_bots: setting(req, "collapse_bots"),
+			bot_users: setting(req, "bot_users").split('+').map(String::from).filter(|s| !s.is_empty()).collect(),
+			collapse_deleted: setting(req, "collapse_deleted"),
+			read_posts: setting(req, "read_posts"),
+			posts_per_page: setting(req, "posts_per_page"),
+			infinite_scroll: setting(req, "infinite_scroll"),
+			keyboard_shortcuts: setting(req, "keyboard_shortcuts"),
+			search_suggestions: setting(req, "search_suggestions"),
+			timezone: setting(req, "timezone"),
+			locale: setting(req, "locale"),
+			strip_tracking: setting(req, "strip_tracking"),
+			blocked_users: setting(req, "blocked_users").split('+').map(String::from).filter(|s| !s.is_empty()).collect(),
 			hide_awards: setting(req, "hide_awards"),
 			hide_score: setting(req, "hide_score"),
 		}
 	}
+
+	/// Subscribed subreddits, leaving out users.
+	pub fn subscribed_subreddits(&self) -> Vec<&str> {
+		self.subscriptions.iter().map(String::as_str).filter(|sub| !sub.starts_with("u_")).collect()
+	}
+
+	/// The default sort chosen for the given subreddit, or an empty string.
+	pub fn subreddit_sort(&self, sub: &str) -> String {
+		self.subreddit_sorts.get(&sub.to_lowercase()).cloned().unwrap_or_default()
+	}
+}
+
+/// Post sorts that can be chosen as a subreddit's default.
+pub const POST_SORTS: [&str; 5] = ["hot", "new", "top", "rising", "controversial"];
+
+/// Comment sorts that can be chosen as the default.
+pub const COMMENT_SORTS: [&str; 5] = ["confidence", "top", "new", "controversial", "old"];
+
+/// Parses a list of per-subreddit default sorts such as `news:new+pics:hot`
+/// into a map keyed by lowercased subreddit name. Unknown sorts are ignored.
+pub fn parse_subreddit_sorts(value: &str) -> BTreeMap<String, String> {
+	value
+		.split('+')
+		.filter_map(|entry| entry.split_once(':'))
+		.filter(|(sub, sort)| !sub.is_empty() && POST_SORTS.contains(sort))
+		.map(|(sub, sort)| (sub.to_lowercase(), sort.to_string()))
+		.collect()
+}
+
+/// Inverse of [`parse_subreddit_sorts`].
+pub fn encode_subreddit_sorts(sorts: &BTreeMap<String, String>) -> String {
+	sorts.iter().map(|(sub, sort)| format!("{sub}:{sort}")).collect::<Vec<_>>().join("+")
+}
+
+/// Gets the per-subreddit default sorts from the cookie in the given
+/// `Request`, dropping subreddits the user is no longer subscribed to.
+pub fn get_subreddit_sorts(req: &Request<Body>) -> BTreeMap<String, String> {
+	let subscriptions = setting(req, "subscriptions").to_lowercase();
+	let subscriptions: HashSet<&str> = subscriptions.split('+').collect();
+	let mut sorts = parse_subreddit_sorts(&setting(req, "subreddit_sorts"));
+	sorts.retain(|sub, _| subscriptions.contains(sub.as_str()));
+	sorts
+}
+
+/// Reads available theme names from embedded css files, followed by the
+/// custom themes in `REDLIB_THEMES_DIR`.
+/// Always makes the default "system" theme available.
+pub fn available_themes() -> Vec<String> {
+	let mut themes = vec!["system".to_string()];
+	for file in ThemeAssets::iter() {
+		let chunks: Vec<&str> = file.as_ref().split(".css").collect();
+		themes.push(chunks[0].to_owned());
+	}
+	themes.extend(custom_themes().iter().map(|theme| theme.name.clone()));
+	themes
 }
 
 /// Gets a `HashSet` of filters from the cookie in the given `Request`.
@@ -682,6 +937,266 @@ pub fn filter_posts(posts: &mut Vec<Post>, filters: &HashSet<String>) -> (u64, b
 	}
 }
 
+// Most keyword filter patterns compiled per request
+const MAX_KEYWORD_FILTERS: usize = 100;
+
+/// Hides posts whose title (and optionally body and flair) matches any of the
+/// patterns from the user's keyword filter preference.
+pub struct KeywordFilter {
+	patterns: RegexSet,
+	include_body: bool,
+}
+
+impl KeywordFilter {
+	/// Compiles one case-insensitive pattern per line. Lines that aren't valid
+	/// regexes are matched literally. Returns `None` if there are no patterns.
+	pub fn new(patterns: &str, include_body: bool) -> Option<Self> {
+		let patterns: Vec<String> = keyword_patterns(patterns)
+			.map(|pattern| if Regex::new(pattern).is_ok() { pattern.to_string() } else { regex::escape(pattern) })
+			.collect();
+		if patterns.is_empty() {
+			return None;
+		}
+		match RegexSetBuilder::new(patterns).case_insensitive(true).size_limit(1 << 20).build() {
+			Ok(patterns) => Some(Self { patterns, include_body }),
+			Err(e) => {
+				warn!("Ignoring keyword filters that failed to compile: {e}");
+				None
+			}
+		}
+	}
+
+	/// Builds the filter from the preferences in the given `Request`.
+	pub fn from_request(req: &Request<Body>) -> Option<Self> {
+		Self::new(&decode_cookie_value(&setting(req, "keyword_filters")), setting(req, "keyword_filter_body") == "on")
+	}
+
+	/// Whether the given post should be hidden.
+	pub fn matches(&self, post: &Post) -> bool {
+		self.is_match(&post.title, &post.body, &post.flair.text)
+	}
+
+	fn is_match(&self, title: &str, body: &str, flair: &str) -> bool {
+		self.patterns.is_match(title) || (self.include_body && (self.patterns.is_match(body) || self.patterns.is_match(flair)))
+	}
+
+	/// Removes matching posts, returning how many were removed.
+	pub fn apply(&self, posts: &mut Vec<Post>) -> u64 {
+		let before = posts.len();
+		posts.retain(|post| !self.matches(post));
+		(before - posts.len()) as u64
+	}
+}
+
+/// Non-empty lines of a keyword filter preference, up to the supported maximum.
+fn keyword_patterns(patterns: &str) -> impl Iterator<Item = &str> {
+	patterns.lines().map(str::trim).filter(|line| !line.is_empty()).take(MAX_KEYWORD_FILTERS)
+}
+
+/// Keyword filter patterns that aren't valid regexes, and so are matched literally.
+pub fn invalid_keyword_patterns(patterns: &str) -> Vec<&str> {
+	keyword_patterns(patterns).filter(|pattern| Regex::new(pattern).is_err()).collect()
+}
+
+/// Applies the keyword filter of the given `Request` to `posts`, returning how
+/// many posts were hidden.
+pub fn filter_keywords(req: &Request<Body>, posts: &mut Vec<Post>) -> u64 {
+	KeywordFilter::from_request(req).map_or(0, |filter| filter.apply(posts))
+}
+
+/// Subreddits and users whose posts are hidden from listings, set with the
+/// block list preferences.
+pub struct Blocklist {
+	subreddits: HashSet<String>,
+	users: HashSet<String>,
+}
+
+impl Blocklist {
+	/// Builds the block lists from the preferences in the given `Request`.
+	pub fn from_request(req: &Request<Body>) -> Self {
+		let names = |name: &str| setting(req, name).split('+').filter(|s| !s.is_empty()).map(str::to_lowercase).collect();
+		Self {
+			subreddits: names("blocked_subreddits"),
+			users: names("blocked_users"),
+		}
+	}
+
+	/// Removes posts by blocked users, and if `aggregate` is set because the
+	/// listing mixes several subreddits, posts in blocked subreddits. Returns
+	/// how many posts were removed.
+	pub fn apply(&self, posts: &mut Vec<Post>, aggregate: bool) -> u64 {
+		let before = posts.len();
+		posts.retain(|post| !(self.users.contains(&post.author.name.to_lowercase()) || (aggregate && self.subreddits.contains(&post.community.to_lowercase()))));
+		(before - posts.len()) as u64
+	}
+}
+
+/// Subreddits that may be viewed on this instance, restricted by the
+/// operator with REDLIB_ALLOWED_SUBREDDITS and REDLIB_BLOCKED_SUBREDDITS.
+pub static SUBREDDIT_POLICY: Lazy<SubredditPolicy> =
+	Lazy::new(|| SubredditPolicy::new(get_setting("REDLIB_ALLOWED_SUBREDDITS").as_deref(), get_setting("REDLIB_BLOCKED_SUBREDDITS").as_deref()));
+
+#[derive(Default)]
+pub struct SubredditPolicy {
+	// If set, the only subreddits that may be viewed
+	allowed: Option<HashSet<String>>,
+	blocked: HashSet<String>,
+}
+
+impl SubredditPolicy {
+	/// Builds the policy from `+`-delimited lists of subreddits.
+	pub fn new(allowed: Option<&str>, blocked: Option<&str>) -> Self {
+		let names = |list: &str| list.split('+').map(str::trim).filter(|s| !s.is_empty()).map(str::to_lowercase).collect::<HashSet<_>>();
+		Self {
+			allowed: allowed.map(names).filter(|allowed| !allowed.is_empty()),
+			blocked: blocked.map(names).unwrap_or_default(),
+		}
+	}
+
+	/// Whether the single subreddit `sub` may be viewed. With an allowlist,
+	/// r/all and r/popular may only be viewed if listed.
+	fn allows_one(&self, sub: &str) -> bool {
+		let sub = sub.to_lowercase();
+		!self.blocked.contains(&sub) && self.allowed.as_ref().is_none_or(|allowed| allowed.contains(&sub))
+	}
+
+	/// Whether the subreddit or multireddit `sub` may be viewed. A multireddit
+	/// may be viewed if any of its subreddits may, as the posts of the others
+	/// are removed by `apply`.
+	pub fn allows(&self, sub: &str) -> bool {
+		sub.split('+').filter(|s| !s.is_empty()).any(|s| self.allows_one(s))
+	}
+
+	/// Removes posts in subreddits that may not be viewed, so that they don't
+	/// show up in listings mixing several subreddits. Returns how many posts
+	/// were removed.
+	pub fn apply(&self, posts: &mut Vec<Post>) -> u64 {
+		let before = posts.len();
+		posts.retain(|post| self.allows_one(&post.community));
+		(before - posts.len()) as u64
+	}
+
+	/// The multireddit of allowed subreddits, shown on the front page instead
+	/// of r/popular when there is an allowlist.
+	pub fn front_page(&self) -> Option<String> {
+		let mut subs = self.allowed.as_ref()?.iter().map(String::as_str).collect::<Vec<_>>();
+		subs.sort_unstable();
+		Some(subs.join("+"))
+	}
+}
+
+/// The absolute URL of the instance, without a trailing slash. This is
+/// `full_url` if configured, which may include a path prefix, or else is
+/// worked out from the headers of the request.
+pub fn base_url(full_url: Option<String>, headers: &HeaderMap) -> String {
+	if let Some(full_url) = full_url.filter(|url| !url.is_empty()) {
+		return full_url.trim_end_matches('/').to_string();
+	}
+	let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+	let scheme = header("x-forwarded-proto").filter(|scheme| *scheme == "https").unwrap_or("http");
+	let host = header("x-forwarded-host").or_else(|| header("host")).unwrap_or("localhost");
+	format!("{scheme}://{host}")
+}
+
+/// The canonical URL of the page at `path`, if REDLIB_CANONICAL_URL is set to
+/// "instance" or "reddit". Canonical URLs of this instance are only absolute if
+/// REDLIB_FULL_URL is set. The query, such as sorting and pagination, is left
+/// out so that every version of a page shares one canonical URL.
+pub fn canonical_url(path: &str) -> Option<String> {
+	let base = match get_setting("REDLIB_CANONICAL_URL")?.as_str() {
+		"instance" => get_setting("REDLIB_FULL_URL").unwrap_or_default().trim_end_matches('/').to_string(),
+		"reddit" => "https://www.reddit.com".to_string(),
+		_ => return None,
+	};
+	let path = path.split(['?', '#']).next().unwrap_or_default();
+	Some(format!("{base}{}", if path.is_empty() { "/" } else { path }))
+}
+
+/// Declare the canonical URL of the page at `path` in a `Link` header, see
+/// [`canonical_url`].
+pub fn with_canonical_link(mut response: Response<Body>, path: &str) -> Response<Body> {
+	if let Some(value) = canonical_url(path).and_then(|url| HeaderValue::from_str(&format!("<{url}>; rel=\"canonical\"")).ok()) {
+		response.headers_mut().insert(hyper::header::LINK, value);
+	}
+	response
+}
+
+/// The message shown when visiting a subreddit that may not be viewed.
+pub fn unavailable_subreddit(sub: &str) -> String {
+	format!("r/{sub} is not available on this instance")
+}
+
+/// Bounds of the posts per page preference.
+pub const POSTS_PER_PAGE: std::ops::RangeInclusive<u32> = 10..=100;
+
+/// The query of a listing request to pass on to Reddit, asking for the
+/// `limit` given in it or else the user's preferred number of posts per page,
+/// bounded to [`POSTS_PER_PAGE`]. Without either, Reddit's default is used.
+pub fn listing_query(req: &Request<Body>) -> String {
+	let query = req.uri().query().unwrap_or_default();
+	let limit = param(&format!("?{query}"), "limit").unwrap_or_else(|| setting(req, "posts_per_page"));
+	let mut upstream = query
+		.split('&')
+		.filter(|pair| !pair.is_empty() && pair.split('=').next() != Some("limit"))
+		.collect::<Vec<_>>()
+		.join("&");
+	if let Some(limit) = posts_per_page(&limit) {
+		upstream.push_str(&format!("&limit={limit}"));
+	}
+	upstream
+}
+
+// Parse the posts per page preference, clamping it to the supported range
+fn posts_per_page(value: &str) -> Option<u32> {
+	value.trim().parse::<u32>().ok().map(|limit| limit.clamp(*POSTS_PER_PAGE.start(), *POSTS_PER_PAGE.end()))
+}
+
+/// Removes posts scoring below `min_score`, returning how many were removed.
+/// Posts whose score is hidden are kept, and a threshold of 0 keeps all posts.
+pub fn filter_min_score(posts: &mut Vec<Post>, min_score: i64) -> u64 {
+	if min_score <= 0 {
+		return 0;
+	}
+	let before = posts.len();
+	posts.retain(|post| meets_min_score(&post.score.1, min_score));
+	(before - posts.len()) as u64
+}
+
+// Whether a post with the given full score is shown. Hidden scores aren't numbers.
+fn meets_min_score(score: &str, min_score: i64) -> bool {
+	score.parse::<i64>().map_or(true, |score| score >= min_score)
+}
+
+/// Reads the minimum score preference, which is 0 (disabled) if unset or invalid.
+pub fn min_score(req: &Request<Body>) -> i64 {
+	setting(req, "min_score").trim().parse().unwrap_or_default()
+}
+
+/// Normalizes a list of subreddit or user names entered in the settings, such
+/// as `r/news, pics`, to the `+`-separated form they are stored in.
+pub fn normalize_name_list(value: &str) -> String {
+	let mut names: Vec<&str> = Vec::new();
+	for name in value.split(|c: char| c == '+' || c == ',' || c.is_whitespace()) {
+		let name = name.trim_start_matches('/');
+		let name = ["r/", "u/", "user/"].iter().find_map(|prefix| name.strip_prefix(prefix)).unwrap_or(name);
+		if !name.is_empty() && !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+			names.push(name);
+		}
+	}
+	names.join("+")
+}
+
+/// Percent-encodes a preference that may contain characters not allowed in
+/// cookies, such as newlines and semicolons.
+pub fn encode_cookie_value(value: &str) -> String {
+	url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+/// Inverse of [`encode_cookie_value`].
+pub fn decode_cookie_value(value: &str) -> String {
+	url::form_urlencoded::parse(value.as_bytes()).map(|(key, _)| key).collect()
+}
+
 /// Creates a [`Post`] from a provided JSON.
 pub async fn parse_post(post: &Value) -> Post {
 	// Grab UTC time as unix timestamp
@@ -733,7 +1248,8 @@ pub async fn parse_post(post: &Value) -> Post {
 		permalink,
 		link_title: val(post, "link_title"),
 		poll,
-		score: format_num(score),
+		crosspost: Crosspost::parse(&post["data"]),
+		score: format_score(score, post["data"]["hide_score"].as_bool().unwrap_or_default()),
 		upvote_ratio: ratio as i64,
 		post_type,
 		media,
@@ -744,6 +1260,7 @@ pub async fn parse_post(post: &Value) -> Post {
 			height: post["data"]["thumbnail_height"].as_i64().unwrap_or_default(),
 			poster: String::new(),
 			download_name: String::new(),
+			audio_url: String::new(),
 		},
 		flair: Flair {
 			flair_parts: FlairPart::parse(
@@ -768,6 +1285,7 @@ pub async fn parse_post(post: &Value) -> Post {
 		rel_time,
 		created,
 		created_ts,
+		edited: edited(&post["data"]["edited"]),
 		num_duplicates: post["data"]["num_duplicates"].as_u64().unwrap_or(0),
 		comments: format_num(post["data"]["num_comments"].as_i64().unwrap_or_default()),
 		gallery,
@@ -822,17 +1340,37 @@ pub fn setting_or_default(req: &Request<Body>, name: &str, default: String) -> S
 	}
 }
 
-// Detect and redirect in the event of a random subreddit
-pub async fn catch_random(sub: &str, additional: &str) -> Result<Response<Body>, String> {
-	if sub == "random" || sub == "randnsfw" {
-		let new_sub = json(format!("/r/{sub}/about.json?raw_json=1"), false).await?["data"]["display_name"]
-			.as_str()
-			.unwrap_or_default()
-			.to_string();
-		Ok(redirect(&format!("/r/{new_sub}{additional}")))
-	} else {
-		Err("No redirect needed".to_string())
+// Detect and redirect in the event of a random subreddit. Returns `None` for
+// any other subreddit, and a readable message if none could be picked.
+pub async fn catch_random(req: &Request<Body>, sub: &str, additional: &str) -> Result<Option<Response<Body>>, String> {
+	if sub != "random" && sub != "randnsfw" {
+		return Ok(None);
+	}
+	if sub == "randnsfw" {
+		if sfw_only() {
+			return Err("NSFW content is disabled on this instance".to_string());
+		}
+		if setting(req, "show_nsfw") != "on" {
+			return Err("Enable \"Show NSFW posts\" in your settings to visit a random NSFW subreddit".to_string());
+		}
 	}
+	let new_sub = json_uncached(format!("/r/{sub}/about.json?raw_json=1"), false)
+		.await
+		.ok()
+		.and_then(|res| res["data"]["display_name"].as_str().map(String::from))
+		.filter(|name| !name.is_empty())
+		.ok_or("Couldn't find a random subreddit, please try again")?;
+	Ok(Some(redirect(&format!("/r/{new_sub}{additional}"))))
+}
+
+// Permalink of the post returned by Reddit's random post endpoint, which
+// responds with either a listing or a post and its comments
+pub fn random_permalink(res: &Value) -> Option<String> {
+	let listing = if res.is_array() { &res[0] } else { res };
+	listing["data"]["children"][0]["data"]["permalink"]
+		.as_str()
+		.filter(|permalink| permalink.starts_with('/'))
+		.map(String::from)
 }
 
 static REGEX_URL_WWW: Lazy<Regex> = Lazy::new(|| Regex::new(r"https?://www\.reddit\.com/(.*)").unwrap());
@@ -913,12 +1451,97 @@ static REDDIT_PREVIEW_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"https?://(e
 static REDDIT_EMOJI_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"https?://(www|).redditstatic\.com/(.*)").unwrap());
 static REDLIB_PREVIEW_LINK_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r#"/(img|preview/)(pre|external-pre)?/(.*?)>"#).unwrap());
 static REDLIB_PREVIEW_TEXT_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r">(.*?)</a>").unwrap());
+// Spoilers Reddit left unrendered, as in `>!hidden!<`. They may wrap other
+// inline markup and span several lines, but not paragraphs.
+static SPOILER_TEXT_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)&gt;!(.+?)!&lt;").unwrap());
+
+// Turn spoilers into elements that can be focused, and so revealed, by
+// clicking or tabbing to them
+pub fn rewrite_spoilers(input_text: &str) -> String {
+	let text = SPOILER_TEXT_REGEX.replace_all(input_text, |caps: &regex::Captures| {
+		let whole = &caps[0];
+		let hidden = &caps[1];
+		let start = caps.get(0).map_or(0, |m| m.start());
+		// Leave code alone, and anything crossing block boundaries
+		let in_code = input_text[..start].matches("<code").count() > input_text[..start].matches("</code>").count();
+		if in_code || hidden.contains("</p>") || hidden.contains("<p>") || hidden.contains("<pre") {
+			whole.to_string()
+		} else {
+			format!("<span class=\"md-spoiler-text\">{hidden}</span>")
+		}
+	});
+	text.replace("<span class=\"md-spoiler-text\">", "<span class=\"md-spoiler-text\" tabindex=\"0\">")
+}
+
+// Paragraphs, which hold any pipe tables Reddit left unrendered
+static PARAGRAPH_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)<p>(.*?)</p>").unwrap());
+static TABLE_SEPARATOR_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\|?\s*:?-+:?\s*(\|\s*:?-+:?\s*)*\|?$").unwrap());
+
+// Split a table row into its cells, honouring escaped pipes
+fn table_cells(row: &str) -> Vec<String> {
+	let row = row.trim();
+	let row = row.strip_prefix('|').unwrap_or(row);
+	let row = if row.ends_with('|') && !row.ends_with("\\|") { &row[..row.len() - 1] } else { row };
+
+	let mut cells = vec![String::new()];
+	let mut chars = row.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'\\' if chars.peek() == Some(&'|') => {
+				cells.last_mut().unwrap().push('|');
+				chars.next();
+			}
+			'|' => cells.push(String::new()),
+			_ => cells.last_mut().unwrap().push(c),
+		}
+	}
+	cells.iter().map(|cell| cell.trim().to_string()).collect()
+}
+
+// Render a paragraph as a table if it is a pipe table
+fn table_html(paragraph: &str) -> Option<String> {
+	let lines: Vec<&str> = paragraph.trim().lines().collect();
+	if lines.len() < 2 || !TABLE_SEPARATOR_REGEX.is_match(lines[1].trim()) || !lines.iter().all(|line| line.contains('|')) {
+		return None;
+	}
+
+	let header = table_cells(lines[0]);
+	let alignments: Vec<&str> = table_cells(lines[1])
+		.iter()
+		.map(|cell| match (cell.starts_with(':'), cell.ends_with(':')) {
+			(true, true) => " align=\"center\"",
+			(true, false) => " align=\"left\"",
+			(false, true) => " align=\"right\"",
+			(false, false) => "",
+		})
+		.collect();
+	if header.len() != alignments.len() {
+		return None;
+	}
+
+	// Rows are padded or cut to the width of the header
+	let row_html = |cells: &[String], tag: &str| {
+		let cells: String = (0..header.len())
+			.map(|i| format!("<{tag}{}>{}</{tag}>", alignments[i], cells.get(i).map_or("", String::as_str)))
+			.collect();
+		format!("<tr>{cells}</tr>")
+	};
+	let body: String = lines[2..].iter().map(|line| row_html(&table_cells(line), "td")).collect();
+	Some(format!("<table><thead>{}</thead><tbody>{body}</tbody></table>", row_html(&header, "th")))
+}
+
+// Render pipe tables Reddit left as plain paragraphs
+pub fn rewrite_tables(input_text: &str) -> String {
+	PARAGRAPH_REGEX
+		.replace_all(input_text, |caps: &regex::Captures| table_html(&caps[1]).unwrap_or_else(|| caps[0].to_string()))
+		.to_string()
+}
 
 // Rewrite Reddit links to Redlib in body of text
 pub fn rewrite_urls(input_text: &str) -> String {
 	let mut text1 =
 		// Rewrite Reddit links to Redlib
-		REDDIT_REGEX.replace_all(input_text, r#"href="/"#)
+		REDDIT_REGEX.replace_all(&rewrite_spoilers(&rewrite_tables(input_text)), r#"href="/"#)
 			.to_string();
 
 	loop {
@@ -991,6 +1614,79 @@ pub fn rewrite_urls(input_text: &str) -> String {
 // These links all follow a pattern of "https://reddit-econ-prod-assets-permanent.s3.amazonaws.com/asset-manager/SUBREDDIT_ID/RANDOM_FILENAME.png"
 static REDDIT_EMOTE_LINK_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r#"https://reddit-econ-prod-assets-permanent.s3.amazonaws.com/asset-manager/(.*)"#).unwrap());
 
+static HREF_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r#"href="([^"]*)""#).unwrap());
+
+// Query parameters that only track who shared or clicked a link. Anything else
+// is kept, as the linked site may need it.
+const TRACKING_PARAMS: [&str; 12] = [
+	"fbclid", "gclid", "dclid", "gbraid", "wbraid", "msclkid", "yclid", "twclid", "igshid", "mc_cid", "mc_eid", "_hsenc",
+];
+// Parameters added by Reddit's share buttons, only removed from Reddit links
+// as other sites use some of the same names (like `ref` for Git branches).
+// `context` is kept, as it picks how many parent comments are shown.
+const REDDIT_TRACKING_PARAMS: [&str; 7] = ["share_id", "ref", "ref_source", "ref_campaign", "rdt", "correlation_id", "post_fullname"];
+// Sites whose `si` parameter identifies who shared the link
+const SHARE_ID_HOSTS: [&str; 6] = ["youtube.com", "www.youtube.com", "m.youtube.com", "music.youtube.com", "youtu.be", "open.spotify.com"];
+
+/// Remove tracking parameters from the query of `url`, keeping its fragment.
+/// Relative URLs are Reddit links rewritten to this instance. Returns `None`
+/// if there is nothing to remove.
+fn strip_tracking_params(url: &str) -> Option<String> {
+	let (rest, fragment) = url.split_once('#').map_or((url, None), |(rest, fragment)| (rest, Some(fragment)));
+	let (base, query) = rest.split_once('?')?;
+	let host = base.split_once("://").map(|(_, rest)| rest.split('/').next().unwrap_or_default().to_ascii_lowercase());
+	let reddit = match &host {
+		Some(host) => host == "reddit.com" || host.ends_with(".reddit.com") || host == "redd.it",
+		None => base.starts_with('/') && !base.starts_with("//"),
+	};
+	let share_id = host.as_deref().is_some_and(|host| SHARE_ID_HOSTS.contains(&host));
+	let is_tracking = |pair: &str| {
+		let key = pair.split('=').next().unwrap_or_default().to_ascii_lowercase();
+		key.starts_with("utm_") || TRACKING_PARAMS.contains(&key.as_str()) || (reddit && REDDIT_TRACKING_PARAMS.contains(&key.as_str())) || (share_id && key == "si")
+	};
+
+	let pairs = query.split('&').filter(|pair| !pair.is_empty()).collect::<Vec<_>>();
+	let kept = pairs.iter().copied().filter(|pair| !is_tracking(pair)).collect::<Vec<_>>();
+	if kept.len() == pairs.len() {
+		return None;
+	}
+	let mut stripped = base.to_string();
+	if !kept.is_empty() {
+		stripped.push('?');
+		stripped.push_str(&kept.join("&"));
+	}
+	if let Some(fragment) = fragment {
+		stripped.push('#');
+		stripped.push_str(fragment);
+	}
+	Some(stripped)
+}
+
+/// Remove tracking parameters from the links in `html`, if the user asks to.
+pub fn clean_links(html: &str, prefs: &Preferences) -> String {
+	if prefs.strip_tracking != "on" {
+		return html.to_string();
+	}
+	HREF_REGEX
+		.replace_all(html, |captures: &regex::Captures<'_>| {
+			// Ampersands are escaped in attributes
+			match strip_tracking_params(&captures[1].replace("&amp;", "&")) {
+				Some(url) => format!("href=\"{}\"", url.replace('&', "&amp;")),
+				None => captures[0].to_string(),
+			}
+		})
+		.into_owned()
+}
+
+/// Remove tracking parameters from `url`, if the user asks to.
+pub fn clean_url(url: &str, prefs: &Preferences) -> String {
+	if prefs.strip_tracking == "on" {
+		strip_tracking_params(url).unwrap_or_else(|| url.to_string())
+	} else {
+		url.to_string()
+	}
+}
+
 // These all follow a pattern of '"emote|SUBREDDIT_IT|NUMBER"', we want the number
 static REDDIT_EMOTE_ID_NUMBER_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r#""emote\|.*\|(.*)""#).unwrap());
 
@@ -1065,21 +1761,59 @@ pub fn rewrite_emotes(media_metadata: &Value, comment: String) -> String {
 	rewrite_urls(&comment)
 }
 
-// Format vote count to a string that will be displayed.
-// Append `m` and `k` for millions and thousands respectively, and
-// round to the nearest tenth.
+// Format a count to a string that will be displayed, alongside the full count.
+// Counts from a thousand on are abbreviated like Reddit does, with `k`, `m`
+// and `b` for thousands, millions and billions, rounded half up to the nearest
+// tenth without a trailing `.0`. A count rounding up to a thousand of its unit
+// moves to the next one, so 999,950 is `1m` rather than `1000k`.
 pub fn format_num(num: i64) -> (String, String) {
-	let truncated = if num >= 1_000_000 || num <= -1_000_000 {
-		format!("{:.1}m", num as f64 / 1_000_000.0)
-	} else if num >= 1000 || num <= -1000 {
-		format!("{:.1}k", num as f64 / 1_000.0)
-	} else {
+	let abs = u128::from(num.unsigned_abs());
+	let truncated = if abs < 1000 {
 		num.to_string()
+	} else {
+		let sign = if num < 0 { "-" } else { "" };
+		let units = [(1_000, "k"), (1_000_000, "m"), (1_000_000_000, "b")];
+		let (tenths, suffix) = units
+			.iter()
+			.map(|&(unit, suffix)| ((abs * 10 + unit / 2) / unit, suffix))
+			.find(|&(tenths, suffix)| tenths < 10_000 || suffix == "b")
+			.unwrap_or_default();
+		if tenths % 10 == 0 {
+			format!("{sign}{}{suffix}", tenths / 10)
+		} else {
+			format!("{sign}{}.{}{suffix}", tenths / 10, tenths % 10)
+		}
 	};
 
 	(truncated, num.to_string())
 }
 
+// Format the votes of a poll, taken by reference as templates give them
+pub fn format_votes(votes: &u64) -> (String, String) {
+	format_num(i64::try_from(*votes).unwrap_or(i64::MAX))
+}
+
+// Format the score of a post or comment, which may be hidden by the subreddit
+// for a while after posting.
+pub fn format_score(score: i64, hidden: bool) -> (String, String) {
+	if hidden {
+		("\u{2022}".to_string(), "Hidden".to_string())
+	} else {
+		format_num(score)
+	}
+}
+
+// Parse the relative and absolute time of an edit. Reddit gives `false` for
+// unedited content, and either a timestamp or (for old edits) `true` otherwise.
+// An edit without a known time only gets the absolute part, as a tooltip.
+pub fn edited(edited: &Value) -> (String, String) {
+	match edited {
+		Value::Number(ts) => ts.as_f64().map_or((String::new(), String::new()), time),
+		Value::Bool(true) => (String::new(), "Edited at an unknown time".to_string()),
+		_ => (String::new(), String::new()),
+	}
+}
+
 // Parse a relative and absolute time from a UNIX timestamp
 pub fn time(created: f64) -> (String, String) {
 	let time = OffsetDateTime::from_unix_timestamp(created.round() as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH);
@@ -1121,10 +1855,28 @@ pub fn val(j: &Value, k: &str) -> String {
 	j["data"][k].as_str().unwrap_or_default().to_string()
 }
 
+/// Whether any of `posts` is a video with a separate audio track, which pages
+/// play with syncVideoAudio.js.
+pub fn has_audio(posts: &[Post]) -> bool {
+	posts.iter().any(|post| !post.media.audio_url.is_empty())
+}
+
+/// Escape text for use in HTML or XML, in element content and attributes alike.
+pub fn escape(text: &str) -> String {
+	text
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+		.replace('\'', "&#39;")
+}
+
 //
 // NETWORKING
 //
 
+/// Render a template into an HTML response. Pages that can get large should
+/// use [`stream_template`] instead.
 pub fn template(t: &impl Template) -> Response<Body> {
 	Response::builder()
 		.status(200)
@@ -1133,6 +1885,128 @@ pub fn template(t: &impl Template) -> Response<Body> {
 		.unwrap_or_default()
 }
 
+// Largest piece of a streamed page sent at once
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Render a template into an HTML response that is sent as it is rendered,
+/// rather than once the whole page is built. The `<head>` is sent as soon as
+/// it's rendered, so that browsers can load stylesheets in the meantime, and
+/// the rest in pieces of up to `CHUNK_SIZE`. The page has no Content-Length,
+/// so it's sent with chunked transfer encoding.
+///
+/// Rendering may fail once the headers are sent. The response is then aborted
+/// instead of ended, so that clients see the page is incomplete rather than
+/// showing or caching a truncated one.
+pub fn stream_template<T: Template + Send + 'static>(t: T) -> Response<Body> {
+	let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
+	tokio::task::spawn_blocking(move || {
+		let mut writer = ChunkWriter {
+			buffer: String::with_capacity(CHUNK_SIZE),
+			head_sent: false,
+			sender,
+		};
+		match t.render_into(&mut writer) {
+			// This only fails if the client went away
+			Ok(()) => {
+				let _ = writer.send();
+			}
+			Err(e) => writer.abort(&e.to_string()),
+		}
+	});
+	let body = futures_lite::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+	Response::builder()
+		.status(200)
+		.header("content-type", "text/html")
+		.body(Body::wrap_stream(body))
+		.unwrap_or_default()
+}
+
+/// Sends a page to the client in pieces while it's being rendered
+struct ChunkWriter {
+	buffer: String,
+	head_sent: bool,
+	sender: tokio::sync::mpsc::Sender<std::io::Result<hyper::body::Bytes>>,
+}
+
+impl ChunkWriter {
+	// Send what's rendered so far. This fails if the client went away, which
+	// stops rendering the rest of the page.
+	fn send(&mut self) -> std::fmt::Result {
+		if self.buffer.is_empty() {
+			return Ok(());
+		}
+		let chunk = std::mem::replace(&mut self.buffer, String::with_capacity(CHUNK_SIZE));
+		self.sender.blocking_send(Ok(chunk.into())).map_err(|_| std::fmt::Error)
+	}
+
+	// End the response with an error, unless the client already went away
+	fn abort(&self, reason: &str) {
+		if !self.sender.is_closed() {
+			error!("Failed to render page: {reason}");
+			let _ = self.sender.blocking_send(Err(std::io::Error::other(reason.to_string())));
+		}
+	}
+}
+
+impl std::fmt::Write for ChunkWriter {
+	fn write_str(&mut self, s: &str) -> std::fmt::Result {
+		self.buffer.push_str(s);
+		if !self.head_sent && s.contains("</head>") {
+			self.head_sent = true;
+			return self.send();
+		}
+		if self.buffer.len() >= CHUNK_SIZE {
+			return self.send();
+		}
+		Ok(())
+	}
+}
+
+impl Drop for ChunkWriter {
+	// Templates unwrap the rendering of nested templates, so a page can panic
+	// halfway through. Abort it then rather than end it early.
+	fn drop(&mut self) {
+		if std::thread::panicking() {
+			self.abort("rendering panicked");
+		}
+	}
+}
+
+/// Whether a request asks for JSON instead of HTML, which is only served if
+/// REDLIB_ENABLE_API is "on". The `.json` paths are taken by JSON Feeds, so
+/// the API is negotiated with the Accept header.
+pub fn wants_json(req: &Request<Body>) -> bool {
+	get_setting("REDLIB_ENABLE_API").as_deref() == Some("on") && accepts_json(req.headers())
+}
+
+fn accepts_json(headers: &HeaderMap) -> bool {
+	headers
+		.get_all(hyper::header::ACCEPT)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|accept| accept.split(','))
+		.any(|media_type| media_type.split(';').next().unwrap_or_default().trim() == "application/json")
+}
+
+/// Respond to an API request with the given value as JSON.
+pub fn json_response(value: &impl Serialize) -> Response<Body> {
+	Response::builder()
+		.status(200)
+		.header("content-type", "application/json")
+		.header("vary", "Accept")
+		.body(serde_json::to_string(value).unwrap_or_default().into())
+		.unwrap_or_default()
+}
+
+/// Respond to an API request with a page of posts, along with the cursor to
+/// pass as `after` for the next page, if there is one.
+pub fn listing_json(posts: &[Post], after: &str) -> Response<Body> {
+	json_response(&serde_json::json!({
+		"posts": posts,
+		"after": Some(after).filter(|after| !after.is_empty()),
+	}))
+}
+
 pub fn redirect(path: &str) -> Response<Body> {
 	Response::builder()
 		.status(302)
@@ -1142,9 +2016,22 @@ pub fn redirect(path: &str) -> Response<Body> {
 		.unwrap_or_default()
 }
 
-/// Renders a generic error landing page.
+/// Renders a generic error landing page, or a JSON error for API requests.
 pub async fn error(req: Request<Body>, msg: &str) -> Result<Response<Body>, String> {
 	error!("Error page rendered: {}", msg.split('|').next().unwrap_or_default());
+	if let Some(unavailable) = Unavailable::of(msg) {
+		return Ok(unavailable_page(&req, msg, unavailable));
+	}
+	if wants_json(&req) {
+		let body = serde_json::json!({ "error": msg.split('|').next().unwrap_or_default() });
+		return Ok(
+			Response::builder()
+				.status(404)
+				.header("content-type", "application/json")
+				.body(body.to_string().into())
+				.unwrap_or_default(),
+		);
+	}
 	let url = req.uri().to_string();
 	let body = ErrorTemplate {
 		msg: msg.to_string(),
@@ -1157,6 +2044,30 @@ pub async fn error(req: Request<Body>, msg: &str) -> Result<Response<Body>, Stri
 	Ok(Response::builder().status(404).header("content-type", "text/html").body(body.into()).unwrap_or_default())
 }
 
+// Tell users to come back later when Reddit is down or rate limiting this
+// instance, with a status that doesn't blame the page.
+fn unavailable_page(req: &Request<Body>, msg: &str, unavailable: Unavailable) -> Response<Body> {
+	let rate_limited = matches!(unavailable, Unavailable::RateLimited { .. });
+	let status = if rate_limited { 429 } else { 503 };
+	let retry_after = unavailable.retry_after();
+	let builder = Response::builder().status(status).header("retry-after", retry_after.to_string());
+
+	if wants_json(req) {
+		let body = serde_json::json!({ "error": msg.split('|').next().unwrap_or_default().trim(), "retry_after": retry_after });
+		return builder.header("content-type", "application/json").body(body.to_string().into()).unwrap_or_default();
+	}
+	let body = UnavailableTemplate {
+		rate_limited,
+		starting: unavailable == Unavailable::Starting,
+		retry_after,
+		prefs: Preferences::new(req),
+		url: req.uri().to_string(),
+	}
+	.render()
+	.unwrap_or_default();
+	builder.header("content-type", "text/html").body(body.into()).unwrap_or_default()
+}
+
 /// Returns true if the config/env variable `REDLIB_SFW_ONLY` carries the
 /// value `on`.
 ///
@@ -1181,6 +2092,16 @@ pub fn enable_rss() -> bool {
 	}
 }
 
+/// Returns true if the config/env variable `REDLIB_STRIP_IMAGE_METADATA` is set
+/// to "on". If this variable is set as such, EXIF and other metadata is removed
+/// from proxied JPEG, PNG and WebP images, at the cost of buffering them.
+pub fn strip_image_metadata() -> bool {
+	match get_setting("REDLIB_STRIP_IMAGE_METADATA") {
+		Some(val) => val == "on",
+		None => false,
+	}
+}
+
 /// Returns true if the config/env variable `REDLIB_ROBOTS_DISABLE_INDEXING` carries the
 /// value `on`.
 ///
@@ -1238,13 +2159,12 @@ pub async fn nsfw_landing(req: Request<Body>, req_url: String) -> Result<Respons
 pub fn url_path_basename(path: &str) -> String {
 	let url_result = Url::parse(format!("https://libredd.it/{path}").as_str());
 
-	if url_result.is_err() {
-		path.to_string()
-	} else {
-		let mut url = url_result.unwrap();
+	if let Ok(mut url) = url_result {
 		url.path_segments_mut().unwrap().pop_if_empty();
 
-		url.path_segments().unwrap().last().unwrap().to_string()
+		url.path_segments().unwrap().next_back().unwrap().to_string()
+	} else {
+		path.to_string()
 	}
 }
 
@@ -1264,15 +2184,41 @@ pub fn get_post_url(post: &Post) -> String {
 
 #[cfg(test)]
 mod tests {
-	use super::{format_num, format_url, rewrite_urls};
+	use super::{canonical_url, format_num, format_score, format_url, rewrite_urls, with_canonical_link};
+	use hyper::Response;
+	use sealed_test::prelude::*;
 
 	#[test]
 	fn format_num_works() {
 		assert_eq!(format_num(567), ("567".to_string(), "567".to_string()));
 		assert_eq!(format_num(1234), ("1.2k".to_string(), "1234".to_string()));
-		assert_eq!(format_num(1999), ("2.0k".to_string(), "1999".to_string()));
-		assert_eq!(format_num(1001), ("1.0k".to_string(), "1001".to_string()));
-		assert_eq!(format_num(1_999_999), ("2.0m".to_string(), "1999999".to_string()));
+		assert_eq!(format_num(1999), ("2k".to_string(), "1999".to_string()));
+		assert_eq!(format_num(1001), ("1k".to_string(), "1001".to_string()));
+		assert_eq!(format_num(1_999_999), ("2m".to_string(), "1999999".to_string()));
+	}
+
+	#[test]
+	fn format_num_rounds_at_boundaries() {
+		let short = |num| format_num(num).0;
+		assert_eq!(short(0), "0");
+		assert_eq!(short(999), "999");
+		assert_eq!(short(1000), "1k");
+		assert_eq!(short(1049), "1k");
+		assert_eq!(short(1050), "1.1k");
+		assert_eq!(short(12_345), "12.3k");
+		assert_eq!(short(999_949), "999.9k");
+		assert_eq!(short(999_950), "1m");
+		assert_eq!(short(1_250_000), "1.3m");
+		assert_eq!(short(2_500_000_000), "2.5b");
+		assert_eq!(short(-999), "-999");
+		assert_eq!(short(-1234), "-1.2k");
+		assert_eq!(short(i64::MIN), "-9223372036.9b");
+	}
+
+	#[test]
+	fn format_score_hides() {
+		assert_eq!(format_score(1234, false), ("1.2k".to_string(), "1234".to_string()));
+		assert_eq!(format_score(1234, true), ("\u{2022}".to_string(), "Hidden".to_string()));
 	}
 
 	#[test]
@@ -1331,6 +2277,102 @@ mod tests {
 		assert_eq!(format_url("nsfw"), "");
 		assert_eq!(format_url("spoiler"), "");
 	}
+
+	#[test]
+	#[sealed_test(env = [("REDLIB_CANONICAL_URL", "reddit")])]
+	fn test_canonical_url_reddit() {
+		assert_eq!(canonical_url("/r/rust/top?t=week&after=t3_abc").as_deref(), Some("https://www.reddit.com/r/rust/top"));
+		assert_eq!(canonical_url("/").as_deref(), Some("https://www.reddit.com/"));
+		assert_eq!(canonical_url("").as_deref(), Some("https://www.reddit.com/"));
+	}
+
+	#[test]
+	#[sealed_test(env = [("REDLIB_CANONICAL_URL", "instance"), ("REDLIB_FULL_URL", "https://redlib.example.com/")])]
+	fn test_canonical_url_instance() {
+		assert_eq!(
+			canonical_url("/r/rust/comments/abc/title/#c").as_deref(),
+			Some("https://redlib.example.com/r/rust/comments/abc/title/")
+		);
+		let response = with_canonical_link(Response::default(), "/user/spez?sort=new");
+		assert_eq!(response.headers()["link"], "<https://redlib.example.com/user/spez>; rel=\"canonical\"");
+	}
+
+	#[test]
+	fn test_canonical_url_unset() {
+		assert_eq!(canonical_url("/r/rust"), None);
+		assert!(with_canonical_link(Response::default(), "/r/rust").headers().get("link").is_none());
+	}
+}
+
+#[test]
+fn test_parse_gallery() {
+	let items = serde_json::json!([
+		{ "media_id": "a", "caption": "First", "outbound_url": "https://example.com" },
+		{ "media_id": "b" },
+		{ "media_id": "c" },
+		{ "media_id": "d" }
+	]);
+	let metadata = serde_json::json!({
+		"a": { "status": "valid", "m": "image/png", "s": { "u": "https://i.redd.it/a.png", "x": 640, "y": 480 } },
+		"b": { "status": "valid", "m": "image/gif", "s": { "gif": "https://i.redd.it/b.gif", "mp4": "https://i.redd.it/b.mp4" } },
+		"c": { "status": "failed" },
+		"d": { "status": "valid", "m": "image/jpg", "s": {} }
+	});
+	let gallery = GalleryMedia::parse(&items, &metadata);
+	assert_eq!(gallery.iter().map(|image| image.url.as_str()).collect::<Vec<_>>(), ["/img/a.png", "/img/b.gif"]);
+	assert_eq!((gallery[0].width, gallery[0].height), (640, 480));
+	assert_eq!(gallery[0].caption, "First");
+	assert_eq!(gallery[0].outbound_url, "https://example.com");
+}
+
+#[test]
+fn test_parse_crosspost() {
+	let crosspost = Crosspost::parse(&serde_json::json!({
+		"crosspost_parent": "t3_abc",
+		"crosspost_parent_list": [{ "title": "Original", "subreddit": "rust", "author": "ferris", "permalink": "/r/rust/comments/abc/original/", "removed_by_category": null }]
+	}))
+	.unwrap();
+	assert!(crosspost.available);
+	assert_eq!((crosspost.community.as_str(), crosspost.author.as_str()), ("rust", "ferris"));
+	assert_eq!(crosspost.permalink, "/r/rust/comments/abc/original/");
+
+	let removed = Crosspost::parse(&serde_json::json!({
+		"crosspost_parent_list": [{ "title": "Original", "author": "[deleted]", "removed_by_category": "deleted" }]
+	}))
+	.unwrap();
+	assert!(!removed.available);
+
+	let missing = Crosspost::parse(&serde_json::json!({ "crosspost_parent": "t3_abc", "crosspost_parent_list": [] })).unwrap();
+	assert!(!missing.available);
+	assert_eq!(missing.permalink, "/comments/abc");
+
+	assert!(Crosspost::parse(&serde_json::json!({ "title": "Not a crosspost" })).is_none());
+}
+
+#[test]
+fn test_parse_poll() {
+	let options = serde_json::json!([{ "id": "1", "text": "Yes", "vote_count": 3 }, { "id": "2", "text": "No", "vote_count": 1 }]);
+	let closed = Poll::parse(&serde_json::json!({ "total_vote_count": 4, "voting_end_timestamp": 1_700_000_000_000_u64, "options": options })).unwrap();
+	assert!(!closed.open);
+	assert!(!closed.results_hidden());
+	assert_eq!(closed.most_votes(), 3);
+
+	let voting_end = (OffsetDateTime::now_utc().unix_timestamp() + 86400) * 1000;
+	let open = Poll::parse(&serde_json::json!({ "voting_end_timestamp": voting_end, "options": [{ "id": "1", "text": "Yes" }] })).unwrap();
+	assert!(open.open);
+	assert!(open.results_hidden());
+	assert_eq!(open.total_vote_count, 0);
+
+	assert!(Poll::parse(&Value::Null).is_none());
+}
+
+#[test]
+fn test_random_permalink() {
+	let listing = serde_json::json!({ "data": { "children": [{ "data": { "permalink": "/r/rust/comments/abc/hello/" } }] } });
+	assert_eq!(random_permalink(&listing).as_deref(), Some("/r/rust/comments/abc/hello/"));
+	let post = serde_json::json!([listing, { "data": { "children": [] } }]);
+	assert_eq!(random_permalink(&post).as_deref(), Some("/r/rust/comments/abc/hello/"));
+	assert_eq!(random_permalink(&serde_json::json!({ "data": { "children": [] } })), None);
 }
 
 #[test]
@@ -1394,3 +2436,351 @@ fn test_rewriting_emotes() {
 	let output = r#"<div class="comment_body "><div class="md"><p><img loading="lazy" src="/emote/t5_31hpy/PW6WsOaLcd.png" width="60" height="60" style="vertical-align:text-bottom"></p></div></div>"#;
 	assert_eq!(rewrite_emotes(&json_input, comment_input.to_string()), output);
 }
+
+#[test]
+fn test_parse_subreddit_sorts() {
+	let sorts = parse_subreddit_sorts("News:new+pics:hot+rust:bogus+:top+askreddit");
+	assert_eq!(sorts.len(), 2);
+	assert_eq!(sorts.get("news").map(String::as_str), Some("new"));
+	assert_eq!(sorts.get("pics").map(String::as_str), Some("hot"));
+	assert_eq!(encode_subreddit_sorts(&sorts), "news:new+pics:hot");
+	assert!(parse_subreddit_sorts("").is_empty());
+}
+
+#[test]
+fn test_keyword_filter() {
+	let filter = KeywordFilter::new("spoiler\n\n^\\[meta\\]\n(unclosed", false).unwrap();
+	assert!(filter.is_match("Huge SPOILER ahead", "", ""));
+	assert!(filter.is_match("[Meta] Rules update", "", ""));
+	assert!(!filter.is_match("Not [meta] at the start", "", ""));
+	// Invalid regexes are matched literally
+	assert!(filter.is_match("Smiley (unclosed", "", ""));
+	assert!(!filter.is_match("Nothing to see", "spoiler", "spoiler"));
+
+	let filter = KeywordFilter::new("spoiler", true).unwrap();
+	assert!(filter.is_match("Nothing to see", "a spoiler", ""));
+	assert!(filter.is_match("Nothing to see", "", "Spoiler"));
+
+	assert!(KeywordFilter::new(" \n", false).is_none());
+	assert_eq!(invalid_keyword_patterns("ok\n(bad"), vec!["(bad"]);
+}
+
+#[test]
+fn test_cookie_value_encoding() {
+	let value = "a+b\r\nc; d=e&f";
+	assert_eq!(decode_cookie_value(&encode_cookie_value(value)), value);
+	assert!(!encode_cookie_value(value).contains([';', '\n']));
+}
+
+#[test]
+fn test_normalize_name_list() {
+	assert_eq!(normalize_name_list("r/news, pics\n/r/Rust  Pics+u/spez"), "news+pics+Rust+spez");
+	assert_eq!(normalize_name_list("user/someone"), "someone");
+	assert_eq!(normalize_name_list(" , "), "");
+}
+
+#[test]
+fn test_meets_min_score() {
+	assert!(meets_min_score("42", 10));
+	assert!(meets_min_score("10", 10));
+	assert!(!meets_min_score("9", 10));
+	assert!(!meets_min_score("-3", 1));
+	// Hidden scores are always shown
+	assert!(meets_min_score("Hidden", 10));
+}
+
+#[test]
+fn test_listing_query() {
+	let query = |uri: &str, posts_per_page: Option<&str>| {
+		let mut builder = Request::builder().uri(uri);
+		if let Some(posts_per_page) = posts_per_page {
+			builder = builder.header("cookie", format!("posts_per_page={posts_per_page}"));
+		}
+		listing_query(&builder.body(Body::empty()).unwrap())
+	};
+	assert_eq!(query("/r/rust?t=day", None), "t=day");
+	assert_eq!(query("/r/rust?t=day", Some("50")), "t=day&limit=50");
+	// A limit in the query takes precedence, but is bounded all the same
+	assert_eq!(query("/r/rust?limit=25&t=day", Some("50")), "t=day&limit=25");
+	assert_eq!(query("/r/rust?limit=1000", None), "&limit=100");
+	assert_eq!(query("/r/rust?limit=1", None), "&limit=10");
+	assert_eq!(query("/r/rust?limit=all&after=t3_x", Some("50")), "after=t3_x");
+}
+
+#[tokio::test]
+async fn test_stream_template() {
+	use hyper::body::HttpBody;
+
+	#[derive(Template)]
+	#[template(source = "<head></head>{% for line in lines %}{{ line }}{% endfor %}", ext = "html")]
+	struct Page<T: std::fmt::Display> {
+		lines: Vec<T>,
+	}
+
+	// A value that fails to render
+	struct Broken;
+	impl std::fmt::Display for Broken {
+		fn fmt(&self, _: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+			Err(std::fmt::Error)
+		}
+	}
+
+	let line = "a".repeat(1000);
+	let mut res = stream_template(Page { lines: vec![line.clone(); 40] });
+	assert_eq!(res.body().size_hint().exact(), None);
+	let mut chunks = Vec::new();
+	while let Some(chunk) = res.body_mut().data().await {
+		chunks.push(chunk.unwrap());
+	}
+	// The head comes first, then the rest in pieces
+	assert_eq!(chunks[0], "<head></head>");
+	assert!(chunks.len() > 2);
+	assert!(chunks.iter().all(|chunk| chunk.len() < CHUNK_SIZE + line.len()));
+	assert_eq!(chunks.concat(), format!("<head></head>{}", line.repeat(40)).as_bytes());
+
+	// A page failing to render ends with an error instead of cleanly
+	let mut res = stream_template(Page { lines: vec![Broken] });
+	assert_eq!(res.body_mut().data().await.unwrap().unwrap(), "<head></head>");
+	assert!(res.body_mut().data().await.unwrap().is_err());
+}
+
+#[test]
+fn test_escape() {
+	assert_eq!(escape(r#"<a href="/r/rust?a=1&b='2'">"#), "&lt;a href=&quot;/r/rust?a=1&amp;b=&#39;2&#39;&quot;&gt;");
+	assert_eq!(escape("plain text"), "plain text");
+}
+
+#[test]
+fn test_posts_per_page() {
+	assert_eq!(posts_per_page("50"), Some(50));
+	assert_eq!(posts_per_page("5"), Some(10));
+	assert_eq!(posts_per_page("500"), Some(100));
+	assert_eq!(posts_per_page(""), None);
+	assert_eq!(posts_per_page("many"), None);
+}
+
+#[test]
+fn test_rewrite_spoilers() {
+	// Spoilers rendered by Reddit become focusable
+	assert_eq!(
+		rewrite_spoilers(r#"<p><span class="md-spoiler-text">hidden</span></p>"#),
+		r#"<p><span class="md-spoiler-text" tabindex="0">hidden</span></p>"#
+	);
+	// Literal spoilers, with nested markup and line breaks
+	assert_eq!(
+		rewrite_spoilers("<p>a &gt;!<em>very</em>\nsecret!&lt; b &gt;!two!&lt;</p>"),
+		"<p>a <span class=\"md-spoiler-text\" tabindex=\"0\"><em>very</em>\nsecret</span> b <span class=\"md-spoiler-text\" tabindex=\"0\">two</span></p>"
+	);
+	// Not across paragraphs, nor in code
+	assert_eq!(rewrite_spoilers("<p>&gt;!a</p><p>b!&lt;</p>"), "<p>&gt;!a</p><p>b!&lt;</p>");
+	assert_eq!(rewrite_spoilers("<code>&gt;!a!&lt;</code>"), "<code>&gt;!a!&lt;</code>");
+}
+
+#[test]
+fn test_rewrite_tables() {
+	assert_eq!(
+		rewrite_tables("<blockquote><p>| a | b | c |\n|:--|:-:|--:|\n| 1 | x \\| y |\n| 2 |</p></blockquote>"),
+		concat!(
+			"<blockquote><table>",
+			r#"<thead><tr><th align="left">a</th><th align="center">b</th><th align="right">c</th></tr></thead><tbody>"#,
+			r#"<tr><td align="left">1</td><td align="center">x | y</td><td align="right"></td></tr>"#,
+			r#"<tr><td align="left">2</td><td align="center"></td><td align="right"></td></tr>"#,
+			"</tbody></table></blockquote>"
+		)
+	);
+	// Extra cells are dropped
+	assert_eq!(
+		rewrite_tables("<p>a | b\n--- | ---\n1 | 2 | 3</p>"),
+		"<table><thead><tr><th>a</th><th>b</th></tr></thead><tbody><tr><td>1</td><td>2</td></tr></tbody></table>"
+	);
+	// Not tables
+	assert_eq!(rewrite_tables("<p>a | b</p>"), "<p>a | b</p>");
+	assert_eq!(rewrite_tables("<p>a | b\nc | d</p>"), "<p>a | b\nc | d</p>");
+	assert_eq!(rewrite_tables("<p>a | b\n--- | --- | ---</p>"), "<p>a | b\n--- | --- | ---</p>");
+}
+
+#[test]
+fn test_edited() {
+	assert_eq!(edited(&serde_json::json!(false)), (String::new(), String::new()));
+	assert_eq!(edited(&Value::Null), (String::new(), String::new()));
+	assert_eq!(edited(&serde_json::json!(true)), (String::new(), "Edited at an unknown time".to_string()));
+	let (rel, full) = edited(&serde_json::json!(1_600_000_000.0));
+	assert_eq!(rel, "Sep 13 '20");
+	assert_eq!(full, "Sep 13 2020, 12:26:40 UTC");
+}
+
+#[test]
+fn test_video_audio_url() {
+	let video = serde_json::json!({"fallback_url": "https://v.redd.it/abc123/DASH_720.mp4?source=fallback", "has_audio": true});
+	assert_eq!(video_audio_url(&video), "/vid/abc123/audio");
+	// Older videos don't say whether they have audio
+	let video = serde_json::json!({"fallback_url": "https://v.redd.it/abc123/DASH_720"});
+	assert_eq!(video_audio_url(&video), "/vid/abc123/audio");
+	let video = serde_json::json!({"fallback_url": "https://v.redd.it/abc123/DASH_720.mp4", "has_audio": false});
+	assert_eq!(video_audio_url(&video), "");
+	let video = serde_json::json!({"fallback_url": "https://example.com/video.mp4"});
+	assert_eq!(video_audio_url(&video), "");
+}
+
+#[test]
+fn test_parse_richtext_flair() {
+	let richtext = serde_json::json!([
+		{"e": "emoji", "a": ":verified:", "u": "https://emoji.redditmedia.com/abc/verified.png"},
+		{"e": "text", "t": " Expert"}
+	]);
+	let parts = FlairPart::parse("richtext", richtext.as_array(), None);
+	assert_eq!(parts.len(), 2);
+	assert_eq!(parts[0].flair_part_type, "emoji");
+	assert_eq!(parts[0].value, "/emoji/abc/verified.png");
+	assert_eq!(parts[0].name, ":verified:");
+	assert_eq!(parts[1].value, " Expert");
+}
+
+#[test]
+fn test_awards_shown() {
+	let items: Vec<Value> = (1..=7)
+		.map(|i| serde_json::json!({"name": format!("Award {i}"), "count": i, "icon_url": "https://i.redd.it/award.png"}))
+		.collect();
+	let awards = Awards::parse(&Value::Array(items));
+	assert_eq!(awards.len(), 7);
+	assert_eq!(awards[0].icon_url, "/img/award.png");
+	assert_eq!(awards.shown().len(), 5);
+	assert_eq!(awards.hidden_count(), 6 + 7);
+	assert_eq!(Awards::parse(&Value::Null).hidden_count(), 0);
+}
+
+#[test]
+fn test_subreddit_policy() {
+	let policy = SubredditPolicy::new(None, Some("pics+Funny"));
+	assert!(policy.allows("rust"));
+	assert!(policy.allows("all"));
+	assert!(!policy.allows("funny"));
+	assert!(policy.allows("funny+rust"));
+	assert!(!policy.allows("funny+pics"));
+	assert_eq!(policy.front_page(), None);
+
+	let policy = SubredditPolicy::new(Some("rust+linux"), Some("linux"));
+	assert!(policy.allows("Rust"));
+	assert!(!policy.allows("linux"));
+	assert!(!policy.allows("all"));
+	assert!(!policy.allows("popular"));
+	assert!(!policy.allows(""));
+	assert_eq!(policy.front_page(), Some("linux+rust".to_string()));
+
+	assert!(SubredditPolicy::new(Some(""), None).allows("all"));
+}
+
+#[test]
+fn test_base_url() {
+	let mut headers = HeaderMap::new();
+	assert_eq!(base_url(None, &headers), "http://localhost");
+	headers.insert("host", "127.0.0.1:8080".parse().unwrap());
+	assert_eq!(base_url(None, &headers), "http://127.0.0.1:8080");
+	headers.insert("x-forwarded-proto", "https".parse().unwrap());
+	headers.insert("x-forwarded-host", "redlib.example.com".parse().unwrap());
+	assert_eq!(base_url(None, &headers), "https://redlib.example.com");
+	assert_eq!(base_url(Some("https://example.com/redlib/".to_string()), &headers), "https://example.com/redlib");
+}
+
+#[test]
+fn test_accepts_json() {
+	let headers = |accept: &str| {
+		let mut headers = HeaderMap::new();
+		headers.insert(hyper::header::ACCEPT, accept.parse().unwrap());
+		headers
+	};
+	assert!(accepts_json(&headers("application/json")));
+	assert!(accepts_json(&headers("text/html;q=0.9, application/json;q=1.0")));
+	assert!(!accepts_json(&headers("text/html,application/xhtml+xml,*/*;q=0.8")));
+	assert!(!accepts_json(&HeaderMap::new()));
+}
+
+#[test]
+fn test_comment_json() {
+	let comment = Comment {
+		id: "abc".to_string(),
+		body: "<p>Hello</p>".to_string(),
+		replies: vec![Comment::default()],
+		..Comment::default()
+	};
+	let value = serde_json::to_value(&comment).unwrap();
+	assert_eq!(value["id"], "abc");
+	assert_eq!(value["body"], "<p>Hello</p>");
+	assert_eq!(value["replies"].as_array().map(Vec::len), Some(1));
+	assert!(value.get("prefs").is_none());
+}
+
+#[test]
+fn test_themes_define_all_variables() {
+	// Missing variables silently fall back to the system theme's
+	for file in ThemeAssets::iter() {
+		let theme = ThemeAssets::get(file.as_ref()).unwrap();
+		let css = std::str::from_utf8(theme.data.as_ref()).unwrap();
+		for variable in crate::themes::THEME_VARIABLES {
+			assert!(css.contains(&format!("{variable}:")), "{file} doesn't define {variable}");
+		}
+	}
+}
+
+#[test]
+fn test_strip_tracking_params() {
+	assert_eq!(
+		strip_tracking_params("https://example.com/a?utm_source=reddit&id=3&fbclid=x#top").as_deref(),
+		Some("https://example.com/a?id=3#top")
+	);
+	assert_eq!(strip_tracking_params("https://example.com/a?UTM_Medium=social").as_deref(), Some("https://example.com/a"));
+	assert_eq!(strip_tracking_params("https://youtu.be/abc?si=share&t=30").as_deref(), Some("https://youtu.be/abc?t=30"));
+	// Reddit's share parameters, with `context` kept
+	assert_eq!(
+		strip_tracking_params("/r/rust/comments/abc/title/def/?context=3&share_id=x&ref=share").as_deref(),
+		Some("/r/rust/comments/abc/title/def/?context=3")
+	);
+	assert_eq!(
+		strip_tracking_params("https://www.reddit.com/r/rust?rdt=1").as_deref(),
+		Some("https://www.reddit.com/r/rust")
+	);
+	// Nothing to remove, including names that are only tracking elsewhere
+	assert_eq!(strip_tracking_params("https://github.com/redlib-org/redlib/blob/x?ref=main"), None);
+	assert_eq!(strip_tracking_params("https://example.com/?si=1"), None);
+	assert_eq!(strip_tracking_params("https://example.com/#utm_source=x"), None);
+	assert_eq!(strip_tracking_params("https://example.com/"), None);
+}
+
+#[test]
+fn test_clean_links() {
+	let html = r#"<a href="https://example.com/?a=1&amp;utm_source=x&amp;b=2">x</a> <a href="/r/rust">r/rust</a>"#;
+	let mut prefs = Preferences::default();
+	assert_eq!(clean_links(html, &prefs), html);
+	prefs.strip_tracking = "on".to_string();
+	assert_eq!(
+		clean_links(html, &prefs),
+		r#"<a href="https://example.com/?a=1&amp;b=2">x</a> <a href="/r/rust">r/rust</a>"#
+	);
+	assert_eq!(clean_url("https://example.com/?gclid=x", &prefs), "https://example.com/");
+}
+
+#[tokio::test]
+async fn test_error_when_reddit_unavailable() {
+	let request = |accept: &str| Request::builder().uri("/r/rust").header("accept", accept).body(Body::empty()).unwrap();
+
+	let res = error(request("text/html"), "Reddit is having issues, check if there's an outage | /r/rust.json")
+		.await
+		.unwrap();
+	assert_eq!(res.status(), 503);
+	assert_eq!(res.headers()["retry-after"], "60");
+	let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+	assert!(String::from_utf8_lossy(&body).contains("Reddit is currently unavailable"));
+
+	let res = error(
+		request("application/json"),
+		"Reddit rate limit exceeded. Try refreshing in a few seconds. Rate limit will reset in: 30",
+	)
+	.await
+	.unwrap();
+	assert_eq!(res.status(), 429);
+	assert_eq!(res.headers()["retry-after"], "30");
+
+	// Errors of the page itself are unchanged
+	let res = error(request("text/html"), "Failed to parse page JSON data: expected value | /r/rust.json").await.unwrap();
+	assert_eq!(res.status(), 404);
+}
@@ -0,0 +1,44 @@
+// @license http://www.gnu.org/licenses/agpl-3.0.html AGPL-3.0
+// Suggests subreddits matching what is typed in the search box.
+(function () {
+    // Milliseconds to wait after the last key press before asking for suggestions
+    var DELAY = 300;
+    var MIN_LENGTH = 2;
+
+    var list = document.createElement("datalist");
+    list.id = "subreddit_suggestions";
+    document.body.appendChild(list);
+
+    document.querySelectorAll("input#search").forEach(function (input) {
+        input.setAttribute("list", list.id);
+        input.setAttribute("autocomplete", "off");
+        var timer = null;
+        var last = "";
+
+        input.addEventListener("input", function () {
+            clearTimeout(timer);
+            var query = input.value.trim().replace(/^\/?r\//, "");
+            // Only single words can be subreddit names
+            if (query.length < MIN_LENGTH || /\s/.test(query) || query === last) {
+                return;
+            }
+            timer = setTimeout(function () {
+                last = query;
+                fetch("/autocomplete?q=" + encodeURIComponent(query))
+                    .then(function (res) {
+                        return res.ok ? res.json() : [];
+                    })
+                    .then(function (names) {
+                        list.replaceChildren();
+                        names.forEach(function (name) {
+                            var option = document.createElement("option");
+                            option.value = "r/" + name;
+                            list.appendChild(option);
+                        });
+                    })
+                    .catch(function () {});
+            }, DELAY);
+        });
+    });
+})();
+// @license-end
@@ -0,0 +1,110 @@
+// @license http://www.gnu.org/licenses/agpl-3.0.html AGPL-3.0
+// Loads the next page of a listing when scrolling to its end, following the
+// "next" link that is used for pagination when JavaScript is disabled.
+(function () {
+    var posts = document.getElementById("posts");
+    var footer = document.querySelector("#column_one > footer");
+    if (!posts || !footer || !("IntersectionObserver" in window)) {
+        return;
+    }
+
+    // Pages loaded so far are kept for the session, so that coming back to
+    // the listing shows them again and the browser can restore the scroll
+    // position
+    var KEY = "redlib_infinite_scroll:" + location.pathname + location.search;
+    // Pages kept before falling back to pagination, bounding memory and storage
+    var MAX_PAGES = 20;
+    var loading = false;
+
+    function nextLink(doc) {
+        return doc.querySelector("#column_one > footer a[accesskey='N']");
+    }
+
+    function append(html) {
+        var page = document.createElement("div");
+        page.innerHTML = html;
+        while (page.firstChild) {
+            posts.appendChild(page.firstChild);
+        }
+    }
+
+    function setNext(href) {
+        var next = nextLink(document);
+        if (href && next) {
+            next.href = href;
+        } else if (href) {
+            footer.insertAdjacentHTML("beforeend", '<a accesskey="N">NEXT</a>');
+            nextLink(document).href = href;
+        } else if (next) {
+            next.remove();
+        }
+    }
+
+    function load() {
+        try {
+            return JSON.parse(sessionStorage.getItem(KEY)) || { pages: [], next: null };
+        } catch (e) {
+            return { pages: [], next: null };
+        }
+    }
+
+    function save(state) {
+        try {
+            sessionStorage.setItem(KEY, JSON.stringify(state));
+        } catch (e) {
+            // Storage is full or disabled, so pages won't be restored
+        }
+    }
+
+    var state = load();
+    state.pages.forEach(append);
+    if (state.pages.length > 0) {
+        setNext(state.next);
+    }
+
+    function loadNext() {
+        var next = nextLink(document);
+        if (loading || !next || state.pages.length >= MAX_PAGES) {
+            return;
+        }
+        loading = true;
+        fetch(next.href, { credentials: "same-origin" })
+            .then(function (response) {
+                if (!response.ok) {
+                    throw new Error(response.status);
+                }
+                return response.text();
+            })
+            .then(function (text) {
+                var doc = new DOMParser().parseFromString(text, "text/html");
+                var page = doc.getElementById("posts");
+                if (!page) {
+                    throw new Error("No posts");
+                }
+                // Scripts aren't run when inserted, so leave them out
+                page.querySelectorAll("script").forEach(function (script) {
+                    script.remove();
+                });
+                var following = nextLink(doc);
+                var href = following ? following.href : null;
+                state.pages.push(page.innerHTML);
+                state.next = href;
+                save(state);
+                append(page.innerHTML);
+                setNext(href);
+            })
+            .catch(function () {
+                // Leave the "next" link for manual pagination
+            })
+            .finally(function () {
+                loading = false;
+            });
+    }
+
+    new IntersectionObserver(function (entries) {
+        if (entries.some(function (entry) { return entry.isIntersecting; })) {
+            loadNext();
+        }
+    }, { rootMargin: "0px 0px 600px 0px" }).observe(footer);
+})();
+// @license-end
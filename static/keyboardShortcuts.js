@@ -0,0 +1,132 @@
+// @license http://www.gnu.org/licenses/agpl-3.0.html AGPL-3.0
+// Keyboard shortcuts to move between the posts and comments marked with
+// data-nav, open them and search. "?" lists the shortcuts.
+(function () {
+    var SHORTCUTS = [
+        ["j", "Next post or comment"],
+        ["k", "Previous post or comment"],
+        ["o / Enter", "Open post, or collapse comment"],
+        ["/", "Search"],
+        ["?", "Show these shortcuts"],
+        ["Esc", "Close"],
+    ];
+    var selected = null;
+    var help = null;
+
+    // Visible items, looked up on every key press so that posts loaded while
+    // scrolling and collapsed comments are taken into account
+    function items() {
+        return Array.prototype.filter.call(document.querySelectorAll("[data-nav]"), function (item) {
+            return item.offsetParent !== null;
+        });
+    }
+
+    function select(item) {
+        if (selected) {
+            selected.classList.remove("nav_selected");
+        }
+        selected = item;
+        item.classList.add("nav_selected");
+        item.scrollIntoView({ block: "nearest" });
+    }
+
+    function move(step) {
+        var list = items();
+        if (list.length === 0) {
+            return;
+        }
+        var index = list.indexOf(selected);
+        if (index === -1) {
+            select(list[step > 0 ? 0 : list.length - 1]);
+        } else {
+            select(list[Math.min(Math.max(index + step, 0), list.length - 1)]);
+        }
+    }
+
+    function open() {
+        if (!selected) {
+            return;
+        }
+        if (selected.dataset.nav === "comment") {
+            var details = selected.querySelector("details");
+            if (details) {
+                details.open = !details.open;
+            }
+        } else if (selected.dataset.href) {
+            location.href = selected.dataset.href;
+        }
+    }
+
+    function toggleHelp() {
+        if (!help) {
+            help = document.createElement("div");
+            help.id = "shortcuts_help";
+            help.hidden = true;
+            var list = document.createElement("dl");
+            SHORTCUTS.forEach(function (shortcut) {
+                var key = document.createElement("dt");
+                key.textContent = shortcut[0];
+                var description = document.createElement("dd");
+                description.textContent = shortcut[1];
+                list.appendChild(key);
+                list.appendChild(description);
+            });
+            var title = document.createElement("h2");
+            title.textContent = "Keyboard shortcuts";
+            help.appendChild(title);
+            help.appendChild(list);
+            help.addEventListener("click", toggleHelp);
+            document.body.appendChild(help);
+        }
+        help.hidden = !help.hidden;
+    }
+
+    document.addEventListener("keydown", function (event) {
+        if (event.ctrlKey || event.metaKey || event.altKey) {
+            return;
+        }
+        // Leave keys alone while typing
+        var target = event.target;
+        if (target.isContentEditable || /^(INPUT|TEXTAREA|SELECT)$/.test(target.tagName)) {
+            if (event.key === "Escape") {
+                target.blur();
+            }
+            return;
+        }
+        switch (event.key) {
+            case "j":
+                move(1);
+                break;
+            case "k":
+                move(-1);
+                break;
+            case "o":
+            case "Enter":
+                // Enter keeps following focused links and buttons
+                if (event.key === "Enter" && target !== document.body) {
+                    return;
+                }
+                open();
+                break;
+            case "/":
+                var search = document.getElementById("search");
+                if (!search) {
+                    return;
+                }
+                search.focus();
+                break;
+            case "?":
+                toggleHelp();
+                break;
+            case "Escape":
+                if (help && !help.hidden) {
+                    toggleHelp();
+                }
+                return;
+            default:
+                return;
+        }
+        event.preventDefault();
+    });
+})();
+// @license-end
@@ -0,0 +1,73 @@
+// @license http://www.gnu.org/licenses/agpl-3.0.html AGPL-3.0
+// Shows the times marked with data-time (a UNIX timestamp) in the time zone
+// and locale picked in the settings, as they are rendered in UTC and English.
+(function () {
+    var script = document.currentScript;
+    // "local" stands for the device's own, which Intl uses when given none
+    var timeZone = script.dataset.timezone === "local" ? undefined : script.dataset.timezone || "UTC";
+    var locale = script.dataset.locale === "local" ? undefined : script.dataset.locale || "en";
+    var MINUTE = 60;
+    var HOUR = 60 * MINUTE;
+    var DAY = 24 * HOUR;
+
+    var absolute, date, relative;
+    try {
+        absolute = new Intl.DateTimeFormat(locale, {
+            year: "numeric",
+            month: "short",
+            day: "numeric",
+            hour: "2-digit",
+            minute: "2-digit",
+            second: "2-digit",
+            timeZone: timeZone,
+            timeZoneName: "short",
+        });
+        date = new Intl.DateTimeFormat(locale, { year: "numeric", month: "short", day: "numeric", timeZone: timeZone });
+        relative = new Intl.RelativeTimeFormat(locale, { style: "narrow" });
+    } catch (e) {
+        // Unknown time zone or locale, so the UTC times are left as they are
+        return;
+    }
+
+    function localise(element) {
+        var time = Number(element.dataset.time) * 1000;
+        if (!time) {
+            return;
+        }
+        // Negative for past times, positive for future ones
+        var delta = (time - Date.now()) / 1000;
+        var seconds = Math.abs(delta);
+        var text;
+        // As on the server, older times show the date rather than how long ago
+        if (seconds > 30 * DAY) {
+            text = date.format(time);
+        } else if (seconds >= DAY) {
+            text = relative.format(Math.trunc(delta / DAY), "day");
+        } else if (seconds >= HOUR) {
+            text = relative.format(Math.trunc(delta / HOUR), "hour");
+        } else {
+            text = relative.format(Math.trunc(delta / MINUTE), "minute");
+        }
+        // Keep the spacing some templates put before the time
+        element.textContent = element.textContent.match(/^\s*/)[0] + text;
+        element.title = absolute.format(time);
+    }
+
+    document.querySelectorAll("[data-time]").forEach(localise);
+
+    // Posts loaded while scrolling
+    new MutationObserver(function (mutations) {
+        mutations.forEach(function (mutation) {
+            mutation.addedNodes.forEach(function (node) {
+                if (node.nodeType !== Node.ELEMENT_NODE) {
+                    return;
+                }
+                if (node.matches("[data-time]")) {
+                    localise(node);
+                }
+                node.querySelectorAll("[data-time]").forEach(localise);
+            });
+        });
+    }).observe(document.body, { childList: true, subtree: true });
+})();
+// @license-end
@@ -0,0 +1,11 @@
+// @license http://www.gnu.org/licenses/agpl-3.0.html AGPL-3.0
+// Registers the service worker that caches the app shell.
+(function () {
+    var base = document.currentScript.dataset.base;
+    if ("serviceWorker" in navigator) {
+        navigator.serviceWorker.register(base + "/sw.js", { scope: base + "/" }).catch(function () {
+            // Without the service worker, pages are just loaded as usual
+        });
+    }
+})();
+// @license-end
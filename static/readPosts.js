@@ -0,0 +1,59 @@
+// @license http://www.gnu.org/licenses/agpl-3.0.html AGPL-3.0
+// Remembers opened posts in localStorage, and dims or hides them in listings.
+(function () {
+    var KEY = "redlib_read_posts";
+    // Oldest posts are forgotten past this many
+    var MAX_POSTS = 2000;
+    var mode = document.currentScript.dataset.mode;
+
+    function load() {
+        try {
+            var ids = JSON.parse(localStorage.getItem(KEY) || "[]");
+            return Array.isArray(ids) ? ids : [];
+        } catch (e) {
+            return [];
+        }
+    }
+
+    function save(ids) {
+        try {
+            localStorage.setItem(KEY, JSON.stringify(ids.slice(-MAX_POSTS)));
+        } catch (e) {
+            // Storage is full or disabled, so the post won't be remembered
+        }
+    }
+
+    var ids = load();
+
+    // Mark the post being viewed as read
+    var current = document.querySelector(".post.highlighted[data-id]");
+    if (current) {
+        var id = current.dataset.id;
+        ids = ids.filter(function (read) { return read !== id; });
+        ids.push(id);
+        save(ids);
+    }
+
+    // Dim or hide read posts in listings
+    var read = new Set(ids);
+    document.querySelectorAll(".post[id]").forEach(function (post) {
+        if (read.has(post.id)) {
+            post.classList.add(mode === "hide" ? "post_read_hidden" : "post_read");
+        }
+    });
+
+    var clear = document.getElementById("clear_read_posts");
+    if (clear) {
+        clear.hidden = false;
+        clear.addEventListener("click", function () {
+            try {
+                localStorage.removeItem(KEY);
+            } catch (e) {
+                // Storage is disabled, so nothing was remembered
+            }
+            clear.textContent = "Read history cleared";
+            clear.disabled = true;
+        });
+    }
+})();
+// @license-end
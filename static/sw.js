@@ -0,0 +1,64 @@
+// @license http://www.gnu.org/licenses/agpl-3.0.html AGPL-3.0
+// Service worker caching the app shell, so that pages load faster and an
+// offline page is shown without a connection. Pages and proxied media depend
+// on preferences or come from Reddit, so only the static assets below are
+// cached.
+var VERSION = "__VERSION__";
+var CACHE = "redlib-" + VERSION;
+var SHELL = [
+    "offline.html",
+    "style.css?v=" + VERSION,
+    "favicon.ico",
+    "logo.png",
+    "Inter.var.woff2",
+    "autocomplete.js",
+    "syncVideoAudio.js",
+];
+
+function shellUrl(path) {
+    return new URL(path, self.registration.scope).href;
+}
+
+self.addEventListener("install", function (event) {
+    event.waitUntil(
+        caches.open(CACHE).then(function (cache) {
+            return cache.addAll(SHELL.map(shellUrl));
+        }).then(function () {
+            return self.skipWaiting();
+        })
+    );
+});
+
+// Drop the caches of previous versions
+self.addEventListener("activate", function (event) {
+    event.waitUntil(
+        caches.keys().then(function (keys) {
+            return Promise.all(keys.filter(function (key) {
+                return key.startsWith("redlib-") && key !== CACHE;
+            }).map(function (key) {
+                return caches.delete(key);
+            }));
+        }).then(function () {
+            return self.clients.claim();
+        })
+    );
+});
+
+self.addEventListener("fetch", function (event) {
+    var request = event.request;
+    if (request.method !== "GET") {
+        return;
+    }
+    if (request.mode === "navigate") {
+        event.respondWith(fetch(request).catch(function () {
+            return caches.match(shellUrl("offline.html"));
+        }));
+        return;
+    }
+    if (SHELL.map(shellUrl).indexOf(request.url) !== -1) {
+        event.respondWith(caches.match(request).then(function (cached) {
+            return cached || fetch(request);
+        }));
+    }
+});
+// @license-end
@@ -0,0 +1,59 @@
+// @license http://www.gnu.org/licenses/agpl-3.0.html AGPL-3.0
+// Plays the separate audio track of Reddit videos in sync with them.
+(function () {
+    // Drift in seconds tolerated before the audio is seeked back in line
+    var MAX_DRIFT = 0.3;
+
+    document.querySelectorAll("video[data-audio]").forEach(function (video) {
+        var audio = new Audio();
+        audio.preload = "none";
+        // Videos without a sound track get an error, after which they play silently
+        var failed = false;
+        audio.addEventListener("error", function () {
+            failed = true;
+        });
+
+        function sync() {
+            if (Math.abs(audio.currentTime - video.currentTime) > MAX_DRIFT) {
+                audio.currentTime = video.currentTime;
+            }
+        }
+
+        function play() {
+            if (failed) {
+                return;
+            }
+            if (!audio.src) {
+                audio.src = video.dataset.audio;
+            }
+            audio.volume = video.volume;
+            audio.muted = video.muted;
+            audio.playbackRate = video.playbackRate;
+            sync();
+            audio.play().catch(function () {});
+        }
+
+        video.addEventListener("play", play);
+        video.addEventListener("playing", play);
+        video.addEventListener("pause", function () {
+            audio.pause();
+        });
+        video.addEventListener("waiting", function () {
+            audio.pause();
+        });
+        video.addEventListener("seeked", sync);
+        video.addEventListener("timeupdate", function () {
+            if (!video.paused && audio.src) {
+                sync();
+            }
+        });
+        video.addEventListener("volumechange", function () {
+            audio.volume = video.volume;
+            audio.muted = video.muted;
+        });
+        video.addEventListener("ratechange", function () {
+            audio.playbackRate = video.playbackRate;
+        });
+    });
+})();
+// @license-end